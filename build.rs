@@ -0,0 +1,13 @@
+fn main() {
+    // Generated gRPC code is only needed by the `grpc` feature
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/mini_kv.proto");
+        // Use the vendored protoc unless the caller points at their own
+        if std::env::var_os("PROTOC").is_none() {
+            let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+            std::env::set_var("PROTOC", protoc);
+        }
+        tonic_build::compile_protos("proto/mini_kv.proto").expect("compiling proto/mini_kv.proto");
+    }
+}
@@ -0,0 +1,38 @@
+//! Arbitrary bytes as a log file: scanning it must account for every byte,
+//! and an engine must either refuse to open it or recover something it can
+//! then write to and reopen
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mini_kv::{Engine, Format, MemStorage, RecoveryMode, ScanItem, Scanner};
+
+const PATH: &str = "/fuzz/data.db";
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((format, start)) = Format::parse(data) {
+        let mut pos = start;
+        for item in Scanner::with_format(data, start, &format) {
+            let (offset, len) = match item {
+                ScanItem::Valid { offset, size, .. } | ScanItem::Unreadable { offset, size, .. } => (offset, size),
+                ScanItem::Corrupt { offset, len, .. } => (offset, len),
+            };
+            // Items follow on from each other, but for a segment footer
+            // the scanner skips
+            assert!(offset as usize >= pos);
+            pos = offset as usize + len;
+        }
+        assert!(pos <= data.len());
+    }
+
+    for mode in [RecoveryMode::TruncateTail, RecoveryMode::Strict, RecoveryMode::SkipCorrupt] {
+        let storage = MemStorage::with_file(PATH, data.to_vec());
+        let Ok(mut engine) = Engine::builder(PATH).storage(storage.clone()).recovery_mode(mode).open() else { continue };
+        let _ = engine.scan_prefix(b"");
+        let _ = engine.verify();
+        engine.put(b"after".to_vec(), b"recovery".to_vec()).unwrap();
+        engine.sync().unwrap();
+        drop(engine);
+        let engine = Engine::builder(PATH).storage(storage).recovery_mode(mode).open().unwrap();
+        assert_eq!(engine.get(b"after").unwrap().as_deref(), Some(&b"recovery"[..]));
+    }
+});
@@ -0,0 +1,49 @@
+/* C interface to mini-kv. Link against the cdylib built by `cargo build`
+ * (libmini_kv.so / libmini_kv.dylib / mini_kv.dll). */
+#ifndef MINI_KV_H
+#define MINI_KV_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MINI_KV_OK                    0
+#define MINI_KV_NOT_FOUND             1   /* get/delete: key does not exist */
+#define MINI_KV_ERR_INVALID_ARGUMENT (-1)
+#define MINI_KV_ERR_IO               (-2)
+#define MINI_KV_ERR_CORRUPTION       (-3)
+#define MINI_KV_ERR_INTERNAL         (-4)
+#define MINI_KV_ERR_DISK_FULL        (-5)   /* delete keys and compact to free space */
+#define MINI_KV_ERR_QUOTA_EXCEEDED   (-6)   /* write would exceed max_size */
+#define MINI_KV_ERR_POISONED         (-7)   /* a sync failed; reopen the database */
+
+typedef struct MiniKv MiniKv;
+
+/* Open or create the database at `path` (fsync after every write). */
+int mini_kv_open(const char *path, MiniKv **out);
+
+int mini_kv_put(MiniKv *db, const uint8_t *key, size_t key_len,
+                const uint8_t *value, size_t value_len);
+
+/* On MINI_KV_OK, *value_out must be released with mini_kv_free_value. */
+int mini_kv_get(MiniKv *db, const uint8_t *key, size_t key_len,
+                uint8_t **value_out, size_t *value_len_out);
+void mini_kv_free_value(uint8_t *value, size_t len);
+
+int mini_kv_delete(MiniKv *db, const uint8_t *key, size_t key_len);
+int mini_kv_sync(MiniKv *db);
+
+/* Syncs and snapshots the index, then frees the handle (even on failure). */
+int mini_kv_close(MiniKv *db);
+
+/* Last error on this thread, or NULL; valid until the next call. */
+const char *mini_kv_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MINI_KV_H */
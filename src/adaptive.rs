@@ -0,0 +1,65 @@
+//! `SyncMode::Adaptive`: a batch size tuned, as writes come in, to how
+//! long the disk takes to fsync and how fast puts arrive
+//!
+//! A write waits for the rest of its batch and then for the fsync, so with
+//! puts `gap` apart and fsyncs taking `fsync`, a batch of `n` leaves a
+//! write unsynced for about `(n - 1) * gap + fsync`. The batch is the
+//! largest `n` keeping that within `max_latency`, out of running averages
+//! of both. A deadline on the oldest unsynced write still syncs early when
+//! puts slow down.
+
+use std::time::{Duration, Instant};
+
+/// Weight of each new sample in the running averages
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Default)]
+pub(crate) struct AdaptiveSync {
+    /// Running averages in seconds, from the first sample of each on
+    fsync_secs: Option<f64>,
+    gap_secs: Option<f64>,
+    last_put: Option<Instant>,
+    /// When the first write not yet synced was made
+    oldest_unsynced: Option<Instant>,
+}
+
+fn average(current: Option<f64>, sample: f64) -> f64 {
+    match current {
+        Some(current) => current + SMOOTHING * (sample - current),
+        None => sample,
+    }
+}
+
+impl AdaptiveSync {
+    /// Count a put made at `now`, with `unsynced` writes (this one
+    /// included) waiting for a sync; whether to sync now
+    pub(crate) fn put(&mut self, now: Instant, unsynced: usize, max_latency: Duration) -> bool {
+        if let Some(last) = self.last_put.replace(now) {
+            self.gap_secs = Some(average(self.gap_secs, now.duration_since(last).as_secs_f64()));
+        }
+        let oldest = *self.oldest_unsynced.get_or_insert(now);
+        let waited = now.duration_since(oldest).as_secs_f64() + self.fsync_secs.unwrap_or(0.0);
+        unsynced >= self.batch(max_latency) || waited >= max_latency.as_secs_f64()
+    }
+
+    /// Count a sync, of `took` if it was timed; everything written before
+    /// it is durable, or on its way to be
+    pub(crate) fn synced(&mut self, took: Option<Duration>) {
+        self.oldest_unsynced = None;
+        if let Some(took) = took {
+            self.fsync_secs = Some(average(self.fsync_secs, took.as_secs_f64()));
+        }
+    }
+
+    /// Writes to sync after so that none waits longer than `max_latency`
+    pub(crate) fn batch(&self, max_latency: Duration) -> usize {
+        match (self.fsync_secs, self.gap_secs) {
+            (Some(fsync), Some(gap)) if max_latency.as_secs_f64() > fsync => {
+                (((max_latency.as_secs_f64() - fsync) / gap) as usize).saturating_add(1)
+            }
+            // Until both are measured, and on a disk slower than the bound,
+            // the closest it gets is syncing every write
+            _ => 1,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Archiving settled table files to a second directory, for
+//! `EngineBuilder::archive`
+//!
+//! Once the compactions after a flush are done, `EngineKind::Lsm` copies
+//! the tables its `ArchivePolicy` picks into the archive directory, makes
+//! them durable there, and marks them archived in the `MANIFEST`. With
+//! `ArchiveMode::Move` it then deletes the local file and reads the table
+//! from the archive from then on; with `ArchiveMode::Copy` it keeps reading
+//! the local file, and falls back to the archive if that is gone at open.
+//! A compaction that merges an archived table deletes it from the archive
+//! like any other input, so the archive holds what the manifest names and
+//! nothing more. Each archive directory belongs to one store.
+//!
+//! Tables are archived under their own file names, next to a `CATALOG`
+//! describing them, replaced atomically whenever the set changes, so the
+//! archive can be understood without the store:
+//!
+//! ```text
+//! CATALOG: magic "MKVC"(4) | count(4) | { seq(8) | level(4) | size(8)
+//!          | records(8) | first_len(4) | first | last_len(4) | last }*
+//!          | crc32(4)
+//! ```
+//!
+//! With the `s3` feature, `ArchivePolicy::remote` names an object store the
+//! archive is uploaded to as well: each table before the manifest marks it
+//! archived, and the catalog whenever it is replaced, under
+//! `<prefix>archive/`. The archive directory then serves as a cache of the
+//! bucket: an archived table missing from it, and from the store's own
+//! directory, is downloaded when the store opens instead of failing it, so
+//! a node can drop its archive to reclaim space or start from the backup
+//! of another.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compaction::TableInfo;
+use crate::engine::sync_parent_dir;
+#[cfg(feature = "s3")]
+use crate::s3::{S3Client, S3Config};
+
+const CATALOG_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x43]; // "MKVC"
+pub(crate) const CATALOG: &str = "CATALOG";
+
+/// What happens to the local file of an archived table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveMode {
+    /// Delete it, and read the table from the archive
+    Move,
+    /// Keep it and read from it; the archive holds a copy
+    Copy,
+}
+
+/// Which tables `EngineBuilder::archive` moves to the archive, and where
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivePolicy {
+    pub dir: PathBuf,
+    pub mode: ArchiveMode,
+    /// Tables at this level or deeper are archived. `Leveled` rarely
+    /// rewrites its deepest levels; `SizeTiered` keeps every table at
+    /// level 0, so use `min_size` to pick its large, settled ones.
+    pub min_level: u32,
+    /// Tables smaller than this stay local
+    pub min_size: u64,
+    /// Object store to upload archived tables to and fetch missing ones
+    /// from
+    #[cfg(feature = "s3")]
+    pub remote: Option<S3Config>,
+}
+
+impl ArchivePolicy {
+    /// Move tables of level 1 and deeper, of any size, to `dir`
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            mode: ArchiveMode::Move,
+            min_level: 1,
+            min_size: 0,
+            #[cfg(feature = "s3")]
+            remote: None,
+        }
+    }
+
+    /// Whether a table not yet archived should be
+    pub(crate) fn picks(&self, level: u32, size: u64) -> bool {
+        level >= self.min_level && size >= self.min_size
+    }
+
+    /// Upload `path`, a table or the catalog in the archive, to the
+    /// remote, if there is one
+    pub(crate) fn upload(&self, path: &Path) -> Result<()> {
+        #[cfg(feature = "s3")]
+        if let Some(config) = &self.remote {
+            S3Client::new(config.clone()).upload_file(&remote_key(path), path)?;
+        }
+        #[cfg(not(feature = "s3"))]
+        let _ = path;
+        Ok(())
+    }
+
+    /// Download the remote's copy of archive file `path`; false if there
+    /// is no remote or it lacks the file
+    pub(crate) fn fetch(&self, path: &Path) -> Result<bool> {
+        #[cfg(feature = "s3")]
+        if let Some(config) = &self.remote {
+            fs::create_dir_all(&self.dir)?;
+            return S3Client::new(config.clone()).download_file(&remote_key(path), path);
+        }
+        #[cfg(not(feature = "s3"))]
+        let _ = path;
+        Ok(false)
+    }
+
+    /// Delete the remote's copy of archive file `path`, if there is one;
+    /// like the local copy, one left behind is only wasted space
+    pub(crate) fn remove_remote(&self, path: &Path) {
+        #[cfg(feature = "s3")]
+        if let Some(config) = &self.remote {
+            let _ = S3Client::new(config.clone()).delete(&remote_key(path));
+        }
+        #[cfg(not(feature = "s3"))]
+        let _ = path;
+    }
+}
+
+/// Object key of archive file `path`
+#[cfg(feature = "s3")]
+fn remote_key(path: &Path) -> String {
+    format!("archive/{}", path.file_name().unwrap_or_default().to_string_lossy())
+}
+
+/// Copy `from` to `to` through a temporary file, durably, replacing any
+/// file at `to`
+pub(crate) fn copy_durably(from: &Path, to: &Path) -> Result<()> {
+    let mut tmp_path = to.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::copy(from, &tmp_path).with_context(|| format!("copying {} to {}", from.display(), tmp_path.display()))?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, to)?;
+    Ok(())
+}
+
+/// Replace the catalog in `dir` with one describing `tables`
+pub(crate) fn write_catalog(dir: &Path, tables: &[TableInfo]) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&CATALOG_MAGIC);
+    buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+    for table in tables {
+        buf.extend_from_slice(&table.id.to_le_bytes());
+        buf.extend_from_slice(&table.level.to_le_bytes());
+        buf.extend_from_slice(&table.size.to_le_bytes());
+        buf.extend_from_slice(&table.records.to_le_bytes());
+        for key in [&table.first_key, &table.last_key] {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+        }
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let path = dir.join(CATALOG);
+    let tmp_path = dir.join(format!("{}.tmp", CATALOG));
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_parent_dir(&path)?;
+    Ok(())
+}
+
+/// The tables the catalog in archive directory `dir` describes; none if it
+/// has no catalog yet
+pub fn read_catalog(dir: impl AsRef<Path>) -> Result<Vec<TableInfo>> {
+    let path = dir.as_ref().join(CATALOG);
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    if buf.len() < 12 || !buf.starts_with(&CATALOG_MAGIC) {
+        bail!("{} is not an archive catalog", path.display());
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        bail!("archive catalog CRC mismatch");
+    }
+    let count = u32::from_le_bytes(body[4..8].try_into().unwrap());
+    let mut rest = &body[8..];
+    let mut take = |len: usize| -> Result<&[u8]> {
+        let taken = rest.get(..len).ok_or_else(|| anyhow!("archive catalog truncated"))?;
+        rest = &rest[len..];
+        Ok(taken)
+    };
+    let mut tables = Vec::new();
+    for _ in 0..count {
+        let id = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let level = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let size = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let records = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let first_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let first_key = take(first_len)?.to_vec();
+        let last_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let last_key = take(last_len)?.to_vec();
+        tables.push(TableInfo { id, level, size, records, first_key, last_key });
+    }
+    if !rest.is_empty() {
+        bail!("archive catalog has bytes after its last table");
+    }
+    Ok(tables)
+}
@@ -1,10 +1,43 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use mini_kv::{Engine, SyncMode};
-use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::fs;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[path = "bench/alloc.rs"]
+mod alloc;
+#[path = "bench/distribution.rs"]
+mod distribution;
+#[path = "bench/latency.rs"]
+mod latency;
+#[path = "bench/options.rs"]
+mod options;
+#[path = "bench/report.rs"]
+mod report;
+#[path = "bench/store.rs"]
+mod store;
+#[path = "bench/ycsb.rs"]
+mod ycsb;
+use distribution::{KeyChooser, KeyDistribution};
+use latency::Latencies;
+use options::{Action, BenchArgs, Mix};
+use report::{Output, Row};
+use store::{Backend, Io, Store};
+
+#[global_allocator]
+static ALLOCATOR: alloc::Counting = alloc::Counting;
+
+/// Timed opens of each kind in a recovery run
+const RECOVERY_OPENS: usize = 5;
+use ycsb::{Keys, Op, Preset};
 
 struct BenchConfig {
     name: String,
+    backend: Backend,
     sync_mode: SyncMode,
     record_size: usize,
     count: usize,
@@ -13,106 +46,595 @@ struct BenchConfig {
 struct BenchResult {
     throughput: f64,
     total_time: Duration,
-    latencies: Vec<Duration>,
+    latencies: Latencies,
+    io: Io,
+    /// Most heap held at once, for phases that measure it
+    peak_heap: Option<u64>,
+    /// Bytes the log shrank by, for compactions
+    space_reclaimed: Option<u64>,
+    /// Footprint of the store at the end of a run, for its `disk` row
+    disk: Option<Disk>,
+}
+
+#[derive(Clone, Copy)]
+struct Disk {
+    bytes: u64,
+    records: usize,
+    logical_bytes: u64,
 }
 
-fn run_bench(config: &BenchConfig) -> Result<BenchResult> {
+/// Each phase of a run and how it did: the puts, then gets, then each mix
+fn run_bench(config: &BenchConfig, args: &BenchArgs) -> Result<Vec<(String, BenchResult)>> {
     let path = format!("bench_{}.db", config.name);
     
     // 清理旧文件
-    let _ = fs::remove_file(&path);
-    
-    let mut engine = Engine::with_sync(&path, config.sync_mode)?;
+    let mut engine = store::create(config.backend, &path, config.sync_mode)?;
     
     // 预热
-    for i in 0..1000 {
+    for i in 0..args.warmup {
         let key = format!("warmup_{}", i).into_bytes();
         let value = vec![0u8; config.record_size];
         engine.put(key, value)?;
     }
+    // Any other distribution overwrites, so every key exists to begin with
+    let count = config.count as u64;
+    if args.distribution != KeyDistribution::Sequential {
+        for i in 0..count {
+            engine.put(format!("key{}", i).into_bytes(), vec![0u8; config.record_size])?;
+        }
+    }
     
-    let mut latencies = Vec::with_capacity(config.count);
+    let mut latencies = latency::new();
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut chooser = KeyChooser::new(args.distribution, count);
     
+    let before = engine.io();
     let start = Instant::now();
     
     for i in 0..config.count {
-        let key = format!("key{}", i).into_bytes();
+        let key = format!("key{}", chooser.next(&mut rng)).into_bytes();
         // 用固定值代替随机值
         let value = vec![(i % 256) as u8; config.record_size];
         
         let op_start = Instant::now();
         engine.put(key, value)?;
-        latencies.push(op_start.elapsed());
+        latency::record(&mut latencies, op_start.elapsed());
     }
     
     let total_time = start.elapsed();
+    let io = engine.io().since(before);
     
     // 强制sync剩余数据
     engine.sync()?;
+    let puts = bench_result(latencies, total_time, io);
     
-    Ok(BenchResult {
-        throughput: config.count as f64 / total_time.as_secs_f64(),
-        total_time,
-        latencies,
-    })
+    // Reopened, the engine's caches are empty; the OS page cache is not
+    drop(engine);
+    let mut engine = store::open(config.backend, &path, config.sync_mode)?;
+    let mut chooser = KeyChooser::over_existing(args.distribution, count);
+    let keys: Vec<_> = (0..config.count).map(|_| format!("key{}", chooser.next(&mut rng)).into_bytes()).collect();
+    let cold = run_gets(&*engine, &keys, true)?;
+    let warm = run_gets(&*engine, &keys, true)?;
+    let missing: Vec<_> = (0..config.count).map(|i| format!("missing{}", i).into_bytes()).collect();
+    let missing = run_gets(&*engine, &missing, false)?;
+    
+    let mut phases: Vec<_> = [("put", puts), ("get_cold", cold), ("get_warm", warm), ("get_missing", missing)]
+        .into_iter()
+        .map(|(op, result)| (op.to_string(), result))
+        .collect();
+    for &mix in &args.mixes {
+        let before = engine.io();
+        let (gets, puts, total_time) = if args.concurrent {
+            run_mix_concurrent(&mut *engine, config, mix, args.distribution)?
+        } else {
+            run_mix(&mut *engine, config, mix, args.distribution, &mut rng)?
+        };
+        // Only the puts write
+        let io = engine.io().since(before);
+        let all = latency::merge([gets.clone(), puts.clone()]);
+        phases.push((mix.name(), bench_result(all, total_time, io)));
+        phases.push((format!("{}:get", mix.name()), bench_result(gets, total_time, io.zeroed())));
+        phases.push((format!("{}:put", mix.name()), bench_result(puts, total_time, io)));
+    }
+    phases.push(("disk".to_string(), disk_result(engine, &path)?));
+    
+    Ok(phases)
+}
+
+/// The footprint of `engine`'s store at `path`, once closed
+fn disk_result(engine: Box<dyn Store>, path: &str) -> Result<BenchResult> {
+    let (records, logical_bytes) = engine.live()?;
+    drop(engine);
+    let mut result = bench_result(latency::new(), Duration::ZERO, Io::default());
+    result.throughput = 0.0;
+    result.disk = Some(Disk { bytes: store::disk_usage(path)?, records, logical_bytes });
+    Ok(result)
 }
 
-fn percentile(latencies: &[Duration], p: f64) -> Duration {
-    if latencies.is_empty() {
-        return Duration::from_nanos(0);
+/// Gets and puts of existing keys picked by `distribution`, `mix.reads`
+/// percent of them gets, one after another; returns the latencies of each
+/// and the time taken
+fn run_mix(
+    engine: &mut dyn Store,
+    config: &BenchConfig,
+    mix: Mix,
+    distribution: KeyDistribution,
+    rng: &mut StdRng,
+) -> Result<(Latencies, Latencies, Duration)> {
+    let mut chooser = KeyChooser::over_existing(distribution, config.count as u64);
+    let mut gets = latency::new();
+    let mut puts = latency::new();
+    
+    let start = Instant::now();
+    
+    for i in 0..config.count {
+        let key = format!("key{}", chooser.next(rng)).into_bytes();
+        if rng.gen_range(0..100) < mix.reads {
+            let op_start = Instant::now();
+            engine.get(&key)?;
+            latency::record(&mut gets, op_start.elapsed());
+        } else {
+            let value = vec![(i % 256) as u8; config.record_size];
+            let op_start = Instant::now();
+            engine.put(key, value)?;
+            latency::record(&mut puts, op_start.elapsed());
+        }
     }
     
-    let mut sorted: Vec<_> = latencies.iter().map(|d| d.as_nanos()).collect();
-    sorted.sort_unstable();
+    Ok((gets, puts, start.elapsed()))
+}
+
+/// `run_mix`, but with the gets on one thread and the puts on another,
+/// both at once. Each waits for the engine while the other has it, so a
+/// get can wait out a put's fsync.
+fn run_mix_concurrent(
+    engine: &mut dyn Store,
+    config: &BenchConfig,
+    mix: Mix,
+    distribution: KeyDistribution,
+) -> Result<(Latencies, Latencies, Duration)> {
+    let reads = config.count * mix.reads as usize / 100;
+    let writes = config.count - reads;
+    let engine = Mutex::new(engine);
     
-    let idx = (p * sorted.len() as f64).floor() as usize;
-    let idx = idx.min(sorted.len() - 1);
+    let start = Instant::now();
     
-    Duration::from_nanos(sorted[idx] as u64)
+    let (gets, puts) = std::thread::scope(|s| {
+        let reader = s.spawn(|| -> Result<Latencies> {
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut chooser = KeyChooser::over_existing(distribution, config.count as u64);
+            let mut gets = latency::new();
+            for _ in 0..reads {
+                let key = format!("key{}", chooser.next(&mut rng)).into_bytes();
+                let op_start = Instant::now();
+                engine.lock().unwrap().get(&key)?;
+                latency::record(&mut gets, op_start.elapsed());
+            }
+            Ok(gets)
+        });
+        let writer = s.spawn(|| -> Result<Latencies> {
+            let mut rng = StdRng::seed_from_u64(2);
+            let mut chooser = KeyChooser::over_existing(distribution, config.count as u64);
+            let mut puts = latency::new();
+            for i in 0..writes {
+                let key = format!("key{}", chooser.next(&mut rng)).into_bytes();
+                let value = vec![(i % 256) as u8; config.record_size];
+                let op_start = Instant::now();
+                engine.lock().unwrap().put(key, value)?;
+                latency::record(&mut puts, op_start.elapsed());
+            }
+            Ok(puts)
+        });
+        (reader.join().unwrap(), writer.join().unwrap())
+    });
+    
+    Ok((gets?, puts?, start.elapsed()))
 }
 
-fn main() -> Result<()> {
-    println!("mode,record_size,count,total_time_ms,throughput,p50_ns,p99_ns,p999_ns");
-    
-    let configs = vec![
-        ("always_128b", SyncMode::Always, 128, 10_000),
-        ("batch100_128b", SyncMode::Batch(100), 128, 10_000),
-        ("batch1000_128b", SyncMode::Batch(1000), 128, 10_000),
-        ("periodic_10ms", SyncMode::Periodic(Duration::from_millis(10)), 128, 10_000),
-        ("periodic_100ms", SyncMode::Periodic(Duration::from_millis(100)), 128, 10_000),
-    ];
-    
-    for (name, mode, size, count) in configs {
-        let config = BenchConfig {
-            name: name.to_string(),
-            sync_mode: mode,
-            record_size: size,
-            count,
+/// YCSB workload `preset`: load `count` records, then run `count` ops.
+/// Returns the load, all the ops, and each kind of op in the mix.
+fn run_ycsb(config: &BenchConfig, preset: &Preset) -> Result<Vec<(String, BenchResult)>> {
+    let path = format!("bench_{}_ycsb_{}.db", config.name, preset.name);
+    let mut engine = store::create(config.backend, &path, config.sync_mode)?;
+    let mut rng = StdRng::seed_from_u64(0);
+    let value = |rng: &mut StdRng| (0..config.record_size).map(|_| rng.gen()).collect::<Vec<u8>>();
+    
+    let mut latencies = latency::new();
+    let before = engine.io();
+    let start = Instant::now();
+    for i in 0..config.count as u64 {
+        let value = value(&mut rng);
+        let op_start = Instant::now();
+        engine.put(ycsb::key(i), value)?;
+        latency::record(&mut latencies, op_start.elapsed());
+    }
+    let load = bench_result(latencies, start.elapsed(), engine.io().since(before));
+    
+    let mut keys = Keys::new(config.count as u64, preset.choice);
+    let mut latencies: Vec<Latencies> = preset.mix.iter().map(|_| latency::new()).collect();
+    let before = engine.io();
+    let start = Instant::now();
+    for _ in 0..config.count {
+        let op = preset.next_op(&mut rng);
+        let key = match preset.mix[op].0 {
+            Op::Insert => keys.insert(),
+            _ => keys.existing(&mut rng),
         };
+        let value = value(&mut rng);
+        let op_start = Instant::now();
+        match preset.mix[op].0 {
+            Op::Read => {
+                engine.get(&ycsb::key(key))?;
+            }
+            Op::Update | Op::Insert => engine.put(ycsb::key(key), value)?,
+            Op::Scan => {
+                engine.scan_prefix(&ycsb::scan_prefix(key))?;
+            }
+            Op::ReadModifyWrite => {
+                engine.get(&ycsb::key(key))?;
+                engine.put(ycsb::key(key), value)?;
+            }
+        }
+        latency::record(&mut latencies[op], op_start.elapsed());
+    }
+    let total_time = start.elapsed();
+    let io = engine.io().since(before);
+    
+    let name = format!("ycsb-{}", preset.name);
+    let mut phases = vec![(format!("{}:load", name), load)];
+    phases.push((name.clone(), bench_result(latency::merge(latencies.clone()), total_time, io)));
+    // No preset has more than one kind of op that writes
+    for (&(op, _), latencies) in preset.mix.iter().zip(latencies) {
+        let io = if op.writes() { io } else { io.zeroed() };
+        phases.push((format!("{}:{}", name, op.name()), bench_result(latencies, total_time, io)));
+    }
+    phases.push((format!("{}:disk", name), disk_result(engine, &path)?));
+    Ok(phases)
+}
+
+/// Puts of new keys, then gets of random written ones, on 1 to `threads`
+/// threads sharing the engine behind a mutex. Each thread count runs
+/// `count` ops in all, split evenly, so a row's throughput is the
+/// aggregate and its latencies are each op's, whichever thread ran it.
+fn run_threads(config: &BenchConfig, args: &BenchArgs, threads: usize) -> Result<Vec<(String, BenchResult)>> {
+    let path = format!("bench_{}_threads.db", config.name);
+    let mut engine = store::create(config.backend, &path, config.sync_mode)?;
+    let engine = Mutex::new(&mut *engine);
+    let mut phases = Vec::new();
+    
+    for n in 1..=threads {
+        let before = engine.lock().unwrap().io();
+        let mut puts = on_threads(n, config.count / n, |thread| {
+            let engine = &engine;
+            move |i| {
+                let key = format!("key{}_{}_{}", n, thread, i).into_bytes();
+                let value = vec![(i % 256) as u8; config.record_size];
+                let op_start = Instant::now();
+                engine.lock().unwrap().put(key, value)?;
+                Ok(op_start.elapsed())
+            }
+        })?;
+        puts.io = engine.lock().unwrap().io().since(before);
+        phases.push((format!("put@{}", n), puts));
+    }
+    for n in 1..=threads {
+        let before = engine.lock().unwrap().io();
+        let mut gets = on_threads(n, config.count / n, |thread| {
+            let engine = &engine;
+            let mut rng = StdRng::seed_from_u64(thread as u64);
+            let mut chooser = KeyChooser::over_existing(args.distribution, config.count as u64);
+            move |_| {
+                // Of the keys the single thread wrote
+                let key = format!("key1_0_{}", chooser.next(&mut rng)).into_bytes();
+                let op_start = Instant::now();
+                let value = engine.lock().unwrap().get(&key)?;
+                let elapsed = op_start.elapsed();
+                if value.is_none() {
+                    bail!("get of {} found nothing", String::from_utf8_lossy(&key));
+                }
+                Ok(elapsed)
+            }
+        })?;
+        gets.io = engine.lock().unwrap().io().since(before);
+        phases.push((format!("get@{}", n), gets));
+    }
+    
+    Ok(phases)
+}
+
+/// Run an op `per_thread` times on each of `threads` threads at once.
+/// `thread_ops` makes thread i's op, which takes the op's number and
+/// returns the latency it measured.
+fn on_threads<F>(threads: usize, per_thread: usize, thread_ops: impl Fn(usize) -> F + Sync) -> Result<BenchResult>
+where
+    F: FnMut(usize) -> Result<Duration> + Send,
+{
+    let start = Instant::now();
+    let latencies = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+                let mut op = thread_ops(thread);
+                s.spawn(move || -> Result<Latencies> {
+                    let mut latencies = latency::new();
+                    for i in 0..per_thread {
+                        latency::record(&mut latencies, op(i)?);
+                    }
+                    Ok(latencies)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Result<Vec<_>>>()
+    })?;
+    Ok(bench_result(latency::merge(latencies), start.elapsed(), Io::default()))
+}
+
+/// Opens of a log of `count` records: with the index snapshot the last
+/// close left (`open`), and for mini-kv without it, scanning the whole log
+/// as after a crash (`open_scan`). Each is timed `RECOVERY_OPENS` times;
+/// one more open measures its peak heap, since counting allocations would
+/// slow the timed ones.
+fn run_recovery(config: &BenchConfig) -> Result<Vec<(String, BenchResult)>> {
+    let path = format!("bench_{}_recovery.db", config.name);
+    let mut engine = store::create(config.backend, &path, config.sync_mode)?;
+    for i in 0..config.count {
+        engine.put(format!("key{}", i).into_bytes(), vec![(i % 256) as u8; config.record_size])?;
+    }
+    engine.sync()?;
+    drop(engine);
+    
+    let mut kinds = vec![("open", false)];
+    if config.backend == Backend::MiniKv {
+        kinds.push(("open_scan", true));
+    }
+    let mut phases = Vec::new();
+    for (op, scan) in kinds {
+        let open = || {
+            if scan {
+                let _ = fs::remove_file(format!("{}.index", path));
+            }
+            store::open(config.backend, &path, config.sync_mode)
+        };
+        let mut latencies = latency::new();
+        let mut total_time = Duration::ZERO;
+        let mut io = Io::default();
+        for _ in 0..RECOVERY_OPENS {
+            let op_start = Instant::now();
+            let engine = open()?;
+            let elapsed = op_start.elapsed();
+            latency::record(&mut latencies, elapsed);
+            total_time += elapsed;
+            // What the open itself synced and wrote
+            io = engine.io();
+        }
+        let (engine, peak_heap) = alloc::peak_during(open);
+        drop(engine?);
         
-        match run_bench(&config) {
-            Ok(result) => {
-                let p50 = percentile(&result.latencies, 0.5);
-                let p99 = percentile(&result.latencies, 0.99);
-                let p999 = percentile(&result.latencies, 0.999);
-                
-                println!(
-                    "{},{},{},{:.2},{:.2},{},{},{}",
-                    name,
-                    size,
-                    count,
-                    result.total_time.as_millis(),
-                    result.throughput,
-                    p50.as_nanos(),
-                    p99.as_nanos(),
-                    p999.as_nanos(),
-                );
+        let mut result = bench_result(latencies, total_time, io);
+        // Records recovered a second
+        result.throughput = (config.count * RECOVERY_OPENS) as f64 / total_time.as_secs_f64();
+        // RocksDB allocates in C++, out of the counting allocator's sight
+        result.peak_heap = (config.backend != Backend::RocksDb).then_some(peak_heap);
+        phases.push((op.to_string(), result));
+    }
+    
+    Ok(phases)
+}
+
+/// For each share of garbage: a log of `count` live records, with enough
+/// overwrites of them that dead records are that share of it, compacted
+/// while another thread puts new keys through the same mutex, as on a
+/// `SharedEngine`. Rows for the compaction (`compact@<share>`), the puts
+/// it overlapped (`:put`), which waited on it, and the puts before it
+/// (`:put_before`) to compare them with.
+fn run_compaction(config: &BenchConfig, garbage: &[f64]) -> Result<Vec<(String, BenchResult)>> {
+    let mut phases = Vec::new();
+    for &share in garbage {
+        let path = format!("bench_{}_compaction.db", config.name);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.index", path));
+        let mut engine = Engine::with_sync(&path, config.sync_mode)?;
+        let dead = (config.count as f64 * share / (1.0 - share)).round() as usize;
+        for i in 0..config.count + dead {
+            let key = format!("key{}", i % config.count).into_bytes();
+            engine.put(key, vec![(i % 256) as u8; config.record_size])?;
+        }
+        engine.sync()?;
+        let records = config.count + dead;
+        
+        let engine = Mutex::new(engine);
+        let done = AtomicBool::new(false);
+        let puts_made = AtomicUsize::new(0);
+        let before_puts = (config.count / 100).max(100);
+        let (puts, compaction) = std::thread::scope(|s| -> Result<_> {
+            let writer = s.spawn(|| -> Result<Vec<(Instant, Duration)>> {
+                let mut puts = Vec::new();
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let value = vec![(i % 256) as u8; config.record_size];
+                    let op_start = Instant::now();
+                    engine.lock().unwrap().put(format!("new{}", i).into_bytes(), value)?;
+                    puts.push((op_start, op_start.elapsed()));
+                    puts_made.store(puts.len(), Ordering::Relaxed);
+                    i += 1;
+                }
+                Ok(puts)
+            });
+            while puts_made.load(Ordering::Relaxed) < before_puts && !writer.is_finished() {
+                std::thread::yield_now();
+            }
+            let compacted = (|| -> Result<_> {
+                let mut engine = engine.lock().unwrap();
+                let (size, before) = (engine.stats().file_size, Store::io(&*engine));
+                let start = Instant::now();
+                engine.compact()?;
+                let elapsed = start.elapsed();
+                let reclaimed = size.saturating_sub(engine.stats().file_size);
+                Ok((start, elapsed, Store::io(&*engine).since(before), reclaimed))
+            })();
+            // Let the writer make as many puts again, after it
+            let after = puts_made.load(Ordering::Relaxed) + before_puts;
+            while puts_made.load(Ordering::Relaxed) < after && !writer.is_finished() {
+                std::thread::yield_now();
+            }
+            done.store(true, Ordering::Relaxed);
+            let puts = writer.join().unwrap()?;
+            Ok((puts, compacted?))
+        })?;
+        let (start, elapsed, io, reclaimed) = compaction;
+        
+        let end = start + elapsed;
+        let mut during = latency::new();
+        let mut during_count = 0;
+        let mut before = latency::new();
+        let mut before_time = Duration::ZERO;
+        for &(op_start, latency) in &puts {
+            if op_start < end && op_start + latency > start {
+                latency::record(&mut during, latency);
+                during_count += 1;
+            } else if op_start + latency <= start {
+                latency::record(&mut before, latency);
+                before_time += latency;
+            }
+        }
+        let name = format!("compact@{}", share);
+        let mut compact = latency::new();
+        latency::record(&mut compact, elapsed);
+        let mut result = bench_result(compact, elapsed, io);
+        // Records of the log rewritten or dropped a second
+        result.throughput = records as f64 / elapsed.as_secs_f64();
+        result.space_reclaimed = Some(reclaimed);
+        phases.push((name.clone(), result));
+        let mut result = bench_result(during, elapsed, Io::default());
+        result.throughput = during_count as f64 / elapsed.as_secs_f64();
+        phases.push((format!("{}:put", name), result));
+        phases.push((format!("{}:put_before", name), bench_result(before, before_time, Io::default())));
+    }
+    Ok(phases)
+}
+
+/// Look up each of `keys`, all of which are `present` or all missing
+fn run_gets(engine: &dyn Store, keys: &[Vec<u8>], present: bool) -> Result<BenchResult> {
+    let mut latencies = latency::new();
+    
+    let before = engine.io();
+    let start = Instant::now();
+    
+    for key in keys {
+        let op_start = Instant::now();
+        let value = engine.get(key)?;
+        latency::record(&mut latencies, op_start.elapsed());
+        if value.is_some() != present {
+            bail!("get of {} returned {:?}", String::from_utf8_lossy(key), value.map(|v| v.len()));
+        }
+    }
+    
+    Ok(bench_result(latencies, start.elapsed(), engine.io().since(before)))
+}
+
+/// Throughput of the ops `latencies` were measured for, over `total_time`
+fn bench_result(latencies: Latencies, total_time: Duration, io: Io) -> BenchResult {
+    BenchResult {
+        throughput: latencies.len() as f64 / total_time.as_secs_f64(),
+        total_time,
+        latencies,
+        io,
+        peak_heap: None,
+        space_reclaimed: None,
+        disk: None,
+    }
+}
+
+fn main() -> Result<()> {
+    let action = Action::parse().unwrap_or_else(|e| {
+        eprintln!("{:#}", e);
+        std::process::exit(2);
+    });
+    let args = match action {
+        Action::Run(args) => args,
+        Action::Compare { baseline, current, threshold } => {
+            let regressions = report::compare(&baseline, &current, threshold)?;
+            if regressions > 0 {
+                eprintln!("{} phase(s) regressed by more than {}%", regressions, threshold);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    };
+    let mut out = Output::new(args.output.as_deref())?;
+    if let Some(dir) = &args.latency_curves {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    
+    let mut configs = Vec::new();
+    for &backend in &args.engines {
+        for &mode in &args.sync_modes {
+            for &size in &args.record_sizes {
+                for &count in &args.counts {
+                    let mut name = format!("{}_{}b", options::mode_name(mode), size);
+                    if args.counts.len() > 1 {
+                        name += &format!("_{}", count);
+                    }
+                    if backend != Backend::MiniKv {
+                        name = format!("{}_{}", backend.name(), name);
+                    }
+                    configs.push(BenchConfig { name, backend, sync_mode: mode, record_size: size, count });
+                }
+            }
+        }
+    }
+    
+    for config in configs {
+        let phases = if !args.compaction.is_empty() {
+            run_compaction(&config, &args.compaction)
+        } else if args.recovery {
+            run_recovery(&config)
+        } else if let Some(threads) = args.threads {
+            run_threads(&config, &args, threads)
+        } else if args.ycsb.is_empty() {
+            run_bench(&config, &args)
+        } else {
+            args.ycsb.iter().map(|preset| run_ycsb(&config, preset)).collect::<Result<Vec<_>>>().map(|runs| runs.into_iter().flatten().collect())
+        };
+        match phases {
+            Ok(phases) => {
+                if let Some(dir) = &args.latency_curves {
+                    let path = dir.join(format!("{}.csv", config.name));
+                    let mut curves = io::BufWriter::new(
+                        fs::File::create(&path).with_context(|| format!("creating {}", path.display()))?,
+                    );
+                    writeln!(curves, "{}", latency::CURVE_HEADER)?;
+                    for (op, result) in &phases {
+                        latency::write_curve(&mut curves, op, &result.latencies)?;
+                    }
+                    curves.flush()?;
+                }
+                for (op, result) in phases {
+                    out.row(Row {
+                        mode: config.name.clone(),
+                        op,
+                        record_size: config.record_size,
+                        count: result.disk.map_or(result.latencies.len() as usize, |disk| disk.records),
+                        total_time_ms: result.total_time.as_millis() as u64,
+                        throughput: result.throughput,
+                        p50_ns: result.latencies.value_at_quantile(0.5),
+                        p99_ns: result.latencies.value_at_quantile(0.99),
+                        p999_ns: result.latencies.value_at_quantile(0.999),
+                        fsyncs: result.io.fsyncs,
+                        bytes_written: result.io.bytes_written,
+                        syncs_per_op: result.io.fsyncs as f64 / result.latencies.len().max(1) as f64,
+                        peak_heap_bytes: result.peak_heap,
+                        space_reclaimed_bytes: result.space_reclaimed,
+                        disk_bytes: result.disk.map(|disk| disk.bytes),
+                        bytes_per_record: result.disk.map(|disk| disk.bytes as f64 / disk.records.max(1) as f64),
+                        space_amplification: result.disk.map(|disk| disk.bytes as f64 / disk.logical_bytes.max(1) as f64),
+                    })?;
+                }
             }
             Err(e) => {
-                eprintln!("Error running {}: {}", name, e);
+                eprintln!("Error running {}: {}", config.name, e);
             }
         }
     }
     
-    Ok(())
-}
\ No newline at end of file
+    out.finish()
+}
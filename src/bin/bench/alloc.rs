@@ -0,0 +1,70 @@
+//! The heap's high-water mark while a phase runs, from a global allocator
+//! that counts only when asked to, so other phases pay one relaxed load
+//! per allocation and share no counter between threads
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+
+pub struct Counting;
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+/// Bytes allocated less bytes freed since tracking began; below zero if
+/// more was freed than allocated
+static NET: AtomicIsize = AtomicIsize::new(0);
+static PEAK: AtomicIsize = AtomicIsize::new(0);
+
+fn grew(bytes: usize) {
+    if TRACKING.load(Ordering::Relaxed) {
+        let net = NET.fetch_add(bytes as isize, Ordering::Relaxed) + bytes as isize;
+        PEAK.fetch_max(net, Ordering::Relaxed);
+    }
+}
+
+fn shrank(bytes: usize) {
+    if TRACKING.load(Ordering::Relaxed) {
+        NET.fetch_sub(bytes as isize, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        shrank(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            grew(new_size);
+            shrank(layout.size());
+        }
+        new
+    }
+}
+
+/// Peak bytes of heap `f` held at once, on any thread, beyond what was
+/// allocated before it; with what it returned
+pub fn peak_during<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    NET.store(0, Ordering::Relaxed);
+    PEAK.store(0, Ordering::Relaxed);
+    TRACKING.store(true, Ordering::SeqCst);
+    let result = f();
+    TRACKING.store(false, Ordering::SeqCst);
+    (result, PEAK.load(Ordering::Relaxed) as u64)
+}
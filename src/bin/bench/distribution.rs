@@ -0,0 +1,143 @@
+//! Which of the keys loaded so far a bench op picks
+
+use anyhow::{bail, Result};
+use rand::Rng;
+
+/// Skew YCSB uses for its zipfian distributions
+pub const ZIPFIAN_CONSTANT: f64 = 0.99;
+/// Share of the keys that are hot under `KeyDistribution::Hotspot`, and of
+/// the ops that go to them; YCSB's defaults
+pub const HOTSPOT_KEYS: f64 = 0.2;
+pub const HOTSPOT_OPS: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDistribution {
+    /// Each key once, in order; reads pick uniformly
+    Sequential,
+    Uniform,
+    /// Scrambled zipfian, so a few keys take most ops
+    Zipfian,
+    /// Zipfian, with the highest numbered (last loaded) keys the hottest
+    Latest,
+    /// `HOTSPOT_OPS` of the ops go to the first `HOTSPOT_KEYS` of the keys
+    Hotspot,
+}
+
+impl std::str::FromStr for KeyDistribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sequential" => Ok(KeyDistribution::Sequential),
+            "uniform" => Ok(KeyDistribution::Uniform),
+            "zipfian" => Ok(KeyDistribution::Zipfian),
+            "latest" => Ok(KeyDistribution::Latest),
+            "hotspot" => Ok(KeyDistribution::Hotspot),
+            _ => bail!("unknown key distribution {:?} (expected sequential, uniform, zipfian, latest or hotspot)", s),
+        }
+    }
+}
+
+/// Picks key indexes in `0..items` as its distribution says
+pub struct KeyChooser {
+    distribution: KeyDistribution,
+    items: u64,
+    /// The next key for `Sequential`
+    next: u64,
+    zipfian: Option<Zipfian>,
+}
+
+impl KeyChooser {
+    pub fn new(distribution: KeyDistribution, items: u64) -> Self {
+        let zipfian = matches!(distribution, KeyDistribution::Zipfian | KeyDistribution::Latest).then(|| Zipfian::new(items));
+        Self { distribution, items, next: 0, zipfian }
+    }
+
+    /// A chooser among keys that all exist already, for which
+    /// `Sequential` picks uniformly instead of in order
+    pub fn over_existing(distribution: KeyDistribution, items: u64) -> Self {
+        match distribution {
+            KeyDistribution::Sequential => Self::new(KeyDistribution::Uniform, items),
+            _ => Self::new(distribution, items),
+        }
+    }
+
+    pub fn next(&mut self, rng: &mut impl Rng) -> u64 {
+        match self.distribution {
+            KeyDistribution::Sequential => {
+                self.next += 1;
+                (self.next - 1) % self.items
+            }
+            KeyDistribution::Uniform => rng.gen_range(0..self.items),
+            KeyDistribution::Zipfian => scramble(self.zipfian.as_ref().unwrap().next(rng), self.items),
+            KeyDistribution::Latest => self.items - 1 - self.zipfian.as_ref().unwrap().next(rng),
+            KeyDistribution::Hotspot => {
+                let hot = ((self.items as f64 * HOTSPOT_KEYS) as u64).clamp(1, self.items);
+                if hot == self.items || rng.gen_bool(HOTSPOT_OPS) {
+                    rng.gen_range(0..hot)
+                } else {
+                    rng.gen_range(hot..self.items)
+                }
+            }
+        }
+    }
+}
+
+/// Ranks `0..items`, rank r drawn with probability proportional to
+/// 1 / (r + 1)^theta, so rank 0 is the most popular. This is the method of
+/// Gray et al., "Quickly Generating Billion-Record Synthetic Databases", as
+/// in YCSB's `ZipfianGenerator`; `items` can grow as keys are inserted.
+pub struct Zipfian {
+    items: u64,
+    theta: f64,
+    /// Sum of 1 / i^theta for i in 1..=items
+    zeta: f64,
+    zeta2: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    pub fn new(items: u64) -> Self {
+        let theta = ZIPFIAN_CONSTANT;
+        let zeta2 = 1.0 + 0.5f64.powf(theta);
+        let mut zipfian = Self { items: 0, theta, zeta: 0.0, zeta2, alpha: 1.0 / (1.0 - theta), eta: 0.0 };
+        zipfian.grow(items);
+        zipfian
+    }
+
+    /// Draw from `0..items` from now on, `items` no fewer than before
+    pub fn grow(&mut self, items: u64) {
+        for i in self.items + 1..=items {
+            self.zeta += 1.0 / (i as f64).powf(self.theta);
+        }
+        self.items = items;
+        self.eta = (1.0 - (2.0 / items as f64).powf(1.0 - self.theta)) / (1.0 - self.zeta2 / self.zeta);
+    }
+
+    pub fn next(&self, rng: &mut impl Rng) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zeta;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < self.zeta2 {
+            return 1;
+        }
+        let rank = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        rank.min(self.items - 1)
+    }
+}
+
+/// Key index of zipfian `rank` among `items` keys, hashed so the popular
+/// keys are spread over the key space instead of all written first, as in
+/// YCSB's `ScrambledZipfianGenerator`
+pub fn scramble(rank: u64, items: u64) -> u64 {
+    // FNV-1a over the rank's bytes
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in rank.to_le_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash % items
+}
@@ -0,0 +1,51 @@
+//! A phase's op latencies, in nanoseconds, as an HDR histogram: three
+//! significant digits from 1ns to an hour, in the same 256KB however many
+//! ops ran
+
+use anyhow::Result;
+use hdrhistogram::Histogram;
+use std::io::Write;
+use std::time::Duration;
+
+pub type Latencies = Histogram<u64>;
+
+const MAX_NS: u64 = 3600 * 1_000_000_000;
+
+// Fixed bounds: values recorded into an auto-resizing one can land in the
+// wrong bucket when it grows
+pub fn new() -> Latencies {
+    Histogram::new_with_bounds(1, MAX_NS, 3).expect("bounds are in range")
+}
+
+/// Recorded as an hour if longer
+pub fn record(latencies: &mut Latencies, latency: Duration) {
+    latencies.saturating_record(latency.as_nanos().min(MAX_NS as u128) as u64);
+}
+
+/// All of `parts` in one
+pub fn merge(parts: impl IntoIterator<Item = Latencies>) -> Latencies {
+    let mut all = new();
+    for part in parts {
+        all.add(part).expect("histograms with the same bounds merge");
+    }
+    all
+}
+
+/// The percentile curve of `latencies` as CSV rows of `op`, ever finer
+/// toward the tail: each row is a percentile, the latency that many of
+/// the ops were at or under, and how many ops fell since the last row
+pub fn write_curve(out: &mut impl Write, op: &str, latencies: &Latencies) -> Result<()> {
+    for step in latencies.iter_quantiles(5) {
+        writeln!(
+            out,
+            "{},{:.6},{},{}",
+            op,
+            step.quantile_iterated_to() * 100.0,
+            step.value_iterated_to(),
+            step.count_since_last_iteration(),
+        )?;
+    }
+    Ok(())
+}
+
+pub const CURVE_HEADER: &str = "op,percentile,latency_ns,count";
@@ -0,0 +1,295 @@
+//! What to benchmark: command-line flags over an optional TOML workload
+//! file, over the defaults; or two result files to compare
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use mini_kv::SyncMode;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::distribution::KeyDistribution;
+use crate::store::Backend;
+use crate::ycsb::{self, Preset};
+
+/// Measure mini-kv's throughput and latency under each sync mode
+///
+/// Every sync mode runs with every record size and count. Prints a CSV row
+/// per phase.
+#[derive(Parser)]
+#[command(name = "bench", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunFlags,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare two JSON result files, phase by phase; exits with status 1
+    /// if any phase regressed
+    Compare {
+        /// Results to compare against
+        baseline: PathBuf,
+        /// Results to check
+        current: PathBuf,
+        /// Percent drop in throughput, or rise in p99 latency, that counts
+        /// as a regression
+        #[arg(long, default_value_t = 10.0)]
+        threshold: f64,
+    },
+}
+
+#[derive(Args)]
+struct RunFlags {
+    /// TOML workload file; flags given as well override its settings
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Engines to run everything on, comma-separated: mini-kv, and sled
+    /// and rocksdb if built with the bench-sled and bench-rocksdb features
+    /// [default: mini-kv]
+    #[arg(long, value_delimiter = ',')]
+    engines: Option<Vec<Backend>>,
+
+    /// Sync modes, comma-separated: always, batch:<writes>,
+    /// periodic:<milliseconds> or adaptive:<milliseconds> [default: always,batch:100,batch:1000,periodic:10,periodic:100]
+    #[arg(long, value_delimiter = ',')]
+    sync_modes: Option<Vec<SyncMode>>,
+
+    /// Value sizes in bytes, comma-separated [default: 128]
+    #[arg(long, value_delimiter = ',')]
+    record_sizes: Option<Vec<usize>>,
+
+    /// Ops per phase, comma-separated [default: 10000]
+    #[arg(long, value_delimiter = ',')]
+    counts: Option<Vec<usize>>,
+
+    /// Untimed puts before the first phase [default: 1000]
+    #[arg(long)]
+    warmup: Option<usize>,
+
+    /// Write the rows here instead of to stdout, as JSON if the name ends
+    /// in .json and CSV otherwise
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Also write each run's full latency percentile curve, every phase of
+    /// it, to <run>.csv in this directory
+    #[arg(long)]
+    latency_curves: Option<PathBuf>,
+
+    /// Mixed phases as <reads>/<writes> percentages, comma-separated
+    /// [default: 95/5,50/50]
+    #[arg(long, value_delimiter = ',')]
+    mix: Option<Vec<Mix>>,
+
+    /// Run a mix's gets and puts on two threads at once
+    #[arg(long)]
+    concurrent: bool,
+
+    /// Keys the puts, gets and mixes pick: sequential, uniform, zipfian,
+    /// latest or hotspot [default: sequential]
+    #[arg(long)]
+    distribution: Option<KeyDistribution>,
+
+    /// YCSB workloads a to f, comma-separated, or all, instead of the
+    /// phases above
+    #[arg(long)]
+    ycsb: Option<String>,
+
+    /// Scale puts and gets from 1 to this many threads instead
+    #[arg(long, conflicts_with = "ycsb")]
+    threads: Option<usize>,
+
+    /// Time opening a log of each count of records instead, and the heap
+    /// it takes
+    #[arg(long, conflicts_with_all = ["ycsb", "threads"])]
+    recovery: bool,
+
+    /// Time compacting a log this share of which is garbage instead, for
+    /// each share given, comma-separated (e.g. 0.25,0.5,0.75)
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["ycsb", "threads", "recovery"])]
+    compaction: Option<Vec<f64>>,
+}
+
+/// The workload file: each setting as the flag of the same name, with
+/// lists as arrays
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct WorkloadFile {
+    engines: Option<Vec<String>>,
+    sync_modes: Option<Vec<String>>,
+    record_sizes: Option<Vec<usize>>,
+    counts: Option<Vec<usize>>,
+    warmup: Option<usize>,
+    output: Option<PathBuf>,
+    latency_curves: Option<PathBuf>,
+    mix: Option<Vec<String>>,
+    concurrent: Option<bool>,
+    distribution: Option<String>,
+    ycsb: Option<String>,
+    threads: Option<usize>,
+    recovery: Option<bool>,
+    compaction: Option<Vec<f64>>,
+}
+
+pub struct BenchArgs {
+    pub engines: Vec<Backend>,
+    pub sync_modes: Vec<SyncMode>,
+    pub record_sizes: Vec<usize>,
+    pub counts: Vec<usize>,
+    pub warmup: usize,
+    pub output: Option<PathBuf>,
+    /// Directory for each run's percentile curves, if any
+    pub latency_curves: Option<PathBuf>,
+    pub mixes: Vec<Mix>,
+    /// Run a mix's gets and puts on two threads at once, instead of
+    /// interleaved on one
+    pub concurrent: bool,
+    /// Which keys the puts, gets and mixes pick
+    pub distribution: KeyDistribution,
+    /// YCSB workloads to run instead of the phases above
+    pub ycsb: Vec<&'static Preset>,
+    /// Run puts and gets on 1 to this many threads instead, if set
+    pub threads: Option<usize>,
+    /// Time opens of a written log instead
+    pub recovery: bool,
+    /// Time compactions of logs with these shares of garbage instead
+    pub compaction: Vec<f64>,
+}
+
+/// Percentages of a mixed phase's ops that are gets and puts
+#[derive(Debug, Clone, Copy)]
+pub struct Mix {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+impl std::str::FromStr for Mix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (reads, writes) = s.split_once('/').ok_or_else(|| anyhow!("mix {:?} is not <reads>/<writes>", s))?;
+        let mix = Mix { reads: reads.parse().context("mix reads")?, writes: writes.parse().context("mix writes")? };
+        if mix.reads + mix.writes != 100 {
+            bail!("mix {:?} does not add up to 100", s);
+        }
+        Ok(mix)
+    }
+}
+
+impl Mix {
+    pub fn name(self) -> String {
+        format!("mix{}/{}", self.reads, self.writes)
+    }
+}
+
+fn parse_all<T: std::str::FromStr<Err = anyhow::Error>>(values: Vec<String>) -> Result<Vec<T>> {
+    values.iter().map(|value| value.parse()).collect()
+}
+
+/// What a `bench` command line asks for
+pub enum Action {
+    Run(BenchArgs),
+    Compare { baseline: PathBuf, current: PathBuf, threshold: f64 },
+}
+
+impl Action {
+    pub fn parse() -> Result<Self> {
+        let cli = Cli::parse();
+        Ok(match cli.command {
+            Some(Command::Compare { baseline, current, threshold }) => Action::Compare { baseline, current, threshold },
+            None => Action::Run(BenchArgs::new(cli.run)?),
+        })
+    }
+}
+
+impl BenchArgs {
+    /// From the flags, and the workload file they name if any
+    fn new(cli: RunFlags) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => {
+                let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+                toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?
+            }
+            None => WorkloadFile::default(),
+        };
+
+        let args = BenchArgs {
+            engines: match (cli.engines, file.engines) {
+                (Some(engines), _) => engines,
+                (None, Some(engines)) => parse_all(engines)?,
+                (None, None) => vec![Backend::MiniKv],
+            },
+            sync_modes: match (cli.sync_modes, file.sync_modes) {
+                (Some(modes), _) => modes,
+                (None, Some(modes)) => parse_all(modes)?,
+                (None, None) => vec![
+                    SyncMode::Always,
+                    SyncMode::Batch(100),
+                    SyncMode::Batch(1000),
+                    SyncMode::Periodic(Duration::from_millis(10)),
+                    SyncMode::Periodic(Duration::from_millis(100)),
+                ],
+            },
+            record_sizes: cli.record_sizes.or(file.record_sizes).unwrap_or_else(|| vec![128]),
+            counts: cli.counts.or(file.counts).unwrap_or_else(|| vec![10_000]),
+            warmup: cli.warmup.or(file.warmup).unwrap_or(1000),
+            output: cli.output.or(file.output),
+            latency_curves: cli.latency_curves.or(file.latency_curves),
+            mixes: match (cli.mix, file.mix) {
+                (Some(mixes), _) => mixes,
+                (None, Some(mixes)) => parse_all(mixes)?,
+                (None, None) => vec![Mix { reads: 95, writes: 5 }, Mix { reads: 50, writes: 50 }],
+            },
+            concurrent: cli.concurrent || file.concurrent.unwrap_or(false),
+            distribution: match (cli.distribution, file.distribution) {
+                (Some(distribution), _) => distribution,
+                (None, Some(distribution)) => distribution.parse()?,
+                (None, None) => KeyDistribution::Sequential,
+            },
+            ycsb: cli.ycsb.or(file.ycsb).map_or(Ok(Vec::new()), |presets| ycsb::parse_presets(&presets))?,
+            threads: cli.threads.or(file.threads),
+            recovery: cli.recovery || file.recovery.unwrap_or(false),
+            compaction: cli.compaction.or(file.compaction).unwrap_or_default(),
+        };
+        if args.engines.is_empty() || args.sync_modes.is_empty() || args.record_sizes.is_empty() || args.counts.is_empty() {
+            bail!("engines, sync_modes, record_sizes and counts need at least one value each");
+        }
+        if args.counts.contains(&0) {
+            bail!("counts must be at least 1");
+        }
+        if args.threads == Some(0) {
+            bail!("threads must be at least 1");
+        }
+        let modes = [args.threads.is_some(), !args.ycsb.is_empty(), args.recovery, !args.compaction.is_empty()];
+        if modes.iter().filter(|&&given| given).count() > 1 {
+            bail!("threads, ycsb, recovery and compaction are separate runs; give one");
+        }
+        if args.compaction.iter().any(|garbage| !(0.0..1.0).contains(garbage)) {
+            bail!("compaction garbage shares must be at least 0 and below 1");
+        }
+        let adaptive = args.sync_modes.iter().any(|mode| matches!(mode, SyncMode::Adaptive { .. }));
+        if adaptive && args.engines.iter().any(|&engine| engine != Backend::MiniKv) {
+            bail!("adaptive sync is mini-kv's own; the other engines have no equivalent");
+        }
+        if !args.compaction.is_empty() && args.engines.iter().any(|&engine| engine != Backend::MiniKv) {
+            bail!("compaction runs are of mini-kv alone");
+        }
+        Ok(args)
+    }
+}
+
+/// Short name of `mode` for a run's name and file
+pub fn mode_name(mode: SyncMode) -> String {
+    match mode {
+        SyncMode::Always => "always".to_string(),
+        SyncMode::Batch(n) => format!("batch{}", n),
+        SyncMode::Periodic(interval) => format!("periodic{}ms", interval.as_millis()),
+        SyncMode::Adaptive { max_latency } => format!("adaptive{}ms", max_latency.as_millis()),
+    }
+}
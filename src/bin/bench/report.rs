@@ -0,0 +1,172 @@
+//! Where the rows go, CSV or JSON, and `bench compare` of two JSON files
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// One phase of one run, as printed
+#[derive(Serialize, Deserialize)]
+pub struct Row {
+    pub mode: String,
+    pub op: String,
+    pub record_size: usize,
+    pub count: usize,
+    pub total_time_ms: u64,
+    pub throughput: f64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+    pub p999_ns: u64,
+    /// Syncs of the log during the phase, and bytes appended to it; rows
+    /// of only gets, reads or scans have none. Missing from files written
+    /// before these were.
+    #[serde(default)]
+    pub fsyncs: u64,
+    /// `None` for engines that do not count it
+    #[serde(default)]
+    pub bytes_written: Option<u64>,
+    #[serde(default)]
+    pub syncs_per_op: f64,
+    /// Most heap held at once during the phase, beyond what was before
+    /// it; only recovery runs measure it
+    #[serde(default)]
+    pub peak_heap_bytes: Option<u64>,
+    /// How much smaller the log got; only compactions measure it
+    #[serde(default)]
+    pub space_reclaimed_bytes: Option<u64>,
+    /// Of a run's `disk` row: the store's size on disk once closed, that
+    /// over its live records, and over their keys and values
+    #[serde(default)]
+    pub disk_bytes: Option<u64>,
+    #[serde(default)]
+    pub bytes_per_record: Option<f64>,
+    #[serde(default)]
+    pub space_amplification: Option<f64>,
+}
+
+const CSV_HEADER: &str = "mode,op,record_size,count,total_time_ms,throughput,p50_ns,p99_ns,p999_ns,fsyncs,bytes_written,syncs_per_op,peak_heap_bytes,space_reclaimed_bytes,disk_bytes,bytes_per_record,space_amplification";
+
+/// A result file: its rows, under a key so more can sit beside them
+#[derive(Serialize, Deserialize)]
+struct Results {
+    rows: Vec<Row>,
+}
+
+/// CSV rows written as they come, or JSON written when the last is in
+pub enum Output {
+    Csv(Box<dyn Write>),
+    Json { path: PathBuf, rows: Vec<Row> },
+}
+
+impl Output {
+    /// JSON if `path` ends in `.json`, CSV to it otherwise, or CSV to stdout
+    pub fn new(path: Option<&Path>) -> Result<Self> {
+        let mut out: Box<dyn Write> = match path {
+            Some(path) if path.extension().is_some_and(|ext| ext == "json") => {
+                return Ok(Output::Json { path: path.to_path_buf(), rows: Vec::new() });
+            }
+            Some(path) => Box::new(io::BufWriter::new(
+                fs::File::create(path).with_context(|| format!("creating {}", path.display()))?,
+            )),
+            None => Box::new(io::stdout()),
+        };
+        writeln!(out, "{}", CSV_HEADER)?;
+        Ok(Output::Csv(out))
+    }
+
+    pub fn row(&mut self, row: Row) -> Result<()> {
+        match self {
+            Output::Csv(out) => {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{:.2},{},{},{},{},{},{:.4},{},{},{},{},{}",
+                    row.mode,
+                    row.op,
+                    row.record_size,
+                    row.count,
+                    row.total_time_ms,
+                    row.throughput,
+                    row.p50_ns,
+                    row.p99_ns,
+                    row.p999_ns,
+                    row.fsyncs,
+                    row.bytes_written.map_or(String::new(), |bytes| bytes.to_string()),
+                    row.syncs_per_op,
+                    row.peak_heap_bytes.map_or(String::new(), |bytes| bytes.to_string()),
+                    row.space_reclaimed_bytes.map_or(String::new(), |bytes| bytes.to_string()),
+                    row.disk_bytes.map_or(String::new(), |bytes| bytes.to_string()),
+                    row.bytes_per_record.map_or(String::new(), |bytes| format!("{:.2}", bytes)),
+                    row.space_amplification.map_or(String::new(), |ratio| format!("{:.3}", ratio)),
+                )?;
+                out.flush()?;
+            }
+            Output::Json { rows, .. } => rows.push(row),
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if let Output::Json { path, rows } = self {
+            let file = fs::File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+            serde_json::to_writer_pretty(io::BufWriter::new(file), &Results { rows })?;
+        }
+        Ok(())
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Row>> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let results: Results = serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+    Ok(results.rows)
+}
+
+/// Percent `current` is worse than `baseline` by, where higher is better
+/// if `higher_better`
+fn worse_by(baseline: f64, current: f64, higher_better: bool) -> f64 {
+    if baseline == 0.0 {
+        return 0.0;
+    }
+    let change = (current - baseline) / baseline * 100.0;
+    if higher_better {
+        -change
+    } else {
+        change
+    }
+}
+
+/// Prints each phase of `current` against the same one in `baseline`,
+/// flagging those whose throughput fell or p99 rose by more than
+/// `threshold` percent; returns how many were flagged
+pub fn compare(baseline: &Path, current: &Path, threshold: f64) -> Result<usize> {
+    let baseline_rows = load(baseline)?;
+    let current_rows = load(current)?;
+    let mut by_phase: HashMap<(&str, &str), &Row> =
+        baseline_rows.iter().map(|row| ((row.mode.as_str(), row.op.as_str()), row)).collect();
+
+    println!("mode,op,throughput_change_pct,p99_change_pct,regression");
+    let mut regressions = 0;
+    for row in &current_rows {
+        let Some(old) = by_phase.remove(&(row.mode.as_str(), row.op.as_str())) else {
+            println!("{},{},,,new", row.mode, row.op);
+            continue;
+        };
+        let throughput = worse_by(old.throughput, row.throughput, true);
+        let p99 = worse_by(old.p99_ns as f64, row.p99_ns as f64, false);
+        let regressed = throughput > threshold || p99 > threshold;
+        regressions += regressed as usize;
+        println!(
+            "{},{},{:+.1},{:+.1},{}",
+            row.mode,
+            row.op,
+            -throughput,
+            p99,
+            if regressed { "yes" } else { "no" },
+        );
+    }
+    for row in baseline_rows.iter().filter(|row| by_phase.contains_key(&(row.mode.as_str(), row.op.as_str()))) {
+        println!("{},{},,,missing", row.mode, row.op);
+    }
+    Ok(regressions)
+}
@@ -0,0 +1,336 @@
+//! The engines a run can measure: mini-kv, and for context sled and
+//! RocksDB, behind the `bench-sled` and `bench-rocksdb` features
+//!
+//! The adapters sync the way mini-kv's sync modes do, after the same puts,
+//! so a row compares engines at the same loss window.
+
+use anyhow::{bail, Result};
+use mini_kv::{Engine, SyncMode};
+use std::fs;
+use std::io;
+use std::path::Path;
+#[cfg(any(feature = "bench-sled", feature = "bench-rocksdb"))]
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    MiniKv,
+    Sled,
+    RocksDb,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let backend = match s {
+            "mini-kv" => Backend::MiniKv,
+            "sled" => Backend::Sled,
+            "rocksdb" => Backend::RocksDb,
+            _ => bail!("unknown engine {:?} (expected mini-kv, sled or rocksdb)", s),
+        };
+        if backend == Backend::Sled && !cfg!(feature = "bench-sled") {
+            bail!("bench was built without sled; build it with --features bench-sled");
+        }
+        if backend == Backend::RocksDb && !cfg!(feature = "bench-rocksdb") {
+            bail!("bench was built without rocksdb; build it with --features bench-rocksdb");
+        }
+        Ok(backend)
+    }
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::MiniKv => "mini-kv",
+            Backend::Sled => "sled",
+            Backend::RocksDb => "rocksdb",
+        }
+    }
+}
+
+/// What a phase cost the disk
+#[derive(Clone, Copy, Default)]
+pub struct Io {
+    pub fsyncs: u64,
+    /// `None` for engines that do not count it
+    pub bytes_written: Option<u64>,
+}
+
+impl Io {
+    /// A phase's is the difference of the totals after it and before
+    pub fn since(self, before: Io) -> Io {
+        Io {
+            fsyncs: self.fsyncs - before.fsyncs,
+            bytes_written: self.bytes_written.zip(before.bytes_written).map(|(after, before)| after - before),
+        }
+    }
+
+    /// None of either, for ops that do not write, counted as `self` is
+    pub fn zeroed(self) -> Io {
+        Io { fsyncs: 0, bytes_written: self.bytes_written.map(|_| 0) }
+    }
+}
+
+/// The ops the bench times
+pub trait Store: Send {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// How many keys start with `prefix`, having read each of them
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<usize>;
+    fn sync(&mut self) -> Result<()>;
+    /// Totals since open
+    fn io(&self) -> Io;
+    /// Live keys, and the bytes of their keys and values
+    fn live(&self) -> Result<(usize, u64)>;
+}
+
+/// Bytes on disk of the store at `path`: everything under it, and under
+/// the files next to it named `<path>.<something>` (mini-kv's index file,
+/// value log and tables)
+pub fn disk_usage(path: &str) -> Result<u64> {
+    let path = Path::new(path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let entry_name = entry.file_name().to_string_lossy().into_owned();
+        if entry_name == name || entry_name.starts_with(&format!("{}.", name)) {
+            total += tree_size(&entry.path())?;
+        }
+    }
+    Ok(total)
+}
+
+fn tree_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += tree_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// A fresh store at `path`, removing whatever a previous run left there
+pub fn create(backend: Backend, path: &str, mode: SyncMode) -> Result<Box<dyn Store>> {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_dir_all(path);
+    open(backend, path, mode)
+}
+
+/// The store at `path`, reopened
+pub fn open(backend: Backend, path: &str, mode: SyncMode) -> Result<Box<dyn Store>> {
+    let path = Path::new(path);
+    Ok(match backend {
+        Backend::MiniKv => Box::new(Engine::with_sync(path, mode)?),
+        #[cfg(feature = "bench-sled")]
+        Backend::Sled => Box::new(sled_store::SledStore::open(path, mode)?),
+        #[cfg(feature = "bench-rocksdb")]
+        Backend::RocksDb => Box::new(rocksdb_store::RocksDbStore::open(path, mode)?),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("Backend::from_str refuses engines not built in"),
+    })
+}
+
+impl Store for Engine {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        Engine::put(self, key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Engine::get(self, key)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        Ok(Engine::scan_prefix(self, prefix)?.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Engine::sync(self)
+    }
+
+    fn io(&self) -> Io {
+        let stats = self.stats();
+        Io { fsyncs: stats.fsyncs, bytes_written: Some(stats.bytes_written) }
+    }
+
+    fn live(&self) -> Result<(usize, u64)> {
+        let records = Engine::scan_prefix(self, b"")?;
+        Ok((records.len(), records.iter().map(|(key, value)| (key.len() + value.len()) as u64).sum()))
+    }
+}
+
+/// When an adapter syncs: after the puts mini-kv's `SyncMode` would
+#[cfg(any(feature = "bench-sled", feature = "bench-rocksdb"))]
+struct SyncPolicy {
+    mode: SyncMode,
+    writes: usize,
+    last_sync: Instant,
+    fsyncs: u64,
+}
+
+#[cfg(any(feature = "bench-sled", feature = "bench-rocksdb"))]
+impl SyncPolicy {
+    fn new(mode: SyncMode) -> Self {
+        Self { mode, writes: 0, last_sync: Instant::now(), fsyncs: 0 }
+    }
+
+    /// Whether the put just made should be synced
+    fn after_put(&mut self) -> bool {
+        self.writes += 1;
+        match self.mode {
+            SyncMode::Always => true,
+            SyncMode::Batch(n) => self.writes >= n,
+            SyncMode::Periodic(d) => self.last_sync.elapsed() >= d,
+            SyncMode::Adaptive { .. } => unreachable!("BenchArgs refuses adaptive sync for other engines"),
+        }
+    }
+
+    fn synced(&mut self) {
+        self.writes = 0;
+        self.last_sync = Instant::now();
+        self.fsyncs += 1;
+    }
+}
+
+#[cfg(feature = "bench-sled")]
+mod sled_store {
+    use super::{Io, Store, SyncPolicy};
+    use anyhow::Result;
+    use mini_kv::SyncMode;
+    use std::path::Path;
+
+    pub struct SledStore {
+        db: sled::Db,
+        policy: SyncPolicy,
+    }
+
+    impl SledStore {
+        pub fn open(path: &Path, mode: SyncMode) -> Result<Self> {
+            // sled's own background flush is off, so only the policy syncs
+            let db = sled::Config::new().path(path).flush_every_ms(None).open()?;
+            Ok(Self { db, policy: SyncPolicy::new(mode) })
+        }
+    }
+
+    impl Store for SledStore {
+        fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+            self.db.insert(key, value)?;
+            if self.policy.after_put() {
+                self.sync()?;
+            }
+            Ok(())
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.db.get(key)?.map(|value| value.to_vec()))
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Result<usize> {
+            let mut count = 0;
+            for entry in self.db.scan_prefix(prefix) {
+                entry?;
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.db.flush()?;
+            self.policy.synced();
+            Ok(())
+        }
+
+        fn io(&self) -> Io {
+            Io { fsyncs: self.policy.fsyncs, bytes_written: None }
+        }
+
+        fn live(&self) -> Result<(usize, u64)> {
+            let (mut count, mut bytes) = (0, 0);
+            for entry in self.db.iter() {
+                let (key, value) = entry?;
+                count += 1;
+                bytes += (key.len() + value.len()) as u64;
+            }
+            Ok((count, bytes))
+        }
+    }
+}
+
+#[cfg(feature = "bench-rocksdb")]
+mod rocksdb_store {
+    use super::{Io, Store, SyncPolicy};
+    use anyhow::Result;
+    use mini_kv::SyncMode;
+    use rocksdb::{Direction, IteratorMode, Options, DB};
+    use std::path::Path;
+
+    pub struct RocksDbStore {
+        db: DB,
+        policy: SyncPolicy,
+    }
+
+    impl RocksDbStore {
+        pub fn open(path: &Path, mode: SyncMode) -> Result<Self> {
+            let mut options = Options::default();
+            options.create_if_missing(true);
+            let db = DB::open(&options, path)?;
+            Ok(Self { db, policy: SyncPolicy::new(mode) })
+        }
+    }
+
+    impl Store for RocksDbStore {
+        // Writes go to the WAL unsynced; a sync is an fsync of the WAL, as
+        // mini-kv's is of its log
+        fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+            self.db.put(key, value)?;
+            if self.policy.after_put() {
+                self.sync()?;
+            }
+            Ok(())
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.db.get(key)?)
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Result<usize> {
+            let mut count = 0;
+            for entry in self.db.iterator(IteratorMode::From(prefix, Direction::Forward)) {
+                let (key, _) = entry?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.db.flush_wal(true)?;
+            self.policy.synced();
+            Ok(())
+        }
+
+        fn io(&self) -> Io {
+            Io { fsyncs: self.policy.fsyncs, bytes_written: None }
+        }
+
+        fn live(&self) -> Result<(usize, u64)> {
+            let (mut count, mut bytes) = (0, 0);
+            for entry in self.db.iterator(IteratorMode::Start) {
+                let (key, value) = entry?;
+                count += 1;
+                bytes += (key.len() + value.len()) as u64;
+            }
+            Ok((count, bytes))
+        }
+    }
+}
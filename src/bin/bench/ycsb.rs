@@ -0,0 +1,142 @@
+//! The core YCSB workloads, A to F, as in the YCSB distribution's
+//! `workloads/` directory
+//!
+//! Each loads `count` records, then runs `count` ops drawn from its mix.
+//! Keys are picked zipfian (scrambled), or for D skewed towards the ones
+//! inserted last.
+
+use rand::Rng;
+
+use crate::distribution::{scramble, Zipfian};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    /// Put a new value for an existing key
+    Update,
+    /// Put a key not loaded or inserted yet
+    Insert,
+    /// Read the keys sharing all but the last two digits with one, up to 100
+    Scan,
+    /// Read a key, then put a new value for it; timed as one op
+    ReadModifyWrite,
+}
+
+impl Op {
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Update => "update",
+            Op::Insert => "insert",
+            Op::Scan => "scan",
+            Op::ReadModifyWrite => "rmw",
+        }
+    }
+
+    /// Whether it puts
+    pub fn writes(self) -> bool {
+        matches!(self, Op::Update | Op::Insert | Op::ReadModifyWrite)
+    }
+}
+
+/// Whose turn it is among the existing keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    Zipfian,
+    /// Zipfian over how recently the key was inserted
+    Latest,
+}
+
+pub struct Preset {
+    /// `a` to `f`
+    pub name: &'static str,
+    /// Each op and its percentage of the ops
+    pub mix: &'static [(Op, u32)],
+    pub choice: Choice,
+}
+
+pub const PRESETS: &[Preset] = &[
+    // Update heavy: a session store recording recent actions
+    Preset { name: "a", mix: &[(Op::Read, 50), (Op::Update, 50)], choice: Choice::Zipfian },
+    // Read mostly: photo tagging
+    Preset { name: "b", mix: &[(Op::Read, 95), (Op::Update, 5)], choice: Choice::Zipfian },
+    // Read only: a user profile cache
+    Preset { name: "c", mix: &[(Op::Read, 100)], choice: Choice::Zipfian },
+    // Read latest: user status updates
+    Preset { name: "d", mix: &[(Op::Read, 95), (Op::Insert, 5)], choice: Choice::Latest },
+    // Short ranges: threaded conversations
+    Preset { name: "e", mix: &[(Op::Scan, 95), (Op::Insert, 5)], choice: Choice::Zipfian },
+    // Read-modify-write: a user database
+    Preset { name: "f", mix: &[(Op::Read, 50), (Op::ReadModifyWrite, 50)], choice: Choice::Zipfian },
+];
+
+/// The presets named in `s`, comma-separated, or all of them for `all`
+pub fn parse_presets(s: &str) -> anyhow::Result<Vec<&'static Preset>> {
+    if s == "all" {
+        return Ok(PRESETS.iter().collect());
+    }
+    s.split(',')
+        .map(|name| {
+            let name = name.to_ascii_lowercase();
+            PRESETS
+                .iter()
+                .find(|preset| preset.name == name)
+                .ok_or_else(|| anyhow::anyhow!("unknown YCSB workload {:?} (expected a to f, or all)", name))
+        })
+        .collect()
+}
+
+impl Preset {
+    /// Index in `mix` of the next op
+    pub fn next_op(&self, rng: &mut impl Rng) -> usize {
+        let mut roll = rng.gen_range(0..100);
+        for (i, &(_, percent)) in self.mix.iter().enumerate() {
+            if roll < percent {
+                return i;
+            }
+            roll -= percent;
+        }
+        self.mix.len() - 1
+    }
+}
+
+/// Fixed width, so a prefix covers a run of consecutive keys
+pub fn key(i: u64) -> Vec<u8> {
+    format!("user{:012}", i).into_bytes()
+}
+
+/// The first key of `key(i)`'s scan, and the prefix its keys share
+pub fn scan_prefix(i: u64) -> Vec<u8> {
+    let mut key = key(i);
+    key.truncate(key.len() - 2);
+    key
+}
+
+/// Keys loaded and inserted so far, and how to pick among them
+pub struct Keys {
+    count: u64,
+    choice: Choice,
+    zipfian: Zipfian,
+}
+
+impl Keys {
+    pub fn new(loaded: u64, choice: Choice) -> Self {
+        Self { count: loaded, choice, zipfian: Zipfian::new(loaded) }
+    }
+
+    /// An existing key's index
+    pub fn existing(&self, rng: &mut impl Rng) -> u64 {
+        let rank = self.zipfian.next(rng);
+        match self.choice {
+            Choice::Zipfian => scramble(rank, self.count),
+            Choice::Latest => self.count - 1 - rank,
+        }
+    }
+
+    /// The index of a new key, which exists from now on
+    pub fn insert(&mut self) -> u64 {
+        self.count += 1;
+        self.zipfian.grow(self.count);
+        self.count - 1
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use mini_kv::{Engine, Format, RecordKind, ScanItem, Scanner, SstOptions};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+#[path = "cli/csv.rs"]
+mod csv;
+#[path = "cli/transfer.rs"]
+mod transfer;
+
+use transfer::{Encoding, Layout};
+
+/// Write buffer of `import --bulk`
+const BULK_WRITE_BUFFER: usize = 1024 * 1024;
+
+/// Operate on a mini-kv database from the command line
+#[derive(Parser)]
+#[command(name = "mini-kv", version)]
+struct Cli {
+    /// Path to the database file (created if missing)
+    #[arg(short, long, env = "MINI_KV_DB")]
+    db: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the value of a key (raw bytes); exits 1 if the key is missing
+    Get { key: String },
+    /// Set a key to a value
+    Put { key: String, value: String },
+    /// Delete a key; exits 1 if the key is missing
+    Del { key: String },
+    /// List keys and values, sorted by key
+    Scan {
+        /// Only show keys starting with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Print keys only
+        #[arg(long)]
+        keys_only: bool,
+    },
+    /// Print key count and file size
+    Stats,
+    /// Print live and dead bytes, where the dead ones are, and what
+    /// compaction would reclaim
+    Space,
+    /// Print distributions of key lengths, value lengths and record ages
+    /// over every record in the log
+    Histogram,
+    /// Write the keys and values out, e.g. for jq
+    Export {
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: transfer::Format,
+        /// Write here instead of to stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Only export keys starting with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, value_enum, default_value = "utf8")]
+        key_encoding: Encoding,
+        #[arg(long, value_enum, default_value = "utf8")]
+        value_encoding: Encoding,
+        #[command(flatten)]
+        csv: csv::CsvOptions,
+    },
+    /// Put the keys and values of an export, overwriting keys already
+    /// there, and sync once at the end
+    Import {
+        /// Read this instead of stdin
+        input: Option<PathBuf>,
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: transfer::Format,
+        #[arg(long, value_enum, default_value = "utf8")]
+        key_encoding: Encoding,
+        #[arg(long, value_enum, default_value = "utf8")]
+        value_encoding: Encoding,
+        /// Load faster: hand the puts to the log in 1MB writes instead of
+        /// one each. Nothing is durable, or visible to other readers of the
+        /// log, until the import ends.
+        #[arg(long)]
+        bulk: bool,
+        #[command(flatten)]
+        csv: csv::CsvOptions,
+    },
+    /// Write the keys and values into RocksDB SST files in a directory,
+    /// for RocksDB's IngestExternalFile or `ldb ingest_extern_sst`; prints
+    /// the files written
+    ExportSst {
+        dir: PathBuf,
+        /// Start a new file once one reaches this many MB; 0 for one file
+        #[arg(long, default_value_t = 64)]
+        file_size_mb: u64,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut builder = Engine::builder(&cli.db);
+    if let Command::Import { bulk: true, .. } = cli.command {
+        builder = builder.write_buffer(BULK_WRITE_BUFFER);
+    }
+    let mut engine = builder.open()
+        .with_context(|| format!("opening {}", cli.db.display()))?;
+
+    let mut out = io::stdout().lock();
+    match cli.command {
+        Command::Get { key } => match engine.get(key.as_bytes())? {
+            Some(value) => {
+                out.write_all(&value)?;
+                writeln!(out)?;
+            }
+            None => not_found(&key),
+        },
+        Command::Put { key, value } => {
+            engine.put(key.into_bytes(), value.into_bytes())?;
+        }
+        Command::Del { key } => {
+            if !engine.delete(key.as_bytes())? {
+                not_found(&key);
+            }
+        }
+        Command::Scan { prefix, keys_only } => {
+            for (key, value) in engine.scan_prefix(prefix.as_bytes())? {
+                if keys_only {
+                    writeln!(out, "{}", key.escape_ascii())?;
+                } else {
+                    writeln!(out, "{}\t{}", key.escape_ascii(), value.escape_ascii())?;
+                }
+            }
+        }
+        Command::Stats => {
+            let file_size = fs::metadata(engine.path())?.len();
+            writeln!(out, "keys:        {}", engine.len())?;
+            writeln!(out, "log records: {}", engine.log_records())?;
+            writeln!(out, "file size:   {} bytes", file_size)?;
+        }
+        Command::Space => {
+            let report = engine.space_report()?;
+            writeln!(out, "file size:     {} bytes", report.file_bytes)?;
+            writeln!(out, "live:          {} bytes in {} records", report.live_bytes, report.live_records)?;
+            writeln!(out, "dead:          {} bytes in {} records", report.dead_bytes, report.dead_records)?;
+            writeln!(out, "amplification: {:.2}x", report.amplification())?;
+            writeln!(out, "reclaimable:   ~{} bytes", report.reclaimable())?;
+            writeln!(out, "dead bytes by age (oldest first):")?;
+            for band in &report.bands {
+                let total = band.live_bytes + band.dead_bytes;
+                let dead_pct = if total == 0 { 0.0 } else { band.dead_bytes as f64 * 100.0 / total as f64 };
+                writeln!(out, "  {:>12}..{:<12} {:>12} dead ({:.0}%)", band.start, band.end, band.dead_bytes, dead_pct)?;
+            }
+        }
+        Command::Histogram => {
+            let buf = fs::read(engine.path())?;
+            let (format, records_start) = Format::parse(&buf)?;
+            let (mut keys, mut values, mut ages) = (Distribution::new(), Distribution::new(), Distribution::new());
+            let mut put_ordinals = Vec::new();
+            let mut records = 0;
+            for item in Scanner::with_format(&buf, records_start, &format) {
+                match item {
+                    ScanItem::Valid { record, .. } => {
+                        keys.add(record.key.len() as u64);
+                        // A value pointer's length is that of the pointer, not the value
+                        if record.kind == RecordKind::Put {
+                            values.add(record.value.len() as u64);
+                        }
+                        if matches!(record.kind, RecordKind::Put | RecordKind::ValuePointer) {
+                            put_ordinals.push(records);
+                        }
+                    }
+                    ScanItem::Unreadable { key, .. } => {
+                        if let Some(key) = key {
+                            keys.add(key.len() as u64);
+                        }
+                    }
+                    ScanItem::Corrupt { .. } => continue,
+                }
+                records += 1;
+            }
+            for ordinal in put_ordinals {
+                ages.add(records - 1 - ordinal);
+            }
+            keys.print(&mut out, "key length (bytes)")?;
+            values.print(&mut out, "value length (bytes, uncompressed)")?;
+            ages.print(&mut out, "record age (records written since)")?;
+        }
+        Command::Export { format, output, prefix, key_encoding, value_encoding, csv } => {
+            let layout = Layout { format, keys: key_encoding, values: value_encoding, csv };
+            let count = match output {
+                Some(path) => {
+                    let file = fs::File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+                    transfer::export(&engine, BufWriter::new(file), &layout, prefix.as_bytes())?
+                }
+                None => transfer::export(&engine, &mut out, &layout, prefix.as_bytes())?,
+            };
+            eprintln!("exported {} keys", count);
+        }
+        Command::Import { input, format, key_encoding, value_encoding, bulk: _, csv } => {
+            let layout = Layout { format, keys: key_encoding, values: value_encoding, csv };
+            let count = match input {
+                Some(path) => {
+                    let file = fs::File::open(&path).with_context(|| format!("opening {}", path.display()))?;
+                    transfer::import(&mut engine, BufReader::new(file), &layout)?
+                }
+                None => transfer::import(&mut engine, io::stdin().lock(), &layout)?,
+            };
+            eprintln!("imported {} keys", count);
+        }
+        Command::ExportSst { dir, file_size_mb } => {
+            let options = SstOptions { target_file_size: file_size_mb * 1024 * 1024, ..SstOptions::default() };
+            for file in engine.export_sst(&dir, &options)? {
+                writeln!(out, "{}", file.display())?;
+            }
+        }
+    }
+    engine.close()
+}
+
+/// Counts of values in power-of-two buckets: 0, 1, 2-3, 4-7, ...
+struct Distribution {
+    buckets: [u64; 65],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Distribution {
+    fn new() -> Self {
+        Self { buckets: [0; 65], count: 0, sum: 0, max: 0 }
+    }
+
+    fn add(&mut self, n: u64) {
+        self.buckets[(u64::BITS - n.leading_zeros()) as usize] += 1;
+        self.count += 1;
+        self.sum += n;
+        self.max = self.max.max(n);
+    }
+
+    fn print(&self, out: &mut impl Write, title: &str) -> io::Result<()> {
+        writeln!(out, "{}: {} records, mean {:.1}, max {}", title, self.count, self.sum as f64 / self.count.max(1) as f64, self.max)?;
+        let widest = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate().filter(|(_, &n)| n > 0) {
+            let (low, high) = if i == 0 { (0, 0) } else { (1u64 << (i - 1), (1u64 << (i - 1)) * 2 - 1) };
+            seen += n;
+            let bar = "#".repeat((n * 40).div_ceil(widest) as usize);
+            writeln!(out, "  {:>10}..{:<10} {:>10} {:>6.1}% cum  {}", low, high, n, seen as f64 * 100.0 / self.count as f64, bar)?;
+        }
+        writeln!(out)
+    }
+}
+
+fn not_found(key: &str) -> ! {
+    eprintln!("(not found) {}", key);
+    process::exit(1);
+}
@@ -0,0 +1,245 @@
+//! CSV for `mini-kv export --format csv` and `import`
+//!
+//! By default as RFC 4180 has it: fields separated by commas, quoted with
+//! `"` when they hold one, a quote or a line break, and quotes doubled
+//! inside quotes. The delimiter and quote can be changed, and an escape
+//! character can stand in for doubling, as some database dumps have it.
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use std::io::{BufRead, Write};
+
+#[derive(Args, Clone)]
+#[command(next_help_heading = "CSV")]
+pub struct CsvOptions {
+    /// Column of the keys: a header name, or a number from 1
+    #[arg(long, default_value = "1")]
+    pub key_column: Column,
+    /// Column of the values, as --key-column
+    #[arg(long, default_value = "2")]
+    pub value_column: Column,
+    /// The first line is a row, not a header; columns are then numbers
+    #[arg(long)]
+    pub no_header: bool,
+    #[arg(long, default_value_t = ',')]
+    pub delimiter: char,
+    /// Quote around fields holding the delimiter, a quote or a line break
+    #[arg(long, default_value_t = '"')]
+    pub quote: char,
+    /// Character for a quote, or itself, inside a quoted field, instead of
+    /// a doubled quote
+    #[arg(long)]
+    pub escape: Option<char>,
+}
+
+/// Which column of a row holds the keys or values
+#[derive(Clone)]
+pub enum Column {
+    Name(String),
+    /// From 1
+    Number(usize),
+}
+
+impl std::str::FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Ok(Column::Name(s.to_string()));
+        }
+        match s.parse()? {
+            0 => bail!("columns are numbered from 1"),
+            n => Ok(Column::Number(n)),
+        }
+    }
+}
+
+impl Column {
+    /// Its position in rows under `header`, if they have one
+    fn position(&self, header: Option<&[String]>) -> Result<usize> {
+        match (self, header) {
+            (Column::Number(n), _) => Ok(n - 1),
+            (Column::Name(name), Some(header)) => {
+                header.iter().position(|field| field == name).ok_or_else(|| anyhow!("the header has no column {:?}", name))
+            }
+            (Column::Name(name), None) => bail!("column {:?} is a name, but --no-header leaves none to look it up in", name),
+        }
+    }
+
+    /// Header of an exported column: the name, or `default` for a number
+    fn header<'a>(&'a self, default: &'a str) -> &'a str {
+        match self {
+            Column::Name(name) => name,
+            Column::Number(_) => default,
+        }
+    }
+}
+
+impl CsvOptions {
+    /// Write a row of `fields`, quoting those that need it
+    fn write_row(&self, out: &mut impl Write, fields: &[&str]) -> Result<()> {
+        let mut line = String::new();
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                line.push(self.delimiter);
+            }
+            let special = |c: char| c == self.delimiter || c == self.quote || c == '\n' || c == '\r' || Some(c) == self.escape;
+            if !field.contains(special) {
+                line.push_str(field);
+                continue;
+            }
+            line.push(self.quote);
+            for c in field.chars() {
+                if c == self.quote || Some(c) == self.escape {
+                    line.push(self.escape.unwrap_or(self.quote));
+                }
+                line.push(c);
+            }
+            line.push(self.quote);
+        }
+        line.push('\n');
+        out.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Write the header, if there is to be one, and then rows of a key and
+    /// a value each with `row`
+    pub fn writer<W: Write>(&self, mut out: W) -> Result<CsvWriter<'_, W>> {
+        if !self.no_header {
+            self.write_row(&mut out, &[self.key_column.header("key"), self.value_column.header("value")])?;
+        }
+        Ok(CsvWriter { options: self, out })
+    }
+
+    /// Read the header, if there is one, and then the key and value of
+    /// each row with `next_pair`
+    pub fn reader<R: BufRead>(&self, input: R) -> Result<CsvReader<'_, R>> {
+        let mut reader = CsvReader { options: self, input, line: 0, key: 0, value: 0 };
+        let header = match self.no_header {
+            true => None,
+            false => Some(reader.next_row()?.ok_or_else(|| anyhow!("the input is empty, with no header"))?),
+        };
+        reader.key = self.key_column.position(header.as_deref())?;
+        reader.value = self.value_column.position(header.as_deref())?;
+        Ok(reader)
+    }
+}
+
+pub struct CsvWriter<'a, W: Write> {
+    options: &'a CsvOptions,
+    out: W,
+}
+
+impl<W: Write> CsvWriter<'_, W> {
+    pub fn row(&mut self, key: &str, value: &str) -> Result<()> {
+        self.options.write_row(&mut self.out, &[key, value])
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// At the start of a field
+    Start,
+    Unquoted,
+    Quoted,
+    /// Just past the escape character, in a quoted field
+    Escaped,
+    /// Just past a quote ending a quoted field, or, without an escape
+    /// character, the first of a doubled one
+    Closed,
+}
+
+pub struct CsvReader<'a, R: BufRead> {
+    options: &'a CsvOptions,
+    input: R,
+    /// Lines read so far
+    line: usize,
+    /// Positions of the key and value columns
+    key: usize,
+    value: usize,
+}
+
+impl<R: BufRead> CsvReader<'_, R> {
+    /// The key and value of the next row that is not blank, and the line
+    /// it starts on
+    pub fn next_pair(&mut self) -> Result<Option<(usize, String, String)>> {
+        loop {
+            let start = self.line + 1;
+            let Some(mut row) = self.next_row()? else { return Ok(None) };
+            if row.len() == 1 && row[0].is_empty() {
+                continue;
+            }
+            let needed = self.key.max(self.value) + 1;
+            if row.len() < needed {
+                bail!("line {}: {} fields, with no column {}", start, row.len(), needed);
+            }
+            let value = std::mem::take(&mut row[self.value]);
+            let key = match self.key == self.value {
+                true => value.clone(),
+                false => std::mem::take(&mut row[self.key]),
+            };
+            return Ok(Some((start, key, value)));
+        }
+    }
+
+    /// The fields of the next row, which may span lines inside quotes
+    fn next_row(&mut self) -> Result<Option<Vec<String>>> {
+        let options = self.options;
+        let start = self.line + 1;
+        let (mut fields, mut field, mut state) = (Vec::new(), String::new(), State::Start);
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.input.read_line(&mut buf)? == 0 {
+                return match state {
+                    _ if self.line < start => Ok(None),
+                    State::Quoted | State::Escaped => bail!("line {}: a quoted field is never closed", start),
+                    _ => {
+                        fields.push(field);
+                        Ok(Some(fields))
+                    }
+                };
+            }
+            self.line += 1;
+            for c in buf.chars() {
+                state = match state {
+                    State::Start | State::Unquoted if c == options.delimiter => {
+                        fields.push(std::mem::take(&mut field));
+                        State::Start
+                    }
+                    State::Start | State::Unquoted | State::Closed if c == '\n' => {
+                        fields.push(field);
+                        return Ok(Some(fields));
+                    }
+                    // Of a \r\n line end
+                    unquoted @ (State::Start | State::Unquoted | State::Closed) if c == '\r' => unquoted,
+                    State::Start if c == options.quote => State::Quoted,
+                    State::Start | State::Unquoted => {
+                        field.push(c);
+                        State::Unquoted
+                    }
+                    State::Quoted if Some(c) == options.escape => State::Escaped,
+                    State::Quoted if c == options.quote => State::Closed,
+                    State::Quoted | State::Escaped => {
+                        field.push(c);
+                        State::Quoted
+                    }
+                    State::Closed if c == options.quote && options.escape.is_none() => {
+                        field.push(c);
+                        State::Quoted
+                    }
+                    State::Closed if c == options.delimiter => {
+                        fields.push(std::mem::take(&mut field));
+                        State::Start
+                    }
+                    State::Closed => bail!("line {}: {:?} after the closing quote of a field", self.line, c),
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! `mini-kv export` and `import`: the default namespace's keys and values
+//! to and from JSON lines or CSV, or a portable snapshot of the whole store
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use clap::ValueEnum;
+use mini_kv::{Engine, PutOptions};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+use crate::csv::CsvOptions;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// A JSON object per line: {"key": ..., "value": ...}
+    Jsonl,
+    /// A row per key, laid out as the CSV options say
+    Csv,
+    /// `Engine::export_snapshot`'s stream: every namespace, with expiry
+    /// times, checksummed; the encodings do not apply
+    Snapshot,
+}
+
+/// How keys or values are written as JSON strings
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Encoding {
+    /// As text; bytes that are not UTF-8 fail the export
+    Utf8,
+    /// Standard base64, for binary data
+    Base64,
+}
+
+impl Encoding {
+    /// `what` is "key" or "value", for the error and the flag it names
+    fn encode(self, bytes: Vec<u8>, what: &str) -> Result<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes).map_err(|e| {
+                anyhow!("{} {} is not UTF-8; export with --{}-encoding base64", what, e.as_bytes().escape_ascii(), what)
+            }),
+            Encoding::Base64 => Ok(BASE64.encode(bytes)),
+        }
+    }
+
+    fn decode(self, text: String) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Ok(text.into_bytes()),
+            Encoding::Base64 => Ok(BASE64.decode(text)?),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    key: String,
+    value: String,
+}
+
+/// How an export is written, or an import read
+pub struct Layout {
+    pub format: Format,
+    pub keys: Encoding,
+    pub values: Encoding,
+    pub csv: CsvOptions,
+}
+
+/// Write the keys starting with `prefix` to `out`; returns how many
+pub fn export(engine: &Engine, mut out: impl Write, layout: &Layout, prefix: &[u8]) -> Result<u64> {
+    let (keys, values) = (layout.keys, layout.values);
+    let mut count = 0;
+    match layout.format {
+        Format::Snapshot => {
+            if !prefix.is_empty() {
+                bail!("a snapshot holds the whole store; --prefix needs --format jsonl or csv");
+            }
+            return engine.export_snapshot(out);
+        }
+        Format::Jsonl => {
+            for (key, value) in engine.scan_prefix(prefix)? {
+                let line = Line { key: keys.encode(key, "key")?, value: values.encode(value, "value")? };
+                serde_json::to_writer(&mut out, &line)?;
+                writeln!(out)?;
+                count += 1;
+            }
+            out.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = layout.csv.writer(out)?;
+            for (key, value) in engine.scan_prefix(prefix)? {
+                writer.row(&keys.encode(key, "key")?, &values.encode(value, "value")?)?;
+                count += 1;
+            }
+            writer.finish()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Put every key read from `input`, syncing once at the end; returns how
+/// many. On an error the keys before it stay written.
+pub fn import(engine: &mut Engine, input: impl BufRead, layout: &Layout) -> Result<u64> {
+    let mut count = 0;
+    match layout.format {
+        Format::Snapshot => return engine.import_snapshot(input),
+        Format::Jsonl => {
+            for (number, line) in input.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let line: Line = serde_json::from_str(&line).with_context(|| format!("line {}", number + 1))?;
+                put(engine, layout, number + 1, line.key, line.value)?;
+                count += 1;
+            }
+        }
+        Format::Csv => {
+            let mut reader = layout.csv.reader(input)?;
+            while let Some((line, key, value)) = reader.next_pair()? {
+                put(engine, layout, line, key, value)?;
+                count += 1;
+            }
+        }
+    }
+    engine.sync()?;
+    Ok(count)
+}
+
+/// Put the key and value read from `line`, leaving the sync to the end
+fn put(engine: &mut Engine, layout: &Layout, line: usize, key: String, value: String) -> Result<()> {
+    let key = layout.keys.decode(key).with_context(|| format!("line {}: key", line))?;
+    let value = layout.values.decode(value).with_context(|| format!("line {}: value", line))?;
+    let options = PutOptions { sync: Some(false), ..PutOptions::default() };
+    engine.put_with_options(key, value, &options).with_context(|| format!("line {}", line))
+}
@@ -0,0 +1,139 @@
+//! The writes `crash_writer` makes, generated from a seed so `crash_test`
+//! can replay them and know what each prefix of the log should hold
+//!
+//! Every op appends exactly one record: deletes only ever name a live key.
+//! So the writer's durable index counts ops, however they mix.
+
+// Each binary uses part of it
+#![allow(dead_code)]
+
+use anyhow::{bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkloadKind {
+    /// A new key per op, `key_<run>_<i>` = `[i as u8; value_size]`
+    Sequential,
+    /// New keys, overwrites, deletes and batches of them, at random
+    Mixed,
+}
+
+impl std::str::FromStr for WorkloadKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sequential" => Ok(WorkloadKind::Sequential),
+            "mixed" => Ok(WorkloadKind::Mixed),
+            _ => bail!("unknown workload {:?} (expected sequential or mixed)", s),
+        }
+    }
+}
+
+impl WorkloadKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            WorkloadKind::Sequential => "sequential",
+            WorkloadKind::Mixed => "mixed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// Endless source of steps: single ops, or batches the writer syncs once
+pub struct Workload {
+    kind: WorkloadKind,
+    rng: StdRng,
+    run: usize,
+    value_size: usize,
+    ops: usize,
+    next_key: usize,
+    live: Vec<Vec<u8>>,
+}
+
+impl Workload {
+    pub fn new(kind: WorkloadKind, seed: u64, run: usize, value_size: usize) -> Self {
+        let rng = StdRng::seed_from_u64(seed);
+        Self { kind, rng, run, value_size, ops: 0, next_key: 0, live: Vec::new() }
+    }
+
+    /// The next op, or batch of 2 to 16 ops
+    pub fn next_step(&mut self) -> Vec<Op> {
+        if self.kind == WorkloadKind::Mixed && self.rng.gen_range(0..100) < 15 {
+            let len = self.rng.gen_range(2..=16);
+            return (0..len).map(|_| self.next_op()).collect();
+        }
+        vec![self.next_op()]
+    }
+
+    /// The first `count` ops, batches flattened
+    pub fn ops(mut self, count: usize) -> Vec<Op> {
+        let mut ops = Vec::with_capacity(count);
+        while ops.len() < count {
+            ops.extend(self.next_step());
+        }
+        ops.truncate(count);
+        ops
+    }
+
+    fn next_op(&mut self) -> Op {
+        self.ops += 1;
+        if self.kind == WorkloadKind::Sequential {
+            let i = self.ops - 1;
+            return Op::Put(format!("key_{}_{}", self.run, i).into_bytes(), vec![i as u8; self.value_size]);
+        }
+        let choice = if self.live.is_empty() { 0 } else { self.rng.gen_range(0..100) };
+        match choice {
+            0..=49 => {
+                let key = format!("key_{}_{}", self.run, self.next_key).into_bytes();
+                self.next_key += 1;
+                self.live.push(key.clone());
+                Op::Put(key, self.value())
+            }
+            50..=79 => {
+                let key = self.live[self.rng.gen_range(0..self.live.len())].clone();
+                Op::Put(key, self.value())
+            }
+            _ => Op::Delete(self.live.swap_remove(self.rng.gen_range(0..self.live.len()))),
+        }
+    }
+
+    fn value(&mut self) -> Vec<u8> {
+        let mut value = vec![0; self.value_size];
+        self.rng.fill(&mut value[..]);
+        value
+    }
+}
+
+/// The longest prefix of `ops` whose result is exactly `recovered`, if any
+/// is at least `min` ops long; found in one pass by counting the keys on
+/// which the two differ
+pub fn matching_prefix(ops: &[Op], recovered: &HashMap<Vec<u8>, Vec<u8>>, min: usize) -> Option<usize> {
+    let mut expected: HashMap<&[u8], &[u8]> = HashMap::new();
+    let differs = |expected: &HashMap<&[u8], &[u8]>, key: &[u8]| expected.get(key).copied() != recovered.get(key).map(Vec::as_slice);
+    let mut differing = recovered.len();
+    let mut longest = (min == 0 && differing == 0).then_some(0);
+    for (i, op) in ops.iter().enumerate() {
+        let key = match op {
+            Op::Put(key, _) | Op::Delete(key) => key.as_slice(),
+        };
+        let before = differs(&expected, key);
+        match op {
+            Op::Put(_, value) => expected.insert(key, value),
+            Op::Delete(_) => expected.remove(key),
+        };
+        let after = differs(&expected, key);
+        differing = differing + after as usize - before as usize;
+        if differing == 0 && i + 1 >= min {
+            longest = Some(i + 1);
+        }
+    }
+    longest
+}
@@ -1,14 +1,179 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use mini_kv::{Engine, SyncMode};
-use std::fs;
-use std::process::{Command, Child};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Child, ExitStatus};
 use std::thread;
 use std::time::{Duration, Instant};
 use rand::Rng;
+use std::collections::HashMap;
 
+#[path = "common/crash_workload.rs"]
+mod crash_workload;
+use crash_workload::{matching_prefix, Workload, WorkloadKind};
+
+/// Both relative to the run directory the writer is started in
 const DB_PATH: &str = "crash_test.db";
 const PROGRESS_FILE: &str = "durable_progress.txt";
-const TOTAL_WRITES: usize = 10_000;
+/// The most a disk is taken to persist atomically
+const BLOCK: u64 = 4096;
+
+const USAGE: &str = "Usage: crash_test [--runs <n>] [--modes <mode>,...] [--writes <n>] [--value-size <bytes>]
+                  [--workload sequential|mixed] [--seed <n>] [--json <file>]
+  <mode> is always, batch:<writes>, periodic:<ms> or adaptive:<ms>, optionally followed by
+  +buf (a 64KB write buffer) or +flush:<n> (the buffer, flushed every n writes).
+  Run i of each mode uses workload seed <n> + i; <n> is random by default.";
+
+// Buffered writes reach the OS at each flush() or sync(), so a killed
+// writer loses only what it buffered since the last of them
+const DEFAULT_MODES: &str = "always,batch:100,periodic:100,batch:100+buf,batch:100+flush:10";
+
+/// Write buffer the `+buf` and `+flush` modes use
+const WRITE_BUFFER: usize = 64 * 1024;
+
+struct Options {
+    runs: usize,
+    modes: Vec<Mode>,
+    writes: usize,
+    value_size: usize,
+    workload: WorkloadKind,
+    /// Seed of the first run's workload; each run adds its number
+    seed: u64,
+    /// Where to write one JSON object per run, one per line
+    json: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Options> {
+    let mut args = env::args().skip(1);
+    let mut parsed = Options {
+        runs: 10,
+        modes: parse_modes(DEFAULT_MODES)?,
+        writes: 10_000,
+        value_size: 128,
+        workload: WorkloadKind::Sequential,
+        seed: rand::thread_rng().gen(),
+        json: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--runs" => parsed.runs = value()?.parse().context("--runs")?,
+            "--modes" => parsed.modes = parse_modes(&value()?)?,
+            "--writes" => parsed.writes = value()?.parse().context("--writes")?,
+            "--value-size" => parsed.value_size = value()?.parse().context("--value-size")?,
+            "--workload" => parsed.workload = value()?.parse()?,
+            "--seed" => parsed.seed = value()?.parse().context("--seed")?,
+            "--json" => parsed.json = Some(value()?.into()),
+            _ => bail!("unexpected argument {}", arg),
+        }
+    }
+    // Crash points fall between a fifth and four fifths of the writes
+    if parsed.writes < 10 {
+        bail!("--writes must be at least 10");
+    }
+    Ok(parsed)
+}
+
+/// A sync mode and how the writer buffers under it
+#[derive(Debug, Clone, Copy)]
+struct Mode {
+    sync: SyncMode,
+    buffering: Buffering,
+}
+
+fn parse_modes(list: &str) -> Result<Vec<Mode>> {
+    list.split(',').map(|mode| parse_mode(mode).with_context(|| format!("mode {:?}", mode))).collect()
+}
+
+/// `always`, `batch:100+buf`, `periodic:100+flush:10`, ...
+fn parse_mode(s: &str) -> Result<Mode> {
+    let (sync, buffering) = s.split_once('+').map_or((s, None), |(sync, buffering)| (sync, Some(buffering)));
+    let buffering = match buffering.map(|b| b.split_once(':').map_or((b, None), |(name, n)| (name, Some(n)))) {
+        None => None,
+        Some(("buf", None)) => Some((WRITE_BUFFER, 0)),
+        Some(("flush", Some(n))) => Some((WRITE_BUFFER, n.parse()?)),
+        Some(_) => bail!("expected +buf or +flush:<n>"),
+    };
+    Ok(Mode { sync: sync.parse()?, buffering })
+}
+
+impl Mode {
+    /// e.g. `batch_100+flush_10`, for the table and the report
+    fn name(&self) -> String {
+        let mut name = match self.sync {
+            SyncMode::Always => "always".to_string(),
+            SyncMode::Batch(n) => format!("batch_{}", n),
+            SyncMode::Periodic(d) => format!("periodic_{}ms", d.as_millis()),
+            SyncMode::Adaptive { max_latency } => format!("adaptive_{}ms", max_latency.as_millis()),
+        };
+        match self.buffering {
+            Some((_, 0)) => name.push_str("+buf"),
+            Some((_, n)) => name.push_str(&format!("+flush_{}", n)),
+            None => {}
+        }
+        name
+    }
+}
+
+/// What one run found, for the JSON report
+struct RunReport {
+    mode: String,
+    run: usize,
+    seed: u64,
+    /// Durable index the writer was killed after reaching
+    target: usize,
+    /// Durable index when it was killed; `None` if it never got there
+    durable: Option<usize>,
+    /// Ops of the workload the recovered store holds the result of
+    recovered: Option<usize>,
+    killed: bool,
+    torn: bool,
+    corruption: bool,
+    error: Option<String>,
+}
+
+impl RunReport {
+    fn lost(&self) -> Option<usize> {
+        Some(self.durable?.saturating_sub(self.recovered?))
+    }
+
+    fn to_json(&self, options: &Options) -> String {
+        let number = |n: Option<usize>| n.map_or("null".to_string(), |n| n.to_string());
+        format!(
+            "{{\"mode\":{},\"run\":{},\"workload\":{},\"seed\":{},\"writes\":{},\"value_size\":{},\"crash_point\":{},\"durable\":{},\"recovered\":{},\"lost\":{},\"killed\":{},\"torn\":{},\"corruption\":{},\"error\":{}}}",
+            json_string(&self.mode),
+            self.run,
+            json_string(options.workload.name()),
+            self.seed,
+            options.writes,
+            options.value_size,
+            self.target,
+            number(self.durable),
+            number(self.recovered),
+            number(self.lost()),
+            self.killed,
+            self.torn,
+            self.corruption,
+            self.error.as_deref().map_or("null".to_string(), json_string),
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
 
 // 1. 确保结构体定义包含所有需要的字段
 #[derive(Debug, Clone)]
@@ -54,14 +219,20 @@ fn aggregate_results(results: Vec<CrashResult>) -> CrashResult {
     }
 }
 
-fn wait_for_durable_progress(target: usize, timeout: Duration) -> Result<usize> {
+/// Durable index and end of the synced log, as the writer in `dir` last
+/// reported
+fn read_progress(dir: &Path) -> Option<(usize, u64)> {
+    let content = fs::read_to_string(dir.join(PROGRESS_FILE)).ok()?;
+    let mut parts = content.split_whitespace();
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+fn wait_for_durable_progress(dir: &Path, target: usize, timeout: Duration) -> Result<usize> {
     let start = Instant::now();
     while start.elapsed() < timeout {
-        if let Ok(content) = fs::read_to_string(PROGRESS_FILE) {
-            if let Ok(progress) = content.trim().parse::<usize>() {
-                if progress >= target {
-                    return Ok(progress);
-                }
+        if let Some((progress, _)) = read_progress(dir) {
+            if progress >= target {
+                return Ok(progress);
             }
         }
         thread::sleep(Duration::from_millis(2));
@@ -69,37 +240,142 @@ fn wait_for_durable_progress(target: usize, timeout: Duration) -> Result<usize>
     Err(anyhow::anyhow!("Timeout waiting for durable progress {}", target))
 }
 
-fn run_crash_test(mode: SyncMode, runs: usize) -> Result<Vec<CrashResult>> {
+/// A SIGKILL loses nothing the OS already has, but a power loss can
+/// persist only part of the last block written after the final sync. Cut
+/// the log short, or zero it, from a random point in that block, leaving
+/// everything before `synced` alone; false if nothing was unsynced
+fn tear_final_block(dir: &Path, rng: &mut impl Rng, synced: u64) -> Result<bool> {
+    let mut file = OpenOptions::new().write(true).open(dir.join(DB_PATH))?;
+    let len = file.metadata()?.len();
+    if len <= synced {
+        return Ok(false);
+    }
+    let block_start = ((len - 1) / BLOCK * BLOCK).max(synced);
+    let at = rng.gen_range(block_start..len);
+    if rng.gen() {
+        file.set_len(at)?;
+    } else {
+        file.seek(SeekFrom::Start(at))?;
+        file.write_all(&vec![0; (len - at) as usize])?;
+    }
+    file.sync_all()?;
+    Ok(true)
+}
+
+/// The `crash_writer` binary: `$CRASH_WRITER` if set, then the one Cargo
+/// names when this is built as a test or bench, then the one next to this
+/// binary, where Cargo puts every binary of a profile
+fn writer_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("CRASH_WRITER") {
+        return Ok(path.into());
+    }
+    if let Some(path) = option_env!("CARGO_BIN_EXE_crash_writer") {
+        return Ok(path.into());
+    }
+    let exe = env::current_exe()?;
+    let path = exe.with_file_name(format!("crash_writer{}", env::consts::EXE_SUFFIX));
+    if !path.exists() {
+        bail!("{} not found; build it, or set CRASH_WRITER to its path", path.display());
+    }
+    Ok(path)
+}
+
+/// A fresh directory for one run, unique to this process, under the
+/// system's temporary directory
+fn run_dir(mode: &str, run: usize) -> Result<PathBuf> {
+    let dir = env::temp_dir().join(format!("mini-kv-crash-{}-{}-{}", process::id(), mode, run));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Kill the writer with no chance to clean up, as `Child::kill` does:
+/// SIGKILL on Unix, TerminateProcess on Windows. False if it had already
+/// exited on its own, so the run saw no crash.
+fn kill_writer(child: &mut Child) -> Result<bool> {
+    if child.try_wait()?.is_some() {
+        return Ok(false);
+    }
+    child.kill()?;
+    Ok(was_killed(&child.wait()?))
+}
+
+#[cfg(unix)]
+fn was_killed(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal() == Some(nix::sys::signal::Signal::SIGKILL as i32)
+}
+
+/// `Child::kill` terminates with exit code 1
+#[cfg(windows)]
+fn was_killed(status: &ExitStatus) -> bool {
+    status.code() == Some(1)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn was_killed(status: &ExitStatus) -> bool {
+    !status.success()
+}
+
+/// Write buffer size and how many writes apart the writer calls flush(),
+/// for runs that buffer writes between syncs
+type Buffering = Option<(usize, usize)>;
+
+/// Run `mode` `options.runs` times, adding a line per run to `report`;
+/// returns the results of the runs that recovered, and the reports of all
+fn run_crash_test(writer: &Path, mode: Mode, options: &Options, report: &mut Option<File>) -> Result<(Vec<CrashResult>, Vec<RunReport>)> {
     let mut results = Vec::new();
+    let mut reports = Vec::new();
     let mut rng = rand::thread_rng();
+    let mode_display = mode.name();
     
-    let mode_display = match mode {
-        SyncMode::Always => "always".to_string(),
-        SyncMode::Batch(n) => format!("batch_{}", n),
-        SyncMode::Periodic(d) => format!("periodic_{}ms", d.as_millis()),
-    };
+    println!("Testing {} mode ({} runs)...", mode_display, options.runs);
     
-    println!("Testing {} mode ({} runs)...", mode_display, runs);
-    
-    for run in 0..runs {
-        let _ = fs::remove_file(DB_PATH);
-        let _ = fs::remove_file(PROGRESS_FILE);
-        
-        let crash_point = rng.gen_range(2000..8000);
-        let mut child = spawn_writer(&mode, run)?;
+    let (mut torn, mut finished) = (0, 0);
+    for run in 0..options.runs {
+        let dir = run_dir(&mode_display, run)?;
+        let crash_point = rng.gen_range(options.writes / 5..options.writes * 4 / 5);
+        let seed = options.seed.wrapping_add(run as u64);
+        let mut child = spawn_writer(writer, &dir, mode, options, run, seed)?;
+        let mut outcome = RunReport {
+            mode: mode_display.clone(),
+            run,
+            seed,
+            target: crash_point,
+            durable: None,
+            recovered: None,
+            killed: false,
+            torn: false,
+            corruption: false,
+            error: None,
+        };
         
-        match wait_for_durable_progress(crash_point, Duration::from_secs(10)) {
+        match wait_for_durable_progress(&dir, crash_point, Duration::from_secs(10)) {
             Ok(durable_at) => {
-                child.kill().ok();
-                let _ = child.wait();
+                outcome.durable = Some(durable_at);
+                outcome.killed = kill_writer(&mut child)?;
+                if !outcome.killed {
+                    finished += 1;
+                }
                 
+                // Windows may take a moment to release the writer's handles
                 thread::sleep(Duration::from_millis(50));
+
+                // The writer is dead, so its last report is final
+                if let Some((_, synced)) = read_progress(&dir) {
+                    match tear_final_block(&dir, &mut rng, synced) {
+                        Ok(tore) => outcome.torn = tore,
+                        Err(e) => println!("\nCould not tear the log: {}", e),
+                    }
+                }
+                torn += outcome.torn as usize;
                 
                 // 3. 校验逻辑修复：处理 recovered > durable_at 的情况
-                match verify_data(run) {
+                match verify_data(&dir, run, seed, durable_at, options) {
                     Ok(recovered) => {
+                        outcome.recovered = Some(recovered);
                         // 如果实际恢复的大于进度标记，则丢失为0，不报错
-                        let lost = if recovered >= durable_at { 0 } else { durable_at - recovered };
+                        let lost = durable_at.saturating_sub(recovered);
                         
                         results.push(CrashResult {
                             mode: mode_display.clone(),
@@ -112,59 +388,116 @@ fn run_crash_test(mode: SyncMode, runs: usize) -> Result<Vec<CrashResult>> {
                             max_lost: lost,
                         });
                         print!(".");
+                        let _ = fs::remove_dir_all(&dir);
+                    }
+                    // Kept for inspection
+                    Err(e) => {
+                        println!("\nCorruption in {}: {}", dir.display(), e);
+                        outcome.corruption = true;
+                        outcome.error = Some(format!("{:#}", e));
                     }
-                    Err(e) => println!("\nCorruption: {}", e),
                 }
             }
-            Err(_) => { child.kill().ok(); }
+            Err(e) => {
+                let _ = kill_writer(&mut child);
+                let _ = fs::remove_dir_all(&dir);
+                outcome.error = Some(e.to_string());
+            }
+        }
+        if let Some(file) = report {
+            writeln!(file, "{}", outcome.to_json(options))?;
         }
+        reports.push(outcome);
     }
-    println!(" Done.");
-    Ok(results)
+    print!(" Done ({} with a torn final block", torn);
+    if finished > 0 {
+        print!(", {} finished before the kill", finished);
+    }
+    println!(").");
+    Ok((results, reports))
 }
 
-fn spawn_writer(mode: &SyncMode, run: usize) -> Result<Child> {
-    let mode_arg = match mode {
+/// Start the writer in `dir`, where it creates the database and reports
+/// its progress
+fn spawn_writer(writer: &Path, dir: &Path, mode: Mode, options: &Options, run: usize, seed: u64) -> Result<Child> {
+    let mode_arg = match mode.sync {
         SyncMode::Always => "always".to_string(),
         SyncMode::Batch(n) => format!("batch:{}", n),
         SyncMode::Periodic(d) => format!("periodic:{}", d.as_millis()),
+        SyncMode::Adaptive { max_latency } => format!("adaptive:{}", max_latency.as_millis()),
     };
     
-    let mut cmd = Command::new("target/debug/crash_writer.exe");
-    cmd.arg(mode_arg).arg(run.to_string());
-    Ok(cmd.spawn()?)
+    let mut cmd = Command::new(writer);
+    cmd.current_dir(dir).arg(mode_arg).arg(run.to_string());
+    cmd.arg("--writes").arg(options.writes.to_string()).arg("--value-size").arg(options.value_size.to_string());
+    cmd.arg("--workload").arg(options.workload.name());
+    cmd.arg("--seed").arg(seed.to_string());
+    if let Some((write_buffer, flush_every)) = mode.buffering {
+        cmd.arg("--write-buffer").arg(write_buffer.to_string()).arg("--flush-every").arg(flush_every.to_string());
+    }
+    cmd.spawn().with_context(|| format!("starting {}", writer.display()))
 }
 
-fn verify_data(run: usize) -> Result<usize> {
-    let engine = Engine::open(DB_PATH)?;
-    let mut count = 0;
-    for i in 0..TOTAL_WRITES {
-        let key = format!("key_{}_{}", run, i).into_bytes();
-        if engine.contains_key(&key) { count = i + 1; } else { break; }
+/// Replay the run's workload and return the longest prefix of it the
+/// recovered store holds the result of, failing if none of at least
+/// `durable` ops matches (a value that was never written, a durable write
+/// lost, ops recovered out of order) or if the log does not verify
+fn verify_data(dir: &Path, run: usize, seed: u64, durable: usize, options: &Options) -> Result<usize> {
+    let engine = Engine::open(dir.join(DB_PATH))?;
+    let recovered: HashMap<_, _> = engine.scan_prefix(b"")?.into_iter().collect();
+    let ops = Workload::new(options.workload, seed, run, options.value_size).ops(options.writes);
+    let Some(count) = matching_prefix(&ops, &recovered, durable) else {
+        bail!("the {} keys recovered are not the result of the first {} or more writes", recovered.len(), durable);
+    };
+    let report = engine.verify()?;
+    if !report.is_healthy() {
+        bail!("recovered log does not verify: {:?}", report);
     }
     Ok(count)
 }
 
+/// Exits with status 1 if any run found corruption or lost a write that
+/// was durable when the writer was killed
 fn main() -> Result<()> {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{:#}\n{}", e, USAGE);
+        process::exit(2);
+    });
     println!("=== Mini-KV Crash Consistency Lab ===\n");
-    Command::new("cargo").args(&["build", "--bin", "crash_writer"]).status()?;
-    
-    let modes = vec![
-        SyncMode::Always,
-        SyncMode::Batch(100),
-        SyncMode::Periodic(Duration::from_millis(100)),
-    ];
+    if env::var_os("CRASH_WRITER").is_none() {
+        // Build the writer for the profile this binary was built with, so
+        // it lands next to it
+        let mut build = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+        build.args(["build", "--bin", "crash_writer"]);
+        if !cfg!(debug_assertions) {
+            build.arg("--release");
+        }
+        if !build.status().is_ok_and(|status| status.success()) {
+            println!("Could not build crash_writer; using any already built");
+        }
+    }
+    let writer = writer_path()?;
+    let mut report = match &options.json {
+        Some(path) => Some(File::create(path).with_context(|| format!("creating {}", path.display()))?),
+        None => None,
+    };
     
-    println!("\n{:<15} {:>6} {:>12} {:>12} {:>12} {:>10} {:>10} {:>10}",
+    println!("\n{:<20} {:>6} {:>12} {:>12} {:>12} {:>10} {:>10} {:>10}",
              "Mode", "Runs", "Avg Durable", "Avg Recov", "Avg Lost", "Min Rec", "Max Rec", "Max Lost");
-    println!("{:-<95}", "");
+    println!("{:-<100}", "");
     
-    for mode in modes {
-        let results = run_crash_test(mode, 10)?;
+    let mut failures = 0;
+    for &mode in &options.modes {
+        let (results, reports) = run_crash_test(&writer, mode, &options, &mut report)?;
+        failures += reports.iter().filter(|r| r.corruption || r.lost().is_some_and(|lost| lost > 0)).count();
         let agg = aggregate_results(results);
-        println!("{:<15} {:>6} {:>12} {:>12} {:>12} {:>10} {:>10} {:>10}",
+        println!("{:<20} {:>6} {:>12} {:>12} {:>12} {:>10} {:>10} {:>10}",
                  agg.mode, agg.runs, agg.crash_point, agg.recovered, agg.lost, 
                  agg.min_recovered, agg.max_recovered, agg.max_lost);
     }
+    if failures > 0 {
+        println!("\n{} runs lost durable writes or found corruption", failures);
+        process::exit(1);
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,21 +1,66 @@
-use anyhow::Result;
-use mini_kv::{Engine, SyncMode};
+use anyhow::{anyhow, bail, Context, Result};
+use mini_kv::{Engine, PutOptions, SyncMode};
 use std::env;
 use std::time::Duration;
 
+#[path = "common/crash_workload.rs"]
+mod crash_workload;
+use crash_workload::{Op, Workload, WorkloadKind};
+
 const DB_PATH: &str = "crash_test.db";
-const TOTAL_WRITES: usize = 10_000;
+
+const USAGE: &str =
+    "Usage: crash_writer <mode> <run_id> [--writes <n>] [--value-size <bytes>] [--workload sequential|mixed] [--seed <n>] [--write-buffer <bytes>] [--flush-every <n>]";
+
+struct WriterArgs {
+    mode: String,
+    run_id: usize,
+    writes: usize,
+    value_size: usize,
+    workload: WorkloadKind,
+    seed: u64,
+    write_buffer: usize,
+    /// How many writes apart to flush() the write buffer; 0 for never
+    flush_every: usize,
+}
+
+fn parse_args() -> Result<WriterArgs> {
+    let mut args = env::args().skip(1);
+    let mode = args.next().ok_or_else(|| anyhow!("missing <mode>"))?;
+    let run_id = args.next().ok_or_else(|| anyhow!("missing <run_id>"))?.parse().context("<run_id>")?;
+    let mut parsed = WriterArgs {
+        mode,
+        run_id,
+        writes: 10_000,
+        value_size: 128,
+        workload: WorkloadKind::Sequential,
+        seed: 0,
+        write_buffer: 0,
+        flush_every: 0,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--writes" => parsed.writes = value()?.parse().context("--writes")?,
+            "--value-size" => parsed.value_size = value()?.parse().context("--value-size")?,
+            "--workload" => parsed.workload = value()?.parse()?,
+            "--seed" => parsed.seed = value()?.parse().context("--seed")?,
+            "--write-buffer" => parsed.write_buffer = value()?.parse().context("--write-buffer")?,
+            "--flush-every" => parsed.flush_every = value()?.parse().context("--flush-every")?,
+            _ => bail!("unexpected argument {}", arg),
+        }
+    }
+    Ok(parsed)
+}
 
 /// Child process that writes data until killed by parent
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: crash_writer <mode> <run_id>");
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{:#}\n{}", e, USAGE);
         std::process::exit(1);
-    }
-    
-    let mode_str = &args[1];
-    let run_id = args[2].parse::<usize>().unwrap();
+    });
+    let mode_str = &args.mode;
+    let (run_id, write_buffer, flush_every) = (args.run_id, args.write_buffer, args.flush_every);
     
     // Enable progress reporting for parent
     env::set_var("CRASH_TEST", "1");
@@ -31,6 +76,10 @@ fn main() -> Result<()> {
             let ms = s[9..].parse::<u64>().unwrap();
             SyncMode::Periodic(Duration::from_millis(ms))
         },
+        s if s.starts_with("adaptive:") => {
+            let ms = s[9..].parse::<u64>().unwrap();
+            SyncMode::Adaptive { max_latency: Duration::from_millis(ms) }
+        },
         _ => {
             eprintln!("Unknown mode: {}", mode_str);
             std::process::exit(1);
@@ -38,12 +87,32 @@ fn main() -> Result<()> {
     };
     
     // Open engine and start writing
-    let mut engine = Engine::with_sync(DB_PATH, sync_mode)?;
+    let mut engine = Engine::builder(DB_PATH).sync_mode(sync_mode).write_buffer(write_buffer).open()?;
     
-    for i in 0..TOTAL_WRITES {
-        let key = format!("key_{}_{}", run_id, i).into_bytes();
-        let value = vec![i as u8; 128];
-        engine.put(key, value)?;
+    // Ops of a batch skip the syncs the mode would do, and are synced
+    // together at its end
+    let mut workload = Workload::new(args.workload, args.seed, run_id, args.value_size);
+    let mut issued = 0;
+    while issued < args.writes {
+        let mut step = workload.next_step();
+        step.truncate(args.writes - issued);
+        let batch = step.len() > 1;
+        let options = PutOptions { sync: batch.then_some(false), ..Default::default() };
+        for op in step {
+            match op {
+                Op::Put(key, value) => engine.put_with_options(key, value, &options)?,
+                Op::Delete(key) => {
+                    engine.delete(&key)?;
+                }
+            }
+            issued += 1;
+            if flush_every > 0 && issued % flush_every == 0 {
+                engine.flush()?;
+            }
+        }
+        if batch {
+            engine.sync()?;
+        }
     }
     
     for i in 0..10_000 {
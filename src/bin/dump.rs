@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Context, Result};
+use mini_kv::{Format, RecordKind, ScanItem, Scanner};
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "Usage: mini-kv-dump <db> [--prefix <key-prefix>] [--from <offset>] [--to <offset>] [--hex]";
+
+struct DumpArgs {
+    db_path: String,
+    prefix: Option<Vec<u8>>,
+    from: u64,
+    to: u64,
+    hex: bool,
+}
+
+fn parse_args() -> Result<DumpArgs> {
+    let mut args = env::args().skip(1);
+    let mut parsed = DumpArgs { db_path: String::new(), prefix: None, from: 0, to: u64::MAX, hex: false };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--prefix" => parsed.prefix = Some(value()?.into_bytes()),
+            "--from" => parsed.from = value()?.parse().context("--from")?,
+            "--to" => parsed.to = value()?.parse().context("--to")?,
+            "--hex" => parsed.hex = true,
+            _ if arg.starts_with("--") || !parsed.db_path.is_empty() => return Err(anyhow!("unexpected argument {}", arg)),
+            _ => parsed.db_path = arg,
+        }
+    }
+    if parsed.db_path.is_empty() {
+        return Err(anyhow!("missing <db>"));
+    }
+    Ok(parsed)
+}
+
+fn format_key(key: &[u8], hex: bool) -> String {
+    if hex {
+        key.iter().map(|b| format!("{:02x}", b)).collect()
+    } else {
+        key.escape_ascii().to_string()
+    }
+}
+
+fn kind_name(kind: RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Put => "put",
+        RecordKind::Delete => "del",
+        RecordKind::ValuePointer => "vptr",
+        RecordKind::RangeDelete => "rdel",
+    }
+}
+
+/// Print one line per record (and per damaged region) of a db file
+///
+/// Damaged regions are always shown when they fall inside the offset
+/// range, since their keys are unknown.
+fn main() -> Result<()> {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{:#}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    let buf = fs::read(&args.db_path).with_context(|| format!("reading {}", args.db_path))?;
+    let (format, records_start) = Format::parse(&buf)?;
+
+    match dump(&args, Scanner::with_format(&buf, records_start, &format)) {
+        // Piped into `head` or similar
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn dump(args: &DumpArgs, records: Scanner) -> io::Result<()> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    writeln!(out, "{:>12}  {:<8} {:<17} {:>10}  key", "offset", "type", "crc", "value_len")?;
+    for item in records {
+        match item {
+            ScanItem::Valid { offset, record, .. } => {
+                if offset < args.from || offset >= args.to {
+                    continue;
+                }
+                if args.prefix.as_ref().is_some_and(|p| !record.key.starts_with(p)) {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{:>12}  {:<8} {:<17} {:>10}  {}",
+                    offset, kind_name(record.kind), "ok", record.value.len(), format_key(&record.key, args.hex)
+                )?;
+            }
+            // Intact, but compressed with a codec this build lacks, or encrypted
+            ScanItem::Unreadable { offset, key, reason, .. } => {
+                if offset < args.from || offset >= args.to {
+                    continue;
+                }
+                if let (Some(prefix), Some(key)) = (&args.prefix, &key) {
+                    if !key.starts_with(prefix) {
+                        continue;
+                    }
+                }
+                let key = key.map_or_else(|| "?".to_string(), |key| format_key(&key, args.hex));
+                writeln!(out, "{:>12}  {:<8} {:<17} {:>10}  {}  ({})", offset, "?", "ok", "?", key, reason)?;
+            }
+            ScanItem::Corrupt { offset, len, reason } => {
+                if offset < args.from || offset >= args.to {
+                    continue;
+                }
+                writeln!(out, "{:>12}  {:<8} {:<17} {:>10}  ({} bytes)", offset, "corrupt", reason, "-", len)?;
+            }
+        }
+    }
+    out.flush()
+}
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use mini_kv::{Engine, SyncMode};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio_stream::Stream;
+use tonic::{transport::Server, Request, Response, Status};
+
+mod pb {
+    tonic::include_proto!("minikv");
+}
+
+use pb::mini_kv_server::{MiniKv, MiniKvServer};
+use pb::{
+    DeleteRequest, DeleteResponse, GetRequest, GetResponse, KeyValue, PutRequest, PutResponse,
+    ScanRequest, StatsRequest, StatsResponse,
+};
+
+/// Serve a mini-kv database over gRPC (see proto/mini_kv.proto)
+#[derive(Parser)]
+#[command(name = "mini-kv-grpc", version)]
+struct Args {
+    /// Path to the database file (created if missing)
+    #[arg(short, long, env = "MINI_KV_DB")]
+    db: PathBuf,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:50051")]
+    bind: std::net::SocketAddr,
+
+    /// always, batch:<writes>, periodic:<milliseconds> or adaptive:<milliseconds>
+    #[arg(short, long, default_value = "always")]
+    sync: SyncMode,
+}
+
+struct Service {
+    engine: Arc<Mutex<Engine>>,
+}
+
+impl Service {
+    /// Engine calls may fsync, so run them off the async worker threads
+    async fn with_engine<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Engine) -> Result<T> + Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine);
+        tokio::task::spawn_blocking(move || {
+            let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut engine)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(format!("{:#}", e)))
+    }
+}
+
+type ScanStream = Pin<Box<dyn Stream<Item = Result<KeyValue, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl MiniKv for Service {
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        self.with_engine(move |engine| engine.put(key, value)).await?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self.with_engine(move |engine| engine.get(&key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let key = request.into_inner().key;
+        let deleted = self.with_engine(move |engine| engine.delete(&key)).await?;
+        Ok(Response::new(DeleteResponse { deleted }))
+    }
+
+    type ScanStream = ScanStream;
+
+    // Stream items are `Result<_, Status>` by tonic's design
+    #[allow(clippy::result_large_err)]
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let pairs = self.with_engine(move |engine| engine.scan_prefix(&prefix)).await?;
+        let stream = tokio_stream::iter(pairs.into_iter().map(|(key, value)| Ok(KeyValue { key, value })));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn stats(&self, _request: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let stats = self
+            .with_engine(|engine| {
+                Ok(StatsResponse {
+                    keys: engine.len() as u64,
+                    log_records: engine.log_records() as u64,
+                    file_size: std::fs::metadata(engine.path())?.len(),
+                })
+            })
+            .await?;
+        Ok(Response::new(stats))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let engine = Engine::with_sync(&args.db, args.sync)
+        .with_context(|| format!("opening {}", args.db.display()))?;
+
+    eprintln!("mini-kv-grpc: serving {} on {} ({:?})", args.db.display(), args.bind, args.sync);
+    let service = Service { engine: Arc::new(Mutex::new(engine)) };
+    Server::builder()
+        .add_service(MiniKvServer::new(service))
+        .serve(args.bind)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use mini_kv::{Engine, SyncMode};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest request body accepted
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+/// Serve a mini-kv database over HTTP
+///
+/// - `GET /keys/{key}`: value as raw bytes, 404 if missing
+/// - `PUT /keys/{key}`: store the request body as the value
+/// - `DELETE /keys/{key}`: 204, or 404 if missing
+/// - `GET /stats`: JSON counters
+/// - `GET /metrics`: Prometheus metrics, with the `metrics` feature
+///
+/// Keys are percent-decoded, so any byte string can be addressed.
+#[derive(Parser)]
+#[command(name = "mini-kv-http", version)]
+struct Args {
+    /// Path to the database file (created if missing)
+    #[arg(short, long, env = "MINI_KV_DB")]
+    db: PathBuf,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    bind: String,
+
+    /// always, batch:<writes>, periodic:<milliseconds> or adaptive:<milliseconds>
+    #[arg(short, long, default_value = "always")]
+    sync: SyncMode,
+
+    /// Worker threads handling requests
+    #[arg(short, long, default_value_t = 4)]
+    threads: usize,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let engine = Engine::with_sync(&args.db, args.sync)
+        .with_context(|| format!("opening {}", args.db.display()))?;
+    let engine = Arc::new(Mutex::new(engine));
+
+    let server = Server::http(&args.bind).map_err(|e| anyhow!("binding {}: {}", args.bind, e))?;
+    let server = Arc::new(server);
+    eprintln!("mini-kv-http: serving {} on http://{} ({:?})", args.db.display(), args.bind, args.sync);
+
+    let workers: Vec<_> = (0..args.threads.max(1))
+        .map(|_| {
+            let (server, engine) = (Arc::clone(&server), Arc::clone(&engine));
+            thread::spawn(move || loop {
+                let request = match server.recv() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        eprintln!("accept failed: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = handle(request, &engine) {
+                    eprintln!("request failed: {}", e);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn handle(mut request: Request, engine: &Mutex<Engine>) -> std::io::Result<()> {
+    let response = match route(&mut request, engine) {
+        Ok(response) => response,
+        Err(e) => text(500, &format!("{:#}\n", e)),
+    };
+    request.respond(response)
+}
+
+type Reply = Response<std::io::Cursor<Vec<u8>>>;
+
+fn route(request: &mut Request, engine: &Mutex<Engine>) -> Result<Reply> {
+    let url = request.url().split('?').next().unwrap_or_default().to_string();
+
+    if url == "/stats" {
+        return match request.method() {
+            Method::Get => stats(&engine.lock().unwrap_or_else(|e| e.into_inner())),
+            _ => Ok(text(405, "method not allowed\n")),
+        };
+    }
+    #[cfg(feature = "metrics")]
+    if url == "/metrics" {
+        return Ok(match request.method() {
+            Method::Get => Response::from_string(mini_kv::metrics::encode_text())
+                .with_header(content_type("text/plain; version=0.0.4")),
+            _ => text(405, "method not allowed\n"),
+        });
+    }
+    let Some(encoded_key) = url.strip_prefix("/keys/") else {
+        return Ok(text(404, "not found\n"));
+    };
+    let Some(key) = percent_decode(encoded_key) else {
+        return Ok(text(400, "malformed percent-encoding in key\n"));
+    };
+
+    match request.method() {
+        Method::Get => Ok(match engine.lock().unwrap_or_else(|e| e.into_inner()).get(&key)? {
+            Some(value) => Response::from_data(value).with_header(content_type("application/octet-stream")),
+            None => text(404, "key not found\n"),
+        }),
+        Method::Put => {
+            if request.body_length().is_some_and(|len| len > MAX_BODY_LEN) {
+                return Ok(text(413, "value too large\n"));
+            }
+            let mut value = Vec::new();
+            request.as_reader().take(MAX_BODY_LEN as u64 + 1).read_to_end(&mut value)?;
+            if value.len() > MAX_BODY_LEN {
+                return Ok(text(413, "value too large\n"));
+            }
+            engine.lock().unwrap_or_else(|e| e.into_inner()).put(key, value)?;
+            Ok(Response::from_data(Vec::new()).with_status_code(204))
+        }
+        Method::Delete => Ok(if engine.lock().unwrap_or_else(|e| e.into_inner()).delete(&key)? {
+            Response::from_data(Vec::new()).with_status_code(204)
+        } else {
+            text(404, "key not found\n")
+        }),
+        _ => Ok(text(405, "method not allowed\n")),
+    }
+}
+
+fn stats(engine: &Engine) -> Result<Reply> {
+    let file_size = fs::metadata(engine.path())?.len();
+    let body = format!(
+        "{{\"keys\":{},\"log_records\":{},\"file_size\":{}}}\n",
+        engine.len(), engine.log_records(), file_size
+    );
+    Ok(Response::from_string(body).with_header(content_type("application/json")))
+}
+
+fn text(status: u16, body: &str) -> Reply {
+    Response::from_string(body).with_status_code(status).with_header(content_type("text/plain; charset=utf-8"))
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).expect("valid header")
+}
+
+/// Decode `%XX` escapes; returns `None` on a malformed escape
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
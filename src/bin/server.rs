@@ -0,0 +1,347 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use mini_kv::{Cursor, Engine, SyncMode};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Largest bulk string accepted from a client
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+/// Largest number of arguments in one command
+const MAX_ARGS: usize = 1024 * 1024;
+/// Longest line (an inline command, or a RESP or HTTP header line)
+/// accepted from a client, as Redis limits inline commands
+const MAX_LINE: usize = 64 * 1024;
+/// Arguments allocated for before any arrives; more grow the list
+const PREALLOCATED_ARGS: usize = 1024;
+/// Default SCAN page size, as in Redis
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serve a mini-kv database over the Redis protocol (RESP)
+///
+/// Supports GET, SET, DEL, EXISTS, SCAN and PING, enough for redis-cli and
+/// most client libraries.
+#[derive(Parser)]
+#[command(name = "mini-kv-server", version)]
+struct Args {
+    /// Path to the database file (created if missing)
+    #[arg(short, long, env = "MINI_KV_DB")]
+    db: PathBuf,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:6379")]
+    bind: String,
+
+    /// always, batch:<writes>, periodic:<milliseconds> or adaptive:<milliseconds>
+    #[arg(short, long, default_value = "always")]
+    sync: SyncMode,
+
+    /// Also serve Prometheus metrics over HTTP on this address
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_bind: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let engine = Engine::with_sync(&args.db, args.sync)
+        .with_context(|| format!("opening {}", args.db.display()))?;
+    let engine = Arc::new(Mutex::new(engine));
+
+    #[cfg(feature = "metrics")]
+    if let Some(bind) = &args.metrics_bind {
+        let listener = TcpListener::bind(bind).with_context(|| format!("binding {}", bind))?;
+        eprintln!("mini-kv-server: metrics on http://{}/metrics", bind);
+        thread::spawn(move || serve_metrics(listener));
+    }
+
+    let listener = TcpListener::bind(&args.bind).with_context(|| format!("binding {}", args.bind))?;
+    eprintln!("mini-kv-server: serving {} on {} ({:?})", args.db.display(), args.bind, args.sync);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &engine) {
+                eprintln!("connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answer every HTTP request with the metrics; enough for a Prometheus
+/// scrape, one connection at a time
+#[cfg(feature = "metrics")]
+fn serve_metrics(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| {
+            // So a client that never finishes its request cannot hold up the next
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            // Skip the request line and headers, up to the blank line
+            while read_line(&mut reader)?.is_some_and(|line| !line.is_empty()) {}
+            let body = mini_kv::metrics::encode_text();
+            let mut writer = BufWriter::new(stream);
+            write!(
+                writer,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+            writer.flush()
+        });
+        if let Err(e) = result {
+            eprintln!("metrics request failed: {}", e);
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, engine: &Mutex<Engine>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("ERR Protocol error: {}", e)).write(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit { Reply::Ok } else { execute(&args, engine) };
+        reply.write(&mut writer)?;
+
+        // Flush once the client has no more pipelined commands waiting
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+enum Reply {
+    Ok,
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Ok => out.write_all(b"+OK\r\n"),
+            Reply::Status(s) => write!(out, "+{}\r\n", s),
+            Reply::Error(e) => write!(out, "-{}\r\n", e),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(data) => {
+                write!(out, "${}\r\n", data.len())?;
+                out.write_all(data)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Nil => out.write_all(b"$-1\r\n"),
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write(out))
+            }
+        }
+    }
+}
+
+fn execute(args: &[Vec<u8>], engine: &Mutex<Engine>) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let argc = args.len() - 1;
+    let wrong_args = || Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()));
+    // A poisoned lock means a writer panicked mid-command; keep serving
+    let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+
+    let result = match name.as_str() {
+        "PING" => match argc {
+            0 => Ok(Reply::Status("PONG")),
+            1 => Ok(Reply::Bulk(args[1].clone())),
+            _ => return wrong_args(),
+        },
+        "GET" if argc == 1 => engine.get(&args[1]).map(|v| v.map_or(Reply::Nil, Reply::Bulk)),
+        "SET" if argc == 2 => engine.put(args[1].clone(), args[2].clone()).map(|_| Reply::Ok),
+        "SET" if argc > 2 => return Reply::Error("ERR syntax error (SET options are not supported)".into()),
+        "DEL" if argc >= 1 => args[1..]
+            .iter()
+            .try_fold(0, |n, key| engine.delete(key).map(|deleted| n + deleted as i64))
+            .map(Reply::Integer),
+        "EXISTS" if argc >= 1 => {
+            args[1..].iter().try_fold(0, |n, key| engine.contains_key(key).map(|found| n + found as i64)).map(Reply::Integer)
+        }
+        "SCAN" if argc >= 1 => return scan(&args[1..], &engine),
+        // redis-cli asks for command docs on connect; an empty reply is fine
+        "COMMAND" => Ok(Reply::Array(Vec::new())),
+        "GET" | "SET" | "DEL" | "EXISTS" | "SCAN" => return wrong_args(),
+        _ => return Reply::Error(format!("ERR unknown command '{}'", String::from_utf8_lossy(&args[0]))),
+    };
+    result.unwrap_or_else(|e| Reply::Error(format!("ERR {:#}", e)))
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// The cursor is `0` to start, and otherwise a `Cursor` token naming the
+/// last key looked at, so each call reads only the next COUNT keys in
+/// order from there; it stays valid as keys change. MATCH filters each
+/// page after it is read, so a page may hold fewer keys than COUNT, or
+/// none, with more to come: the iteration is over once the returned
+/// cursor is `0`. Keys put behind the cursor mid-iteration are missed.
+fn scan(args: &[Vec<u8>], engine: &Engine) -> Reply {
+    let cursor = match args[0].as_slice() {
+        b"0" => None,
+        token => match std::str::from_utf8(token).ok().and_then(|token| token.parse::<Cursor>().ok()) {
+            Some(cursor) => Some(cursor),
+            None => return Reply::Error("ERR invalid cursor".into()),
+        },
+    };
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = value,
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => match parse_int(value) {
+                Some(n) if n > 0 => count = n,
+                _ => return Reply::Error("ERR value is not an integer or out of range".into()),
+            },
+            _ => return Reply::Error("ERR syntax error".into()),
+        }
+    }
+
+    let (pairs, next) = match engine.scan_page(cursor.as_ref(), count) {
+        Ok(page) => page,
+        Err(e) => return Reply::Error(format!("ERR {:#}", e)),
+    };
+    let page: Vec<Reply> =
+        pairs.into_iter().filter(|(key, _)| glob_match(pattern, key)).map(|(key, _)| Reply::Bulk(key)).collect();
+    let next = next.map_or_else(|| "0".to_string(), |cursor| cursor.to_string());
+    Reply::Array(vec![Reply::Bulk(next.into_bytes()), Reply::Array(page)])
+}
+
+fn parse_int(arg: &[u8]) -> Option<usize> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Redis-style glob supporting `*`, `?` and `\` escapes
+///
+/// Backtracks only to the most recent `*`, so matching stays linear-ish
+/// even for hostile patterns like `*a*a*a*b`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some(&c) if c != b'\\' && c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // Mismatch: let the last `*` absorb one more byte
+        match star {
+            Some((star_p, star_t)) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Read one command: a RESP array of bulk strings, or an inline command
+/// (space-separated, as typed into telnet). Returns `None` on EOF.
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else { return Ok(None) };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line.split(|b| b.is_ascii_whitespace()).filter(|a| !a.is_empty()).map(<[u8]>::to_vec).collect();
+        return Ok(Some(args));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+
+    let mut args = Vec::with_capacity(count.min(PREALLOCATED_ARGS));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = match header.strip_prefix(b"$") {
+            Some(len) => parse_len(len, MAX_BULK_LEN)?,
+            None => return Err(invalid_data("expected '$'")),
+        };
+        let mut data = vec![0u8; len + 2];
+        reader.read_exact(&mut data)?;
+        if !data.ends_with(b"\r\n") {
+            return Err(invalid_data("bulk string not terminated by CRLF"));
+        }
+        data.truncate(len);
+        args.push(data);
+    }
+    Ok(Some(args))
+}
+
+/// One line without its line ending, or `None` on EOF; a line over
+/// `MAX_LINE` bytes is a protocol error, not buffered to its end
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // Room for the longest line and its CRLF
+    if Read::take(&mut *reader, MAX_LINE as u64 + 2).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+    } else if line.len() == MAX_LINE + 2 {
+        return Err(invalid_data("line too long"));
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    match parse_int(digits) {
+        Some(n) if n <= max => Ok(n),
+        _ => Err(invalid_data("invalid length")),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
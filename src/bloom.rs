@@ -0,0 +1,96 @@
+//! Bloom filter over the keys of a sealed file
+//!
+//! Lets a read skip a file that cannot hold the key without touching it.
+//! The index file an index spills to under a memory budget keeps one, so a
+//! lookup that misses the in-memory entries only reads a block of the file
+//! when the filter says the key may be there. With `EngineKind::Lsm` each
+//! table has one, and a get checks the filters from the newest table down,
+//! reading only the tables that may hold the key.
+//!
+//! ```
+//! use mini_kv::BloomFilter;
+//!
+//! let mut filter = BloomFilter::new(1000, 0.01);
+//! filter.insert(b"user:1");
+//! assert!(filter.may_contain(b"user:1"));
+//!
+//! let filter = BloomFilter::decode(&filter.encode())?;
+//! assert!(filter.may_contain(b"user:1"));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Encoded as `magic "MKVB"(4) | hashes(4) | num_bits(8) | bits | crc32(4)`.
+
+use anyhow::{bail, Result};
+
+const BLOOM_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x42]; // "MKVB"
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `expected_keys` keys at about
+    /// `false_positive_rate` (e.g. 0.01)
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        let n = expected_keys.max(1) as f64;
+        let p = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = ((-n * p.ln() / (ln2 * ln2)).ceil() as u64).max(64);
+        let hashes = ((num_bits as f64 / n) * ln2).round().clamp(1.0, 30.0) as u32;
+        Self { bits: vec![0; num_bits.div_ceil(64) as usize], num_bits, hashes }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.positions(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False means `key` was never inserted; true means it probably was
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Double hashing: bit i is h1 + i * h2
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let h1 = twox_hash::XxHash64::oneshot(0, key);
+        let h2 = twox_hash::XxHash64::oneshot(1, key) | 1;
+        let num_bits = self.num_bits;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(20 + self.bits.len() * 8);
+        buf.extend_from_slice(&BLOOM_MAGIC);
+        buf.extend_from_slice(&self.hashes.to_le_bytes());
+        buf.extend_from_slice(&self.num_bits.to_le_bytes());
+        for word in &self.bits {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 20 || !buf.starts_with(&BLOOM_MAGIC) {
+            bail!("not a bloom filter");
+        }
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            bail!("bloom filter CRC mismatch");
+        }
+        let hashes = u32::from_le_bytes(body[4..8].try_into().unwrap());
+        let num_bits = u64::from_le_bytes(body[8..16].try_into().unwrap());
+        let words = &body[16..];
+        if hashes == 0 || num_bits == 0 || words.len() as u64 != num_bits.div_ceil(64) * 8 {
+            bail!("bloom filter has inconsistent sizes");
+        }
+        let bits = words.chunks_exact(8).map(|w| u64::from_le_bytes(w.try_into().unwrap())).collect();
+        Ok(Self { bits, num_bits, hashes })
+    }
+}
@@ -0,0 +1,454 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::archive::ArchivePolicy;
+use crate::checksum::Checksum;
+use crate::compaction::CompactionStrategy;
+use crate::compression::Compression;
+use crate::memtable::{BTreeMemtable, Memtable};
+use crate::ratelimit::RateLimit;
+use crate::record::{MAX_KEY_LEN, MAX_VAL_LEN};
+use crate::engine::{Engine, EngineKind, IoMode, ReadConsistency, RecoveryMode, SyncMode};
+use crate::scrub::ScrubOptions;
+use crate::slow::{SlowOp, SlowOpFn};
+use crate::storage::{FsStorage, Storage};
+
+/// Snapshot of recovery progress, reported while `open()` scans the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryProgress {
+    /// Bytes of the log verified so far
+    pub bytes_scanned: u64,
+    /// Size of the part of the log scanned at open time
+    pub total_bytes: u64,
+    /// Valid records found so far
+    pub records_recovered: usize,
+}
+
+pub(crate) type ProgressFn = Box<dyn FnMut(RecoveryProgress)>;
+
+/// Engine configuration, consumed by `open()`
+///
+/// ```no_run
+/// use mini_kv::{Engine, SyncMode};
+///
+/// let engine = Engine::builder("data.db")
+///     .sync_mode(SyncMode::Batch(100))
+///     .on_recovery_progress(|p| eprintln!("recovered {}/{} bytes", p.bytes_scanned, p.total_bytes))
+///     .open()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct EngineBuilder<S: Storage = FsStorage> {
+    pub(crate) path: PathBuf,
+    pub(crate) sync_mode: SyncMode,
+    pub(crate) io_mode: IoMode,
+    pub(crate) recovery_mode: RecoveryMode,
+    pub(crate) read_consistency: ReadConsistency,
+    pub(crate) compression: Compression,
+    pub(crate) compression_min_size: usize,
+    pub(crate) checksum: Option<Checksum>,
+    pub(crate) max_key_len: usize,
+    pub(crate) max_value_len: usize,
+    #[cfg(feature = "encryption")]
+    pub(crate) encryption_keys: Vec<(u32, [u8; 32])>,
+    pub(crate) recovery_progress: Option<ProgressFn>,
+    pub(crate) slow_op_threshold: Option<Duration>,
+    pub(crate) on_slow_op: Option<SlowOpFn>,
+    pub(crate) scrub: Option<ScrubOptions>,
+    pub(crate) index_memory_budget: Option<usize>,
+    pub(crate) index_snapshot: bool,
+    pub(crate) inline_values: Option<usize>,
+    pub(crate) value_cache: Option<usize>,
+    pub(crate) block_cache: Option<usize>,
+    pub(crate) value_log: Option<usize>,
+    pub(crate) max_unsynced_bytes: Option<u64>,
+    pub(crate) write_buffer: usize,
+    pub(crate) disk_reserve: u64,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) compact_at_max_size: bool,
+    pub(crate) rate_limit: RateLimit,
+    pub(crate) kind: EngineKind,
+    pub(crate) memtable_size: usize,
+    pub(crate) new_memtable: fn() -> Box<dyn Memtable>,
+    pub(crate) compaction_strategy: Option<Box<dyn CompactionStrategy>>,
+    pub(crate) archive: Option<ArchivePolicy>,
+    pub(crate) cold_dir: Option<PathBuf>,
+    pub(crate) storage: S,
+}
+
+impl EngineBuilder {
+    /// Defaults match `Engine::open` (Always sync, Buffered IO, TruncateTail)
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            sync_mode: SyncMode::Always,
+            io_mode: IoMode::Buffered,
+            recovery_mode: RecoveryMode::TruncateTail,
+            read_consistency: ReadConsistency::Acknowledged,
+            compression: Compression::None,
+            compression_min_size: 0,
+            checksum: None,
+            max_key_len: MAX_KEY_LEN,
+            max_value_len: MAX_VAL_LEN,
+            #[cfg(feature = "encryption")]
+            encryption_keys: Vec::new(),
+            recovery_progress: None,
+            slow_op_threshold: None,
+            on_slow_op: None,
+            scrub: None,
+            index_memory_budget: None,
+            index_snapshot: true,
+            inline_values: None,
+            value_cache: None,
+            block_cache: None,
+            value_log: None,
+            max_unsynced_bytes: None,
+            write_buffer: 0,
+            disk_reserve: 0,
+            max_size: None,
+            compact_at_max_size: true,
+            rate_limit: RateLimit::default(),
+            kind: EngineKind::Log,
+            memtable_size: 4 * 1024 * 1024,
+            new_memtable: new_memtable::<BTreeMemtable>,
+            compaction_strategy: None,
+            archive: None,
+            cold_dir: None,
+            storage: FsStorage,
+        }
+    }
+}
+
+impl<S: Storage> EngineBuilder<S> {
+    pub fn sync_mode(mut self, mode: SyncMode) -> Self {
+        self.sync_mode = mode;
+        self
+    }
+
+    pub fn io_mode(mut self, mode: IoMode) -> Self {
+        self.io_mode = mode;
+        self
+    }
+
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// What `get` sees unless `ReadOptions` say otherwise (`Acknowledged`).
+    /// `Durable` keeps track of the keys written between syncs so reads
+    /// can see past them, at the cost of a lookup and a copy of the key
+    /// for the first put to each after a sync; it needs `EngineKind::Log`.
+    pub fn read_consistency(mut self, consistency: ReadConsistency) -> Self {
+        self.read_consistency = consistency;
+        self
+    }
+
+    /// Cap the bytes `Batch` and `Periodic` sync modes let pile up between
+    /// syncs: once this many are waiting, the next `put()` or `delete()`
+    /// syncs before writing, and `try_put()` fails with
+    /// `io::ErrorKind::WouldBlock` instead
+    pub fn max_unsynced_bytes(mut self, bytes: u64) -> Self {
+        self.max_unsynced_bytes = Some(bytes);
+        self
+    }
+
+    /// Collect appends in a buffer of `bytes` (e.g. 64KB) and write them
+    /// to the log in one call when it fills or before every sync, instead
+    /// of one write per `put()` (default 0, unbuffered). Writes since the
+    /// last sync are lost on a crash either way; buffered ones are also
+    /// lost, and invisible to other readers of the file, until written.
+    pub fn write_buffer(mut self, bytes: usize) -> Self {
+        self.write_buffer = bytes;
+        self
+    }
+
+    /// Keep a file of `bytes` (e.g. 16MB) at `<path>.reserve`, written out
+    /// so the space is really taken, and delete it when the disk fills.
+    /// Deletes and `compact()` then still have room to free space, and
+    /// puts fail with `Error::DiskFull` until a compaction, or the next
+    /// open, finds room to write the reserve again. Without one (0, the
+    /// default) a put that meets a full disk still fails with `DiskFull`,
+    /// but so can every other write. Needs `EngineKind::Log`.
+    pub fn disk_reserve(mut self, bytes: u64) -> Self {
+        self.disk_reserve = bytes;
+        self
+    }
+
+    /// A soft limit on the store's size: fail puts that would take the log,
+    /// the value log and the disk reserve past `bytes` with
+    /// `Error::QuotaExceeded`, sizing each at its key and value plus a
+    /// record header. It is not a hard cap on disk use. Deletes are let
+    /// through, so space can always be freed; a compaction needs room for
+    /// a copy of the live records while it runs; and the index file
+    /// (`<path>.index`, about 16 bytes plus the key for each key) is not
+    /// counted. Set it below a device's real budget by that much. Needs
+    /// `EngineKind::Log`.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// With `max_size`, whether a put that would pass it compacts the log
+    /// first when dropping its dead records would make room (default
+    /// true). That put waits for the compaction, which needs room for a
+    /// copy of the live records while it runs.
+    pub fn compact_at_max_size(mut self, enabled: bool) -> Self {
+        self.compact_at_max_size = enabled;
+        self
+    }
+
+    /// On-disk layout (default `EngineKind::Log`). A log opened as
+    /// `EngineKind::Lsm` moves its records into table files, after which it
+    /// only opens as `Lsm`.
+    pub fn kind(mut self, kind: EngineKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// With `EngineKind::Lsm`, flush the memtable to a table file once it
+    /// holds about this many bytes (default 4MB)
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// With `EngineKind::Lsm`, the memtable implementation (default
+    /// `BTreeMemtable`), e.g. `.memtable::<SkipListMemtable>()`
+    pub fn memtable<M: Memtable + Default + 'static>(mut self) -> Self {
+        self.new_memtable = new_memtable::<M>;
+        self
+    }
+
+    /// With `EngineKind::Lsm`, how table files are merged after flushes
+    /// (default `SizeTiered::default()`); see `compaction::Leveled` for the
+    /// alternative
+    pub fn compaction_strategy(mut self, strategy: impl CompactionStrategy + 'static) -> Self {
+        self.compaction_strategy = Some(Box::new(strategy));
+        self
+    }
+
+    /// With `EngineKind::Lsm`, move or copy settled tables to the policy's
+    /// directory, e.g. on cheaper storage, once compaction is done with
+    /// them; see `archive`. A store with archived tables needs the policy
+    /// at every open.
+    pub fn archive(mut self, policy: ArchivePolicy) -> Self {
+        self.archive = Some(policy);
+        self
+    }
+
+    /// Second directory, e.g. on a slower, larger device, for data that is
+    /// rarely rewritten, while the log stays in its own. `gc_value_log`
+    /// moves the sealed value log segments it finds little garbage in
+    /// there, and the value log's `COLD` list records which ones it holds,
+    /// so reads and later opens find them. With `EngineKind::Lsm`, short for
+    /// `archive(ArchivePolicy::new(dir))` unless `archive` is set. A store
+    /// with data in the cold directory needs it at every open.
+    pub fn cold_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.cold_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Where the log and the index file are kept (default `FsStorage`, the
+    /// file system); see `storage`. The engine opened is an `Engine<S>` of
+    /// that storage type. Storage that is not the file system cannot hold
+    /// `EngineKind::Lsm` tables, a value log or checkpoints, and is not
+    /// scrubbed.
+    pub fn storage<T: Storage>(self, storage: T) -> EngineBuilder<T> {
+        EngineBuilder {
+            path: self.path,
+            sync_mode: self.sync_mode,
+            io_mode: self.io_mode,
+            recovery_mode: self.recovery_mode,
+            read_consistency: self.read_consistency,
+            compression: self.compression,
+            compression_min_size: self.compression_min_size,
+            checksum: self.checksum,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+            #[cfg(feature = "encryption")]
+            encryption_keys: self.encryption_keys,
+            recovery_progress: self.recovery_progress,
+            slow_op_threshold: self.slow_op_threshold,
+            on_slow_op: self.on_slow_op,
+            scrub: self.scrub,
+            index_memory_budget: self.index_memory_budget,
+            index_snapshot: self.index_snapshot,
+            inline_values: self.inline_values,
+            value_cache: self.value_cache,
+            block_cache: self.block_cache,
+            value_log: self.value_log,
+            max_unsynced_bytes: self.max_unsynced_bytes,
+            write_buffer: self.write_buffer,
+            disk_reserve: self.disk_reserve,
+            max_size: self.max_size,
+            compact_at_max_size: self.compact_at_max_size,
+            rate_limit: self.rate_limit,
+            kind: self.kind,
+            memtable_size: self.memtable_size,
+            new_memtable: self.new_memtable,
+            compaction_strategy: self.compaction_strategy,
+            archive: self.archive,
+            cold_dir: self.cold_dir,
+            storage,
+        }
+    }
+
+    /// Compress values of new records (requires a `compression-*` feature).
+    /// Existing records are read back whatever codec they were written with.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Store values shorter than `bytes` uncompressed; small values rarely
+    /// shrink enough to pay for the CPU time
+    pub fn compression_min_size(mut self, bytes: usize) -> Self {
+        self.compression_min_size = bytes;
+        self
+    }
+
+    /// Record checksum for the log. A new file is created with it; an
+    /// existing file keeps its own until `Engine::compact()` rewrites it.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Reject keys longer than `bytes` (default `MAX_KEY_LEN`, 1MB) with
+    /// `Error::KeyTooLarge`: `put()` refuses them, and `open()` fails on a
+    /// record with one among those it scans, as do reads of such a record.
+    /// Capped at `u32::MAX`, the most a record can hold.
+    pub fn max_key_len(mut self, bytes: usize) -> Self {
+        self.max_key_len = bytes;
+        self
+    }
+
+    /// Reject values longer than `bytes` (default `MAX_VAL_LEN`, 10MB) with
+    /// `Error::ValueTooLarge`, as `max_key_len` does keys. Recovery checks
+    /// the stored length, which compression may have made smaller, and
+    /// reads fail with it on a value that decompresses to more.
+    pub fn max_value_len(mut self, bytes: usize) -> Self {
+        self.max_value_len = bytes;
+        self
+    }
+
+    /// Encrypt new records with AES-256-GCM under `key`. The same key is
+    /// needed to open the file again: without it, or with a wrong one,
+    /// `open()` fails with `Error::Decryption` rather than dropping data.
+    ///
+    /// Shorthand for `encryption_key_version(0, key)`.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(self, key: [u8; 32]) -> Self {
+        self.encryption_key_version(0, key)
+    }
+
+    /// Supply key version `id`, as returned by `Engine::rotate_key`. Pass
+    /// every version the file still has records under; new records are
+    /// encrypted with the highest one.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key_version(mut self, id: u32, key: [u8; 32]) -> Self {
+        self.encryption_keys.push((id, key));
+        self
+    }
+
+    /// Run a background thread that keeps re-reading the synced part of the
+    /// log and checking record checksums; see `Engine::scrub_report()`
+    pub fn scrub(mut self, options: ScrubOptions) -> Self {
+        self.scrub = Some(options);
+        self
+    }
+
+    /// Pace `put()`, `delete()` and compaction with token buckets, e.g. so
+    /// a bulk import does not starve latency-sensitive traffic on the same
+    /// disk. A call that has to wait sleeps on the calling thread.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = limit;
+        self
+    }
+
+    /// Keep at most about `bytes` of index entries in memory, spilling the
+    /// rest to a sorted index file next to the log (`<path>.index`) that
+    /// lookups fall back to. Also lets `open()` skip the part of the log
+    /// that file already covers. Without a budget the whole index is in
+    /// memory.
+    pub fn index_memory_budget(mut self, bytes: usize) -> Self {
+        self.index_memory_budget = Some(bytes);
+        self
+    }
+
+    /// Keep values of at most `max_len` bytes (e.g. 64) in the in-memory
+    /// index as well as the log, so `get()` of them never touches the file.
+    /// They count against `index_memory_budget`.
+    pub fn inline_values(mut self, max_len: usize) -> Self {
+        self.inline_values = Some(max_len);
+        self
+    }
+
+    /// Keep up to about `capacity` bytes of recently read values in an LRU
+    /// cache, so repeated `get()`s of hot keys skip the file; see
+    /// `Engine::cache_stats()`
+    pub fn value_cache(mut self, capacity: usize) -> Self {
+        self.value_cache = Some(capacity);
+        self
+    }
+
+    /// Keep up to `capacity` bytes of the log in an in-memory cache of
+    /// `BLOCK_SIZE` (4KB) blocks, so small records next to each other share
+    /// one read; see `Engine::block_cache_stats()`
+    pub fn block_cache(mut self, capacity: usize) -> Self {
+        self.block_cache = Some(capacity);
+        self
+    }
+
+    /// Store values of at least `min_len` bytes (e.g. 64KB) in a separate
+    /// value log (`<path>.vlog/`), leaving only a pointer to them in the
+    /// log, so `compact()` does not copy them again; see
+    /// `Engine::gc_value_log()`. Not supported with `EngineKind::Lsm`.
+    pub fn value_log(mut self, min_len: usize) -> Self {
+        self.value_log = Some(min_len);
+        self
+    }
+
+    /// Whether dropping or closing the engine writes the index to
+    /// `<path>.index`, so the next `open()` loads it and scans only the
+    /// records written since (default true). A snapshot left by an engine
+    /// that is not dropped cleanly is still used for the part of the log
+    /// it covers.
+    pub fn index_snapshot(mut self, enabled: bool) -> Self {
+        self.index_snapshot = enabled;
+        self
+    }
+
+    /// Called on the opening thread as recovery advances, and once more
+    /// with the final counts when it finishes
+    pub fn on_recovery_progress(mut self, callback: impl FnMut(RecoveryProgress) + 'static) -> Self {
+        self.recovery_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Report every `put()`, `get()` and `sync()` that takes at least
+    /// `threshold` (e.g. 100ms), with its duration, key size and the sync
+    /// mode: to `on_slow_op` if set, else as a `tracing` warning with the
+    /// `tracing` feature, else on stderr. A put's time includes any
+    /// sync, write stall or rate limit wait it incurs.
+    pub fn slow_op_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    /// Where `slow_op_threshold` reports slow calls, on the calling thread
+    pub fn on_slow_op(mut self, callback: impl Fn(&SlowOp) + Send + Sync + 'static) -> Self {
+        self.on_slow_op = Some(Box::new(callback));
+        self
+    }
+
+    /// Open or create the database
+    pub fn open(self) -> Result<Engine<S>> {
+        Engine::from_builder(self)
+    }
+}
+
+fn new_memtable<M: Memtable + Default + 'static>() -> Box<dyn Memtable> {
+    Box::new(M::default())
+}
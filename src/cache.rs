@@ -0,0 +1,258 @@
+//! Caches on the read path
+//!
+//! `ValueCache` keeps whole values in LRU order, keyed by the offset of the
+//! record a value came from. A put writes a new record at a new offset, so
+//! a cached value never goes stale; only a rewrite of the log, which moves
+//! every record, clears the cache.
+//!
+//! `BlockCache` instead keeps fixed-size blocks of the log file under a
+//! Clock policy, so small records next to each other share one cached read.
+//! Bytes below the end of the log never change until a rewrite, but the
+//! last block is still growing, so only blocks wholly below the end are
+//! cached.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::storage::StorageFile;
+
+/// Size of a `BlockCache` block
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Rough bookkeeping cost of an entry beyond its value bytes
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Counters from `Engine::cache_stats()` and `Engine::block_cache_stats()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Bytes charged against the capacity; for the value cache this
+    /// includes bookkeeping
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+pub(crate) struct ValueCache {
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Lru {
+    capacity: usize,
+    bytes: usize,
+    /// Bumped on every access; the smallest live tick is evicted first
+    tick: u64,
+    /// Record offset -> value and the tick of its last use
+    entries: HashMap<u64, (Vec<u8>, u64)>,
+    /// Tick of last use -> record offset
+    order: BTreeMap<u64, u64>,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            lru: Mutex::new(Lru {
+                capacity,
+                bytes: 0,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The value of the record at `offset`, if cached
+    pub(crate) fn get(&self, offset: u64) -> Option<Vec<u8>> {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        lru.tick += 1;
+        match lru.entries.get_mut(&offset) {
+            Some((value, used)) => {
+                lru.order.remove(used);
+                *used = lru.tick;
+                lru.order.insert(lru.tick, offset);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Cache the value of the record at `offset`, evicting the least
+    /// recently used values to make room. Values bigger than the whole
+    /// cache are not kept.
+    pub(crate) fn insert(&self, offset: u64, value: &[u8]) {
+        let cost = value.len() + ENTRY_OVERHEAD;
+        let mut lru = self.lru.lock().unwrap();
+        if cost > lru.capacity || lru.entries.contains_key(&offset) {
+            return;
+        }
+        while lru.bytes + cost > lru.capacity {
+            let Some((_, victim)) = lru.order.pop_first() else { break };
+            if let Some((evicted, _)) = lru.entries.remove(&victim) {
+                lru.bytes -= evicted.len() + ENTRY_OVERHEAD;
+            }
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, offset);
+        lru.entries.insert(offset, (value.to_vec(), tick));
+        lru.bytes += cost;
+    }
+
+    /// Drop everything, e.g. after the log is rewritten
+    pub(crate) fn clear(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.entries.clear();
+        lru.order.clear();
+        lru.bytes = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len(),
+            bytes: lru.bytes,
+            capacity: lru.capacity,
+        }
+    }
+}
+
+pub(crate) struct BlockCache {
+    clock: Mutex<Clock>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Clock {
+    max_blocks: usize,
+    slots: Vec<ClockSlot>,
+    /// Block number -> index into `slots`
+    map: HashMap<u64, usize>,
+    /// Next slot to consider for eviction
+    hand: usize,
+}
+
+struct ClockSlot {
+    block: u64,
+    data: Arc<[u8]>,
+    /// Set on every use, cleared as the hand passes; a slot is evicted
+    /// when the hand finds it clear
+    referenced: bool,
+}
+
+impl BlockCache {
+    /// A cache of `capacity / BLOCK_SIZE` blocks
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            clock: Mutex::new(Clock { max_blocks: capacity / BLOCK_SIZE, slots: Vec::new(), map: HashMap::new(), hand: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Fill `buf` from `file` at `offset`, going through the cache for
+    /// every block that lies wholly below `stable_end`
+    pub(crate) fn read_at(&self, file: &dyn StorageFile, buf: &mut [u8], offset: u64, stable_end: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let block = pos / BLOCK_SIZE as u64;
+            let block_start = block * BLOCK_SIZE as u64;
+            if block_start + BLOCK_SIZE as u64 > stable_end {
+                return file.read_exact_at(&mut buf[done..], pos);
+            }
+            let data = match self.lookup(block) {
+                Some(data) => data,
+                None => {
+                    // Read without holding the lock; a racing reader of the
+                    // same block just loses its insert
+                    let mut data = vec![0u8; BLOCK_SIZE];
+                    file.read_exact_at(&mut data, block_start)?;
+                    let data: Arc<[u8]> = data.into();
+                    self.store(block, data.clone());
+                    data
+                }
+            };
+            let within = (pos - block_start) as usize;
+            let n = (BLOCK_SIZE - within).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&data[within..within + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, block: u64) -> Option<Arc<[u8]>> {
+        let mut clock = self.clock.lock().unwrap();
+        match clock.map.get(&block).copied() {
+            Some(i) => {
+                let slot = &mut clock.slots[i];
+                slot.referenced = true;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(slot.data.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn store(&self, block: u64, data: Arc<[u8]>) {
+        let mut clock = self.clock.lock().unwrap();
+        let clock = &mut *clock;
+        if clock.max_blocks == 0 || clock.map.contains_key(&block) {
+            return;
+        }
+        let slot = ClockSlot { block, data, referenced: false };
+        if clock.slots.len() < clock.max_blocks {
+            clock.map.insert(block, clock.slots.len());
+            clock.slots.push(slot);
+            return;
+        }
+        loop {
+            let i = clock.hand;
+            clock.hand = (clock.hand + 1) % clock.slots.len();
+            let victim = &mut clock.slots[i];
+            if victim.referenced {
+                victim.referenced = false;
+                continue;
+            }
+            clock.map.remove(&victim.block);
+            clock.map.insert(block, i);
+            *victim = slot;
+            return;
+        }
+    }
+
+    /// Drop everything, e.g. after the log is rewritten
+    pub(crate) fn clear(&self) {
+        let mut clock = self.clock.lock().unwrap();
+        clock.slots.clear();
+        clock.map.clear();
+        clock.hand = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let clock = self.clock.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: clock.slots.len(),
+            bytes: clock.slots.len() * BLOCK_SIZE,
+            capacity: clock.max_blocks * BLOCK_SIZE,
+        }
+    }
+}
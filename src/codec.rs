@@ -0,0 +1,345 @@
+//! Typed keys and values over the byte-oriented engine
+//!
+//! Key encodings preserve order: for any two keys `a < b`, the encoded
+//! bytes compare the same way, so `scan_prefix` and the sorted results it
+//! returns follow the natural order of the key type.
+//!
+//! - unsigned integers: big-endian
+//! - signed integers: big-endian with the sign bit flipped
+//! - `String` / `Vec<u8>`: bytes with `0x00` escaped as `00 FF`, then a
+//!   `00 00` terminator, so they can be followed by further tuple fields;
+//!   a `00` is never followed by anything but `FF` or `00` within the
+//!   string, whatever the next field starts with
+//! - `[u8; N]` (e.g. `Uuid::into_bytes()`): the raw bytes
+//! - tuples: fields concatenated in order, so a tuple's leading fields
+//!   form a key prefix
+
+use anyhow::{bail, Context, Result};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::engine::Engine;
+
+/// A key type with an order-preserving, self-delimiting encoding
+pub trait KeyCodec: Sized {
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decode one key from the front of `bytes`, returning the remainder
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])>;
+
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_key(&mut out);
+        out
+    }
+
+    fn from_key_bytes(bytes: &[u8]) -> Result<Self> {
+        let (key, rest) = Self::decode_key(bytes)?;
+        if !rest.is_empty() {
+            bail!("{} trailing bytes after key", rest.len());
+        }
+        Ok(key)
+    }
+}
+
+/// How values of type `V` are turned into bytes
+///
+/// Implemented by marker types such as [`Raw`] and [`Utf8`] (and `Json` /
+/// `Bincode` with the `serde` feature), chosen through `Store`'s `C`
+/// parameter.
+pub trait ValueCodec<V> {
+    fn encode_value(value: &V) -> Result<Vec<u8>>;
+    fn decode_value(bytes: &[u8]) -> Result<V>;
+}
+
+fn split_fixed<const N: usize>(bytes: &[u8]) -> Result<([u8; N], &[u8])> {
+    if bytes.len() < N {
+        bail!("key truncated: need {} bytes, have {}", N, bytes.len());
+    }
+    let (head, rest) = bytes.split_at(N);
+    Ok((head.try_into().expect("length checked"), rest))
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl KeyCodec for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+                let (head, rest) = split_fixed(bytes)?;
+                Ok((<$t>::from_be_bytes(head), rest))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KeyCodec for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(out);
+            }
+
+            fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+                let (bits, rest) = <$u>::decode_key(bytes)?;
+                Ok(((bits ^ (1 << (<$u>::BITS - 1))) as $t, rest))
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl<const N: usize> KeyCodec for [u8; N] {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        split_fixed(bytes)
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        for &b in self {
+            out.push(b);
+            if b == 0 {
+                out.push(0xFF);
+            }
+        }
+        out.extend_from_slice(&[0, 0]);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        let mut key = Vec::new();
+        let mut i = 0;
+        loop {
+            match (bytes.get(i), bytes.get(i + 1)) {
+                (None, _) => bail!("key truncated: missing string terminator"),
+                (Some(0), Some(0xFF)) => {
+                    key.push(0);
+                    i += 2;
+                }
+                (Some(0), Some(0)) => return Ok((key, &bytes[i + 2..])),
+                (Some(0), _) => bail!("key malformed: 00 not followed by FF or a second 00"),
+                (Some(&b), _) => {
+                    key.push(b);
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        // Same bytes as Vec<u8>; UTF-8 byte order matches char order
+        self.as_bytes().to_vec().encode_key(out);
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        let (raw, rest) = Vec::<u8>::decode_key(bytes)?;
+        Ok((String::from_utf8(raw).context("key is not valid UTF-8")?, rest))
+    }
+}
+
+macro_rules! tuple_key {
+    ($($name:ident),+) => {
+        impl<$($name: KeyCodec),+> KeyCodec for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_key(out);)+
+            }
+
+            #[allow(non_snake_case)]
+            fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+                let rest = bytes;
+                $(let ($name, rest) = $name::decode_key(rest)?;)+
+                Ok((($($name,)+), rest))
+            }
+        }
+    };
+}
+
+impl KeyCodec for () {
+    fn encode_key(&self, _out: &mut Vec<u8>) {}
+
+    fn decode_key(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        Ok(((), bytes))
+    }
+}
+
+tuple_key!(A);
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+
+/// Values stored as-is (`Vec<u8>`)
+pub struct Raw;
+
+impl ValueCodec<Vec<u8>> for Raw {
+    fn encode_value(value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// `String` values stored as their UTF-8 bytes
+pub struct Utf8;
+
+impl ValueCodec<String> for Utf8 {
+    fn encode_value(value: &String) -> Result<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).context("value is not valid UTF-8")
+    }
+}
+
+/// Serde values stored as JSON
+#[cfg(feature = "serde")]
+pub struct Json;
+
+#[cfg(feature = "serde")]
+impl<V: serde::Serialize + serde::de::DeserializeOwned> ValueCodec<V> for Json {
+    fn encode_value(value: &V) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).context("serializing value as JSON")
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<V> {
+        serde_json::from_slice(bytes).context("decoding JSON value")
+    }
+}
+
+/// Serde values stored as bincode
+#[cfg(feature = "serde")]
+pub struct Bincode;
+
+#[cfg(feature = "serde")]
+impl<V: serde::Serialize + serde::de::DeserializeOwned> ValueCodec<V> for Bincode {
+    fn encode_value(value: &V) -> Result<Vec<u8>> {
+        bincode::serialize(value).context("serializing value as bincode")
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<V> {
+        bincode::deserialize(bytes).context("decoding bincode value")
+    }
+}
+
+/// An engine with keys of type `K` and values of type `V` encoded by `C`
+///
+/// ```no_run
+/// use mini_kv::{Store, Utf8};
+///
+/// let mut store: Store<(String, u64), String, Utf8> = Store::open("events.db")?;
+/// store.put(&("user-1".to_string(), 42), &"login".to_string())?;
+/// // Every event for user-1, ordered by sequence number
+/// let events = store.scan_prefix(&("user-1".to_string(),))?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Store<K, V, C> {
+    engine: Engine,
+    _key: PhantomData<fn() -> K>,
+    _value: PhantomData<fn() -> V>,
+    _codec: PhantomData<C>,
+}
+
+impl<K: KeyCodec, V, C: ValueCodec<V>> Store<K, V, C> {
+    pub fn new(engine: Engine) -> Self {
+        Self { engine, _key: PhantomData, _value: PhantomData, _codec: PhantomData }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(Engine::open(path)?))
+    }
+
+    pub fn put(&mut self, key: &K, value: &V) -> Result<()> {
+        self.engine.put(key.to_key_bytes(), C::encode_value(value)?)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.engine.get(&key.to_key_bytes())?.map(|bytes| C::decode_value(&bytes)).transpose()
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<bool> {
+        self.engine.delete(&key.to_key_bytes())
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.engine.contains_key(&key.to_key_bytes())
+    }
+
+    /// Entries whose key starts with `prefix`, in key order
+    ///
+    /// `prefix` is typically a tuple of `K`'s leading fields. Pass `&()`
+    /// to list everything.
+    pub fn scan_prefix<P: KeyCodec>(&self, prefix: &P) -> Result<Vec<(K, V)>> {
+        self.engine
+            .scan_prefix(&prefix.to_key_bytes())?
+            .into_iter()
+            .map(|(key, value)| Ok((K::from_key_bytes(&key)?, C::decode_value(&value)?)))
+            .collect()
+    }
+
+    /// The underlying engine, for raw access, `sync`, `verify` and so on
+    pub fn engine(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    pub fn into_engine(self) -> Engine {
+        self.engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn round_trips_in_order<K: KeyCodec + Ord + Clone + std::fmt::Debug>(a: K, b: K) -> Result<(), TestCaseError> {
+        let (encoded_a, encoded_b) = (a.to_key_bytes(), b.to_key_bytes());
+        prop_assert_eq!(K::from_key_bytes(&encoded_a).unwrap(), a.clone());
+        prop_assert_eq!(K::from_key_bytes(&encoded_b).unwrap(), b.clone());
+        prop_assert_eq!(encoded_a.cmp(&encoded_b), a.cmp(&b));
+        Ok(())
+    }
+
+    /// Favours the bytes the string encoding treats specially
+    fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(prop_oneof![Just(0u8), Just(0xFF), any::<u8>()], 0..8)
+    }
+
+    fn u64s() -> impl Strategy<Value = u64> {
+        prop_oneof![Just(0), Just(u64::MAX), Just(0xFF00_0000_0000_0000), any::<u64>()]
+    }
+
+    proptest! {
+        #[test]
+        fn string_and_u64_keys_round_trip_in_order(a in ("[a\\x00\\xFF]{0,4}", u64s()), b in ("[a\\x00\\xFF]{0,4}", u64s())) {
+            round_trips_in_order(a, b)?;
+        }
+
+        #[test]
+        fn bytes_and_i64_keys_round_trip_in_order(a in (bytes(), any::<i64>()), b in (bytes(), any::<i64>())) {
+            round_trips_in_order(a, b)?;
+        }
+    }
+
+    #[test]
+    fn strings_followed_by_ff_bytes_decode() {
+        assert_eq!(<(String, u8)>::from_key_bytes(&(String::new(), 255u8).to_key_bytes()).unwrap(), (String::new(), 255));
+        let key = ("a".to_string(), u64::MAX);
+        assert_eq!(<(String, u64)>::from_key_bytes(&key.to_key_bytes()).unwrap(), key);
+        let key = (vec![0, 0xFF, 0], -1i64);
+        assert_eq!(<(Vec<u8>, i64)>::from_key_bytes(&key.to_key_bytes()).unwrap(), key);
+    }
+}
@@ -0,0 +1,189 @@
+//! Choosing which table files `EngineKind::Lsm` merges
+//!
+//! After every flush the engine asks its `CompactionStrategy` for work,
+//! runs what it gets, and asks again until the strategy is content. A
+//! strategy only picks tables; merging them, dropping what the result
+//! shadows and replacing them in the manifest is up to the engine.
+//!
+//! Two are provided: `SizeTiered` (the default), which merges runs of
+//! similar-sized tables and keeps write amplification low, and `Leveled`,
+//! which keeps each level below the first one sorted run of tables of
+//! bounded size, so a lookup touches at most one table per level and
+//! space amplification stays low.
+
+/// What a strategy sees of a table file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableInfo {
+    pub id: u64,
+    pub level: u32,
+    /// File size in bytes
+    pub size: u64,
+    /// Records, tombstones included
+    pub records: u64,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+}
+
+impl TableInfo {
+    /// Whether the key ranges of the two tables intersect
+    pub fn overlaps(&self, other: &TableInfo) -> bool {
+        self.first_key <= other.last_key && other.first_key <= self.last_key
+    }
+}
+
+/// Tables to merge into new ones at `output_level`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionTask {
+    /// Ids of the input tables
+    pub inputs: Vec<u64>,
+    pub output_level: u32,
+    /// Start a new output table once one reaches this many bytes; `None`
+    /// writes a single table
+    pub max_table_size: Option<u64>,
+}
+
+/// Picks the next compaction from the current tables
+pub trait CompactionStrategy: Send {
+    /// `tables` are in lookup order: where two hold the same key, the
+    /// earlier one has the newer value. They are ordered by level, and
+    /// level 0 holds flushed memtables, newest first.
+    ///
+    /// The outputs of a task take the place of its earliest input, and the
+    /// tables are then stably sorted by level again. So a table left out
+    /// that sits between inputs and overlaps them must be at a level above
+    /// `output_level`, or its values would end up behind older ones.
+    fn pick(&self, tables: &[TableInfo]) -> Option<CompactionTask>;
+}
+
+/// Merge runs of adjacent tables of about the same size
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeTiered {
+    /// Tables needed in a run before it is merged
+    pub min_threshold: usize,
+    /// Most tables merged at once
+    pub max_threshold: usize,
+    /// Tables within this factor of a run's average size join the run
+    pub bucket_ratio: f64,
+    /// Tables smaller than this all count as the same size
+    pub min_table_size: u64,
+}
+
+impl Default for SizeTiered {
+    fn default() -> Self {
+        Self { min_threshold: 4, max_threshold: 32, bucket_ratio: 1.5, min_table_size: 8 * 1024 * 1024 }
+    }
+}
+
+impl CompactionStrategy for SizeTiered {
+    fn pick(&self, tables: &[TableInfo]) -> Option<CompactionTask> {
+        let size = |table: &TableInfo| table.size.max(self.min_table_size) as f64;
+        let mut run: Vec<&TableInfo> = Vec::new();
+        let mut run_bytes = 0.0;
+        for table in tables {
+            let average = run_bytes / run.len().max(1) as f64;
+            let fits = run.is_empty() || (size(table) <= average * self.bucket_ratio && size(table) * self.bucket_ratio >= average);
+            if !fits {
+                if run.len() >= self.min_threshold {
+                    break;
+                }
+                run.clear();
+                run_bytes = 0.0;
+            }
+            run.push(table);
+            run_bytes += size(table);
+            if run.len() == self.max_threshold {
+                break;
+            }
+        }
+        (run.len() >= self.min_threshold.max(2)).then(|| CompactionTask {
+            inputs: run.iter().map(|table| table.id).collect(),
+            output_level: 0,
+            max_table_size: None,
+        })
+    }
+}
+
+/// Keep levels 1 and up as sorted runs, each `fanout` times larger than
+/// the one above
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leveled {
+    /// Level 0 tables that trigger merging them into level 1
+    pub level0_tables: usize,
+    /// Bytes level 1 may hold before a table moves down
+    pub level1_size: u64,
+    /// Size ratio between adjacent levels
+    pub fanout: u64,
+    /// Target size of tables in levels 1 and up
+    pub table_size: u64,
+}
+
+impl Default for Leveled {
+    fn default() -> Self {
+        Self { level0_tables: 4, level1_size: 64 * 1024 * 1024, fanout: 10, table_size: 8 * 1024 * 1024 }
+    }
+}
+
+impl Leveled {
+    fn level_limit(&self, level: u32) -> u64 {
+        self.level1_size.saturating_mul(self.fanout.saturating_pow(level.saturating_sub(1)))
+    }
+
+    /// `inputs` plus the tables at `level` that overlap any of them
+    fn with_overlaps(&self, tables: &[TableInfo], inputs: Vec<&TableInfo>, level: u32) -> CompactionTask {
+        let below = tables.iter().filter(|table| table.level == level && inputs.iter().any(|input| input.overlaps(table)));
+        CompactionTask {
+            inputs: inputs.iter().copied().chain(below).map(|table| table.id).collect(),
+            output_level: level,
+            max_table_size: Some(self.table_size),
+        }
+    }
+}
+
+impl CompactionStrategy for Leveled {
+    fn pick(&self, tables: &[TableInfo]) -> Option<CompactionTask> {
+        let level0: Vec<&TableInfo> = tables.iter().filter(|table| table.level == 0).collect();
+        if level0.len() >= self.level0_tables.max(1) {
+            return Some(self.with_overlaps(tables, level0, 1));
+        }
+        let deepest = tables.iter().map(|table| table.level).max()?;
+        for level in 1..=deepest {
+            let in_level = tables.iter().filter(|table| table.level == level);
+            if in_level.clone().map(|table| table.size).sum::<u64>() > self.level_limit(level) {
+                // Move the largest table down: it frees the most room
+                let largest = in_level.max_by_key(|table| table.size)?;
+                return Some(self.with_overlaps(tables, vec![largest], level + 1));
+            }
+        }
+        None
+    }
+}
+
+/// Work done by one compaction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionRound {
+    pub input_tables: usize,
+    pub output_tables: usize,
+    pub output_level: u32,
+    /// Size of the input tables
+    pub bytes_read: u64,
+    /// Size of the tables written
+    pub bytes_written: u64,
+}
+
+/// Totals from `Engine::compaction_stats()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub rounds: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub last_round: Option<CompactionRound>,
+}
+
+impl CompactionStats {
+    pub(crate) fn record(&mut self, round: CompactionRound) {
+        self.rounds += 1;
+        self.bytes_read += round.bytes_read;
+        self.bytes_written += round.bytes_written;
+        self.last_round = Some(round);
+    }
+}
@@ -0,0 +1,137 @@
+//! Optional per-record value compression
+//!
+//! Each codec sits behind its own cargo feature (`compression-lz4`,
+//! `compression-zstd`). The codec used for a record is kept in its flag
+//! byte, so compressed and plain records can share a log; a build without
+//! the matching feature still recovers such a file, it just cannot return
+//! those values.
+
+use anyhow::Result;
+
+#[cfg(any(feature = "compression-lz4", feature = "compression-zstd"))]
+use crate::error::Error;
+use crate::format::Format;
+
+/// Most an LZ4 block can expand: each length byte adds up to 255 bytes
+#[cfg(feature = "compression-lz4")]
+const LZ4_MAX_RATIO: usize = 255;
+
+/// Codec applied to values on `put`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "compression-lz4")]
+    Lz4,
+    /// `level` as in the zstd CLI: 1 (fast) to 22 (small), 0 for the default
+    #[cfg(feature = "compression-zstd")]
+    Zstd { level: i32 },
+}
+
+/// Codec recorded in a record's flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Lz4,
+    Zstd,
+    /// Zstd against the dictionary in the file header
+    ZstdDict,
+}
+
+impl Compression {
+    /// Compress `value`, or return `None` if compression is off or would
+    /// not make it smaller. Zstd uses the file's dictionary when it has one.
+    #[cfg_attr(not(feature = "compression-zstd"), allow(unused_variables))]
+    pub(crate) fn compress(self, value: &[u8], format: &Format) -> Result<Option<(Codec, Vec<u8>)>> {
+        let compressed: Option<(Codec, Vec<u8>)> = match self {
+            Compression::None => None,
+            #[cfg(feature = "compression-lz4")]
+            Compression::Lz4 => Some((Codec::Lz4, lz4_flex::compress_prepend_size(value))),
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd { level } => match format.dictionary_compressor(level)? {
+                Some(mut compressor) => Some((Codec::ZstdDict, compressor.compress(value)?)),
+                None => Some((Codec::Zstd, zstd::bulk::compress(value, level)?)),
+            },
+        };
+        Ok(compressed.filter(|(_, stored)| stored.len() < value.len()))
+    }
+}
+
+#[cfg_attr(not(feature = "compression-zstd"), allow(unused_variables))]
+pub(crate) fn decompress(codec: Codec, data: &[u8], format: &Format) -> Result<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "compression-lz4")]
+        Codec::Lz4 => {
+            // The size is allocated up front, so one a damaged record
+            // claims must be within what LZ4 can expand `data` to
+            let size = data.get(..4).map_or(0, |size| u32::from_le_bytes(size.try_into().unwrap()) as usize);
+            if size > data.len().saturating_mul(LZ4_MAX_RATIO) {
+                anyhow::bail!("LZ4 value claims {} bytes from {}", size, data.len());
+            }
+            if size > format.max_value_len() {
+                return Err(Error::ValueTooLarge { len: size, max: format.max_value_len() }.into());
+            }
+            Ok(lz4_flex::decompress_size_prepended(data)?)
+        }
+        #[cfg(feature = "compression-zstd")]
+        Codec::Zstd => read_within(zstd::stream::read::Decoder::new(data)?, format.max_value_len()),
+        #[cfg(feature = "compression-zstd")]
+        Codec::ZstdDict => {
+            let decoder = zstd::stream::read::Decoder::with_prepared_dictionary(data, format.decoder_dictionary()?)?;
+            read_within(decoder, format.max_value_len())
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            let feature = if codec == Codec::Lz4 { "lz4" } else { "zstd" };
+            anyhow::bail!("value is {}-compressed; rebuild with the compression-{} feature", feature, feature)
+        }
+    }
+}
+
+/// Everything `decoder` yields, if that is at most `max` bytes; a small
+/// frame can expand to far more
+#[cfg(feature = "compression-zstd")]
+fn read_within(decoder: impl std::io::Read, max: usize) -> Result<Vec<u8>> {
+    let mut value = Vec::new();
+    std::io::Read::read_to_end(&mut decoder.take(max as u64 + 1), &mut value)?;
+    if value.len() > max {
+        return Err(Error::ValueTooLarge { len: value.len(), max }.into());
+    }
+    Ok(value)
+}
+
+#[cfg(all(test, any(feature = "compression-lz4", feature = "compression-zstd")))]
+mod tests {
+    use super::*;
+
+    fn is_too_large(result: Result<Vec<u8>>, len: usize) -> bool {
+        matches!(result.unwrap_err().downcast_ref(), Some(Error::ValueTooLarge { len: l, max: 1000 }) if *l == len)
+    }
+
+    #[cfg(feature = "compression-zstd")]
+    #[test]
+    fn zstd_values_decode_within_the_limit() {
+        let value = vec![0; 1001];
+        let stored = zstd::bulk::compress(&value, 0).unwrap();
+        let mut format = Format::default();
+        assert_eq!(decompress(Codec::Zstd, &stored, &format).unwrap(), value);
+        format.set_max_value_len(1000);
+        assert!(is_too_large(decompress(Codec::Zstd, &stored, &format), 1001));
+
+        // A frame of a few KB that would expand past the default limit
+        let bomb = zstd::bulk::compress(&vec![0; crate::MAX_VAL_LEN + 1], 19).unwrap();
+        assert!(bomb.len() < 4096);
+        let result = decompress(Codec::Zstd, &bomb, &Format::default());
+        assert!(matches!(result.unwrap_err().downcast_ref(), Some(Error::ValueTooLarge { .. })));
+    }
+
+    #[cfg(feature = "compression-lz4")]
+    #[test]
+    fn lz4_values_decode_within_the_limit() {
+        let value = vec![0; 1001];
+        let stored = lz4_flex::compress_prepend_size(&value);
+        let mut format = Format::default();
+        assert_eq!(decompress(Codec::Lz4, &stored, &format).unwrap(), value);
+        format.set_max_value_len(1000);
+        assert!(is_too_large(decompress(Codec::Lz4, &stored, &format), 1001));
+    }
+}
@@ -0,0 +1,54 @@
+//! Cursors for paginated scans, from `Engine::scan_page`
+//!
+//! A cursor is the last key of the page it ends, so it stays valid while
+//! the store changes and across restarts and compactions: the next page
+//! starts at the first live key after it, whatever was written since. Its
+//! token is `1` (the version) and the key in lowercase hex, ASCII that can
+//! go in a URL or a job's state file as it is.
+
+use anyhow::{bail, Result};
+use std::fmt;
+
+const VERSION: char = '1';
+
+/// A page of `Engine::scan_page`: its pairs, and the cursor for the next
+/// one
+pub type Page = (Vec<(Vec<u8>, Vec<u8>)>, Option<Cursor>);
+
+/// Where a paginated scan stopped; see `Engine::scan_page`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    /// The last key returned
+    pub(crate) after: Vec<u8>,
+}
+
+impl Cursor {
+    pub(crate) fn new(after: Vec<u8>) -> Self {
+        Self { after }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", VERSION)?;
+        for byte in &self.after {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(token: &str) -> Result<Self> {
+        let Some(hex) = token.strip_prefix(VERSION) else {
+            bail!("not a mini-kv scan cursor: {:?}", token);
+        };
+        if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("scan cursor {:?} is damaged", token);
+        }
+        let after = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16)).collect::<Result<_, _>>()?;
+        Ok(Self { after })
+    }
+}
@@ -0,0 +1,41 @@
+//! AES-256-GCM encryption of record contents (feature `encryption`)
+//!
+//! An encrypted record keeps its plaintext header, so framing and CRC
+//! checks work without the key; key and value are encrypted together, with
+//! the header as associated data so lengths and flags cannot be altered.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Result};
+
+use crate::record::NONCE_LEN;
+
+#[derive(Clone)]
+pub(crate) struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    pub(crate) fn new(key: &[u8; 32]) -> Self {
+        Self(Aes256Gcm::new(key.into()))
+    }
+
+    /// Returns the random nonce followed by ciphertext and tag
+    pub(crate) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| anyhow!("encryption failed"))?;
+        let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    /// `sealed` is nonce, ciphertext and tag as written by `encrypt`
+    pub(crate) fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| anyhow!("authentication failed (wrong key or tampered record)"))
+    }
+}
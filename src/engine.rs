@@ -1,20 +1,77 @@
 use anyhow::Result;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
-use std::path::Path;
-use std::time::{Duration, Instant};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
 
-use crate::record::Record;
+use crate::adaptive::AdaptiveSync;
+use crate::archive::ArchivePolicy;
+use crate::builder::{EngineBuilder, ProgressFn, RecoveryProgress};
+use crate::cache::{BlockCache, CacheStats, ValueCache};
+use crate::checksum::Checksum;
+use crate::compaction::{CompactionStats, SizeTiered, TableInfo};
+use crate::compression::Compression;
+use crate::cursor::{Cursor, Page};
+use crate::error::Error;
+use crate::format::Format;
+use crate::index::{self, Index, IndexWriter, Location};
+use crate::lsm::Lsm;
+use crate::namespace::{self, Namespace, NamespaceStats, CATALOG};
+use crate::ratelimit::RateLimiter;
+use crate::record::{Record, RecordKind, RecordRef, ScanItem, Scanner, MAX_HEADER_SIZE, MAX_REUSED_BUFFER};
+use crate::scrub::{ScrubReport, Scrubber};
+use crate::segment::{self, SegmentFooter, FOOTER_MAGIC, FOOTER_SIZE};
+use crate::histogram::{LatencyHistogram, LatencySnapshot};
+use crate::slow::{SlowOpKind, SlowOpLog};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::sst::{SstOptions, SstWriter};
+use crate::storage::{Appender, FsStorage, ReadAt, Storage, StorageFile};
+use crate::syncer::{PendingSync, SyncHandle, Syncer};
+use crate::tail;
+use crate::vlog::{GcReport, ValueLog, ValuePointer};
+use crate::watch::{ChangeEvent, ChangeOp, Watchers};
+
+/// Logs smaller than this are recovered on the calling thread
+const PARALLEL_RECOVERY_MIN_BYTES: usize = 4 * 1024 * 1024;
+/// Unit of work handed to a recovery thread (and of progress reporting)
+const RECOVERY_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+/// Spans a `SpaceReport` splits the log into
+const SPACE_BANDS: u64 = 10;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SyncMode {
     Always,
     Batch(usize),
     Periodic(Duration),
+    /// Sync in batches sized from the fsyncs and puts seen so far, the
+    /// largest that keeps each write unsynced for at most about
+    /// `max_latency`
+    Adaptive { max_latency: Duration },
+}
+
+/// Parses the command-line form used by the bundled binaries:
+/// `always`, `batch:<writes>`, `periodic:<milliseconds>`, or
+/// `adaptive:<milliseconds>`
+impl std::str::FromStr for SyncMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "always" => Ok(SyncMode::Always),
+            Some(("batch", n)) => Ok(SyncMode::Batch(n.parse()?)),
+            Some(("periodic", ms)) => Ok(SyncMode::Periodic(Duration::from_millis(ms.parse()?))),
+            Some(("adaptive", ms)) => Ok(SyncMode::Adaptive { max_latency: Duration::from_millis(ms.parse()?) }),
+            _ => Err(anyhow::anyhow!("unknown sync mode {:?} (expected always, batch:N, periodic:MS or adaptive:MS)", s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -23,30 +80,311 @@ pub enum IoMode {
     Direct,
 }
 
+/// How the engine lays out data on disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineKind {
+    /// One append-only log, with every key's record location in the index
+    Log,
+    /// The log as a write-ahead log for a sorted memtable that is flushed
+    /// to sorted table files when full (`<path>.tables/`); memory holds
+    /// only the memtable
+    Lsm,
+}
+
+/// What recovery does when it meets a record that fails its checks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryMode {
+    /// Stop at the first bad record and truncate everything after it
+    TruncateTail,
+    /// Fail `open()` with `Error::Corruption` if valid records follow a bad
+    /// one; a partial write at the very end is still truncated
+    Strict,
+    /// Skip to the next sync marker and keep recovering past the damage;
+    /// only a partial write at the very end is truncated. The damaged bytes
+    /// stay in the file, so a later `TruncateTail` open still cuts there.
+    SkipCorrupt,
+}
+
+/// Which writes a read sees
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReadConsistency {
+    /// Every write that has returned, synced or not, including those a
+    /// crash now would take back
+    #[default]
+    Acknowledged,
+    /// Only writes a sync has made durable: a key written since the last
+    /// sync reads as it was then, so nothing read can vanish in a crash.
+    /// Only for `EngineKind::Log`, and for an engine opened with it as its
+    /// `EngineBuilder::read_consistency`, which then keeps track of the
+    /// keys written between syncs.
+    Durable,
+}
+
+/// Per-call overrides for `Engine::get_with_options`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadOptions {
+    /// `None` follows `EngineBuilder::read_consistency`
+    pub consistency: Option<ReadConsistency>,
+}
+
+/// Per-call overrides for `Engine::put_with_options`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PutOptions {
+    /// Store the value uncompressed even if the engine compresses, e.g. for
+    /// payloads that are already compressed (images, zstd blobs)
+    pub no_compress: bool,
+    /// With `EngineBuilder::max_unsynced_bytes` reached, fail with an
+    /// `io::ErrorKind::WouldBlock` error instead of syncing first
+    pub no_wait: bool,
+    /// `Some(true)` syncs right after this write and `Some(false)` skips a
+    /// sync `sync_mode` would do now, leaving it to the next write or
+    /// `sync()`; `None` follows `sync_mode`
+    pub sync: Option<bool>,
+    /// Expire the key at this time, to the millisecond: from then on reads
+    /// treat it as missing, and compaction drops it. Only for
+    /// `EngineKind::Log`.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Result of `Engine::stats()`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    /// Live keys, as `len()`: catalog entries of namespace names included
+    pub keys: usize,
+    /// Log bytes of the records live keys point at; `None` for
+    /// `EngineKind::Lsm`, whose keys live in table files
+    pub live_bytes: Option<u64>,
+    /// The rest of the log past its header: overwritten and deleted
+    /// records and tombstones, which `compact()` would reclaim, and the
+    /// footer a compacted log ends its sealed part with
+    pub dead_bytes: Option<u64>,
+    /// Length of the log, counting writes still in the write buffer; the
+    /// value log and table files are not included
+    pub file_size: u64,
+    /// Syncs of the log since open, asked for or done by the sync mode
+    pub fsyncs: u64,
+    /// Bytes appended to the log and value log by writes since open
+    pub bytes_written: u64,
+    /// Writes since open that returned without a sync, left to a later
+    /// one by the sync mode or `PutOptions::sync`
+    pub syncs_skipped: u64,
+    /// Writes `SyncMode::Adaptive` syncs after, as it stands; `None` in
+    /// the other modes
+    pub sync_batch: Option<usize>,
+    /// As `cache_stats()` and `block_cache_stats()`
+    pub cache: Option<CacheStats>,
+    pub block_cache: Option<CacheStats>,
+    /// Latency percentiles of `put()` (including any sync it does),
+    /// `get()` and `sync()` calls since open
+    pub put_latency: LatencySnapshot,
+    pub get_latency: LatencySnapshot,
+    pub sync_latency: LatencySnapshot,
+}
+
+/// Result of `Engine::verify()`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Bytes of log covered by the scan
+    pub bytes_checked: u64,
+    /// Records that passed their length and CRC checks
+    pub records_checked: usize,
+    /// Offsets of records that failed their checks; a damaged region is
+    /// reported once, at its start
+    pub bad_records: Vec<u64>,
+    /// Keys whose index entry does not point at a valid record for that key
+    pub dangling_index_entries: Vec<Vec<u8>>,
+    /// Whether the sealed records still match the segment footer, or `None`
+    /// if no part of the log is sealed
+    pub sealed_ok: Option<bool>,
+    /// Table files (`EngineKind::Lsm`) with a record that fails its checks
+    pub bad_tables: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(&self) -> bool {
+        self.bad_records.is_empty()
+            && self.dangling_index_entries.is_empty()
+            && self.sealed_ok != Some(false)
+            && self.bad_tables.is_empty()
+    }
+}
+
+/// Result of `Engine::space_report()`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpaceReport {
+    /// Length of the log, counting writes still in the write buffer
+    pub file_bytes: u64,
+    /// Bytes of the records live keys point at
+    pub live_bytes: u64,
+    /// Bytes of overwritten and deleted records, tombstones and damaged
+    /// regions, which `compact()` would drop
+    pub dead_bytes: u64,
+    pub live_records: usize,
+    pub dead_records: usize,
+    /// The log past its header cut into ten spans of equal length, oldest
+    /// first, to show how old the dead bytes are
+    pub bands: Vec<SpaceBand>,
+}
+
+/// One span of the log in a `SpaceReport`; a record counts in the span
+/// it starts in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceBand {
+    pub start: u64,
+    pub end: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl SpaceReport {
+    /// Log bytes per live byte
+    pub fn amplification(&self) -> f64 {
+        self.file_bytes as f64 / self.live_bytes.max(1) as f64
+    }
+
+    /// Estimate of the bytes `compact()` would give back: the dead bytes,
+    /// give or take the header and footer it writes
+    pub fn reclaimable(&self) -> u64 {
+        self.dead_bytes
+    }
+}
+
+/// Result of `Engine::approximate_size_of_range()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeSize {
+    /// Table bytes of the blocks the range spans, plus the key and value
+    /// bytes of its memtable entries
+    pub bytes: u64,
+    /// Entries in the range, counting every version of a key and
+    /// tombstones until compaction merges them
+    pub keys: u64,
+}
+
+/// A key written since the last sync, as it was at that sync
+struct UnsyncedKey {
+    /// Its record then, `None` if it was missing or expired
+    durable: Option<Location>,
+    expires_at: Option<u64>,
+    /// End of its latest write in the log, which a sync past it makes
+    /// durable
+    end: u64,
+}
+
+/// Why the engine is poisoned after a failed `sync_async()`
+const BACKGROUND_SYNC_FAILED: &str = "a background sync of the log failed";
+
 /// Log-structured KV store core engine
 /// 
 /// # Crash Consistency
 /// - `logical_index`: number of put() calls made
 /// - `durable_index`: number of entries fsync'd to disk
 /// - Invariant: `durable_index ≤ logical_index`
-pub struct Engine {
-    file: File,
-    /// In-memory index: key -> file offset
-    index: HashMap<Vec<u8>, u64>,
+pub struct Engine<S: Storage = FsStorage> {
+    /// The log, appended to and read from
+    file: Box<dyn StorageFile>,
+    /// Where the log and the index file are kept
+    storage: S,
+    /// Location of the log, for opening independent read handles
+    path: PathBuf,
+    /// Key -> record location, in memory or, over the budget, partly on disk
+    index: Index,
     /// Current write position (end of file)
     pos: u64,
     pub sync_mode: SyncMode,
     pub io_mode: IoMode,
+    /// What `get` and `get_many` see, and `get_with_options` unless told;
+    /// `Durable` keeps `unsynced_keys`
+    read_consistency: ReadConsistency,
+    /// Codec applied to values of new records
+    compression: Compression,
+    /// Values shorter than this are stored uncompressed
+    compression_min_size: usize,
+    /// Longest key and value `put()` and recovery accept
+    max_key_len: usize,
+    max_value_len: usize,
+    /// Settings from the file header (defaults if it has none) and the
+    /// encryption key
+    format: Format,
+    /// Offset of the first record, just past the file header
+    log_start: u64,
+    /// Checksum asked for at open, applied when the log is next rewritten
+    /// if the file uses another one
+    checksum: Option<Checksum>,
     /// Write counter for batch mode
     write_count: usize,
+    /// Where calls over `EngineBuilder::slow_op_threshold` are reported
+    slow_ops: Option<SlowOpLog>,
+    /// Channels `watch()` handed out, told of every put and delete
+    watchers: Watchers,
+    /// Latency of every put, get and sync since open, for `stats()`
+    put_latency: LatencyHistogram,
+    get_latency: LatencyHistogram,
+    sync_latency: LatencyHistogram,
+    /// Kept between appends so encoding a record reuses its allocation
+    encode_buf: Vec<u8>,
+    /// Appends not yet written to the file, the last bytes before `pos`
+    write_buf: Vec<u8>,
+    /// Size of `write_buf` from `EngineBuilder::write_buffer`; 0 writes
+    /// every append straight to the file
+    write_buf_size: usize,
+    /// Bytes written to the log and value log since the last sync
+    unsynced_bytes: u64,
+    /// Writes stall once `unsynced_bytes` reaches this
+    max_unsynced_bytes: Option<u64>,
+    /// Counted since open, for `stats()`
+    fsyncs: u64,
+    bytes_written: u64,
+    syncs_skipped: u64,
+    /// Pace puts by key and value bytes and by calls, and compaction by
+    /// bytes written, if `EngineBuilder::rate_limit` set limits
+    put_bytes_limiter: Option<RateLimiter>,
+    put_ops_limiter: Option<RateLimiter>,
+    compaction_limiter: Option<RateLimiter>,
     /// Last sync time for periodic mode
     last_sync: Instant,
+    /// Fsync times and put arrivals, for adaptive mode
+    adaptive: AdaptiveSync,
     /// Total put() calls made (logical writes)
     logical_index: usize,
     /// Total entries fsync'd to disk (durable writes)
     durable_index: usize,
     /// Progress file for crash test harness
     progress_file: Option<File>,
+    /// `<log>.synced`, where `LogTailer`s read how far the log is synced
+    synced_file: Option<Box<dyn StorageFile>>,
+    /// Background checksum scrubber, if enabled
+    scrubber: Option<Scrubber>,
+    /// Thread running `sync_async()`'s fsyncs, started by the first, and
+    /// those not yet seen to finish, oldest first
+    syncer: Option<Syncer>,
+    pending_syncs: Vec<PendingSync>,
+    /// The first write or sync to have failed, after which writes and
+    /// syncs fail with `Error::EnginePoisoned`
+    poisoned: Option<String>,
+    /// Size of `<log>.reserve`, from `EngineBuilder::disk_reserve`
+    disk_reserve: u64,
+    /// The reserve was deleted for a full disk and not yet written again,
+    /// so puts fail with `Error::DiskFull`
+    disk_full: bool,
+    /// From `EngineBuilder::max_size` and `compact_at_max_size`
+    max_size: Option<u64>,
+    compact_at_max_size: bool,
+    /// Keys written since the last sync, for `ReadConsistency::Durable`
+    unsynced_keys: HashMap<Vec<u8>, UnsyncedKey>,
+    /// Recently read values, if enabled
+    cache: Option<ValueCache>,
+    /// Recently read blocks of the log, if enabled
+    block_cache: Option<BlockCache>,
+    /// Memtable and table files, for `EngineKind::Lsm`
+    lsm: Option<Lsm>,
+    /// Segments holding large values, if the database has any
+    value_log: Option<ValueLog>,
+    /// Values at least this long go to the value log
+    value_log_min: Option<usize>,
+    /// Log offset the index file on disk covers, if there is one
+    index_file_end: Option<u64>,
+    /// Write an index snapshot on drop; set once open succeeds
+    snapshot_index: bool,
 }
 
 impl Engine {
@@ -66,34 +404,159 @@ impl Engine {
         sync_mode: SyncMode, 
         io_mode: IoMode
     ) -> Result<Self> {
-        let mut options = OpenOptions::new();
-        options.create(true).read(true).write(true);
-        
-        #[cfg(unix)]
-        options.mode(0o600);  // Owner read/write only
-        
-        let file = options.open(&path)?;
+        Self::builder(path).sync_mode(sync_mode).io_mode(io_mode).open()
+    }
+
+    /// Start building an engine with non-default options
+    pub fn builder(path: impl AsRef<Path>) -> EngineBuilder {
+        EngineBuilder::new(path)
+    }
+}
+
+impl<S: Storage> Engine<S> {
+    pub(crate) fn from_builder(builder: EngineBuilder<S>) -> Result<Self> {
+        let storage = builder.storage;
+        let on_fs = storage.is_file_system();
+        if !on_fs && (builder.kind == EngineKind::Lsm || builder.value_log.is_some() || builder.scrub.is_some()) {
+            anyhow::bail!("EngineKind::Lsm, the value log and scrubbing need storage on the file system");
+        }
+        let created = !storage.exists(&builder.path);
+        let file = storage.open(&builder.path, true)?;
+        // Otherwise the new log's name could be lost along with the writes
+        // synced to it
+        if created {
+            storage.sync_dir(&builder.path)?;
+        }
         
+        let mut format = Format::default();
+        format.set_max_key_len(builder.max_key_len.min(u32::MAX as usize));
+        format.set_max_value_len(builder.max_value_len.min(u32::MAX as usize));
+        #[cfg(feature = "encryption")]
+        for (id, key) in &builder.encryption_keys {
+            format.add_encryption_key(*id, key);
+        }
+
+        let tables_dir = tables_dir(&builder.path);
+        let value_log_dir = value_log_dir(&builder.path);
+        let lsm = match builder.kind {
+            EngineKind::Lsm if builder.value_log.is_some() || (on_fs && ValueLog::exists(&value_log_dir)) => {
+                anyhow::bail!("the value log is not supported with EngineKind::Lsm");
+            }
+            EngineKind::Lsm if builder.read_consistency == ReadConsistency::Durable => {
+                anyhow::bail!("ReadConsistency::Durable needs EngineKind::Log");
+            }
+            EngineKind::Lsm if builder.disk_reserve > 0 => anyhow::bail!("disk reserves need EngineKind::Log"),
+            EngineKind::Lsm if builder.max_size.is_some() => anyhow::bail!("max_size needs EngineKind::Log"),
+            EngineKind::Lsm => {
+                let strategy = builder.compaction_strategy.unwrap_or_else(|| Box::new(SizeTiered::default()));
+                let archive = builder.archive.or_else(|| builder.cold_dir.as_ref().map(ArchivePolicy::new));
+                Some(Lsm::open(tables_dir, builder.new_memtable, builder.memtable_size, strategy, archive, &format)?)
+            }
+            EngineKind::Log if on_fs && Lsm::exists(&tables_dir) => {
+                anyhow::bail!("{} has table files; open it with EngineKind::Lsm", builder.path.display());
+            }
+            EngineKind::Log => None,
+        };
+        let log_kind = lsm.is_none();
+        let value_log = if builder.value_log.is_some() || (on_fs && ValueLog::exists(&value_log_dir)) {
+            let mut segment_format = format.clone();
+            if let Some(checksum) = builder.checksum {
+                segment_format.set_checksum(checksum);
+            }
+            Some(ValueLog::open(value_log_dir, builder.cold_dir.clone(), &segment_format)?)
+        } else {
+            None
+        };
+
         let mut engine = Engine {
             file,
-            index: HashMap::new(),
+            storage,
+            path: builder.path,
+            // The memtable takes the index's place in LSM mode
+            index: if log_kind {
+                Index::new(builder.index_memory_budget, builder.inline_values)
+            } else {
+                Index::new(None, None)
+            },
             pos: 0,
-            sync_mode,
-            io_mode,
+            sync_mode: builder.sync_mode,
+            io_mode: builder.io_mode,
+            read_consistency: builder.read_consistency,
+            compression: builder.compression,
+            compression_min_size: builder.compression_min_size,
+            max_key_len: builder.max_key_len.min(u32::MAX as usize),
+            max_value_len: builder.max_value_len.min(u32::MAX as usize),
+            format,
+            log_start: 0,
+            checksum: builder.checksum,
             write_count: 0,
+            slow_ops: builder.slow_op_threshold.map(|threshold| SlowOpLog::new(threshold, builder.on_slow_op)),
+            watchers: Watchers::default(),
+            put_latency: LatencyHistogram::new(),
+            get_latency: LatencyHistogram::new(),
+            sync_latency: LatencyHistogram::new(),
+            encode_buf: Vec::new(),
+            write_buf: Vec::with_capacity(builder.write_buffer),
+            write_buf_size: builder.write_buffer,
+            unsynced_bytes: 0,
+            max_unsynced_bytes: builder.max_unsynced_bytes,
+            fsyncs: 0,
+            bytes_written: 0,
+            syncs_skipped: 0,
+            put_bytes_limiter: builder.rate_limit.bytes_per_sec.map(RateLimiter::new),
+            put_ops_limiter: builder.rate_limit.ops_per_sec.map(RateLimiter::new),
+            compaction_limiter: builder.rate_limit.compaction_bytes_per_sec.map(RateLimiter::new),
             last_sync: Instant::now(),
+            adaptive: AdaptiveSync::default(),
             logical_index: 0,
             durable_index: 0,
             progress_file: None,
+            synced_file: None,
+            scrubber: None,
+            syncer: None,
+            pending_syncs: Vec::new(),
+            poisoned: None,
+            disk_reserve: builder.disk_reserve,
+            disk_full: false,
+            max_size: builder.max_size,
+            compact_at_max_size: builder.compact_at_max_size,
+            unsynced_keys: HashMap::new(),
+            cache: builder.value_cache.filter(|_| log_kind).map(ValueCache::new),
+            block_cache: builder.block_cache.filter(|_| log_kind).map(BlockCache::new),
+            lsm,
+            value_log,
+            value_log_min: builder.value_log,
+            index_file_end: None,
+            snapshot_index: false,
         };
 
-        engine.recover()?;
+        engine.recover(builder.recovery_mode, builder.recovery_progress)?;
+        // A file with no records yet takes the requested checksum now
+        if engine.pos == engine.log_start && engine.checksum.is_some_and(|c| c != engine.format.checksum()) {
+            engine.rewrite(engine.rewrite_format())?;
+        }
+        #[cfg(feature = "compression-zstd")]
+        if let Compression::Zstd { level } = engine.compression {
+            engine.format.prepare_encoder(level);
+        }
+
+        if let Some(options) = builder.scrub {
+            // Everything recovered is durable
+            engine.scrubber = Some(Scrubber::start(engine.path.clone(), options, engine.pos)?);
+        }
+
+        engine.snapshot_index = builder.index_snapshot && log_kind;
+        engine.synced_file = Some(engine.storage.open(&tail::synced_path(&engine.path), true)?);
+        // Everything recovered is durable
+        engine.publish_synced_end(engine.pos)?;
+        engine.flush_if_full()?;
+        engine.fill_reserve()?;
 
         // Crash test harness: enable progress reporting
         if std::env::var("CRASH_TEST").is_ok() {
             let p_file = File::create("durable_progress.txt")?;
             engine.progress_file = Some(p_file);
-            engine.update_progress_file()?;
+            engine.update_progress_file(engine.pos)?;
         }
         
         Ok(engine)
@@ -101,46 +564,819 @@ impl Engine {
 
     /// Recover from existing log file
     /// Scans all records, rebuilds index, truncates partial writes
-    fn recover(&mut self) -> Result<()> {
-        self.file.seek(SeekFrom::Start(0))?;
-        let mut buf = Vec::new();
-        self.file.read_to_end(&mut buf)?;
-        
-        let mut curr_pos = 0;
-        let mut count = 0;
+    ///
+    /// In `Strict` mode a bad record followed by valid ones fails the open
+    /// before anything is truncated.
+    /// In `SkipCorrupt` mode a bad record does not end recovery: the scan
+    /// resumes at the next sync marker that starts a valid record, and the
+    /// damaged bytes are left in place (the index never points at them).
+    ///
+    /// With an index file that matches the log (a bounded index's spill, or
+    /// the snapshot of a clean shutdown), only the records after the part
+    /// it covers are read. Positions in `buf` are relative to `base`, where
+    /// reading started.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(path = %self.path.display())))]
+    fn recover(&mut self, recovery_mode: RecoveryMode, mut progress: Option<ProgressFn>) -> Result<()> {
+        let file_len = self.file.len()?;
+        self.log_start = self.format.read_header_from(&mut ReadAt { file: &*self.file, pos: 0, end: file_len })? as u64;
+        let covered = match self.lsm {
+            Some(_) => None,
+            None => self.index.load(&self.storage, &self.index_path(), &*self.file, self.log_start, file_len)?,
+        };
+        self.index_file_end = covered;
+        let base = covered.unwrap_or(self.log_start);
+        let mut buf = vec![0u8; (file_len - base) as usize];
+        self.file.read_exact_at(&mut buf, base)?;
+        let total_bytes = buf.len() as u64;
+
+        let threads = if buf.len() < PARALLEL_RECOVERY_MIN_BYTES {
+            1
+        } else {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        };
+        let mut report = RecoveryProgress { bytes_scanned: 0, total_bytes, records_recovered: 0 };
+        let mut on_chunk = |bytes: u64, records: usize| {
+            if let Some(callback) = progress.as_mut() {
+                report.bytes_scanned += bytes;
+                report.records_recovered += records;
+                callback(report);
+            }
+        };
 
-        while curr_pos < buf.len() {
-            match Record::decode(&buf[curr_pos..]) {
-                Ok((record, size)) => {
-                    self.index.insert(record.key, curr_pos as u64);
-                    curr_pos += size;
-                    count += 1;
+        let footer = self.format.footer_offset().and_then(|offset| offset.checked_sub(base)).map(|offset| offset as usize);
+        let mut run_start = 0;
+        let mut valid_end;
+        let mut count = 0;
+        loop {
+            // The segment footer is not a record, so a run stops there
+            let limit = footer.filter(|&offset| run_start <= offset && offset < buf.len()).unwrap_or(buf.len());
+            let run = &buf[..limit];
+            // The memtable holds values, so decode all of them
+            let options = DecodeOptions {
+                inline_max: if self.lsm.is_some() { Some(usize::MAX) } else { self.index.inline_max() },
+                max_key_len: self.max_key_len,
+                max_value_len: self.max_value_len,
+            };
+            let (records, run_end) = verify_run(run, base, run_start, &self.format, options, threads, &mut on_chunk)?;
+            // A pointer to a value that never reached the value log ends
+            // the log there, like a torn write
+            let mut torn_at = None;
+            for record in records {
+                if let (Some(pointer), Some(location)) = (record.pointer, record.location) {
+                    let Some(value_log) = &self.value_log else {
+                        let reason = "record points into a value log, but there is none".to_string();
+                        return Err(Error::Corruption { offset: location.offset, reason }.into());
+                    };
+                    if !value_log.written(pointer)? {
+                        torn_at = Some(location.offset);
+                        break;
+                    }
+                }
+                count += 1;
+                if let Some(end) = &record.range_end {
+                    let Some(lsm) = &mut self.lsm else {
+                        anyhow::bail!("range tombstone in a log not opened as EngineKind::Lsm");
+                    };
+                    lsm.apply_range(&record.key, end, |_| {})?;
+                    continue;
                 }
-                Err(_) => break,  // Partial/corrupted record, truncate later
+                if let Some(lsm) = &mut self.lsm {
+                    if record.expires_at.is_some() {
+                        anyhow::bail!("record with an expiry time in a log opened as EngineKind::Lsm");
+                    }
+                    let value = match record.location {
+                        Some(location) => Some(record.value.ok_or_else(|| Error::Corruption {
+                            offset: location.offset,
+                            reason: "value cannot be decoded".to_string(),
+                        })?),
+                        None => None,
+                    };
+                    lsm.apply(record.key, value)?;
+                    continue;
+                }
+                let key = namespace::into_index_key(record.namespace, record.key);
+                match record.location {
+                    Some(location) => self.index.insert(key, location, record.value.as_deref(), record.expires_at)?,
+                    None => {
+                        self.index.remove(&key)?;
+                    }
+                }
+                // Recovered records are durable, so the spill can cover them
+                if self.index.over_budget() {
+                    self.spill_index(record.end)?;
+                }
+            }
+            valid_end = run_end;
+            if let Some(offset) = torn_at {
+                valid_end = (offset - base) as usize;
+                break;
+            }
+
+            if run_end == limit && buf.get(limit..limit + FOOTER_SIZE).is_some_and(|f| f.starts_with(&FOOTER_MAGIC)) {
+                run_start = limit + FOOTER_SIZE;
+                continue;
+            }
+            if run_end == buf.len() || recovery_mode == RecoveryMode::TruncateTail {
+                break;
+            }
+            // The damaged record may still carry an intact marker, look past it.
+            // Nothing valid after it means a torn final write, not corruption.
+            let Some(skip) = Record::resync_with(&buf[run_end + 1..], &self.format) else { break };
+            if recovery_mode == RecoveryMode::Strict {
+                let reason = Record::decode_with(&buf[run_end..], &self.format)
+                    .err()
+                    .map_or_else(|| "invalid header".to_string(), |e| e.to_string());
+                return Err(Error::Corruption { offset: base + run_end as u64, reason }.into());
             }
+            run_start = run_end + 1 + skip;
         }
 
-        self.pos = curr_pos as u64;
+        self.pos = base + valid_end as u64;
         self.logical_index = count;
         self.durable_index = count;  // Recovered data is durable by definition
 
         // Truncate partial writes at end of file
-        if self.pos < buf.len() as u64 {
+        if self.pos < file_len {
             self.file.set_len(self.pos)?;
         }
-        
+
+        if let Some(callback) = progress.as_mut() {
+            callback(RecoveryProgress { bytes_scanned: total_bytes, total_bytes, records_recovered: count });
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(records = count, bytes_scanned = total_bytes, truncated = file_len - self.pos, "recovered");
+
         Ok(())
     }
 
     /// Write a key-value pair
     pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        let record = Record::new(key.clone(), value);
-        let encoded = record.encode();
-        let current_record_pos = self.pos;
+        self.put_with_options(key, value, &PutOptions::default())
+    }
+
+    /// `put`, but fail with an `io::ErrorKind::WouldBlock` error rather
+    /// than sync when `EngineBuilder::max_unsynced_bytes` is reached; call
+    /// `sync()` and retry
+    pub fn try_put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put_with_options(key, value, &PutOptions { no_wait: true, ..PutOptions::default() })
+    }
+
+    /// `put`, synced before it returns whatever the sync mode, along with
+    /// every write before it: for the few writes that must not be lost,
+    /// such as configuration changes, among bulk ones the mode may leave
+    /// unsynced for a while
+    pub fn put_durable(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put_with_options(key, value, &PutOptions { sync: Some(true), ..PutOptions::default() })
+    }
+
+    /// `put`, with the key expiring `ttl` from now; see
+    /// `PutOptions::expires_at`
+    pub fn put_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let options = PutOptions { expires_at: Some(SystemTime::now() + ttl), ..PutOptions::default() };
+        self.put_with_options(key, value, &options)
+    }
+
+    /// Write a key-value pair, overriding engine settings for this call
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(key_len = key.len(), value_len = value.len()))
+    )]
+    pub fn put_with_options(&mut self, key: Vec<u8>, value: Vec<u8>, options: &PutOptions) -> Result<()> {
+        self.put_in(0, key, value, options)
+    }
+
+    /// `put_with_options` for a key of namespace `namespace`
+    pub(crate) fn put_in(&mut self, namespace: u32, key: Vec<u8>, value: Vec<u8>, options: &PutOptions) -> Result<()> {
+        let (started, key_len) = (Instant::now(), key.len());
+        let result = self.write_pair(namespace, key, value, options);
+        self.finish_op(SlowOpKind::Put, started, key_len);
+        result
+    }
+
+    fn write_pair(&mut self, namespace: u32, key: Vec<u8>, value: Vec<u8>, options: &PutOptions) -> Result<()> {
+        if key.len() > self.max_key_len {
+            return Err(Error::KeyTooLarge { len: key.len(), max: self.max_key_len }.into());
+        }
+        if value.len() > self.max_value_len {
+            return Err(Error::ValueTooLarge { len: value.len(), max: self.max_value_len }.into());
+        }
+        if options.expires_at.is_some() && self.lsm.is_some() {
+            anyhow::bail!("expiry times need EngineKind::Log");
+        }
+        self.check_writable()?;
+        if self.disk_full {
+            return Err(Error::DiskFull.into());
+        }
+        let separate = self.value_log_min.is_some_and(|min| value.len() >= min);
+        let mut len = key.len() + value.len() + MAX_HEADER_SIZE;
+        if separate {
+            len += key.len() + ValuePointer::SIZE + MAX_HEADER_SIZE;
+        }
+        self.make_room(len as u64)?;
+        self.stall_if_unsynced(options.no_wait)?;
+        self.throttle(key.len() + value.len());
+        let before = self.before_write(&namespace::index_key(namespace, &key), options.sync)?;
+        let compression = self.compression_for(value.len(), options.no_compress);
+        let record = match &mut self.value_log {
+            Some(value_log) if separate => {
+                let data = Record::new(key, value).in_namespace(namespace);
+                let pointer = match value_log.append(&data, compression) {
+                    Err(e) if is_disk_full(&e) => return Err(Error::DiskFull.into()),
+                    pointer => self.poison_on_error(pointer)?,
+                };
+                self.unsynced_bytes += pointer.len as u64;
+                self.bytes_written += pointer.len as u64;
+                Record::value_pointer(data.key, pointer.encode())
+            }
+            _ => Record::new(key, value),
+        }
+        .in_namespace(namespace)
+        .with_expiry(options.expires_at.map(unix_millis));
+        let location = self.append(&record, compression, options.sync)?;
+        #[cfg(feature = "metrics")]
+        crate::metrics::METRICS.puts.inc();
+        let watched = (namespace == 0 && self.watchers.wants(&record.key)).then(|| record.key.clone());
+        if let Some(lsm) = &mut self.lsm {
+            lsm.apply(record.key, Some(record.value))?;
+        } else {
+            // Update in-memory index (even if not yet durable)
+            let inline = (record.kind == RecordKind::Put).then_some(record.value.as_slice());
+            let index_key = namespace::into_index_key(namespace, record.key);
+            self.after_write(&index_key, before);
+            self.index.insert(index_key, location, inline, record.expires_at)?;
+        }
+        self.watchers.notify(watched, ChangeOp::Put);
+        match self.lsm {
+            Some(_) => self.flush_if_full(),
+            None => self.spill_if_over_budget(),
+        }
+    }
+
+    /// What `index_key` is as of the last sync, for `after_write`, if
+    /// durable reads are kept track of, a write about to be made to it may
+    /// be left unsynced, and none since the sync has said so already
+    fn before_write(&self, index_key: &[u8], sync: Option<bool>) -> Result<Option<UnsyncedKey>> {
+        let syncs_now = sync.unwrap_or(self.sync_mode == SyncMode::Always);
+        if self.read_consistency != ReadConsistency::Durable || syncs_now || self.unsynced_keys.contains_key(index_key) {
+            return Ok(None);
+        }
+        let durable = match self.is_expired(index_key) {
+            true => None,
+            false => self.index.get(index_key)?,
+        };
+        Ok(Some(UnsyncedKey { durable, expires_at: self.index.expires_at(index_key), end: 0 }))
+    }
+
+    /// Just after appending a write to `index_key`, note that it is not
+    /// yet durable, if the append did not sync it; `before` is from
+    /// `before_write`
+    fn after_write(&mut self, index_key: &[u8], before: Option<UnsyncedKey>) {
+        if self.durable_index == self.logical_index {
+            return;
+        }
+        let end = self.pos;
+        match self.unsynced_keys.get_mut(index_key) {
+            Some(unsynced) => unsynced.end = end,
+            None => {
+                if let Some(before) = before {
+                    self.unsynced_keys.insert(index_key.to_vec(), UnsyncedKey { end, ..before });
+                }
+            }
+        }
+    }
+
+    /// Delete a key by appending a tombstone
+    /// Returns false (and writes nothing) if the key was not present
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        self.delete_in(0, key)
+    }
+
+    /// `delete` for a key of namespace `namespace`
+    pub(crate) fn delete_in(&mut self, namespace: u32, key: &[u8]) -> Result<bool> {
+        if !self.contains_key_in(namespace, key)? {
+            return Ok(false);
+        }
+        self.write_tombstone(namespace, key, None)?;
+        Ok(true)
+    }
+
+    /// Append a tombstone for a live key and apply it; `sync` as for
+    /// `append`
+    fn write_tombstone(&mut self, namespace: u32, key: &[u8], sync: Option<bool>) -> Result<()> {
+        self.stall_if_unsynced(false)?;
+        self.throttle(key.len());
+        let index_key = namespace::index_key(namespace, key);
+        let before = self.before_write(&index_key, sync)?;
+        self.append(&Record::tombstone(key.to_vec()).in_namespace(namespace), Compression::None, sync)?;
+        #[cfg(feature = "metrics")]
+        crate::metrics::METRICS.deletes.inc();
+        if let Some(lsm) = &mut self.lsm {
+            lsm.apply(key.to_vec(), None)?;
+        } else {
+            self.after_write(&index_key, before);
+            self.index.remove(&index_key)?;
+        }
+        let watched = namespace == 0 && self.watchers.wants(key);
+        self.watchers.notify(watched.then(|| key.to_vec()), ChangeOp::Delete);
+        match self.lsm {
+            Some(_) => self.flush_if_full(),
+            None => self.spill_if_over_budget(),
+        }
+    }
+
+    /// Delete every key starting with `prefix` and return how many there
+    /// were. With `EngineKind::Lsm` this is a single `delete_range` up to
+    /// the first key past the prefix (for an empty prefix or one of only
+    /// 0xFF bytes there is none, and the keys get a tombstone each).
+    /// Otherwise every key gets a tombstone, found through the index
+    /// rather than looked up one by one, and the sync mode is applied
+    /// once, after the last.
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        self.delete_prefix_in(0, prefix)
+    }
+
+    /// `delete_prefix` within namespace `namespace`
+    pub(crate) fn delete_prefix_in(&mut self, namespace: u32, prefix: &[u8]) -> Result<usize> {
+        let keys = match &self.lsm {
+            Some(lsm) => {
+                if let Some(end) = prefix_end(prefix) {
+                    return self.delete_range(prefix.to_vec()..end);
+                }
+                lsm.entries_from(prefix, false)
+                    .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(prefix)))
+                    .map(|entry| Ok(entry?.0))
+                    .collect::<Result<Vec<_>>>()?
+            }
+            None => self
+                .namespace_entries(namespace, prefix)?
+                .into_iter()
+                .map(|(index_key, _)| namespace::split_index_key(&index_key).1.to_vec())
+                .collect(),
+        };
+        for (i, key) in keys.iter().enumerate() {
+            let sync = (i + 1 < keys.len()).then_some(false);
+            self.write_tombstone(namespace, key, sync)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Estimate the bytes and the number of keys from `range.start` up to
+    /// `range.end`, exclusive, without reading the range: each table's
+    /// share is measured on its block index, so it is off by up to a block
+    /// (about 4KB) at either end, and its keys are prorated from its
+    /// record count. Overwritten and deleted keys still count until
+    /// compaction drops them, so a range with many recent overwrites
+    /// looks bigger than it is. Memtable entries are counted exactly.
+    /// Only for `EngineKind::Lsm`, whose tables are sorted.
+    pub fn approximate_size_of_range<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<RangeSize> {
+        let (start, end) = (range.start.as_ref(), range.end.as_ref());
+        let Some(lsm) = &self.lsm else { anyhow::bail!("approximate_size_of_range needs EngineKind::Lsm") };
+        if start >= end {
+            return Ok(RangeSize::default());
+        }
+        let (bytes, keys) = lsm.approximate_size(start, end);
+        Ok(RangeSize { bytes, keys })
+    }
+
+    /// Write a consistent copy of the store into `dir`, created if missing,
+    /// that opens as a store of its own: with the same builder settings
+    /// and keys, and the log's file name, at `dir/<name>`. Syncs first, so
+    /// the copy holds every write so far. The log is copied up to its end,
+    /// as is the value log segment still appended to; table files, older
+    /// value log segments and the index file are never written in place,
+    /// so they are hard linked where the file system allows and copied
+    /// elsewhere. Writes to either store afterwards leave the other be.
+    /// Fails if `dir` already holds the log.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(dir = %dir.as_ref().display())))]
+    pub fn checkpoint(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if !self.storage.is_file_system() {
+            anyhow::bail!("checkpoint needs storage on the file system");
+        }
+        let name = self.path.file_name().ok_or_else(|| anyhow::anyhow!("log path {} has no file name", self.path.display()))?;
+        std::fs::create_dir_all(dir)?;
+        let log = dir.join(name);
+        if log.exists() {
+            anyhow::bail!("{} already exists", log.display());
+        }
+        self.sync()?;
+        copy_prefix(&self.path, &log, self.pos)?;
+        if let Some(lsm) = &self.lsm {
+            lsm.checkpoint(&tables_dir(&log))?;
+        }
+        if let Some(value_log) = &self.value_log {
+            value_log.checkpoint(&value_log_dir(&log))?;
+        }
+        // Tied to the log bytes it covers, which the copy has too. It is
+        // a cache, so one replaced since is no loss.
+        if self.index_path().exists() {
+            let _ = link_or_copy(&self.index_path(), &index_path(&log));
+        }
+        sync_parent_dir(&log)?;
+        Ok(())
+    }
+
+    /// Upload a checkpoint of the store to `client`'s bucket as backup
+    /// `name`, under `<prefix>backups/<name>/`; `S3Client::download_dir`
+    /// restores it into a directory the store opens from at `dir/<name of
+    /// the log>`. The checkpoint goes through a directory next to the log,
+    /// hard linked where it can be, and is removed afterwards. Archived
+    /// tables are included, so the backup stands on its own.
+    #[cfg(feature = "s3")]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(name = name)))]
+    pub fn backup_to_s3(&mut self, client: &crate::s3::S3Client, name: &str) -> Result<()> {
+        let log_name = self.path.file_name().ok_or_else(|| anyhow::anyhow!("log path {} has no file name", self.path.display()))?;
+        let mut staging = log_name.to_os_string();
+        staging.push(".backup");
+        let staging = self.path.with_file_name(staging);
+        // Left behind by a backup cut short
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        let result = self.checkpoint(&staging).and_then(|()| client.upload_dir(&staging, name));
+        let _ = std::fs::remove_dir_all(&staging);
+        result
+    }
+
+    /// Write the latest value of every live key, with its namespace and
+    /// expiry time, to `out` as a portable snapshot: a single checksummed
+    /// stream that names its own format and holds nothing of the log's
+    /// layout, so `import_snapshot` can load it into a store on another
+    /// machine, architecture or engine kind. Keys already expired are left
+    /// out. Writes that are not yet synced are included. Returns how many
+    /// keys were written.
+    pub fn export_snapshot(&self, out: impl Write) -> Result<u64> {
+        let mut namespaces = Vec::new();
+        if self.lsm.is_none() {
+            for (name, id) in self.scan_prefix_in(CATALOG, &[])? {
+                namespaces.push((u32::from_le_bytes(id.as_slice().try_into()?), String::from_utf8(name)?));
+            }
+        }
+        let mut snapshot = SnapshotWriter::new(out, self.format.checksum(), &namespaces)?;
+        if let Some(lsm) = &self.lsm {
+            for entry in lsm.entries_from(&[], true) {
+                let (key, value) = entry?;
+                snapshot.entry(0, 0, &key, &value)?;
+            }
+            return snapshot.finish();
+        }
+        for entry in self.index.entries() {
+            let (index_key, location) = entry?;
+            let (namespace, key) = namespace::split_index_key(&index_key);
+            if namespace == CATALOG || self.is_expired(&index_key) {
+                continue;
+            }
+            let value = match self.index.inline_value(&index_key) {
+                Some(value) => value.to_vec(),
+                None => self.resolve(self.read_record(location)?)?,
+            };
+            snapshot.entry(namespace, self.index.expires_at(&index_key).unwrap_or(0), key, &value)?;
+        }
+        snapshot.finish()
+    }
+
+    /// Put every key of a snapshot from `export_snapshot`, into the
+    /// namespaces of the same names (created if missing), with the same
+    /// expiry times, then sync. Keys already in the store are overwritten
+    /// and others left as they are. Each part of the snapshot is checked
+    /// before it is written, but a snapshot found cut short or damaged
+    /// part way leaves the keys before that point written: import into an
+    /// empty store to start over cleanly. Keys that expired since the
+    /// export are skipped, and with `EngineKind::Lsm`, which keeps no
+    /// expiry times, so are all keys with one, rather than kept past it;
+    /// a snapshot naming namespaces fails before anything is written.
+    /// Returns how many keys were written.
+    pub fn import_snapshot(&mut self, input: impl Read) -> Result<u64> {
+        let (mut snapshot, names) = SnapshotReader::new(input)?;
+        let mut namespaces = HashMap::from([(0, 0)]);
+        for (id, name) in names {
+            namespaces.insert(id, self.namespace(&name)?.id());
+        }
+        let now = unix_millis(SystemTime::now());
+        let mut written = 0;
+        while let Some(entry) = snapshot.next_entry()? {
+            let Some(&namespace) = namespaces.get(&entry.namespace) else {
+                anyhow::bail!("snapshot entry {} is in namespace {}, which its header does not name", written, entry.namespace);
+            };
+            if entry.expires_at != 0 && (entry.expires_at <= now || self.lsm.is_some()) {
+                continue;
+            }
+            let options = PutOptions {
+                sync: Some(false),
+                expires_at: (entry.expires_at != 0).then(|| UNIX_EPOCH + Duration::from_millis(entry.expires_at)),
+                ..PutOptions::default()
+            };
+            self.put_in(namespace, entry.key, entry.value, &options)?;
+            written += 1;
+        }
+        self.sync()?;
+        Ok(written)
+    }
+
+    /// Write the live keys of the default namespace, sorted, into SST
+    /// files in `dir` (created if missing), named `000001.sst` on and cut
+    /// at `options.target_file_size`, in the format RocksDB's
+    /// `SstFileWriter` makes, for loading into a RocksDB database with
+    /// `IngestExternalFile`. The files are synced, and their key ranges do
+    /// not overlap, so they can be ingested together. Expiry times are
+    /// dropped. Writes that are not yet synced are included. Nothing is
+    /// written for an empty store. Returns the files written.
+    pub fn export_sst(&self, dir: impl AsRef<Path>, options: &SstOptions) -> Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        let mut writer = None;
+        let mut add = |key: &[u8], value: &[u8]| -> Result<()> {
+            let sst = match &mut writer {
+                Some(sst) => sst,
+                None => {
+                    let path = dir.join(format!("{:06}.sst", files.len() + 1));
+                    let file = OpenOptions::new().write(true).create_new(true).open(&path)
+                        .map_err(|e| anyhow::anyhow!("creating {}: {}", path.display(), e))?;
+                    files.push(path);
+                    writer.insert(SstWriter::new(io::BufWriter::new(file), options))
+                }
+            };
+            sst.add(key, value)?;
+            if options.target_file_size > 0 && sst.size() >= options.target_file_size {
+                finish_sst(writer.take().expect("just added to"))?;
+            }
+            Ok(())
+        };
+        if let Some(lsm) = &self.lsm {
+            for entry in lsm.entries_from(&[], true) {
+                let (key, value) = entry?;
+                add(&key, &value)?;
+            }
+        } else {
+            for (index_key, location) in self.namespace_entries(0, &[])? {
+                let value = match self.index.inline_value(&index_key) {
+                    Some(value) => value.to_vec(),
+                    None => self.resolve(self.read_record(location)?)?,
+                };
+                add(namespace::split_index_key(&index_key).1, &value)?;
+            }
+        }
+        if let Some(sst) = writer {
+            finish_sst(sst)?;
+        }
+        if !files.is_empty() {
+            sync_parent_dir(&files[0])?;
+        }
+        Ok(files)
+    }
+
+    /// Set when a live key expires, replacing any expiry time it had, by
+    /// appending a copy of its record with the new time; a time already
+    /// past expires it at once. A value in the value log stays where it
+    /// is. Returns false (and writes nothing) if the key was not present.
+    /// Only for `EngineKind::Log`.
+    pub fn expire_at(&mut self, key: &[u8], at: SystemTime) -> Result<bool> {
+        self.expire_at_in(0, key, at)
+    }
+
+    /// `expire_at` for a key of namespace `namespace`
+    pub(crate) fn expire_at_in(&mut self, namespace: u32, key: &[u8], at: SystemTime) -> Result<bool> {
+        if self.lsm.is_some() {
+            anyhow::bail!("expiry times need EngineKind::Log");
+        }
+        let index_key = namespace::index_key(namespace, key);
+        if self.is_expired(&index_key) {
+            return Ok(false);
+        }
+        let Some(location) = self.index.get(&index_key)? else { return Ok(false) };
+        let record = self.read_record(location)?.with_expiry(Some(unix_millis(at)));
+        self.make_room((record.key.len() + record.value.len() + MAX_HEADER_SIZE) as u64)?;
+        self.stall_if_unsynced(false)?;
+        self.throttle(record.key.len() + record.value.len());
+        let before = self.before_write(&index_key, None)?;
+        let location = self.append(&record, self.compression_for(record.value.len(), false), None)?;
+        let inline = (record.kind == RecordKind::Put).then_some(record.value.as_slice());
+        self.after_write(&index_key, before);
+        self.index.insert(index_key.into_owned(), location, inline, record.expires_at)?;
+        self.spill_if_over_budget()?;
+        Ok(true)
+    }
+
+    /// How long until a live key expires: `None` if it has no expiry time,
+    /// or is missing (tell the two apart with `contains_key`). Always
+    /// `None` for `EngineKind::Lsm`.
+    pub fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.ttl_in(0, key)
+    }
+
+    /// `ttl` for a key of namespace `namespace`
+    pub(crate) fn ttl_in(&self, namespace: u32, key: &[u8]) -> Result<Option<Duration>> {
+        let index_key = namespace::index_key(namespace, key);
+        let Some(expires_at) = self.index.expires_at(&index_key) else { return Ok(None) };
+        let now = unix_millis(SystemTime::now());
+        if expires_at <= now || self.index.get(&index_key)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Duration::from_millis(expires_at - now)))
+    }
+
+    /// Whether the key with index key `index_key` has expired; it stays in
+    /// the index until compaction or its next write
+    fn is_expired(&self, index_key: &[u8]) -> bool {
+        self.index.expires_at(index_key).is_some_and(|at| at <= unix_millis(SystemTime::now()))
+    }
+
+    /// Delete every key from `range.start` up to `range.end`, exclusive,
+    /// by appending a single range tombstone, and return how many keys
+    /// were live. Writes nothing if there were none. Only for
+    /// `EngineKind::Lsm`, whose tables are sorted: reads skip what the
+    /// tombstone covers, recovery replays it and compaction drops the
+    /// values it hides. Counting the deleted keys reads every key in the
+    /// range, but no values.
+    pub fn delete_range<K: AsRef<[u8]>>(&mut self, range: Range<K>) -> Result<usize> {
+        let (start, end) = (range.start.as_ref(), range.end.as_ref());
+        let Some(lsm) = &self.lsm else { anyhow::bail!("delete_range needs EngineKind::Lsm") };
+        for key in [start, end] {
+            if key.len() > self.max_key_len {
+                return Err(Error::KeyTooLarge { len: key.len(), max: self.max_key_len }.into());
+            }
+        }
+        if start >= end || !lsm.any_live_in(start, end)? {
+            return Ok(0);
+        }
+        self.stall_if_unsynced(false)?;
+        self.throttle(start.len() + end.len());
+        self.append(&Record::range_tombstone(start.to_vec(), end.to_vec()), Compression::None, None)?;
+        #[cfg(feature = "metrics")]
+        crate::metrics::METRICS.deletes.inc();
+        let (watchers, mut watched) = (&self.watchers, Vec::new());
+        let deleted = match &mut self.lsm {
+            Some(lsm) => lsm.apply_range(start, end, |key| {
+                if watchers.wants(key) {
+                    watched.push(key.to_vec());
+                }
+            })?,
+            None => 0,
+        };
+        self.watchers.notify_all(watched, ChangeOp::Delete);
+        self.flush_if_full()?;
+        Ok(deleted)
+    }
+
+    /// Subscribe to writes to keys starting with `prefix` (all keys for an
+    /// empty one) in the default namespace. Each successful `put()` or
+    /// `delete()` of such a key sends
+    /// an event once a `get()` sees the write, which is before it is
+    /// necessarily durable. Drop the receiver to unsubscribe. Changes made
+    /// by recovery, `compact()` and other rewrites are not reported: they
+    /// leave every key's value as it was.
+    pub fn watch(&mut self, prefix: &[u8]) -> mpsc::Receiver<ChangeEvent> {
+        self.watchers.subscribe(prefix)
+    }
 
-        // 1. Write to file (may be buffered)
-        self.file.write_all(&encoded)?;
+    /// The namespace called `name`, a keyspace apart from the default one
+    /// the other methods use, created on first use by writing its name to
+    /// the catalog of namespaces. Only for `EngineKind::Log`.
+    pub fn namespace(&mut self, name: &str) -> Result<Namespace<'_, S>> {
+        if self.lsm.is_some() {
+            anyhow::bail!("namespaces need EngineKind::Log");
+        }
+        let acknowledged = ReadOptions { consistency: Some(ReadConsistency::Acknowledged) };
+        let id = match self.get_in(CATALOG, name.as_bytes(), &acknowledged)? {
+            Some(id) => u32::from_le_bytes(id.as_slice().try_into()?),
+            None => {
+                let mut last = 0;
+                for (_, id) in self.scan_prefix_in(CATALOG, &[])? {
+                    last = last.max(u32::from_le_bytes(id.as_slice().try_into()?));
+                }
+                if last + 1 == CATALOG {
+                    anyhow::bail!("every namespace id is taken");
+                }
+                self.put_in(CATALOG, name.as_bytes().to_vec(), (last + 1).to_le_bytes().to_vec(), &PutOptions::default())?;
+                last + 1
+            }
+        };
+        Ok(Namespace::new(self, id))
+    }
+
+    /// Names of the namespaces created so far, sorted
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        self.keys_in(CATALOG)?.into_iter().map(|name| Ok(String::from_utf8(name)?)).collect()
+    }
+
+    /// Once `max_unsynced_bytes` are waiting for a sync, sync before taking
+    /// another write, or with `no_wait` fail with `WouldBlock`
+    fn stall_if_unsynced(&mut self, no_wait: bool) -> Result<()> {
+        if self.max_unsynced_bytes.is_none_or(|max| self.unsynced_bytes < max) {
+            return Ok(());
+        }
+        if no_wait {
+            let message = format!("{} bytes are waiting for a sync", self.unsynced_bytes);
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, message).into());
+        }
+        self.sync()
+    }
+
+    /// Wait for the rate limits to admit one write of `bytes`
+    fn throttle(&self, bytes: usize) {
+        if let Some(limiter) = &self.put_ops_limiter {
+            limiter.acquire(1);
+        }
+        if let Some(limiter) = &self.put_bytes_limiter {
+            limiter.acquire(bytes as u64);
+        }
+    }
+
+    /// Spill the index entries to disk if they have outgrown the memory
+    /// budget. The index file may only cover durable records, so this syncs
+    /// first.
+    fn spill_if_over_budget(&mut self) -> Result<()> {
+        if !self.index.over_budget() {
+            return Ok(());
+        }
+        self.sync()?;
+        self.spill_index(self.pos)
+    }
+
+    /// Write the index entries to the index file, covering the log up to
+    /// `end`
+    fn spill_index(&mut self, end: u64) -> Result<()> {
+        let tag = index::log_tag(&*self.file, self.log_start, end)?;
+        self.index.spill(&self.storage, &self.index_path(), end, tag)?;
+        self.index_file_end = Some(end);
+        Ok(())
+    }
+
+    fn flush_if_full(&mut self) -> Result<()> {
+        match &self.lsm {
+            Some(lsm) if lsm.memtable_full() => self.flush_memtable(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the memtable out as a table file, then start the log over:
+    /// the table now holds everything it did
+    fn flush_memtable(&mut self) -> Result<()> {
+        let format = self.rewrite_format();
+        let (compression, min_size) = (self.compression, self.compression_min_size);
+        let compression_for = |len: usize| if len < min_size { Compression::None } else { compression };
+        let Some(lsm) = &mut self.lsm else { return Ok(()) };
+        if lsm.memtable_is_empty() {
+            return Ok(());
+        }
+        lsm.flush(&format, &compression_for, self.compaction_limiter.as_ref())?;
+        self.rewrite(format)
+    }
+
+    /// `<log>.index`, where a bounded index keeps the entries it spills
+    fn index_path(&self) -> PathBuf {
+        index_path(&self.path)
+    }
+
+    fn compression_for(&self, value_len: usize, no_compress: bool) -> Compression {
+        if no_compress || value_len < self.compression_min_size {
+            Compression::None
+        } else {
+            self.compression
+        }
+    }
+
+    /// Append a record to the log, syncing according to `sync_mode` unless
+    /// `sync` overrides it. Returns where the record was written
+    fn append(&mut self, record: &Record, compression: Compression, sync: Option<bool>) -> Result<Location> {
+        let frees_space = matches!(record.kind, RecordKind::Delete | RecordKind::RangeDelete);
+        self.append_to_log(record, compression, sync, frees_space)
+    }
+
+    /// `append`; a record that `frees_space` is taken with the disk full,
+    /// and may use the reserve
+    fn append_to_log(&mut self, record: &Record, compression: Compression, sync: Option<bool>, frees_space: bool) -> Result<Location> {
+        self.check_writable()?;
+        if self.disk_full && !frees_space {
+            return Err(Error::DiskFull.into());
+        }
+        let encoded = record.encode_parts(compression, &self.format, std::mem::take(&mut self.encode_buf))?;
+        let len = encoded.len();
+        let location = Location::new(self.pos, len)?;
+
+        // 1. Write to the write buffer if it has room, or to the file
+        // straight from the record
+        if len < self.write_buf_size {
+            if self.write_buf.len() + len > self.write_buf_size {
+                self.flush()?;
+            }
+            for part in encoded.io_slices() {
+                self.write_buf.extend_from_slice(&part);
+            }
+        } else {
+            self.flush()?;
+            let end = self.written_end();
+            self.write_log(end, frees_space, |file| file.append_vectored(&mut encoded.io_slices()))?;
+        }
         self.logical_index += 1;
+        self.pos += len as u64;
+        self.unsynced_bytes += len as u64;
+        self.bytes_written += len as u64;
+        self.encode_buf = encoded.into_buffer();
+        if self.encode_buf.capacity() > MAX_REUSED_BUFFER {
+            self.encode_buf = Vec::new();
+        }
 
         // 2. Determine if we need to sync based on mode
         let should_sync = match self.sync_mode {
@@ -150,42 +1386,2016 @@ impl Engine {
                 self.write_count >= n
             }
             SyncMode::Periodic(d) => self.last_sync.elapsed() >= d,
+            SyncMode::Adaptive { max_latency } => {
+                self.write_count += 1;
+                self.adaptive.put(Instant::now(), self.write_count, max_latency)
+            }
         };
 
-        if should_sync {
+        if sync.unwrap_or(should_sync) {
             self.sync()?;
+        } else {
+            self.syncs_skipped += 1;
+            if !self.pending_syncs.is_empty() {
+                self.reap_syncs()?;
+            }
         }
 
-        // 3. Update in-memory index (even if not yet durable)
-        self.index.insert(key, current_record_pos);
-        self.pos += encoded.len() as u64;
-        
-        Ok(())
+        Ok(location)
     }
 
-    /// Force sync to disk, making all writes up to now durable
-    pub fn sync(&mut self) -> Result<()> {
-        self.file.sync_data()?;
-        self.durable_index = self.logical_index;
-        self.write_count = 0;
-        self.last_sync = Instant::now();
-        self.update_progress_file()?;
-        Ok(())
+    /// Read the current value of a key, as `EngineBuilder::read_consistency`
+    /// says
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_in(0, key, &ReadOptions::default())
     }
 
-    /// Update progress file with current durable index (for crash testing)
-    fn update_progress_file(&mut self) -> Result<()> {
-        if let Some(file) = &mut self.progress_file {
-            file.set_len(0)?;
-            file.seek(SeekFrom::Start(0))?;
-            write!(file, "{}", self.durable_index)?;
-            file.sync_data()?;  // Ensure parent process sees it
-        }
-        Ok(())
+    /// `get`, overriding engine settings for this call
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        self.get_in(0, key, options)
     }
 
-    /// Check if key exists in index
-    pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.index.contains_key(key)
+    /// `get` for each of `keys`, lazily: the iterator reads a key's value
+    /// only when asked for the next item, and yields it with the key, so a
+    /// large set of keys is never held in memory at once and the caller
+    /// can work on each value as it arrives. A key that fails to read
+    /// yields its error and the rest are still read.
+    pub fn get_many<'a, K, I>(&'a self, keys: I) -> impl Iterator<Item = (K, Result<Option<Vec<u8>>>)> + 'a
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+        I::IntoIter: 'a,
+    {
+        self.get_many_in(0, keys)
+    }
+
+    /// `get_many` for keys of namespace `namespace`
+    pub(crate) fn get_many_in<'a, K, I>(&'a self, namespace: u32, keys: I) -> impl Iterator<Item = (K, Result<Option<Vec<u8>>>)> + 'a
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+        I::IntoIter: 'a,
+    {
+        keys.into_iter().map(move |key| {
+            let value = self.get_in(namespace, key.as_ref(), &ReadOptions::default());
+            (key, value)
+        })
+    }
+
+    /// `get_with_options` for a key of namespace `namespace`
+    pub(crate) fn get_in(&self, namespace: u32, key: &[u8], options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        #[cfg(feature = "metrics")]
+        crate::metrics::METRICS.gets.inc();
+        let started = Instant::now();
+        let result = match options.consistency.unwrap_or(self.read_consistency) {
+            ReadConsistency::Acknowledged => self.read_value(namespace, key),
+            ReadConsistency::Durable => self.read_durable_value(namespace, key),
+        };
+        self.finish_op(SlowOpKind::Get, started, key.len());
+        result
     }
-}
\ No newline at end of file
+
+    /// How far into the log the background syncs have made it durable,
+    /// though the engine has not yet seen them finish: 0 if none has
+    fn background_synced_end(&self) -> u64 {
+        let finished = self.pending_syncs.iter().map_while(|sync| sync.outcome().filter(|&succeeded| succeeded).map(|_| sync.pos));
+        finished.last().unwrap_or(0)
+    }
+
+    /// `read_value` as of the last sync
+    fn read_durable_value(&self, namespace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.read_consistency != ReadConsistency::Durable {
+            anyhow::bail!("durable reads need an engine opened with ReadConsistency::Durable");
+        }
+        let unsynced = self.unsynced_keys.get(&*namespace::index_key(namespace, key));
+        let Some(unsynced) = unsynced.filter(|unsynced| unsynced.end > self.background_synced_end()) else {
+            return self.read_value(namespace, key);
+        };
+        if unsynced.expires_at.is_some_and(|at| at <= unix_millis(SystemTime::now())) {
+            return Ok(None);
+        }
+        let Some(location) = unsynced.durable else { return Ok(None) };
+        let record = self.read_record(location)?;
+        if record.key != key || record.namespace != namespace {
+            anyhow::bail!("durable location of a key points at the record for another at offset {}", location.offset);
+        }
+        Ok(Some(self.resolve(record)?))
+    }
+
+    fn read_value(&self, namespace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(lsm) = &self.lsm {
+            return lsm.get(key);
+        }
+        let index_key = namespace::index_key(namespace, key);
+        if self.is_expired(&index_key) {
+            return Ok(None);
+        }
+        if let Some(value) = self.index.inline_value(&index_key) {
+            return Ok(Some(value.to_vec()));
+        }
+        let Some(location) = self.index.get(&index_key)? else { return Ok(None) };
+        if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(location.offset)) {
+            return Ok(Some(value));
+        }
+        let record = self.read_record(location)?;
+        // Entries in an index file are not checksummed, the record is
+        if record.key != key || record.namespace != namespace {
+            anyhow::bail!("index entry for a key points at the record for another at offset {}", location.offset);
+        }
+        let value = self.resolve(record)?;
+        if let Some(cache) = &self.cache {
+            cache.insert(location.offset, &value);
+        }
+        Ok(Some(value))
+    }
+
+    /// All live pairs whose key starts with `prefix`, sorted by key
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_prefix_in(0, prefix)
+    }
+
+    /// `scan_prefix` within namespace `namespace`
+    pub(crate) fn scan_prefix_in(&self, namespace: u32, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if let Some(lsm) = &self.lsm {
+            return lsm
+                .entries_from(prefix, true)
+                .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(prefix)))
+                .collect();
+        }
+        self.namespace_entries(namespace, prefix)?
+            .into_iter()
+            .map(|(index_key, location)| {
+                let value = match self.index.inline_value(&index_key) {
+                    Some(value) => value.to_vec(),
+                    None => self.resolve(self.read_record(location)?)?,
+                };
+                Ok((namespace::split_index_key(&index_key).1.to_vec(), value))
+            })
+            .collect()
+    }
+
+    /// Up to `limit` live pairs, sorted by key, after `cursor` or from the
+    /// first key, and the cursor to pass for the next page: `None` once the
+    /// last key has been returned. A `limit` of 0 is an error. A cursor
+    /// holds only the key it stopped at, so it stays valid across writes,
+    /// compactions and restarts, and a page shows the keys after it as they
+    /// are when it is read: keys put behind the cursor are not seen, keys
+    /// deleted ahead of it are skipped. `Cursor` converts to and from a
+    /// token string for handing to clients. A page reads the index file
+    /// (with `EngineBuilder::index_memory_budget`) from the cursor on, for
+    /// no more entries than it returns, and looks through the in-memory
+    /// index entries once, keeping only the lowest `limit`.
+    pub fn scan_page(&self, cursor: Option<&Cursor>, limit: usize) -> Result<Page> {
+        self.scan_page_in(0, cursor, limit)
+    }
+
+    /// `scan_page` within namespace `namespace`
+    pub(crate) fn scan_page_in(&self, namespace: u32, cursor: Option<&Cursor>, limit: usize) -> Result<Page> {
+        if limit == 0 {
+            anyhow::bail!("scan_page needs a limit of at least 1");
+        }
+        let after = cursor.map(|cursor| cursor.after.as_slice());
+        let mut page = Vec::with_capacity(limit.min(1024));
+        let mut more = false;
+        if let Some(lsm) = &self.lsm {
+            for entry in lsm.entries_from(after.unwrap_or(&[]), true) {
+                let (key, value) = entry?;
+                if Some(key.as_slice()) == after {
+                    continue;
+                }
+                if page.len() == limit {
+                    more = true;
+                    break;
+                }
+                page.push((key, value));
+            }
+        } else {
+            let lower = match after {
+                Some(after) => Bound::Excluded(namespace::index_key(namespace, after)),
+                None => Bound::Included(namespace::index_key(namespace, &[])),
+            };
+            let upper = namespace::index_key_end(namespace);
+            let keep = |index_key: &[u8]| !self.is_expired(index_key);
+            let mut entries = self.index.range_page(lower.as_ref().map(|key| &**key), upper.as_deref(), limit.saturating_add(1), keep)?;
+            more = entries.len() > limit;
+            entries.truncate(limit);
+            for (index_key, location) in &entries {
+                let value = match self.index.inline_value(index_key) {
+                    Some(value) => value.to_vec(),
+                    None => self.resolve(self.read_record(*location)?)?,
+                };
+                page.push((namespace::split_index_key(index_key).1.to_vec(), value));
+            }
+        }
+        let next = match more {
+            true => page.last().map(|(key, _)| Cursor::new(key.clone())),
+            false => None,
+        };
+        Ok((page, next))
+    }
+
+    /// Index entries of the keys of `namespace` that start with `prefix`,
+    /// sorted by key. The default namespace's escaped entries sort after
+    /// its others, as keys starting with the escape byte do.
+    fn namespace_entries(&self, namespace: u32, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Location)>> {
+        let mut entries = self.index.prefix_entries(&namespace::index_key(namespace, prefix))?;
+        entries.retain(|(index_key, _)| {
+            let (key_namespace, key) = namespace::split_index_key(index_key);
+            key_namespace == namespace && key.starts_with(prefix) && !self.is_expired(index_key)
+        });
+        Ok(entries)
+    }
+
+    /// Read and verify the record at `location`, in one positioned read
+    fn read_record(&self, location: Location) -> Result<Record> {
+        let mut buf = vec![0u8; location.len as usize];
+        let written_end = self.written_end();
+        if location.offset >= written_end {
+            let start = (location.offset - written_end) as usize;
+            let buffered = self.write_buf.get(start..start + buf.len()).ok_or_else(|| {
+                anyhow::anyhow!("index entry for offset {} runs past the end of the log", location.offset)
+            })?;
+            buf.copy_from_slice(buffered);
+        } else {
+            match &self.block_cache {
+                Some(cache) => cache.read_at(&*self.file, &mut buf, location.offset, written_end)?,
+                None => self.file.read_exact_at(&mut buf, location.offset)?,
+            }
+        }
+        let (record, size) = Record::decode_with(&buf, &self.format)?;
+        if size != buf.len() {
+            anyhow::bail!("index entry for offset {} has the wrong record length", location.offset);
+        }
+        Ok(record)
+    }
+
+    /// The value of a put record, fetched from the value log if the record
+    /// only points there
+    fn resolve(&self, record: Record) -> Result<Vec<u8>> {
+        if record.kind != RecordKind::ValuePointer {
+            return Ok(record.value);
+        }
+        let pointer = ValuePointer::decode(&record.value)?;
+        match &self.value_log {
+            Some(value_log) => value_log.read(pointer, &record.key),
+            None => anyhow::bail!("record points into a value log, but there is none"),
+        }
+    }
+
+    /// Live keys, in no particular order. With a bounded index this reads
+    /// the spilled part from disk.
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        if let Some(lsm) = &self.lsm {
+            return lsm.entries_from(&[], false).map(|entry| Ok(entry?.0)).collect();
+        }
+        let mut keys = Vec::new();
+        for entry in self.index.entries() {
+            let index_key = entry?.0;
+            if let (0, key) = namespace::split_index_key(&index_key) {
+                if !self.is_expired(&index_key) {
+                    keys.push(key.to_vec());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Live keys of namespace `namespace`, sorted
+    pub(crate) fn keys_in(&self, namespace: u32) -> Result<Vec<Vec<u8>>> {
+        let (start, end) = (namespace::index_key(namespace, &[]), namespace::index_key_end(namespace));
+        let keep = |index_key: &[u8]| !self.is_expired(index_key);
+        let entries = self.index.range_page(Bound::Included(&start), end.as_deref(), usize::MAX, keep)?;
+        Ok(entries.into_iter().map(|(index_key, _)| namespace::split_index_key(&index_key).1.to_vec()).collect())
+    }
+
+    /// Keys and bytes of `namespace`, counted as the index goes by rather
+    /// than collected
+    pub(crate) fn namespace_stats(&self, namespace: u32) -> Result<NamespaceStats> {
+        let (start, end) = (namespace::index_key(namespace, &[]), namespace::index_key_end(namespace));
+        let mut stats = NamespaceStats::default();
+        for entry in self.index.range_entries(Bound::Included(&start), end.as_deref(), |index_key| !self.is_expired(index_key)) {
+            stats.keys += 1;
+            stats.live_bytes += entry?.1.len as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Number of live keys, in every namespace. The catalog entry naming
+    /// each namespace counts as a key too, so a store with one namespace
+    /// holding 2 keys and 1 default-namespace key has a `len()` of 4.
+    /// Expired keys count until `compact()` drops them or they are written
+    /// again.
+    pub fn len(&self) -> usize {
+        match &self.lsm {
+            Some(lsm) => lsm.len(),
+            None => self.index.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records appended to the log, including overwritten ones and
+    /// tombstones. After an open that used an index file, only those the
+    /// open read are counted.
+    pub fn log_records(&self) -> usize {
+        self.logical_index
+    }
+
+    /// Bytes written since the last sync, which a crash could lose
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.unsynced_bytes
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hand the writes held in the write buffer to the OS, without an
+    /// fsync. They then survive the process crashing or being killed, but
+    /// not an OS crash or power loss; only `sync()` covers those. Without
+    /// `EngineBuilder::write_buffer` every write is already with the OS
+    /// when `put()` returns, and this does nothing.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.write_buf.is_empty() {
+            self.check_writable()?;
+            // Acknowledged writes, which may use the reserve
+            let end = self.written_end();
+            let buf = std::mem::take(&mut self.write_buf);
+            let written = self.write_log(end, true, |file| file.append(&buf));
+            self.write_buf = buf;
+            written?;
+            self.write_buf.clear();
+        }
+        Ok(())
+    }
+
+    /// Force sync to disk, making all writes up to now durable, flushing
+    /// them first
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(unsynced_bytes = self.unsynced_bytes)))]
+    pub fn sync(&mut self) -> Result<()> {
+        let started = Instant::now();
+        let result = self.sync_files();
+        self.finish_op(SlowOpKind::Sync, started, 0);
+        result
+    }
+
+    /// Shut down as dropping the engine does, but returning the first
+    /// error rather than ignoring it, for shutdown hooks that must fail
+    /// when writes may be lost: write out the write buffer, sync the log
+    /// and value log, snapshot the index (unless `index_snapshot(false)`
+    /// or already up to date), then stop the background threads and close
+    /// the files. After an error the engine is still dropped, and writes
+    /// since the last successful sync may not be durable. mini-kv takes no
+    /// lock on its files, so there is none to release.
+    pub fn close(mut self) -> Result<()> {
+        self.sync()?;
+        if self.snapshot_index && self.index_file_end != Some(self.pos) {
+            self.spill_index(self.pos)?;
+        }
+        Ok(())
+    }
+
+    fn sync_files(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.flush()?;
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::METRICS.fsync_seconds.start_timer();
+        // Values first, so a durable pointer never outlives its value
+        let started = Instant::now();
+        if let Some(value_log) = &mut self.value_log {
+            let synced = value_log.sync();
+            self.poison_on_error(synced)?;
+        }
+        let synced = self.file.sync();
+        self.poison_on_error(synced)?;
+        self.adaptive.synced(Some(started.elapsed()));
+        self.fsyncs += 1;
+        if let Some(scrubber) = &self.scrubber {
+            scrubber.set_end(self.pos);
+        }
+        self.durable_index = self.logical_index;
+        // That covers every background sync still running
+        self.pending_syncs.clear();
+        self.unsynced_keys.clear();
+        self.write_count = 0;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+        self.synced_to(self.pos)?;
+        Ok(())
+    }
+
+    /// Start making all writes up to now durable, flushing them first, and
+    /// return without waiting for the fsync, which runs on a background
+    /// thread. A writer can go on with other work, or more puts, and wait
+    /// on the handle when it needs them durable. For the sync mode this is
+    /// a sync as soon as it is started: the batch or period starts over.
+    ///
+    /// The value log, if any, is still synced before this returns, so a
+    /// durable pointer never outlives its value. On storage whose files
+    /// cannot be synced from another thread (see
+    /// `StorageFile::try_clone`) the sync happens in place, and the handle
+    /// comes back done.
+    pub fn sync_async(&mut self) -> Result<SyncHandle> {
+        self.check_writable()?;
+        self.flush()?;
+        let Some(file) = self.file.try_clone()? else {
+            self.sync()?;
+            return Ok(SyncHandle::finished(Ok(())));
+        };
+        if let Some(value_log) = &mut self.value_log {
+            let synced = value_log.sync();
+            self.poison_on_error(synced)?;
+        }
+        if self.syncer.is_none() {
+            self.syncer = Some(Syncer::start()?);
+        }
+        let syncer = self.syncer.as_ref().expect("started above");
+        let (handle, pending) = syncer.queue(file, self.logical_index, self.pos);
+        self.pending_syncs.push(pending);
+        self.adaptive.synced(None);
+        self.fsyncs += 1;
+        self.write_count = 0;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+        Ok(handle)
+    }
+
+    /// Count the writes of the background syncs that have finished as
+    /// durable; a failed one covers nothing, and it is up to its handle's
+    /// holder to sync again
+    fn reap_syncs(&mut self) -> Result<()> {
+        while let Some(succeeded) = self.pending_syncs.first().and_then(PendingSync::outcome) {
+            let sync = self.pending_syncs.remove(0);
+            if !succeeded {
+                self.poisoned.get_or_insert_with(|| BACKGROUND_SYNC_FAILED.to_string());
+            }
+            if succeeded && sync.logical_index > self.durable_index {
+                self.durable_index = sync.logical_index;
+                self.unsynced_keys.retain(|_, unsynced| unsynced.end > sync.pos);
+                if let Some(scrubber) = &self.scrubber {
+                    scrubber.set_end(sync.pos);
+                }
+                self.synced_to(sync.pos)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Time the call begun at `started` into its latency histogram, and
+    /// report it if it took longer than `EngineBuilder::slow_op_threshold`
+    fn finish_op(&self, kind: SlowOpKind, started: Instant, key_len: usize) {
+        let histogram = match kind {
+            SlowOpKind::Put => &self.put_latency,
+            SlowOpKind::Get => &self.get_latency,
+            SlowOpKind::Sync => &self.sync_latency,
+        };
+        histogram.record(started.elapsed());
+        if let Some(slow_ops) = &self.slow_ops {
+            slow_ops.check(kind, started, key_len, self.sync_mode);
+        }
+    }
+
+    /// Fail with `Error::EnginePoisoned` once a write or sync has failed,
+    /// background syncs that finished included
+    fn check_writable(&mut self) -> Result<()> {
+        if !self.pending_syncs.is_empty() {
+            self.reap_syncs()?;
+        }
+        match &self.poisoned {
+            Some(reason) => Err(Error::EnginePoisoned { reason: reason.clone() }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// `result` of writing to or syncing the log or value log; an error
+    /// poisons the engine, as the disk may now hold any part of the write
+    /// and a failed fsync may have dropped writes it was to make durable
+    fn poison_on_error<T, E: Into<anyhow::Error>>(&mut self, result: Result<T, E>) -> Result<T> {
+        result.map_err(|e| {
+            let e = e.into();
+            self.poisoned.get_or_insert_with(|| format!("{:#}", e));
+            e
+        })
+    }
+
+    /// Write to the end of the log, at `end` in the file, with `write`. On
+    /// a full disk what it wrote is cut off again, and it fails with `Error::DiskFull` after
+    /// one more try in the space of the reserve if `use_reserve`; any other
+    /// error poisons the engine
+    fn write_log(&mut self, end: u64, use_reserve: bool, mut write: impl FnMut(&mut dyn StorageFile) -> io::Result<()>) -> Result<()> {
+        let mut written = write(&mut *self.file);
+        if use_reserve && is_storage_full(&written) && self.reserve_held() {
+            self.cut_log(end)?;
+            self.release_reserve()?;
+            written = write(&mut *self.file);
+        }
+        if is_storage_full(&written) {
+            self.cut_log(end)?;
+            return Err(Error::DiskFull.into());
+        }
+        self.poison_on_error(written)
+    }
+
+    /// Cut the log file back to `end`, past which a failed write left part
+    /// of its bytes
+    fn cut_log(&mut self, end: u64) -> Result<()> {
+        let cut = self.file.set_len(end);
+        self.poison_on_error(cut)
+    }
+
+    /// Fail with `Error::QuotaExceeded` unless `len` more bytes fit in
+    /// `EngineBuilder::max_size`, compacting first if the log's dead
+    /// records are what stands in the way
+    fn make_room(&mut self, len: u64) -> Result<()> {
+        let Some(max) = self.max_size else { return Ok(()) };
+        if self.pos + self.counted_besides_log() + len <= max {
+            return Ok(());
+        }
+        if self.compact_at_max_size && self.log_start + self.index.live_bytes() + self.counted_besides_log() + len <= max {
+            #[cfg(feature = "tracing")]
+            tracing::info!(path = %self.path.display(), max, "compacting to stay within max_size");
+            self.rewrite(self.rewrite_format())?;
+        }
+        let len = self.pos + self.counted_besides_log() + len;
+        if len > max {
+            return Err(Error::QuotaExceeded { len, max }.into());
+        }
+        Ok(())
+    }
+
+    /// Bytes counted against `EngineBuilder::max_size` besides the log:
+    /// the value log's local segments and the reserve
+    fn counted_besides_log(&self) -> u64 {
+        let value_log = self.value_log.as_ref().map_or(0, ValueLog::local_size);
+        value_log + if self.reserve_held() { self.disk_reserve } else { 0 }
+    }
+
+    fn reserve_held(&self) -> bool {
+        self.disk_reserve > 0 && !self.disk_full
+    }
+
+    /// Delete the reserve, for a full disk, and refuse puts until it is
+    /// written again
+    fn release_reserve(&mut self) -> Result<()> {
+        match self.storage.remove(&reserve_path(&self.path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.disk_full = true;
+        #[cfg(feature = "tracing")]
+        tracing::warn!(path = %self.path.display(), "disk full; reserve released, puts refused until compaction");
+        Ok(())
+    }
+
+    /// Write the reserve out to `disk_reserve` bytes, leaving the disk full
+    /// if there is no room for it yet; remove it if there should be none
+    fn fill_reserve(&mut self) -> Result<()> {
+        let path = reserve_path(&self.path);
+        if self.disk_reserve == 0 {
+            if self.storage.exists(&path) {
+                self.storage.remove(&path)?;
+            }
+            return Ok(());
+        }
+        match write_reserve(&self.storage, &path, self.disk_reserve) {
+            Ok(()) => self.disk_full = false,
+            Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+                let _ = self.storage.remove(&path);
+                self.disk_full = true;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// Whether puts fail with `Error::DiskFull` for now: the disk filled
+    /// up, and the reserve of `EngineBuilder::disk_reserve` was given up to
+    /// deletes and compaction. A compaction that leaves room for the
+    /// reserve again, or reopening, ends it.
+    pub fn is_disk_full(&self) -> bool {
+        self.disk_full
+    }
+
+    /// Why writes fail with `Error::EnginePoisoned`, once a write or sync
+    /// to the log or value log has failed: the first such error. Reads
+    /// still work, from what the engine held when it failed.
+    pub fn poisoned(&self) -> Option<&str> {
+        // A background sync that failed since the last write poisons the
+        // engine as soon as the write sees it
+        let failed = || self.pending_syncs.iter().any(|sync| sync.outcome() == Some(false)).then_some(BACKGROUND_SYNC_FAILED);
+        self.poisoned.as_deref().or_else(failed)
+    }
+
+    /// End of the log in the file, short of `pos` by the write buffer
+    fn written_end(&self) -> u64 {
+        self.pos - self.write_buf.len() as u64
+    }
+
+    /// Record that the log is durable up to `synced_end`, for tailers and
+    /// the crash test harness
+    fn synced_to(&mut self, synced_end: u64) -> Result<()> {
+        self.publish_synced_end(synced_end)?;
+        self.update_progress_file(synced_end)
+    }
+
+    /// Write `synced_end` to `<log>.synced`. Not synced itself: after a
+    /// crash it can only be behind the log, never ahead of it.
+    fn publish_synced_end(&mut self, synced_end: u64) -> Result<()> {
+        if let Some(file) = &mut self.synced_file {
+            file.write_at(&tail::encode_synced_end(synced_end), 0)?;
+        }
+        Ok(())
+    }
+
+    /// Update progress file with current durable index and `synced_end`,
+    /// the end of the synced log (for crash testing)
+    fn update_progress_file(&mut self, synced_end: u64) -> Result<()> {
+        if let Some(file) = &mut self.progress_file {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{} {}", self.durable_index, synced_end)?;
+            file.sync_data()?;  // Ensure parent process sees it
+        }
+        Ok(())
+    }
+
+    /// Check if key exists in index
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.contains_key_in(0, key)
+    }
+
+    pub(crate) fn contains_key_in(&self, namespace: u32, key: &[u8]) -> Result<bool> {
+        if let Some(lsm) = &self.lsm {
+            return Ok(lsm.get(key)?.is_some());
+        }
+        let index_key = namespace::index_key(namespace, key);
+        Ok(!self.is_expired(&index_key) && self.index.get(&index_key)?.is_some())
+    }
+
+    /// Train a zstd dictionary of at most `max_size` bytes on a sample of
+    /// the live values, then rewrite the log with the dictionary in its
+    /// file header and every value recompressed against it; later puts use
+    /// it too. Pays off for many small, similar values (e.g. JSON blobs)
+    /// that barely compress one at a time.
+    ///
+    /// Requires `Compression::Zstd`. Rewrites the whole file, dropping
+    /// overwritten values and tombstones along the way.
+    #[cfg(feature = "compression-zstd")]
+    pub fn train_dictionary(&mut self, max_size: usize) -> Result<()> {
+        use anyhow::Context;
+
+        if !matches!(self.compression, Compression::Zstd { .. }) {
+            anyhow::bail!("train_dictionary needs an engine opened with Compression::Zstd");
+        }
+        if self.lsm.is_some() {
+            anyhow::bail!("train_dictionary is not supported with EngineKind::Lsm");
+        }
+        // zstd suggests about 100x the dictionary size in samples; the
+        // index's hash order makes this a cheap random sample
+        let budget = max_size.saturating_mul(100);
+        let mut samples = Vec::new();
+        let mut sampled = 0;
+        for entry in self.index.entries() {
+            let (_, location) = entry?;
+            if sampled >= budget {
+                break;
+            }
+            // Values in the value log are not compressed with the dictionary
+            let record = self.read_record(location)?;
+            if record.kind == RecordKind::ValuePointer {
+                continue;
+            }
+            sampled += record.value.len();
+            samples.push(record.value);
+        }
+        let dictionary = zstd::dict::from_samples(&samples, max_size)
+            .with_context(|| format!("training a zstd dictionary on {} values", samples.len()))?;
+
+        let mut format = self.rewrite_format();
+        format.set_dictionary(dictionary);
+        self.rewrite(format)
+    }
+
+    /// Encrypt new records with `key` from now on, returning its version
+    /// (one above the current newest, or 0 if the engine had no key).
+    /// Records already written keep their old key until `compact()`
+    /// re-encrypts them, and values in the value log until
+    /// `gc_value_log()` moves them, so reopen with every version until then.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_key(&mut self, key: [u8; 32]) -> Result<u32> {
+        let id = match self.format.encryption_key_id() {
+            Some(newest) => newest.checked_add(1).ok_or_else(|| anyhow::anyhow!("key versions exhausted"))?,
+            None => 0,
+        };
+        self.format.add_encryption_key(id, &key);
+        if let Some(value_log) = &mut self.value_log {
+            value_log.add_encryption_key(id, &key);
+        }
+        Ok(id)
+    }
+
+    /// Rewrite the log with only the live records, dropping overwritten
+    /// values and tombstones. Each record is re-encoded with the current
+    /// compression and newest encryption key, so afterwards that key alone
+    /// opens the file, and with the checksum chosen at open.
+    ///
+    /// Values in the value log stay where they are, only their pointers
+    /// are copied; `gc_value_log()` reclaims space there.
+    ///
+    /// With `EngineKind::Lsm` this flushes the memtable and merges every
+    /// table into one instead.
+    pub fn compact(&mut self) -> Result<()> {
+        if self.lsm.is_none() {
+            return self.rewrite(self.rewrite_format());
+        }
+        self.flush_memtable()?;
+        let format = self.rewrite_format();
+        let (compression, min_size) = (self.compression, self.compression_min_size);
+        let compression_for = |len: usize| if len < min_size { Compression::None } else { compression };
+        if let Some(lsm) = &mut self.lsm {
+            lsm.compact(&format, &compression_for, self.compaction_limiter.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Reclaim space in the value log. Each segment in which overwritten
+    /// and deleted values take up at least `min_garbage` (0.0 to 1.0) of
+    /// the file has its live values moved, which appends them to the newest
+    /// segment and new pointers to the log, and is deleted once
+    /// both are synced. The segment being appended to is sealed first if
+    /// it qualifies. With `EngineBuilder::cold_dir`, the sealed segments
+    /// that do not qualify move to the cold directory. Does nothing without
+    /// a value log.
+    ///
+    /// Segments are read whole and every value in them is looked up, so
+    /// this costs about a read of the value log.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip(self)))]
+    pub fn gc_value_log(&mut self, min_garbage: f64) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        let Some(value_log) = &self.value_log else { return Ok(report) };
+        let newest = value_log.newest_id();
+        for id in value_log.segment_ids() {
+            let Some(value_log) = &self.value_log else { break };
+            let Some(values) = value_log.values(id)? else { continue };
+            let size = value_log.segment_len(id);
+            report.segments_checked += 1;
+            let mut live = Vec::new();
+            let mut live_bytes = 0;
+            for (record, pointer) in values {
+                if self.points_to(record.namespace, &record.key, pointer)? {
+                    live_bytes += pointer.len as u64;
+                    live.push(record);
+                }
+            }
+            let empty = size == value_log.segment_start(id);
+            if (id == newest && empty) || ((size - live_bytes) as f64) < min_garbage * size as f64 {
+                // Few of its values were overwritten, so it is likely to
+                // stay as it is
+                if id != newest {
+                    if let Some(value_log) = &mut self.value_log {
+                        if value_log.move_cold(id)? {
+                            report.segments_moved_cold += 1;
+                        }
+                    }
+                }
+                continue;
+            }
+            if id == newest {
+                if let Some(value_log) = &mut self.value_log {
+                    value_log.roll()?;
+                }
+            }
+            report.values_moved += live.len();
+            for record in live {
+                self.move_value(record)?;
+            }
+            self.sync()?;
+            if let Some(value_log) = &mut self.value_log {
+                value_log.remove(id)?;
+            }
+            report.segments_removed += 1;
+            report.bytes_reclaimed += size - live_bytes;
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(?report, "value log collected");
+        Ok(report)
+    }
+
+    /// Append the value of `record` to the newest value log segment and a
+    /// pointer to it to the log, keeping the key's expiry time, for
+    /// `gc_value_log`. The value is unchanged, so unlike a put this is not
+    /// told to watchers or counted as one, and moving it is what frees
+    /// space, so it is neither throttled nor held to `max_size` and may
+    /// use the reserve.
+    fn move_value(&mut self, record: Record) -> Result<()> {
+        self.check_writable()?;
+        let namespace = record.namespace;
+        let index_key = namespace::index_key(namespace, &record.key).into_owned();
+        let expires_at = self.index.expires_at(&index_key);
+        let before = self.before_write(&index_key, Some(false))?;
+        let compression = self.compression_for(record.value.len(), false);
+        let Some(value_log) = &mut self.value_log else { return Ok(()) };
+        let pointer = match value_log.append(&record, compression) {
+            Err(e) if is_disk_full(&e) => return Err(Error::DiskFull.into()),
+            pointer => self.poison_on_error(pointer)?,
+        };
+        self.unsynced_bytes += pointer.len as u64;
+        self.bytes_written += pointer.len as u64;
+        let record = Record::value_pointer(record.key, pointer.encode()).in_namespace(namespace).with_expiry(expires_at);
+        let location = self.append_to_log(&record, compression, Some(false), true)?;
+        if let Some(lsm) = &mut self.lsm {
+            lsm.apply(record.key, Some(record.value))?;
+        } else {
+            self.after_write(&index_key, before);
+            self.index.insert(index_key, location, None, record.expires_at)?;
+        }
+        match self.lsm {
+            Some(_) => self.flush_if_full(),
+            None => self.spill_if_over_budget(),
+        }
+    }
+
+    /// Whether the live record of `key` points at `pointer`
+    fn points_to(&self, namespace: u32, key: &[u8], pointer: ValuePointer) -> Result<bool> {
+        let index_key = match &self.lsm {
+            Some(_) => Cow::Borrowed(key),
+            None => namespace::index_key(namespace, key),
+        };
+        let Some(location) = self.index.get(&index_key)? else { return Ok(false) };
+        let record = self.read_record(location)?;
+        Ok(record.kind == RecordKind::ValuePointer && ValuePointer::decode(&record.value)? == pointer)
+    }
+
+    /// The current format with the checksum requested at open
+    fn rewrite_format(&self) -> Format {
+        let mut format = self.format.clone();
+        if let Some(checksum) = self.checksum {
+            format.set_checksum(checksum);
+        }
+        format
+    }
+
+    /// Write the live records to a new file in `format` and atomically
+    /// replace the log with it
+    #[cfg_attr(not(feature = "compression-zstd"), allow(unused_mut))]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(path = %self.path.display())))]
+    fn rewrite(&mut self, mut format: Format) -> Result<()> {
+        #[cfg(feature = "compression-zstd")]
+        if let Compression::Zstd { level } = self.compression {
+            format.prepare_encoder(level);
+        }
+        self.check_writable()?;
+        self.flush()?;
+        // The new log is durable once written, so the values its pointers
+        // refer to must be too
+        if let Some(value_log) = &mut self.value_log {
+            let synced = value_log.sync();
+            self.poison_on_error(synced)?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".rewrite");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut written = self.write_live_records(&tmp_path, &mut format);
+        // Compaction is what frees space, so it may use the reserve
+        if written.as_ref().is_err_and(is_disk_full) && self.reserve_held() {
+            let _ = self.storage.remove(&tmp_path);
+            self.release_reserve()?;
+            written = self.write_live_records(&tmp_path, &mut format);
+        }
+        let (mut index, log_start, pos) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = self.storage.remove(&tmp_path);
+                return Err(e);
+            }
+        };
+        // An index file left over from the old log fails its log check on
+        // the next open, but removing it saves the work
+        let _ = self.storage.remove(&self.index_path());
+        {
+            let mut scrub_end = self.scrubber.as_ref().map(Scrubber::lock_end);
+            self.storage.rename(&tmp_path, &self.path)?;
+            if let Some(end) = scrub_end.as_deref_mut() {
+                *end = pos;
+            }
+        }
+        index.persist(&self.storage, &self.index_path())?;
+        self.storage.sync_dir(&self.path)?;
+        self.index_file_end = index.bounded().then_some(pos);
+
+        self.file = self.storage.open(&self.path, false)?;
+        self.index = index;
+        // Cached values and blocks are keyed by offsets in the old file
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        if let Some(cache) = &self.block_cache {
+            cache.clear();
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(bytes_before = self.pos, bytes_after = pos, records = self.index.len(), "log rewritten");
+        self.pos = pos;
+        self.format = format;
+        self.log_start = log_start;
+        self.logical_index = self.index.len();
+        self.durable_index = self.logical_index;
+        // Syncs still running cover the old file, which is gone
+        self.pending_syncs.clear();
+        self.unsynced_keys.clear();
+        self.adaptive.synced(None);
+        self.write_count = 0;
+        self.unsynced_bytes = 0;
+        self.synced_to(self.pos)?;
+        if self.disk_full {
+            self.fill_reserve()?;
+        }
+        Ok(())
+    }
+
+    /// Write a header for `format` and every live record to a fresh file
+    /// at `path`, seal it with a footer, fsync it, and return the new
+    /// index, the offset of the first record and the end offset.
+    /// `format` is updated to point at the footer.
+    ///
+    /// A bounded index gets an index file for the new log, built as the
+    /// records are copied and left for `Index::persist` to put in place.
+    fn write_live_records(&self, path: &Path, format: &mut Format) -> Result<(Index, u64, u64)> {
+        let mut file = self.storage.open(path, true)?;
+        file.set_len(0)?;
+        let mut out = Appender::new(file);
+
+        // Placeholder footer offset, so the header has its final length
+        format.set_footer_offset(Some(0));
+        let header = format.encode_header();
+        out.write_all(&header)?;
+        let mut pos = header.len() as u64;
+        let mut digest = segment::digest();
+
+        let mut record_count = 0;
+        let mut encoded = Vec::new();
+        let mut copy = |location: Location| -> Result<(Location, Record)> {
+            let record = self.read_record(location)?;
+            record.encode_into(self.compression_for(record.value.len(), false), format, &mut encoded)?;
+            if let Some(limiter) = &self.compaction_limiter {
+                limiter.acquire(encoded.len() as u64);
+            }
+            #[cfg(feature = "metrics")]
+            crate::metrics::METRICS.compaction_bytes.inc_by(encoded.len() as u64);
+            out.write_all(&encoded)?;
+            digest.update(&encoded);
+            record_count += 1;
+            let written = Location::new(pos, encoded.len())?;
+            pos += encoded.len() as u64;
+            Ok((written, record))
+        };
+        let mut index = self.index.fresh();
+        let mut index_writer = None;
+        if self.index.bounded() {
+            // Copy in key order, so the new index file can be written as
+            // the records are, without holding the index in memory
+            let mut writer = IndexWriter::create(&self.storage, &self.index_path(), self.index.len())?;
+            for entry in self.index.sorted_entries() {
+                let (key, location) = entry?;
+                if self.is_expired(&key) {
+                    continue;
+                }
+                writer.add(&key, copy(location)?.0)?;
+                if let Some(expires_at) = self.index.expires_at(&key) {
+                    writer.add_expiry(&key, expires_at);
+                }
+            }
+            index_writer = Some(writer);
+        } else {
+            // Copy in file order so the old log is read sequentially
+            let mut live = self.index.entries().collect::<Result<Vec<_>>>()?;
+            live.sort_unstable_by_key(|&(_, location)| location.offset);
+            for (key, location) in live {
+                if self.is_expired(&key) {
+                    continue;
+                }
+                let (written, record) = copy(location)?;
+                let inline = (record.kind == RecordKind::Put).then_some(record.value.as_slice());
+                index.insert(key, written, inline, record.expires_at)?;
+            }
+        }
+
+        let footer = SegmentFooter {
+            record_count,
+            byte_count: pos - header.len() as u64,
+            checksum: digest.finish(),
+        };
+        out.write_all(&footer.encode())?;
+        let mut out = Appender::finish(out)?;
+        format.set_footer_offset(Some(pos));
+        out.write_at(&format.encode_header(), 0)?;
+        out.sync()?;
+
+        let end = pos + FOOTER_SIZE as u64;
+        if let Some(writer) = index_writer {
+            let tag = index::log_tag(&*out, header.len() as u64, end)?;
+            index.set_file(writer.finish_unplaced(&self.storage, end, tag)?);
+        }
+        Ok((index, header.len() as u64, end))
+    }
+
+    /// Hits, misses and size of the value cache, if
+    /// `EngineBuilder::value_cache` enabled it
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(ValueCache::stats)
+    }
+
+    /// Key count, space use and I/O counters, for capacity planning; read
+    /// from counters kept up to date, so cheap to call often
+    pub fn stats(&self) -> EngineStats {
+        let live_bytes = self.lsm.is_none().then(|| self.index.live_bytes());
+        EngineStats {
+            keys: self.len(),
+            live_bytes,
+            dead_bytes: live_bytes.map(|live| (self.pos - self.log_start).saturating_sub(live)),
+            file_size: self.pos,
+            fsyncs: self.fsyncs,
+            bytes_written: self.bytes_written,
+            syncs_skipped: self.syncs_skipped,
+            sync_batch: match self.sync_mode {
+                SyncMode::Adaptive { max_latency } => Some(self.adaptive.batch(max_latency)),
+                _ => None,
+            },
+            cache: self.cache_stats(),
+            block_cache: self.block_cache_stats(),
+            put_latency: self.put_latency.snapshot(),
+            get_latency: self.get_latency.snapshot(),
+            sync_latency: self.sync_latency.snapshot(),
+        }
+    }
+
+    /// Hits and misses (counted per block) and size of the block cache, if
+    /// `EngineBuilder::block_cache` enabled it
+    pub fn block_cache_stats(&self) -> Option<CacheStats> {
+        self.block_cache.as_ref().map(BlockCache::stats)
+    }
+
+    /// Table files of an `EngineKind::Lsm` engine, in lookup order; none
+    /// for `EngineKind::Log`
+    pub fn tables(&self) -> Vec<TableInfo> {
+        self.lsm.as_ref().map(Lsm::tables).unwrap_or_default()
+    }
+
+    /// The tables of `tables()` that `EngineBuilder::archive` archived
+    pub fn archived_tables(&self) -> Vec<TableInfo> {
+        self.lsm.as_ref().map(Lsm::archived_tables).unwrap_or_default()
+    }
+
+    /// Compactions run by an `EngineKind::Lsm` engine since it opened
+    pub fn compaction_stats(&self) -> Option<CompactionStats> {
+        self.lsm.as_ref().map(Lsm::stats)
+    }
+
+    /// Findings of the background scrubber, if `EngineBuilder::scrub`
+    /// enabled it
+    pub fn scrub_report(&self) -> Option<ScrubReport> {
+        self.scrubber.as_ref().map(Scrubber::report)
+    }
+
+    /// Re-read the whole log, check every record's length and CRC, and make
+    /// sure each index entry points at a valid record for its key. With
+    /// `EngineKind::Lsm` every table record is checked too.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut buf = vec![0u8; self.written_end() as usize];
+        self.file.read_exact_at(&mut buf, 0)?;
+        buf.extend_from_slice(&self.write_buf);
+
+        let mut report = VerifyReport { bytes_checked: buf.len() as u64, ..Default::default() };
+        let footer_offset = self.format.footer_offset();
+        let mut sealed_records = 0;
+        for item in Scanner::with_format(&buf, self.log_start as usize, &self.format) {
+            match item {
+                ScanItem::Valid { offset, .. } | ScanItem::Unreadable { offset, .. } => {
+                    report.records_checked += 1;
+                    if footer_offset.is_some_and(|footer| offset < footer) {
+                        sealed_records += 1;
+                    }
+                }
+                ScanItem::Corrupt { offset, .. } => report.bad_records.push(offset),
+            }
+        }
+
+        if let Some(offset) = footer_offset {
+            let sealed = buf.get(self.log_start as usize..offset as usize).unwrap_or_default();
+            report.sealed_ok = Some(
+                buf.get(offset as usize..)
+                    .and_then(|rest| SegmentFooter::decode(rest).ok())
+                    .is_some_and(|footer| footer.record_count == sealed_records && footer.matches(sealed)),
+            );
+        }
+
+        for entry in self.index.entries() {
+            let (key, location) = entry?;
+            let valid = buf
+                .get(location.offset as usize..)
+                .and_then(|rest| match RecordRef::decode_with(rest, &self.format) {
+                    Ok((record, _)) => Some((namespace::index_key(record.namespace, record.key) == key.as_slice(), record.kind)),
+                    Err(_) if !Record::is_plain(rest) => {
+                        let (namespace, record_key, kind, _) = Record::try_decode_key(rest, &self.format).ok()??;
+                        Some((namespace::index_key(namespace, &record_key) == key.as_slice(), kind))
+                    }
+                    Err(_) => None,
+                })
+                .is_some_and(|(same_key, kind)| {
+                    same_key
+                        && match kind {
+                            RecordKind::Put => true,
+                            // The value has to read back from the value log too
+                            RecordKind::ValuePointer => self.read_record(location).and_then(|r| self.resolve(r)).is_ok(),
+                            RecordKind::Delete | RecordKind::RangeDelete => false,
+                        }
+                });
+            if !valid {
+                report.dangling_index_entries.push(key);
+            }
+        }
+
+        if let Some(lsm) = &self.lsm {
+            let (checked, bad_tables) = lsm.verify();
+            report.records_checked += checked;
+            report.bad_tables = bad_tables;
+        }
+        Ok(report)
+    }
+
+    /// Walk the log and measure how much of it live keys use and how old
+    /// the rest is, to tell whether `compact()` is worth its I/O. Reads
+    /// the whole log and looks up every record's key. Only for `EngineKind::Log`; a value log is not included.
+    pub fn space_report(&self) -> Result<SpaceReport> {
+        if self.lsm.is_some() {
+            anyhow::bail!("space_report() covers EngineKind::Log; see tables() for EngineKind::Lsm");
+        }
+        let mut buf = vec![0u8; self.written_end() as usize];
+        self.file.read_exact_at(&mut buf, 0)?;
+        buf.extend_from_slice(&self.write_buf);
+
+        let file_bytes = buf.len() as u64;
+        let span = file_bytes.saturating_sub(self.log_start).div_ceil(SPACE_BANDS).max(1);
+        let mut report = SpaceReport {
+            file_bytes,
+            bands: (0..SPACE_BANDS)
+                .map(|i| SpaceBand {
+                    start: (self.log_start + i * span).min(file_bytes),
+                    end: (self.log_start + (i + 1) * span).min(file_bytes),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let is_live = |namespace: u32, key: &[u8], offset: u64| -> Result<bool> {
+            let location = self.index.get(&namespace::index_key(namespace, key))?;
+            Ok(location.is_some_and(|location| location.offset == offset))
+        };
+        for item in Scanner::with_format(&buf, self.log_start as usize, &self.format) {
+            let (offset, size, live) = match item {
+                ScanItem::Valid { offset, size, record } => (offset, size, is_live(record.namespace, &record.key, offset)?),
+                ScanItem::Unreadable { offset, size, key: Some(key), namespace, .. } => {
+                    (offset, size, is_live(namespace, &key, offset)?)
+                }
+                // No key to look up; counted live, as compaction may keep it
+                ScanItem::Unreadable { offset, size, key: None, .. } => (offset, size, true),
+                ScanItem::Corrupt { offset, len, .. } => (offset, len, false),
+            };
+            let band = &mut report.bands[((offset - self.log_start) / span) as usize];
+            if live {
+                report.live_bytes += size as u64;
+                report.live_records += 1;
+                band.live_bytes += size as u64;
+            } else {
+                report.dead_bytes += size as u64;
+                report.dead_records += 1;
+                band.dead_bytes += size as u64;
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<S: Storage> Drop for Engine<S> {
+    /// A clean shutdown writes out the write buffer and snapshots the
+    /// index, so the next open only scans the records written after it.
+    /// Failures are ignored: unsynced writes may be lost on any crash, and
+    /// a missing snapshot just costs that open a full scan. `close()`
+    /// reports them instead.
+    fn drop(&mut self) {
+        let _ = self.flush();
+        if self.snapshot_index && self.index_file_end != Some(self.pos) {
+            let _ = self.sync().and_then(|()| self.spill_index(self.pos));
+        }
+    }
+}
+
+/// `time` in milliseconds since the Unix epoch, as records store expiry
+/// times; 0 before the epoch
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The first key after every key starting with `prefix`, if there is one
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+/// `<log>.index`, for `Engine::index_path`
+fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_os_string();
+    index.push(".index");
+    PathBuf::from(index)
+}
+
+/// `<log>.reserve`, the space `EngineBuilder::disk_reserve` holds back
+fn reserve_path(path: &Path) -> PathBuf {
+    let mut reserve = path.as_os_str().to_os_string();
+    reserve.push(".reserve");
+    PathBuf::from(reserve)
+}
+
+/// Grow the file at `path` to `size` bytes of zeros, or cut it to that
+/// size, durably. The zeros are written, not left as a hole, so the file
+/// really holds the space.
+fn write_reserve(storage: &dyn Storage, path: &Path, size: u64) -> io::Result<()> {
+    let mut file = storage.open(path, true)?;
+    let mut len = file.len()?;
+    if len == size {
+        return Ok(());
+    }
+    if len > size {
+        file.set_len(size)?;
+    }
+    let zeros = [0; 64 * 1024];
+    while len < size {
+        let n = (size - len).min(zeros.len() as u64) as usize;
+        file.append(&zeros[..n])?;
+        len += n as u64;
+    }
+    file.sync()?;
+    storage.sync_dir(path)
+}
+
+fn is_storage_full<T>(result: &io::Result<T>) -> bool {
+    result.as_ref().is_err_and(|e| e.kind() == io::ErrorKind::StorageFull)
+}
+
+/// Whether `error` comes from the disk running out of space
+fn is_disk_full(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::StorageFull))
+}
+
+/// Whether `error` is a key or value over the limits
+fn is_too_large(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(Error::KeyTooLarge { .. } | Error::ValueTooLarge { .. }))
+}
+
+/// `<log>.tables/`, where `EngineKind::Lsm` keeps its table files
+fn tables_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_os_string();
+    dir.push(".tables");
+    PathBuf::from(dir)
+}
+
+/// `<log>.vlog/`, where the value log keeps its segments
+fn value_log_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_os_string();
+    dir.push(".vlog");
+    PathBuf::from(dir)
+}
+
+/// Hard link `from`, a file that is never written again, at `to`, or copy
+/// it where linking fails, e.g. across file systems
+pub(crate) fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    File::open(to)?.sync_all()
+}
+
+/// Copy the first `len` bytes of `from` to a new file at `to`, and fsync it
+pub(crate) fn copy_prefix(from: &Path, to: &Path, len: u64) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut out = options.open(to)?;
+    let copied = io::copy(&mut File::open(from)?.take(len), &mut out)?;
+    if copied != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than {} bytes", from.display(), len)));
+    }
+    out.sync_all()
+}
+
+/// Write the rest of an SST file from `Engine::export_sst`, and fsync it
+fn finish_sst(sst: SstWriter<io::BufWriter<File>>) -> Result<()> {
+    sst.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Make a rename into `path`'s directory durable
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Positioned read. On Windows this moves the handle's cursor, so use it
+/// on handles that are only read from.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A valid record found by recovery
+struct Recovered {
+    key: Vec<u8>,
+    namespace: u32,
+    /// `None` for a tombstone
+    location: Option<Location>,
+    /// Offset just past the record
+    end: u64,
+    /// The value, if it is short enough to keep inline
+    value: Option<Vec<u8>>,
+    /// When the key expires, for a put with an expiry time
+    expires_at: Option<u64>,
+    /// Where the value is, if the record only points into the value log
+    pointer: Option<ValuePointer>,
+    /// End of the range, for a range tombstone from `key`
+    range_end: Option<Vec<u8>>,
+}
+
+/// The valid prefix of a chunk
+type DecodedChunk = Vec<Recovered>;
+
+/// What recovery decodes and accepts
+#[derive(Debug, Clone, Copy)]
+struct DecodeOptions {
+    /// Values no longer than this are decoded as well
+    inline_max: Option<usize>,
+    max_key_len: usize,
+    max_value_len: usize,
+}
+
+/// Verify the run of back-to-back records starting at `start`
+///
+/// Record boundaries are found with a cheap sequential walk over the
+/// headers; the expensive part (CRC check + decode) then runs on a pool of
+/// worker threads, one chunk at a time, and the per-chunk results are
+/// merged in file order, so later records still win.
+/// Returns the valid records and the offset just past the last of them.
+/// `buf` holds the log from offset `base` on: `start` and the returned end
+/// are positions in `buf`, record offsets are offsets in the log.
+///
+/// Fails if an intact record cannot be decrypted, since truncating there
+/// would throw away data that is only unreadable with the key at hand.
+fn verify_run(
+    buf: &[u8],
+    base: u64,
+    start: usize,
+    format: &Format,
+    options: DecodeOptions,
+    threads: usize,
+    on_chunk: &mut dyn FnMut(u64, usize),
+) -> Result<(DecodedChunk, usize)> {
+    // 1. Locate record boundaries (stops at the first implausible header)
+    let mut frames = Vec::new();
+    let mut scan_pos = start;
+    while let Some(size) = Record::frame_len_with(&buf[scan_pos..], format) {
+        frames.push((scan_pos, size));
+        scan_pos += size;
+    }
+
+    // Group frames into chunks of roughly RECOVERY_CHUNK_BYTES each
+    let mut chunks: Vec<&[(usize, usize)]> = Vec::new();
+    let mut chunk_start = 0;
+    let mut chunk_bytes = 0;
+    for (i, &(_, size)) in frames.iter().enumerate() {
+        chunk_bytes += size;
+        if chunk_bytes >= RECOVERY_CHUNK_BYTES {
+            chunks.push(&frames[chunk_start..=i]);
+            chunk_start = i + 1;
+            chunk_bytes = 0;
+        }
+    }
+    if chunk_start < frames.len() {
+        chunks.push(&frames[chunk_start..]);
+    }
+
+    // 2. Verify and decode chunks in parallel, reporting from this thread
+    let mut decoded: Vec<Option<Result<DecodedChunk>>> = (0..chunks.len()).map(|_| None).collect();
+    let next_chunk = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..threads.min(chunks.len()) {
+            let tx = tx.clone();
+            let (chunks, next_chunk) = (&chunks, &next_chunk);
+            s.spawn(move || loop {
+                let i = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if i >= chunks.len() {
+                    break;
+                }
+                if tx.send((i, decode_chunk(buf, base, chunks[i], format, options))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        for (i, records) in rx {
+            let recovered = records.as_ref().map_or(0, |records| records.len());
+            on_chunk(chunks[i].iter().map(|&(_, size)| size as u64).sum(), recovered);
+            decoded[i] = Some(records);
+        }
+    });
+
+    // 3. Merge in file order up to the first corrupted record
+    let mut records = Vec::with_capacity(frames.len());
+    let mut end = start;
+    for (chunk, chunk_records) in chunks.iter().zip(decoded) {
+        let chunk_records = chunk_records.expect("recovery worker exited early")?;
+        let complete = chunk_records.len() == chunk.len();
+        for (&(frame_start, size), record) in chunk.iter().zip(chunk_records) {
+            records.push(record);
+            end = frame_start + size;
+        }
+        if !complete {
+            break;
+        }
+    }
+    Ok((records, end))
+}
+
+/// Verify and decode one chunk of framed records.
+/// Returns the valid prefix of the chunk; a result shorter than `frames`
+/// means the record after it failed its CRC.
+/// Values no longer than `options.inline_max` are decoded as well.
+/// Fails on an intact record with a key or value over the limits.
+fn decode_chunk(
+    buf: &[u8],
+    base: u64,
+    frames: &[(usize, usize)],
+    format: &Format,
+    options: DecodeOptions,
+) -> Result<DecodedChunk> {
+    let mut out = Vec::with_capacity(frames.len());
+    for &(start, size) in frames {
+        let offset = base + start as u64;
+        let frame = &buf[start..start + size];
+        // Records stored as-is are read in place, the rest decoded
+        let (namespace, key, kind, plain_value) = if Record::is_plain(frame) {
+            let record = match RecordRef::decode_with(frame, format) {
+                Ok((record, _)) => record,
+                // Intact, but over the limits
+                Err(e) if is_too_large(&e) => return Err(e.context(format!("record at offset {}", offset))),
+                Err(_) => break,
+            };
+            (record.namespace, record.key.to_vec(), record.kind, Some(record.value))
+        } else {
+            let decoded = Record::try_decode_key(frame, format)
+                .map_err(|e| Error::Decryption { offset, reason: e.to_string() })?;
+            let Some((namespace, key, kind, _)) = decoded else { break };
+            (namespace, key, kind, None)
+        };
+        let decode_value = || -> Result<Cow<[u8]>> {
+            Ok(match plain_value {
+                Some(value) => Cow::Borrowed(value),
+                None => Cow::Owned(Record::decode_with(frame, format)?.0.value),
+            })
+        };
+        let (key_len, value_len) = Record::stored_lens(frame).unwrap_or_default();
+        let too_large = if key_len > options.max_key_len {
+            Some(Error::KeyTooLarge { len: key_len, max: options.max_key_len })
+        } else if value_len > options.max_value_len {
+            Some(Error::ValueTooLarge { len: value_len, max: options.max_value_len })
+        } else {
+            None
+        };
+        if let Some(e) = too_large {
+            return Err(anyhow::Error::from(e).context(format!("record at offset {}", offset)));
+        }
+        let location = match kind {
+            RecordKind::Put | RecordKind::ValuePointer => Some(Location::new(offset, size)?),
+            RecordKind::Delete | RecordKind::RangeDelete => None,
+        };
+        let pointer = match kind {
+            RecordKind::ValuePointer => Some(ValuePointer::decode(&decode_value()?)?),
+            _ => None,
+        };
+        let range_end = match kind {
+            RecordKind::RangeDelete => Some(decode_value()?.into_owned()),
+            _ => None,
+        };
+        // A frame only a little longer than its key may hold a short
+        // value; the framing and sealing overhead is well under 64 bytes
+        let value = options.inline_max
+            .filter(|&max| kind == RecordKind::Put && size - key.len() <= max.saturating_add(64))
+            .and_then(|max| decode_value().ok().filter(|value| value.len() <= max).map(Cow::into_owned));
+        let expires_at = Record::header_expiry(frame);
+        out.push(Recovered { key, namespace, location, end: offset + size as u64, value, expires_at, pointer, range_end });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fault, FaultyStorage, MemStorage};
+    use std::io::IoSlice;
+
+    const PATH: &str = "/test/data.db";
+
+    /// `MemStorage` holding up to `capacity` bytes, past which a write
+    /// stores part of its bytes and fails as on a full disk, and whose
+    /// background syncs, through `try_clone`, can be made to fail
+    #[derive(Clone)]
+    struct TestDisk {
+        inner: MemStorage,
+        capacity: u64,
+        fail_background_syncs: bool,
+    }
+
+    impl TestDisk {
+        fn new(capacity: u64, fail_background_syncs: bool) -> Self {
+            Self { inner: MemStorage::new(), capacity, fail_background_syncs }
+        }
+
+        fn used(&self) -> u64 {
+            self.inner.paths().iter().map(|path| self.inner.read(path).map_or(0, |file| file.len() as u64)).sum()
+        }
+    }
+
+    struct TestFile {
+        inner: Box<dyn StorageFile>,
+        fail_syncs: bool,
+        disk: TestDisk,
+    }
+
+    impl Storage for TestDisk {
+        fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+            Ok(Box::new(TestFile { inner: self.inner.open(path, create)?, fail_syncs: false, disk: self.clone() }))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove(path)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn sync_dir(&self, path: &Path) -> io::Result<()> {
+            self.inner.sync_dir(path)
+        }
+    }
+
+    impl StorageFile for TestFile {
+        fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+            let free = self.disk.capacity.saturating_sub(self.disk.used()) as usize;
+            if buf.len() > free {
+                self.inner.append(&buf[..free / 2])?;
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            self.inner.append(buf)
+        }
+
+        fn append_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+            self.append(&bufs.iter().flat_map(|buf| buf.iter().copied()).collect::<Vec<u8>>())
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let grows = (offset + buf.len() as u64).saturating_sub(self.inner.len()?);
+            if grows > self.disk.capacity.saturating_sub(self.disk.used()) {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            self.inner.write_at(buf, offset)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.inner.read_at(buf, offset)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            if self.fail_syncs {
+                return Err(io::Error::other("injected background sync failure"));
+            }
+            self.inner.sync()
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            self.inner.len()
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.inner.set_len(len)
+        }
+
+        fn try_clone(&self) -> io::Result<Option<Box<dyn StorageFile>>> {
+            let Some(inner) = self.inner.try_clone()? else { return Ok(None) };
+            Ok(Some(Box::new(TestFile { inner, fail_syncs: self.disk.fail_background_syncs, disk: self.disk.clone() })))
+        }
+    }
+
+    fn assert_error<T: std::fmt::Debug>(result: Result<T>, expected: fn(&Error) -> bool) {
+        let e = result.unwrap_err();
+        assert!(e.downcast_ref::<Error>().is_some_and(expected), "unexpected error: {:#}", e);
+    }
+
+    fn is_poisoned(e: &Error) -> bool {
+        matches!(e, Error::EnginePoisoned { .. })
+    }
+
+    /// After the failed `put` of "c": writes refused, reads served, and a
+    /// reopen on the storage beneath writable with what was acknowledged
+    fn check_poisoned<S: Storage>(mut engine: Engine<S>, inner: &MemStorage) {
+        assert!(engine.poisoned().is_some());
+        assert_error(engine.put(b"d".to_vec(), b"4".to_vec()), is_poisoned);
+        assert_error(engine.delete(b"a"), is_poisoned);
+        assert_error(engine.compact(), is_poisoned);
+        assert_error(engine.sync(), is_poisoned);
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(engine.get(b"d").unwrap(), None);
+        drop(engine);
+
+        let mut engine = EngineBuilder::new(PATH).storage(inner.clone()).open().unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+        engine.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        engine.compact().unwrap();
+        assert_eq!(engine.get(b"d").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn failed_writes_and_syncs_poison_the_engine() {
+        let faults: [fn(&FaultyStorage<MemStorage>) -> Fault; 3] = [
+            |storage| Fault::FailSync { nth: storage.syncs() + 1 },
+            |storage| Fault::FailWrite { nth: storage.writes() + 1 },
+            |storage| Fault::ShortWrite { nth: storage.writes() + 1, len: 5 },
+        ];
+        for fault in faults {
+            let storage = FaultyStorage::new(MemStorage::new());
+            let mut engine = EngineBuilder::new(PATH).storage(storage.clone()).open().unwrap();
+            engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+            let fault = fault(&storage);
+            storage.inject(fault);
+            // The first error is the one the storage gave
+            let e = engine.put(b"c".to_vec(), b"3".to_vec()).unwrap_err();
+            assert!(e.downcast_ref::<Error>().is_none(), "{:?}: {:#}", fault, e);
+            assert_eq!(engine.get(b"c").unwrap(), None, "{:?}", fault);
+            check_poisoned(engine, storage.inner());
+        }
+    }
+
+    #[test]
+    fn failed_background_sync_poisons_the_engine() {
+        let disk = TestDisk::new(u64::MAX, true);
+        let mut engine = EngineBuilder::new(PATH).storage(disk.clone()).sync_mode(SyncMode::Batch(1000)).open().unwrap();
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        engine.sync().unwrap();
+        engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert!(engine.sync_async().unwrap().wait().is_err());
+        check_poisoned(engine, &disk.inner);
+    }
+
+    fn durable_engine(storage: &MemStorage) -> Engine<MemStorage> {
+        let builder = EngineBuilder::new(PATH).storage(storage.clone()).sync_mode(SyncMode::Batch(1000));
+        builder.read_consistency(ReadConsistency::Durable).open().unwrap()
+    }
+
+    #[test]
+    fn durable_reads_see_background_syncs_once_finished() {
+        let mut engine = durable_engine(&MemStorage::new());
+        engine.put(b"key".to_vec(), b"v1".to_vec()).unwrap();
+        engine.sync().unwrap();
+        engine.put(b"key".to_vec(), b"v2".to_vec()).unwrap();
+        engine.put(b"new".to_vec(), b"v1".to_vec()).unwrap();
+        assert_eq!(engine.get(b"key").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(engine.get(b"new").unwrap(), None);
+
+        engine.sync_async().unwrap().wait().unwrap();
+        assert_eq!(engine.get(b"key").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(engine.get(b"new").unwrap(), Some(b"v1".to_vec()));
+
+        // Writes after the background sync are not covered by it
+        engine.put(b"new".to_vec(), b"v2".to_vec()).unwrap();
+        assert_eq!(engine.get(b"new").unwrap(), Some(b"v1".to_vec()));
+        let options = ReadOptions { consistency: Some(ReadConsistency::Acknowledged) };
+        assert_eq!(engine.get_with_options(b"new", &options).unwrap(), Some(b"v2".to_vec()));
+    }
+
+    fn is_disk_full(e: &Error) -> bool {
+        matches!(e, Error::DiskFull)
+    }
+
+    #[test]
+    fn full_disk_fails_puts_and_the_reserve_lets_space_be_freed() {
+        let disk = TestDisk::new(200_000, false);
+        let reserve = || disk.inner.read("/test/data.db.reserve").map(|reserve| reserve.len());
+        let open = || EngineBuilder::new(PATH).storage(disk.clone()).disk_reserve(20_000).index_snapshot(false).open().unwrap();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        let mut engine = open();
+        assert_eq!(reserve(), Some(20_000));
+
+        let mut written = 0;
+        let e = loop {
+            match engine.put(key(written), vec![7; 1000]) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(e.downcast_ref::<Error>().is_some_and(is_disk_full), "{:#}", e);
+        // Puts alone leave the reserve to deletes and compaction
+        assert!(!engine.is_disk_full());
+        assert_eq!(reserve(), Some(20_000));
+        assert_eq!(engine.poisoned(), None);
+        assert_eq!(engine.get(&key(written)).unwrap(), None);
+
+        // The first delete to meet the full disk gives up the reserve
+        for i in 0..written - 10 {
+            engine.delete(&key(i)).unwrap();
+        }
+        assert!(engine.is_disk_full());
+        assert_eq!(reserve(), None);
+        assert_error(engine.put(key(written), vec![7; 10]), is_disk_full);
+
+        engine.compact().unwrap();
+        assert!(!engine.is_disk_full());
+        assert_eq!(reserve(), Some(20_000));
+        engine.put(key(written), vec![7; 1000]).unwrap();
+        assert_eq!(engine.get(&key(written - 1)).unwrap(), Some(vec![7; 1000]));
+        drop(engine);
+
+        // The record cut short by the full disk left the log whole
+        let engine = open();
+        assert_eq!(engine.len(), 11);
+        assert!(engine.verify().unwrap().is_healthy());
+    }
+
+    fn is_quota_exceeded(e: &Error) -> bool {
+        matches!(e, Error::QuotaExceeded { .. })
+    }
+
+    #[test]
+    fn max_size_fails_puts_past_it() {
+        let storage = MemStorage::new();
+        let mut engine = EngineBuilder::new(PATH).storage(storage.clone()).max_size(50_000).compact_at_max_size(false).open().unwrap();
+        let mut written = 0;
+        let e = loop {
+            match engine.put(b"key".to_vec(), vec![1; 1000]) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(e.downcast_ref(), Some(Error::QuotaExceeded { max: 50_000, .. })), "{:#}", e);
+        assert!(written > 40);
+        assert!(storage.read(PATH).unwrap().len() <= 50_000);
+        // Deletes free space, so they are let through
+        engine.delete(b"key").unwrap();
+        assert_error(engine.put(b"other".to_vec(), vec![1; 1000]), is_quota_exceeded);
+        engine.compact().unwrap();
+        engine.put(b"other".to_vec(), vec![1; 1000]).unwrap();
+    }
+
+    #[test]
+    fn max_size_compacts_first_when_that_makes_room() {
+        let storage = MemStorage::new();
+        let open = |reserve| EngineBuilder::new(PATH).storage(storage.clone()).max_size(100_000).disk_reserve(reserve).open().unwrap();
+        let mut engine = open(0);
+        // Overwrites alone stay within the limit
+        for i in 0..1000 {
+            engine.put(format!("key{}", i % 10).into_bytes(), vec![1; 1000]).unwrap();
+        }
+        assert!(storage.read(PATH).unwrap().len() <= 100_000);
+        assert_eq!(engine.len(), 10);
+
+        // Live data does fill it
+        let mut written = 0;
+        let e = loop {
+            match engine.put(format!("new{}", written).into_bytes(), vec![1; 1000]) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(e.downcast_ref::<Error>().is_some_and(is_quota_exceeded), "{:#}", e);
+        assert!((80..90).contains(&written), "{} new keys", written);
+        drop(engine);
+
+        // A disk reserve counts against the limit
+        let mut engine = open(30_000);
+        assert_error(engine.put(b"more".to_vec(), vec![1; 1000]), is_quota_exceeded);
+        for i in 0..40 {
+            engine.delete(format!("new{}", i).as_bytes()).unwrap();
+        }
+        engine.put(b"more".to_vec(), vec![1; 1000]).unwrap();
+        assert!(storage.read(PATH).unwrap().len() <= 70_000);
+    }
+
+    #[test]
+    fn records_over_the_limits_fail_open_without_being_cut_off() {
+        let storage = MemStorage::new();
+        let open = |builder: fn(EngineBuilder<MemStorage>) -> EngineBuilder<MemStorage>| {
+            builder(EngineBuilder::new(PATH).storage(storage.clone()).index_snapshot(false)).open()
+        };
+        let mut engine = open(|builder| builder).unwrap();
+        engine.put(b"short".to_vec(), vec![1; 10]).unwrap();
+        engine.put(b"a longer key".to_vec(), vec![2; 100]).unwrap();
+        drop(engine);
+        let len = storage.read(PATH).unwrap().len();
+
+        assert_error(open(|builder| builder.max_key_len(8)).map(drop), |e| matches!(e, Error::KeyTooLarge { len: 12, max: 8 }));
+        assert_error(open(|builder| builder.max_value_len(50)).map(drop), |e| matches!(e, Error::ValueTooLarge { len: 100, max: 50 }));
+        assert_eq!(storage.read(PATH).unwrap().len(), len);
+        let engine = open(|builder| builder).unwrap();
+        assert_eq!(engine.get(b"a longer key").unwrap(), Some(vec![2; 100]));
+    }
+
+    #[test]
+    fn value_log_gc_moves_live_values_without_watch_events_or_counted_puts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+        let open = || EngineBuilder::new(&path).value_log(1000).open().unwrap();
+        let mut engine = open();
+        engine.put(b"a".to_vec(), vec![1; 5000]).unwrap();
+        engine.put(b"b".to_vec(), vec![2; 5000]).unwrap();
+        engine.put_with_ttl(b"c".to_vec(), vec![3; 5000], Duration::from_secs(3600)).unwrap();
+        engine.put(b"a".to_vec(), vec![4; 5000]).unwrap();
+        engine.put(b"small".to_vec(), vec![5; 10]).unwrap();
+        let events = engine.watch(b"");
+        let puts = engine.stats().put_latency.count;
+
+        let report = engine.gc_value_log(0.2).unwrap();
+        assert_eq!((report.segments_checked, report.segments_removed, report.values_moved), (1, 1, 3));
+        assert!(report.bytes_reclaimed > 5000);
+        assert!(!dir.path().join("data.db.vlog/00000001.vlog").exists());
+        assert!(events.try_recv().is_err(), "gc reported a change");
+        assert_eq!(engine.stats().put_latency.count, puts);
+        let check = |engine: &Engine| {
+            assert_eq!(engine.get(b"a").unwrap(), Some(vec![4; 5000]));
+            assert_eq!(engine.get(b"b").unwrap(), Some(vec![2; 5000]));
+            assert_eq!(engine.get(b"c").unwrap(), Some(vec![3; 5000]));
+            assert_eq!(engine.get(b"small").unwrap(), Some(vec![5; 10]));
+            assert!(engine.ttl(b"c").unwrap().is_some_and(|ttl| ttl > Duration::from_secs(3500)));
+        };
+        check(&engine);
+        // Nothing left to collect
+        assert_eq!(engine.gc_value_log(0.2).unwrap().segments_removed, 0);
+        drop(engine);
+
+        let mut engine = open();
+        check(&engine);
+        engine.compact().unwrap();
+        check(&engine);
+    }
+
+    #[test]
+    fn value_log_gc_runs_with_puts_over_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = EngineBuilder::new(dir.path().join("data.db")).value_log(1000).max_size(90_000);
+        let mut engine = builder.open().unwrap();
+        for value in 1..=4 {
+            engine.put(format!("key{}", value % 2).into_bytes(), vec![value; 20_000]).unwrap();
+        }
+        assert_error(engine.put(b"more".to_vec(), vec![5; 20_000]), is_quota_exceeded);
+        let report = engine.gc_value_log(0.3).unwrap();
+        assert_eq!((report.segments_removed, report.values_moved), (1, 2));
+        engine.put(b"more".to_vec(), vec![5; 20_000]).unwrap();
+        assert_eq!(engine.get(b"key0").unwrap(), Some(vec![4; 20_000]));
+        assert_eq!(engine.get(b"key1").unwrap(), Some(vec![3; 20_000]));
+    }
+
+    #[test]
+    fn value_log_in_the_cold_dir_is_needed_to_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, cold_dir) = (dir.path().join("data.db"), dir.path().join("cold"));
+        let mut engine = EngineBuilder::new(&path).value_log(1000).cold_dir(&cold_dir).open().unwrap();
+        engine.put(b"a".to_vec(), vec![1; 5000]).unwrap();
+        // Sealed, as once it reaches the segment size
+        engine.value_log.as_mut().unwrap().roll().unwrap();
+        engine.put(b"b".to_vec(), vec![2; 5000]).unwrap();
+        let report = engine.gc_value_log(0.5).unwrap();
+        assert_eq!((report.segments_removed, report.segments_moved_cold), (0, 1));
+        assert!(cold_dir.join("00000001.vlog").exists());
+        drop(engine);
+
+        assert!(EngineBuilder::new(&path).open().is_err());
+        let engine = EngineBuilder::new(&path).cold_dir(&cold_dir).open().unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), Some(vec![1; 5000]));
+        assert_eq!(engine.get(b"b").unwrap(), Some(vec![2; 5000]));
+    }
+
+    #[test]
+    fn snapshot_round_trips_namespaces_and_expiry_times() {
+        let mut engine = EngineBuilder::new(PATH).storage(MemStorage::new()).open().unwrap();
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put_with_ttl(b"ttl".to_vec(), b"2".to_vec(), Duration::from_secs(3600)).unwrap();
+        engine.put_with_ttl(b"gone".to_vec(), b"3".to_vec(), Duration::from_millis(1)).unwrap();
+        engine.namespace("users").unwrap().put(b"a".to_vec(), b"4".to_vec()).unwrap();
+        engine.namespace("empty").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let mut snapshot = Vec::new();
+        assert_eq!(engine.export_snapshot(&mut snapshot).unwrap(), 3);
+
+        // Namespace ids differ in the new store, names are what match
+        let mut copy = EngineBuilder::new(PATH).storage(MemStorage::new()).open().unwrap();
+        copy.namespace("other").unwrap();
+        assert_eq!(copy.import_snapshot(snapshot.as_slice()).unwrap(), 3);
+        assert_eq!(copy.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(copy.get(b"ttl").unwrap(), Some(b"2".to_vec()));
+        assert!(copy.ttl(b"ttl").unwrap().is_some_and(|ttl| ttl > Duration::from_secs(3500)));
+        assert_eq!(copy.ttl(b"a").unwrap(), None);
+        assert_eq!(copy.get(b"gone").unwrap(), None);
+        assert_eq!(copy.namespaces().unwrap(), vec!["empty", "other", "users"]);
+        assert_eq!(copy.namespace("users").unwrap().get(b"a").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn damaged_snapshots_fail_to_import() {
+        let mut engine = EngineBuilder::new(PATH).storage(MemStorage::new()).open().unwrap();
+        for i in 0..10u8 {
+            engine.put(vec![b'k', i], vec![i; 20]).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        engine.export_snapshot(&mut snapshot).unwrap();
+        let import = |snapshot: &[u8]| EngineBuilder::new(PATH).storage(MemStorage::new()).open().unwrap().import_snapshot(snapshot);
+
+        // Cut short anywhere, or at the end of an entry
+        for len in 0..snapshot.len() {
+            assert!(import(&snapshot[..len]).is_err(), "imported the first {} bytes", len);
+        }
+        // A flipped value byte fails that entry
+        let at = snapshot.windows(20).position(|window| window == [3; 20]).unwrap();
+        let mut damaged = snapshot.clone();
+        damaged[at] ^= 1;
+        let e = import(&damaged).unwrap_err();
+        let message = format!("{:#}", e);
+        assert!(message.contains("snapshot entry after") && message.ends_with("fails its checksum"), "{}", message);
+        // A version from the future
+        let mut damaged = snapshot.clone();
+        damaged[8] = 2;
+        let e = import(&damaged).unwrap_err();
+        assert!(format!("{:#}", e).contains("version 2 is not supported"), "{:#}", e);
+        assert_eq!(import(&snapshot).unwrap(), 10);
+    }
+
+    #[test]
+    fn snapshot_import_into_lsm_skips_keys_with_expiry_times() {
+        let mut engine = EngineBuilder::new(PATH).storage(MemStorage::new()).open().unwrap();
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put_with_ttl(b"b".to_vec(), b"2".to_vec(), Duration::from_secs(3600)).unwrap();
+        engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        let mut snapshot = Vec::new();
+        engine.export_snapshot(&mut snapshot).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut lsm = EngineBuilder::new(dir.path().join("data.db")).kind(EngineKind::Lsm).open().unwrap();
+        assert_eq!(lsm.import_snapshot(snapshot.as_slice()).unwrap(), 2);
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"b").unwrap(), None);
+        assert_eq!(lsm.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        // Namespaces fail before any key is written
+        engine.namespace("users").unwrap().put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        let mut snapshot = Vec::new();
+        engine.export_snapshot(&mut snapshot).unwrap();
+        lsm.delete(b"a").unwrap();
+        assert!(lsm.import_snapshot(snapshot.as_slice()).is_err());
+        assert_eq!(lsm.get(b"a").unwrap(), None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+/// Errors callers may want to match on
+///
+/// Engine methods return `anyhow::Result`; these travel inside the
+/// `anyhow::Error` and can be recovered with
+/// `err.downcast_ref::<mini_kv::Error>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A record in the log failed its checks (Strict recovery)
+    Corruption { offset: u64, reason: String },
+    /// An intact encrypted record could not be decrypted: the key is wrong
+    /// or missing, or the record was tampered with
+    Decryption { offset: u64, reason: String },
+    /// A key longer than `EngineBuilder::max_key_len`, passed to `put()` or
+    /// found by recovery
+    KeyTooLarge { len: usize, max: usize },
+    /// A value longer than `EngineBuilder::max_value_len`
+    ValueTooLarge { len: usize, max: usize },
+    /// The log a `LogTailer` had read up to `offset` was replaced by a
+    /// rewrite such as `compact()`
+    LogRewritten { offset: u64 },
+    /// A write or sync to the log failed earlier, with `reason`, leaving
+    /// it unknown what reached the disk; the engine refuses writes and
+    /// syncs until it is reopened, which recovers from what is there
+    EnginePoisoned { reason: String },
+    /// The disk has no room for a put, whose record was cut off again; the
+    /// engine is still writable, and deletes and `compact()` can free space
+    DiskFull,
+    /// A write would take the log and value log to `len` bytes, past
+    /// `EngineBuilder::max_size`
+    QuotaExceeded { len: u64, max: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Corruption { offset, reason } => {
+                write!(f, "corrupted record at offset {}: {}", offset, reason)
+            }
+            Error::Decryption { offset, reason } => {
+                write!(f, "cannot decrypt record at offset {}: {}", offset, reason)
+            }
+            Error::KeyTooLarge { len, max } => write!(f, "key of {} bytes is over the limit of {}", len, max),
+            Error::ValueTooLarge { len, max } => write!(f, "value of {} bytes is over the limit of {}", len, max),
+            Error::LogRewritten { offset } => write!(f, "log was rewritten after offset {} was read; tail it again from the start", offset),
+            Error::EnginePoisoned { reason } => {
+                write!(f, "engine is read-only after a failed write or sync ({}); reopen it to write again", reason)
+            }
+            Error::DiskFull => write!(f, "disk is full; delete keys and compact() to free space"),
+            Error::QuotaExceeded { len, max } => {
+                write!(f, "write would grow the database to {} bytes, over its limit of {}; delete keys and compact() to free space", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,315 @@
+//! Storage that fails on cue, for testing what the engine promises when
+//! writes and syncs go wrong
+//!
+//! `FaultyStorage` wraps another `Storage` and counts every write (append
+//! or `write_at`) and every sync made through it, from 1. `inject`
+//! schedules a `Fault` for a given write or sync, so a test can walk every
+//! failure point of a workload in turn: run it once to learn the counts
+//! from `writes()` and `syncs()`, then once per point.
+//!
+//! `crash()` plays a power loss: each file written through the wrapper is
+//! cut back to the length its last successful sync left, and every file
+//! opened before fails from then on, as the handles of a dead process
+//! would. The engine can then be reopened on the same storage. Names are
+//! not rolled back, so a file created or renamed but never synced
+//! survives, empty or as synced; `MemStorage::after_crash` covers lost
+//! names.
+//!
+//! `Fault::CrashAtWrite` and `Fault::CrashAtSync` stop the process at one
+//! exact point instead: that write or sync and everything after it fail,
+//! so nothing more reaches the inner storage until `crash()`, and a test
+//! can take the image of that moment even if the engine carries on.
+//!
+//! `on_crash_point` gets there without rerunning anything: its callback
+//! runs just before every write and sync, so over `MemStorage` one run of
+//! a workload yields the image each crash point would leave.
+
+use std::collections::HashMap;
+use std::io::{self, IoSlice};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::storage::{Storage, StorageFile};
+
+/// What goes wrong, and at which write or sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The `nth` write fails, writing nothing
+    FailWrite { nth: u64 },
+    /// The `nth` write writes only its first `len` bytes, then fails
+    ShortWrite { nth: u64, len: usize },
+    /// The `nth` sync fails, making nothing durable
+    FailSync { nth: u64 },
+    /// The process dies at the `nth` write: it and every call after it
+    /// fail, until `crash()`
+    CrashAtWrite { nth: u64 },
+    /// The process dies at the `nth` sync, as `CrashAtWrite`
+    CrashAtSync { nth: u64 },
+}
+
+/// A `Storage` that injects faults into another; clones share the counts,
+/// the faults and the crashes
+pub struct FaultyStorage<S> {
+    inner: Arc<S>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl<S> Clone for FaultyStorage<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), state: self.state.clone() }
+    }
+}
+
+#[derive(Default)]
+struct FaultState {
+    writes: u64,
+    syncs: u64,
+    faults: Vec<Fault>,
+    /// Bumped by every crash; files opened before it fail
+    generation: u64,
+    /// A `CrashAtWrite` or `CrashAtSync` fired, and `crash()` has not
+    /// been called since
+    down: bool,
+    /// Length each file had at its last sync, by current name
+    synced: HashMap<PathBuf, Arc<Mutex<u64>>>,
+    on_crash_point: Option<Box<dyn FnMut(Fault) + Send>>,
+}
+
+impl FaultState {
+    /// Count a write, and take the fault scheduled for it
+    fn next_write(&mut self) -> Option<Fault> {
+        self.writes += 1;
+        let nth = self.writes;
+        let i = self.faults.iter().position(|fault| match *fault {
+            Fault::FailWrite { nth: at } | Fault::ShortWrite { nth: at, .. } | Fault::CrashAtWrite { nth: at } => at == nth,
+            Fault::FailSync { .. } | Fault::CrashAtSync { .. } => false,
+        })?;
+        let fault = self.faults.remove(i);
+        self.down |= matches!(fault, Fault::CrashAtWrite { .. });
+        Some(fault)
+    }
+
+    /// Count a sync; true if it is to fail
+    fn next_sync(&mut self) -> bool {
+        self.syncs += 1;
+        let nth = self.syncs;
+        let fail = self.faults.contains(&Fault::FailSync { nth });
+        if self.faults.contains(&Fault::CrashAtSync { nth }) {
+            self.down = true;
+        }
+        self.faults.retain(|&f| f != Fault::FailSync { nth } && f != Fault::CrashAtSync { nth });
+        fail || self.down
+    }
+
+    /// Fail if a `CrashAtWrite` or `CrashAtSync` has fired
+    fn check_up(&self) -> io::Result<()> {
+        if self.down {
+            return Err(io::Error::other("storage crashed"));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner: Arc::new(inner), state: Arc::default() }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Schedule `fault`; one for a write or sync already made never fires
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push(fault);
+    }
+
+    /// Writes made so far, failed ones included
+    pub fn writes(&self) -> u64 {
+        self.state.lock().unwrap().writes
+    }
+
+    /// Syncs made so far, failed ones included
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().unwrap().syncs
+    }
+
+    /// Call `callback` just before each write or sync reaches the inner
+    /// storage, with the `Fault` that would crash there. Over `MemStorage`,
+    /// `after_crash()` from the callback is the image that crash would
+    /// leave, and the callback can open an engine on it. It must not use
+    /// this storage.
+    pub fn on_crash_point(&self, callback: impl FnMut(Fault) + Send + 'static) {
+        self.state.lock().unwrap().on_crash_point = Some(Box::new(callback));
+    }
+
+    /// Whether a `CrashAtWrite` or `CrashAtSync` has fired since the last
+    /// `crash()`
+    pub fn is_down(&self) -> bool {
+        self.state.lock().unwrap().down
+    }
+
+    /// Drop every byte not yet synced, and cut off the files opened so far
+    pub fn crash(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.down = false;
+        state.faults.clear();
+        for (path, synced) in &state.synced {
+            let synced = *synced.lock().unwrap();
+            let mut file = match self.inner.open(path, false) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if file.len()? > synced {
+                file.set_len(synced)?;
+                file.sync()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        self.state.lock().unwrap().check_up()?;
+        let inner = self.inner.open(path, create)?;
+        let mut state = self.state.lock().unwrap();
+        // A file this wrapper has not seen was durable to begin with
+        let len = inner.len()?;
+        let synced = state.synced.entry(path.to_path_buf()).or_insert_with(|| Arc::new(Mutex::new(len))).clone();
+        let generation = state.generation;
+        Ok(Box::new(FaultyFile { inner, state: self.state.clone(), synced, generation }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_up()?;
+        self.inner.rename(from, to)?;
+        match state.synced.remove(from) {
+            Some(synced) => state.synced.insert(to.to_path_buf(), synced),
+            None => state.synced.remove(to),
+        };
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_up()?;
+        self.inner.remove(path)?;
+        state.synced.remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.state.lock().unwrap().check_up()?;
+        self.inner.sync_dir(path)
+    }
+}
+
+struct FaultyFile {
+    inner: Box<dyn StorageFile>,
+    state: Arc<Mutex<FaultState>>,
+    /// Shared with the other handles to the same file
+    synced: Arc<Mutex<u64>>,
+    generation: u64,
+}
+
+impl FaultyFile {
+    /// Fail if a crash came after this file was opened, or is under way
+    fn check_alive(&self, state: &FaultState) -> io::Result<()> {
+        if state.generation != self.generation {
+            return Err(io::Error::other("storage crashed"));
+        }
+        state.check_up()
+    }
+
+    /// Run the `on_crash_point` callback, if any, without holding the lock
+    fn crash_point(&self, point: Fault) {
+        let callback = self.state.lock().unwrap().on_crash_point.take();
+        if let Some(mut callback) = callback {
+            callback(point);
+            self.state.lock().unwrap().on_crash_point.get_or_insert(callback);
+        }
+    }
+
+    /// `append` and `write_at` with any fault scheduled for this write
+    fn write(&mut self, buf: &[u8], offset: Option<u64>) -> io::Result<()> {
+        let (fault, nth) = {
+            let mut state = self.state.lock().unwrap();
+            self.check_alive(&state)?;
+            (state.next_write(), state.writes)
+        };
+        self.crash_point(Fault::CrashAtWrite { nth });
+        let buf = match fault {
+            Some(Fault::FailWrite { .. }) => return Err(io::Error::other("injected write failure")),
+            Some(Fault::CrashAtWrite { .. }) => return Err(io::Error::other("storage crashed")),
+            Some(Fault::ShortWrite { len, .. }) => &buf[..len.min(buf.len())],
+            _ => buf,
+        };
+        match offset {
+            Some(offset) => self.inner.write_at(buf, offset)?,
+            None => self.inner.append(buf)?,
+        }
+        if fault.is_some() {
+            return Err(io::Error::other("injected short write"));
+        }
+        Ok(())
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write(buf, None)
+    }
+
+    /// One write, however many parts
+    fn append_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+        let joined: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.write(&joined, None)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write(buf, Some(offset))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.check_alive(&self.state.lock().unwrap())?;
+        self.inner.read_at(buf, offset)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let (nth, failure) = {
+            let mut state = self.state.lock().unwrap();
+            self.check_alive(&state)?;
+            let failure = state.next_sync().then_some(if state.down { "storage crashed" } else { "injected sync failure" });
+            (state.syncs, failure)
+        };
+        self.crash_point(Fault::CrashAtSync { nth });
+        if let Some(failure) = failure {
+            return Err(io::Error::other(failure));
+        }
+        self.inner.sync()?;
+        *self.synced.lock().unwrap() = self.inner.len()?;
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.check_alive(&self.state.lock().unwrap())?;
+        self.inner.len()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.check_alive(&self.state.lock().unwrap())?;
+        self.inner.set_len(len)?;
+        // Bytes cut off are gone whether or not the cut is synced
+        let mut synced = self.synced.lock().unwrap();
+        *synced = (*synced).min(len);
+        Ok(())
+    }
+}
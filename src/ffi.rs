@@ -0,0 +1,353 @@
+//! C ABI for embedding the engine from C, Python (ctypes), and other runtimes
+//!
+//! See `include/mini_kv.h` for the matching declarations. Every function
+//! returns one of the `MINI_KV_*` codes; on failure `mini_kv_last_error()`
+//! describes the error on the calling thread.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use anyhow::Context;
+
+use crate::engine::Engine;
+use crate::error::Error;
+
+pub const MINI_KV_OK: c_int = 0;
+/// `mini_kv_get` / `mini_kv_delete`: the key does not exist
+pub const MINI_KV_NOT_FOUND: c_int = 1;
+pub const MINI_KV_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const MINI_KV_ERR_IO: c_int = -2;
+pub const MINI_KV_ERR_CORRUPTION: c_int = -3;
+/// Any other failure, including a caught panic
+pub const MINI_KV_ERR_INTERNAL: c_int = -4;
+/// The disk filled up; delete keys and compact to free space
+pub const MINI_KV_ERR_DISK_FULL: c_int = -5;
+/// The write would take the database over its `max_size`
+pub const MINI_KV_ERR_QUOTA_EXCEEDED: c_int = -6;
+/// An earlier sync failed; reopen the database to recover
+pub const MINI_KV_ERR_POISONED: c_int = -7;
+
+/// Opaque handle returned by `mini_kv_open`
+pub struct MiniKv {
+    engine: Engine,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message).unwrap_or_else(|_| c"error message contained NUL".into());
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+fn error_code(err: &anyhow::Error) -> c_int {
+    match err.downcast_ref::<Error>() {
+        Some(Error::Corruption { .. }) => MINI_KV_ERR_CORRUPTION,
+        Some(Error::KeyTooLarge { .. } | Error::ValueTooLarge { .. }) => MINI_KV_ERR_INVALID_ARGUMENT,
+        Some(Error::DiskFull) => MINI_KV_ERR_DISK_FULL,
+        Some(Error::QuotaExceeded { .. }) => MINI_KV_ERR_QUOTA_EXCEEDED,
+        Some(Error::EnginePoisoned { .. }) => MINI_KV_ERR_POISONED,
+        _ if err.downcast_ref::<std::io::Error>().is_some() => MINI_KV_ERR_IO,
+        _ => MINI_KV_ERR_INTERNAL,
+    }
+}
+
+/// Run `f`, turning errors and panics into status codes
+fn guard(f: impl FnOnce() -> anyhow::Result<c_int>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => code,
+        Ok(Err(e)) => {
+            set_last_error(format!("{:#}", e));
+            error_code(&e)
+        }
+        Err(_) => {
+            set_last_error("panic inside mini-kv".to_string());
+            MINI_KV_ERR_INTERNAL
+        }
+    }
+}
+
+fn invalid_argument(what: &str) -> c_int {
+    set_last_error(format!("invalid argument: {}", what));
+    MINI_KV_ERR_INVALID_ARGUMENT
+}
+
+/// Borrow `len` bytes at `data`; a null pointer is only allowed for `len == 0`
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+/// Open or create the database at `path` (Always sync mode)
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_open(path: *const c_char, out: *mut *mut MiniKv) -> c_int {
+    if path.is_null() || out.is_null() {
+        return invalid_argument("path and out must not be null");
+    }
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        return invalid_argument("path is not valid UTF-8");
+    };
+    guard(|| {
+        let engine = Engine::open(path).with_context(|| format!("opening {}", path))?;
+        *out = Box::into_raw(Box::new(MiniKv { engine }));
+        Ok(MINI_KV_OK)
+    })
+}
+
+/// # Safety
+/// `db` must come from `mini_kv_open`; `key`/`value` must point at
+/// `key_len`/`value_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_put(
+    db: *mut MiniKv,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    let (Some(db), Some(key), Some(value)) = (db.as_mut(), bytes(key, key_len), bytes(value, value_len)) else {
+        return invalid_argument("null db, key or value");
+    };
+    guard(|| {
+        db.engine.put(key.to_vec(), value.to_vec())?;
+        Ok(MINI_KV_OK)
+    })
+}
+
+/// Look up `key`. On `MINI_KV_OK`, `*value_out` holds a buffer of
+/// `*value_len_out` bytes that must be released with `mini_kv_free_value`.
+///
+/// # Safety
+/// `db` must come from `mini_kv_open`; `key` must point at `key_len`
+/// readable bytes; the out pointers must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_get(
+    db: *mut MiniKv,
+    key: *const u8,
+    key_len: usize,
+    value_out: *mut *mut u8,
+    value_len_out: *mut usize,
+) -> c_int {
+    let (Some(db), Some(key)) = (db.as_ref(), bytes(key, key_len)) else {
+        return invalid_argument("null db or key");
+    };
+    if value_out.is_null() || value_len_out.is_null() {
+        return invalid_argument("null output pointer");
+    }
+    guard(|| match db.engine.get(key)? {
+        Some(value) => {
+            let value = value.into_boxed_slice();
+            *value_len_out = value.len();
+            *value_out = Box::into_raw(value) as *mut u8;
+            Ok(MINI_KV_OK)
+        }
+        None => {
+            *value_out = ptr::null_mut();
+            *value_len_out = 0;
+            Ok(MINI_KV_NOT_FOUND)
+        }
+    })
+}
+
+/// Release a value returned by `mini_kv_get`
+///
+/// # Safety
+/// `value`/`len` must be exactly what `mini_kv_get` returned, and not
+/// already freed.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_free_value(value: *mut u8, len: usize) {
+    if !value.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(value, len)));
+    }
+}
+
+/// Delete `key`; returns `MINI_KV_NOT_FOUND` if it did not exist
+///
+/// # Safety
+/// `db` must come from `mini_kv_open`; `key` must point at `key_len`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_delete(db: *mut MiniKv, key: *const u8, key_len: usize) -> c_int {
+    let (Some(db), Some(key)) = (db.as_mut(), bytes(key, key_len)) else {
+        return invalid_argument("null db or key");
+    };
+    guard(|| Ok(if db.engine.delete(key)? { MINI_KV_OK } else { MINI_KV_NOT_FOUND }))
+}
+
+/// Force all writes so far to disk
+///
+/// # Safety
+/// `db` must come from `mini_kv_open`.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_sync(db: *mut MiniKv) -> c_int {
+    let Some(db) = db.as_mut() else {
+        return invalid_argument("null db");
+    };
+    guard(|| {
+        db.engine.sync()?;
+        Ok(MINI_KV_OK)
+    })
+}
+
+/// Close the database as `Engine::close` does, reporting a failed sync
+/// or index snapshot, and release the handle. The handle is freed even
+/// on failure; a null `db` is a no-op.
+///
+/// # Safety
+/// `db` must come from `mini_kv_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mini_kv_close(db: *mut MiniKv) -> c_int {
+    if db.is_null() {
+        return MINI_KV_OK;
+    }
+    let db = Box::from_raw(db);
+    guard(move || {
+        db.engine.close()?;
+        Ok(MINI_KV_OK)
+    })
+}
+
+/// Description of the last error on this thread, or NULL. The pointer is
+/// valid until the next mini-kv call on the same thread.
+#[no_mangle]
+pub extern "C" fn mini_kv_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::MAX_KEY_LEN;
+
+    fn last_error() -> String {
+        let message = mini_kv_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }.to_str().unwrap().to_string()
+    }
+
+    fn open(path: &std::path::Path) -> *mut MiniKv {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let mut db = ptr::null_mut();
+        assert_eq!(unsafe { mini_kv_open(path.as_ptr(), &mut db) }, MINI_KV_OK);
+        assert!(!db.is_null());
+        db
+    }
+
+    /// The value of `key`, copied out of the buffer `mini_kv_get` hands
+    /// over, which is then freed
+    fn get(db: *mut MiniKv, key: &[u8]) -> (c_int, Option<Vec<u8>>) {
+        let (mut value, mut len) = (ptr::null_mut(), usize::MAX);
+        let code = unsafe { mini_kv_get(db, key.as_ptr(), key.len(), &mut value, &mut len) };
+        if value.is_null() {
+            assert_eq!(len, 0);
+            return (code, None);
+        }
+        let copy = unsafe { slice::from_raw_parts(value, len) }.to_vec();
+        unsafe { mini_kv_free_value(value, len) };
+        (code, Some(copy))
+    }
+
+    #[test]
+    fn round_trip_through_the_c_abi() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+        let db = open(&path);
+        let put = |key: &[u8], value: &[u8]| unsafe { mini_kv_put(db, key.as_ptr(), key.len(), value.as_ptr(), value.len()) };
+        assert_eq!(put(b"key", b"value"), MINI_KV_OK);
+        assert_eq!(put(b"empty", b""), MINI_KV_OK);
+        assert_eq!(get(db, b"key"), (MINI_KV_OK, Some(b"value".to_vec())));
+        assert_eq!(get(db, b"empty"), (MINI_KV_OK, Some(Vec::new())));
+        assert_eq!(get(db, b"missing"), (MINI_KV_NOT_FOUND, None));
+        assert_eq!(unsafe { mini_kv_delete(db, b"key".as_ptr(), 3) }, MINI_KV_OK);
+        assert_eq!(unsafe { mini_kv_delete(db, b"key".as_ptr(), 3) }, MINI_KV_NOT_FOUND);
+        assert_eq!(get(db, b"key"), (MINI_KV_NOT_FOUND, None));
+        assert_eq!(unsafe { mini_kv_sync(db) }, MINI_KV_OK);
+        assert_eq!(unsafe { mini_kv_close(db) }, MINI_KV_OK);
+
+        let db = open(&path);
+        assert_eq!(get(db, b"empty"), (MINI_KV_OK, Some(Vec::new())));
+        assert_eq!(get(db, b"key"), (MINI_KV_NOT_FOUND, None));
+        assert_eq!(unsafe { mini_kv_close(db) }, MINI_KV_OK);
+    }
+
+    #[test]
+    fn null_arguments_are_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir.path().join("data.db"));
+        let mut out = ptr::null_mut();
+        let (mut value, mut len) = (ptr::null_mut(), 0);
+        unsafe {
+            assert_eq!(mini_kv_open(ptr::null(), &mut out), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "invalid argument: path and out must not be null");
+            assert_eq!(mini_kv_open(c"x".as_ptr(), ptr::null_mut()), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(mini_kv_put(ptr::null_mut(), b"k".as_ptr(), 1, b"v".as_ptr(), 1), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(mini_kv_put(db, ptr::null(), 1, b"v".as_ptr(), 1), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(mini_kv_put(db, b"k".as_ptr(), 1, ptr::null(), 1), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "invalid argument: null db, key or value");
+            assert_eq!(mini_kv_get(db, ptr::null(), 1, &mut value, &mut len), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(mini_kv_get(db, b"k".as_ptr(), 1, ptr::null_mut(), &mut len), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "invalid argument: null output pointer");
+            assert_eq!(mini_kv_delete(ptr::null_mut(), b"k".as_ptr(), 1), MINI_KV_ERR_INVALID_ARGUMENT);
+            assert_eq!(mini_kv_sync(ptr::null_mut()), MINI_KV_ERR_INVALID_ARGUMENT);
+            // A null pointer is fine for an empty key or value
+            assert_eq!(mini_kv_put(db, b"k".as_ptr(), 1, ptr::null(), 0), MINI_KV_OK);
+            assert_eq!(mini_kv_close(ptr::null_mut()), MINI_KV_OK);
+            mini_kv_free_value(ptr::null_mut(), 0);
+            assert_eq!(mini_kv_close(db), MINI_KV_OK);
+        }
+    }
+
+    #[test]
+    fn failures_set_the_last_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir.path().join("data.db"));
+        let key = vec![0; MAX_KEY_LEN + 1];
+        let code = unsafe { mini_kv_put(db, key.as_ptr(), key.len(), b"v".as_ptr(), 1) };
+        assert_eq!(code, MINI_KV_ERR_INVALID_ARGUMENT);
+        assert!(last_error().starts_with(&format!("key of {} bytes is over the limit", MAX_KEY_LEN + 1)), "{}", last_error());
+        assert_eq!(unsafe { mini_kv_close(db) }, MINI_KV_OK);
+
+        // Opening a directory fails with an I/O error
+        let path = CString::new(dir.path().to_str().unwrap()).unwrap();
+        let mut out = ptr::null_mut();
+        assert_eq!(unsafe { mini_kv_open(path.as_ptr(), &mut out) }, MINI_KV_ERR_IO);
+        assert!(out.is_null());
+        assert!(last_error().starts_with(&format!("opening {}", dir.path().display())), "{}", last_error());
+    }
+
+    #[test]
+    fn errors_map_to_their_codes_and_panics_are_caught() {
+        let codes = [
+            (Error::Corruption { offset: 0, reason: String::new() }, MINI_KV_ERR_CORRUPTION),
+            (Error::KeyTooLarge { len: 2, max: 1 }, MINI_KV_ERR_INVALID_ARGUMENT),
+            (Error::ValueTooLarge { len: 2, max: 1 }, MINI_KV_ERR_INVALID_ARGUMENT),
+            (Error::DiskFull, MINI_KV_ERR_DISK_FULL),
+            (Error::QuotaExceeded { len: 2, max: 1 }, MINI_KV_ERR_QUOTA_EXCEEDED),
+            (Error::EnginePoisoned { reason: String::new() }, MINI_KV_ERR_POISONED),
+            (Error::LogRewritten { offset: 0 }, MINI_KV_ERR_INTERNAL),
+        ];
+        for (error, code) in codes {
+            let message = error.to_string();
+            assert_eq!(guard(|| Err(anyhow::Error::from(error).context("in a call"))), code);
+            assert_eq!(last_error(), format!("in a call: {}", message));
+        }
+        assert_eq!(guard(|| Err(std::io::Error::other("disk on fire").into())), MINI_KV_ERR_IO);
+        assert_eq!(guard(|| Err(anyhow::anyhow!("other"))), MINI_KV_ERR_INTERNAL);
+
+        assert_eq!(guard(|| panic!("in a test")), MINI_KV_ERR_INTERNAL);
+        assert_eq!(last_error(), "panic inside mini-kv");
+        assert_eq!(guard(|| Ok(MINI_KV_OK)), MINI_KV_OK);
+    }
+}
@@ -0,0 +1,305 @@
+//! Optional file header and the per-file settings records depend on
+//!
+//! A log may start with a header frame holding settings that apply to
+//! every record after it (the record checksum algorithm and a zstd
+//! dictionary), and the location of the footer of its sealed part. Logs without one use the defaults, so files written before
+//! headers existed still open. The header itself is always CRC32.
+//! Key material supplied at open (the encryption keys) and the limits to
+//! decode records within are carried here too, but never written to the
+//! header.
+//!
+//! ```text
+//! magic "MKVH"(4) | body_len(4) | body | crc32(4)
+//! body = { tag(1) | len(4) | value(len) }*
+//! ```
+
+use anyhow::{bail, Result};
+use std::io::Read;
+#[cfg(feature = "encryption")]
+use std::collections::BTreeMap;
+
+#[cfg(feature = "encryption")]
+use crate::encryption::Cipher;
+use crate::checksum::Checksum;
+use crate::error::Error;
+use crate::record::{MAX_KEY_LEN, MAX_VAL_LEN};
+
+pub const FILE_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x48]; // "MKVH"
+const FIELD_ZSTD_DICTIONARY: u8 = 1;
+const FIELD_CHECKSUM: u8 = 2;
+const FIELD_FOOTER: u8 = 3;
+
+/// Settings read from (or written to) a log's file header, plus anything
+/// derived from them that encoding and decoding need
+#[derive(Default)]
+pub struct Format {
+    checksum: Checksum,
+    footer_offset: Option<u64>,
+    dictionary: Option<Vec<u8>>,
+    #[cfg(feature = "compression-zstd")]
+    decoder_dictionary: Option<zstd::dict::DecoderDictionary<'static>>,
+    #[cfg(feature = "compression-zstd")]
+    encoder_dictionary: Option<(i32, zstd::dict::EncoderDictionary<'static>)>,
+    /// Encryption keys by version; the newest one seals new records
+    #[cfg(feature = "encryption")]
+    keys: BTreeMap<u32, Cipher>,
+    /// `None` for `MAX_KEY_LEN`
+    max_key_len: Option<usize>,
+    /// `None` for `MAX_VAL_LEN`
+    max_value_len: Option<usize>,
+}
+
+/// Copies the settings; prepared encoder dictionaries are rebuilt on demand
+impl Clone for Format {
+    fn clone(&self) -> Self {
+        let mut format = Self {
+            checksum: self.checksum,
+            footer_offset: self.footer_offset,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+            ..Self::default()
+        };
+        if let Some(dictionary) = &self.dictionary {
+            format.set_dictionary(dictionary.clone());
+        }
+        #[cfg(feature = "encryption")]
+        {
+            format.keys = self.keys.clone();
+        }
+        format
+    }
+}
+
+impl Format {
+    /// Parse the header at the start of `buf`. Returns the format and the
+    /// offset of the first record, which is 0 for a log without a header.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut format = Self::default();
+        let start = format.read_header(buf)?;
+        Ok((format, start))
+    }
+
+    /// `parse` for a log read from `file`'s current position, reading only
+    /// the header
+    pub fn read_from(file: &mut impl Read) -> Result<(Self, usize)> {
+        let mut format = Self::default();
+        let start = format.read_header_from(file)?;
+        Ok((format, start))
+    }
+
+    /// `read_header` for a log read from `file`'s current position
+    pub fn read_header_from(&mut self, file: &mut impl Read) -> Result<usize> {
+        let mut header = Vec::new();
+        file.take(8).read_to_end(&mut header)?;
+        if header.len() == 8 && header.starts_with(&FILE_MAGIC) {
+            let body_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+            file.take(body_len + 4).read_to_end(&mut header)?;
+        }
+        self.read_header(&header)
+    }
+
+    /// Like `parse`, but load the header's settings into `self`, keeping
+    /// any key material already set
+    pub fn read_header(&mut self, buf: &[u8]) -> Result<usize> {
+        if !buf.starts_with(&FILE_MAGIC) {
+            return Ok(0);
+        }
+        let corrupt = |reason: &str| Error::Corruption { offset: 0, reason: format!("file header: {}", reason) };
+        let body_len = buf
+            .get(4..8)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| corrupt("truncated"))?;
+        let Some(stored_crc) = 8usize.checked_add(body_len).and_then(|end| buf.get(end..end.checked_add(4)?)) else {
+            return Err(corrupt("truncated").into());
+        };
+        let body_end = 8 + body_len;
+        if crc32fast::hash(&buf[..body_end]) != u32::from_le_bytes(stored_crc.try_into().unwrap()) {
+            return Err(corrupt("CRC mismatch").into());
+        }
+
+        let mut body = &buf[8..body_end];
+        while let [tag, rest @ ..] = body {
+            let len = rest
+                .get(..4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or_else(|| corrupt("truncated field"))?;
+            let value = rest.get(4..).and_then(|rest| rest.get(..len)).ok_or_else(|| corrupt("truncated field"))?;
+            match *tag {
+                FIELD_ZSTD_DICTIONARY => self.set_dictionary(value.to_vec()),
+                FIELD_CHECKSUM => match value {
+                    [id] => self.checksum = Checksum::from_id(*id)?,
+                    _ => return Err(corrupt("bad checksum field").into()),
+                },
+                FIELD_FOOTER => match value.try_into() {
+                    Ok(offset) => self.footer_offset = Some(u64::from_le_bytes(offset)),
+                    Err(_) => return Err(corrupt("bad footer field").into()),
+                },
+                other => bail!("file header field {} is not supported by this version of mini-kv", other),
+            }
+            body = &rest[4 + len..];
+        }
+        Ok(body_end + 4)
+    }
+
+    /// The header frame for this format, or nothing if every setting is
+    /// at its default
+    pub fn encode_header(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        if self.checksum != Checksum::default() {
+            body.push(FIELD_CHECKSUM);
+            body.extend_from_slice(&1u32.to_le_bytes());
+            body.push(self.checksum.id());
+        }
+        if let Some(offset) = self.footer_offset {
+            body.push(FIELD_FOOTER);
+            body.extend_from_slice(&8u32.to_le_bytes());
+            body.extend_from_slice(&offset.to_le_bytes());
+        }
+        if let Some(dictionary) = &self.dictionary {
+            body.push(FIELD_ZSTD_DICTIONARY);
+            body.extend_from_slice(&(dictionary.len() as u32).to_le_bytes());
+            body.extend_from_slice(dictionary);
+        }
+        if body.is_empty() {
+            return Vec::new();
+        }
+
+        let mut buf = Vec::with_capacity(12 + body.len());
+        buf.extend_from_slice(&FILE_MAGIC);
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&body);
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// A default format with only this one's key material and limits, to
+    /// read the header of another file into
+    pub(crate) fn keys_only(&self) -> Self {
+        #[cfg_attr(not(feature = "encryption"), allow(unused_mut))]
+        let mut format = Self { max_key_len: self.max_key_len, max_value_len: self.max_value_len, ..Self::default() };
+        #[cfg(feature = "encryption")]
+        {
+            format.keys = self.keys.clone();
+        }
+        format
+    }
+
+    /// Checksum algorithm of every record in the log
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = checksum;
+    }
+
+    /// Longest key a record may decode to (default `MAX_KEY_LEN`); longer
+    /// ones fail with `Error::KeyTooLarge`
+    pub fn max_key_len(&self) -> usize {
+        self.max_key_len.unwrap_or(MAX_KEY_LEN)
+    }
+
+    pub fn set_max_key_len(&mut self, bytes: usize) {
+        self.max_key_len = Some(bytes);
+    }
+
+    /// Longest value a record may decode to, compressed or not (default
+    /// `MAX_VAL_LEN`); longer ones fail with `Error::ValueTooLarge`
+    pub fn max_value_len(&self) -> usize {
+        self.max_value_len.unwrap_or(MAX_VAL_LEN)
+    }
+
+    pub fn set_max_value_len(&mut self, bytes: usize) {
+        self.max_value_len = Some(bytes);
+    }
+
+    /// Offset of the `SegmentFooter` after the sealed records, if part of
+    /// the log is sealed
+    pub fn footer_offset(&self) -> Option<u64> {
+        self.footer_offset
+    }
+
+    pub fn set_footer_offset(&mut self, offset: Option<u64>) {
+        self.footer_offset = offset;
+    }
+
+    /// Zstd dictionary that dictionary-compressed values are encoded against
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
+    }
+
+    pub fn set_dictionary(&mut self, dictionary: Vec<u8>) {
+        #[cfg(feature = "compression-zstd")]
+        {
+            self.decoder_dictionary = Some(zstd::dict::DecoderDictionary::copy(&dictionary));
+            self.encoder_dictionary = None;
+        }
+        self.dictionary = Some(dictionary);
+    }
+
+    /// Add AES-256-GCM key version `id`. Records are decrypted with the
+    /// version they name, and new ones encrypted with the highest version.
+    #[cfg(feature = "encryption")]
+    pub fn add_encryption_key(&mut self, id: u32, key: &[u8; 32]) {
+        self.keys.insert(id, Cipher::new(key));
+    }
+
+    /// Version of the key new records are encrypted with, if any
+    pub fn encryption_key_id(&self) -> Option<u32> {
+        #[cfg(feature = "encryption")]
+        return self.keys.keys().next_back().copied();
+        #[cfg(not(feature = "encryption"))]
+        None
+    }
+
+    /// Nonce, ciphertext and tag for `plaintext` under key version `key_id`
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn encrypt(&self, key_id: u32, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = self.keys.get(&key_id) {
+            return cipher.encrypt(aad, plaintext);
+        }
+        bail!("no encryption key version {}", key_id)
+    }
+
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn decrypt(&self, key_id: u32, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        return match self.keys.get(&key_id) {
+            Some(cipher) => cipher.decrypt(aad, sealed),
+            None if self.keys.is_empty() => bail!("record is encrypted; open with an encryption key"),
+            None => bail!("record is encrypted with key version {}, which was not supplied", key_id),
+        };
+        #[cfg(not(feature = "encryption"))]
+        bail!("record is encrypted; rebuild with the encryption feature")
+    }
+
+    /// Build the compression-side dictionary for `level` up front, so each
+    /// put does not have to
+    #[cfg(feature = "compression-zstd")]
+    pub(crate) fn prepare_encoder(&mut self, level: i32) {
+        if let Some(dictionary) = &self.dictionary {
+            self.encoder_dictionary = Some((level, zstd::dict::EncoderDictionary::copy(dictionary, level)));
+        }
+    }
+
+    /// A compressor using the file's dictionary, if it has one
+    #[cfg(feature = "compression-zstd")]
+    pub(crate) fn dictionary_compressor(&self, level: i32) -> Result<Option<zstd::bulk::Compressor<'_>>> {
+        Ok(match (&self.encoder_dictionary, &self.dictionary) {
+            (Some((prepared_level, prepared)), _) if *prepared_level == level => {
+                Some(zstd::bulk::Compressor::with_prepared_dictionary(prepared)?)
+            }
+            (_, Some(dictionary)) => Some(zstd::bulk::Compressor::with_dictionary(level, dictionary)?),
+            _ => None,
+        })
+    }
+
+    #[cfg(feature = "compression-zstd")]
+    pub(crate) fn decoder_dictionary(&self) -> Result<&zstd::dict::DecoderDictionary<'static>> {
+        self.decoder_dictionary
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("value needs a zstd dictionary but the file has none"))
+    }
+}
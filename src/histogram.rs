@@ -0,0 +1,96 @@
+//! Latency histograms behind `Engine::stats()`
+//!
+//! Log-linear buckets in the HDR histogram style: each power of two of
+//! nanoseconds is split into `SUB_BUCKETS` equal buckets, so a percentile
+//! is within about 3% of the true latency at any scale, from nanoseconds
+//! to minutes, in a fixed 15KB per histogram. Counts are atomic, so calls
+//! taking `&self` record too.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const SUB_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+/// Exact buckets below `SUB_BUCKETS`, then `SUB_BUCKETS` per power of two
+/// up to 2^63
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Percentiles of one kind of call since the engine was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// Calls timed
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+pub(crate) struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn new() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let count = counts.iter().sum();
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: f64| {
+            let rank = ((p * count as f64).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (i, &n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return Duration::from_nanos(upper_bound(i).min(max));
+                }
+            }
+            Duration::from_nanos(max)
+        };
+        if count == 0 {
+            return LatencySnapshot::default();
+        }
+        LatencySnapshot {
+            count,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max: Duration::from_nanos(max),
+        }
+    }
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exp = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (exp - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exp - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+/// Largest latency `bucket` puts in bucket `i`
+fn upper_bound(i: usize) -> u64 {
+    if i < SUB_BUCKETS {
+        return i as u64;
+    }
+    let shift = (i / SUB_BUCKETS - 1) as u32;
+    let low = ((SUB_BUCKETS + i % SUB_BUCKETS) as u64) << shift;
+    low + ((1u64 << shift) - 1)
+}
@@ -0,0 +1,902 @@
+//! Key → record location index
+//!
+//! Keys are held as `namespace::index_key` forms them, so one index serves
+//! every namespace.
+//!
+//! By default every live key is held in memory. With a memory budget
+//! (`EngineBuilder::index_memory_budget`) only entries written since the
+//! last spill are: once they outgrow the budget they are merged into a
+//! sorted index file next to the log (`<log>.index`), and lookups that miss
+//! in memory go there. For the file, memory holds only a sparse sample of
+//! its keys and a bloom filter, so most lookups of absent keys stay off
+//! disk and the rest read one block.
+//!
+//! With `EngineBuilder::inline_values`, values up to a size limit are kept
+//! alongside the in-memory entries, so reading them needs no I/O. They are
+//! not written to the index file: a spilled entry, or one loaded from a
+//! snapshot, reads its value from the log until the key is written again.
+//!
+//! Expiry times of keys written with a TTL are always all kept in memory,
+//! one entry per such key whatever the budget, and written to the index
+//! file with its entries.
+//!
+//! Dropping an engine also writes the whole index to that file, as a
+//! snapshot that an unbounded index is loaded from at the next open.
+//!
+//! The index file records the log offset it covers and a hash of the log
+//! bytes just before that offset, so it is not used with another log.
+//! Opening a log with a matching index file only re-reads the records
+//! after that offset. The file is a cache: deleting it costs a full scan at
+//! the next open, nothing more.
+//!
+//! ```text
+//! entries: { key_len(4) | key | offset(8) | len(4) }*  sorted by key
+//! sparse:  { key_len(4) | key | entry_pos(8) }*       every SPARSE_EVERY-th entry
+//! bloom:   BloomFilter::encode
+//! expiry:  { key_len(4) | key | expires_at(8) }*      keys with an expiry
+//! trailer: magic "MKVL"(4) | entries_len(8) | sparse_len(8) | bloom_len(8)
+//!          | expiry_len(8) | entry_count(8) | covers_end(8) | log_tag(8)
+//!          | live_bytes(8) | entries_crc32(4) | crc32(4)
+//! ```
+//!
+//! The last CRC covers the sparse sample, the filter, the expiry times and
+//! the trailer. The entries have their own, checked when an unbounded
+//! index loads them all at open; a snapshot that fails it, or has a key
+//! length out of bounds, is dropped for a full scan. A bounded index reads
+//! entries a block at a time, so it checks each key length against the
+//! entries left, and the entry against the key of the record it leads to.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use crate::bloom::BloomFilter;
+use crate::storage::{Appender, ReadAt, Storage, StorageFile};
+
+/// "MKVI" files predate namespaces, and hold default namespace keys that
+/// start with the escape byte unescaped; "MKVJ" files have no expiry
+/// times, and "MKVK" files no entries CRC. All fail this check and are
+/// rebuilt.
+const INDEX_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x4C]; // "MKVL"
+const TRAILER_SIZE: usize = 4 + 8 * 8 + 4 + 4;
+/// One key in this many is sampled into memory
+const SPARSE_EVERY: u64 = 64;
+/// Rough size of an in-memory entry beyond its key bytes: the boxed key
+/// and slot in the map (28 bytes, plus spare capacity and control bytes)
+/// and the key's allocation header
+const ENTRY_OVERHEAD: usize = 52;
+/// Log bytes hashed to tie an index file to its log
+const LOG_TAG_BYTES: u64 = 4096;
+
+/// Where a key's current record is in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Location {
+    pub(crate) offset: u64,
+    /// Length of the whole record, so reading it takes one positioned read
+    pub(crate) len: u32,
+}
+
+impl Location {
+    pub(crate) fn new(offset: u64, len: usize) -> Result<Self> {
+        let len = len.try_into().map_err(|_| anyhow!("record of {} bytes at offset {} is too large", len, offset))?;
+        Ok(Self { offset, len })
+    }
+}
+
+/// In-memory entry, packed into 12 bytes with 4-byte alignment: the record
+/// offset, or `DELETED` for a key deleted since the index file was
+/// written, with the `IN_FILE` bit if the index file has the key (so
+/// deleting it needs a marker rather than just dropping the entry), then
+/// the record length
+#[derive(Debug, Clone, Copy)]
+struct Slot([u32; 3]);
+
+const IN_FILE: u64 = 1 << 63;
+const DELETED: u64 = IN_FILE - 1;
+
+impl Slot {
+    fn new(location: Option<Location>, in_file: bool) -> Self {
+        let word = location.map_or(DELETED, |l| l.offset) | if in_file { IN_FILE } else { 0 };
+        Self([word as u32, (word >> 32) as u32, location.map_or(0, |l| l.len)])
+    }
+
+    fn location(self) -> Option<Location> {
+        let offset = (self.0[0] as u64 | (self.0[1] as u64) << 32) & !IN_FILE;
+        (offset != DELETED).then_some(Location { offset, len: self.0[2] })
+    }
+
+    fn in_file(self) -> bool {
+        self.0[1] as u64 & (IN_FILE >> 32) != 0
+    }
+}
+
+pub(crate) struct Index {
+    /// Boxed rather than `Vec` keys save the capacity word per entry
+    memory: HashMap<Box<[u8]>, Slot>,
+    /// Values of in-memory entries no longer than `inline_max`. A separate
+    /// map, so entries pay nothing for it when inlining is off.
+    inline: HashMap<Box<[u8]>, Box<[u8]>>,
+    inline_max: Option<usize>,
+    /// Expiry times, in milliseconds since the Unix epoch, of the live keys
+    /// that have one; never spilled, and not counted against the budget
+    expiry: HashMap<Box<[u8]>, u64>,
+    file: Option<IndexFile>,
+    /// Byte budget for `memory` and `inline`, if entries spill to an index
+    /// file
+    budget: Option<usize>,
+    memory_bytes: usize,
+    /// Live keys, in memory and in the file together
+    live: usize,
+    /// Log bytes of the records the live keys point at
+    live_bytes: u64,
+}
+
+impl Index {
+    pub(crate) fn new(budget: Option<usize>, inline_max: Option<usize>) -> Self {
+        Self {
+            memory: HashMap::new(),
+            inline: HashMap::new(),
+            inline_max,
+            expiry: HashMap::new(),
+            file: None,
+            budget,
+            memory_bytes: 0,
+            live: 0,
+            live_bytes: 0,
+        }
+    }
+
+    /// An empty index with the same settings
+    pub(crate) fn fresh(&self) -> Self {
+        Self::new(self.budget, self.inline_max)
+    }
+
+    /// Values up to this long may be kept inline
+    pub(crate) fn inline_max(&self) -> Option<usize> {
+        self.inline_max
+    }
+
+    /// Whether entries spill to an index file
+    pub(crate) fn bounded(&self) -> bool {
+        self.budget.is_some()
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Location>> {
+        match (self.memory.get(key), &self.file) {
+            (Some(slot), _) => Ok(slot.location()),
+            (None, Some(file)) => file.get(key),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The value of `key` if it is kept inline
+    pub(crate) fn inline_value(&self, key: &[u8]) -> Option<&[u8]> {
+        self.inline.get(key).map(|value| &**value)
+    }
+
+    /// When `key` expires, if its record has an expiry time
+    pub(crate) fn expires_at(&self, key: &[u8]) -> Option<u64> {
+        if self.expiry.is_empty() {
+            return None;
+        }
+        self.expiry.get(key).copied()
+    }
+
+    /// Point `key` at `location`; `value` is the record's value, kept
+    /// inline if it is short enough, and `expires_at` its expiry time
+    pub(crate) fn insert(&mut self, key: Vec<u8>, location: Location, value: Option<&[u8]>, expires_at: Option<u64>) -> Result<()> {
+        self.set_inline(&key, value.filter(|value| self.inline_max.is_some_and(|max| value.len() <= max)));
+        match expires_at {
+            Some(expires_at) => self.expiry.insert(key.as_slice().into(), expires_at),
+            None => self.expiry.remove(key.as_slice()),
+        };
+        if let Some(slot) = self.memory.get_mut(key.as_slice()) {
+            self.live_bytes += location.len as u64;
+            match slot.location() {
+                Some(old) => self.live_bytes -= old.len as u64,
+                None => self.live += 1,
+            }
+            *slot = Slot::new(Some(location), slot.in_file());
+            return Ok(());
+        }
+        let old = match &self.file {
+            Some(file) => file.get(&key)?,
+            None => None,
+        };
+        self.live_bytes += location.len as u64;
+        match old {
+            Some(old) => self.live_bytes -= old.len as u64,
+            None => self.live += 1,
+        }
+        let in_file = old.is_some();
+        self.memory_bytes += key.len() + ENTRY_OVERHEAD;
+        self.memory.insert(key.into_boxed_slice(), Slot::new(Some(location), in_file));
+        Ok(())
+    }
+
+    fn set_inline(&mut self, key: &[u8], value: Option<&[u8]>) {
+        let old = match value {
+            Some(value) => {
+                self.memory_bytes += key.len() + value.len() + ENTRY_OVERHEAD;
+                self.inline.insert(key.into(), value.into())
+            }
+            None => self.inline.remove(key),
+        };
+        if let Some(old) = old {
+            self.memory_bytes -= key.len() + old.len() + ENTRY_OVERHEAD;
+        }
+    }
+
+    /// Returns whether the key was live
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        self.set_inline(key, None);
+        self.expiry.remove(key);
+        match self.memory.get_mut(key) {
+            Some(slot) => {
+                let Some(old) = slot.location() else { return Ok(false) };
+                self.live -= 1;
+                self.live_bytes -= old.len as u64;
+                if slot.in_file() {
+                    *slot = Slot::new(None, true);
+                } else {
+                    self.memory.remove(key);
+                    self.memory_bytes -= key.len() + ENTRY_OVERHEAD;
+                }
+                Ok(true)
+            }
+            None => {
+                let Some(file) = &self.file else { return Ok(false) };
+                let Some(old) = file.get(key)? else { return Ok(false) };
+                self.live -= 1;
+                self.live_bytes -= old.len as u64;
+                self.memory_bytes += key.len() + ENTRY_OVERHEAD;
+                self.memory.insert(key.into(), Slot::new(None, true));
+                Ok(true)
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.live
+    }
+
+    pub(crate) fn live_bytes(&self) -> u64 {
+        self.live_bytes
+    }
+
+    /// The in-memory entries have outgrown the budget and should be spilled
+    pub(crate) fn over_budget(&self) -> bool {
+        self.budget.is_some_and(|budget| self.memory_bytes > budget)
+    }
+
+    /// Live entries: the in-memory ones in no particular order, then those
+    /// only in the index file, by key
+    pub(crate) fn entries(&self) -> impl Iterator<Item = Result<(Vec<u8>, Location)>> + '_ {
+        let memory = self.memory.iter().filter_map(|(key, slot)| Some(Ok((key.to_vec(), slot.location()?))));
+        let file = self.file.iter().flat_map(|file| file.entries_from(0)).filter(|entry| match entry {
+            Ok((key, _)) => !self.memory.contains_key(key.as_slice()),
+            Err(_) => true,
+        });
+        memory.chain(file)
+    }
+
+    /// Live entries by key
+    pub(crate) fn sorted_entries(&self) -> impl Iterator<Item = Result<(Vec<u8>, Location)>> + '_ {
+        self.merged().filter_map(|entry| match entry {
+            Ok((key, location)) => Some(Ok((key, location?))),
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Live entries whose key starts with `prefix`, sorted by key
+    pub(crate) fn prefix_entries(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Location)>> {
+        let mut matches: Vec<(Vec<u8>, Location)> = self
+            .memory
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, slot)| Some((key.to_vec(), slot.location()?)))
+            .collect();
+        if let Some(file) = &self.file {
+            for entry in file.entries_from(file.block_for(prefix).0) {
+                let (key, location) = entry?;
+                if key.as_slice() < prefix || self.memory.contains_key(key.as_slice()) {
+                    continue;
+                }
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                matches.push((key, location));
+            }
+        }
+        matches.sort_unstable();
+        Ok(matches)
+    }
+
+    /// Live entries with keys from `lower` up to `upper`, exclusive (no
+    /// bound for `None`), that `keep` passes: the in-memory ones in no
+    /// particular order, then those only in the index file, by key. The
+    /// file is read from the block holding `lower` on.
+    pub(crate) fn range_entries<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Option<&'a [u8]>,
+        keep: impl Fn(&[u8]) -> bool + Copy + 'a,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Location)>> + 'a {
+        let memory = self.memory_range(lower, upper, keep).map(|(key, location)| Ok((key.to_vec(), location)));
+        memory.chain(self.file_range(lower, upper, keep))
+    }
+
+    /// The first `limit` entries of `range_entries`, by key. Memory is gone
+    /// through once, keeping only the `limit` lowest keys, and the file is
+    /// read no further than needed.
+    pub(crate) fn range_page(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Option<&[u8]>,
+        limit: usize,
+        keep: impl Fn(&[u8]) -> bool + Copy,
+    ) -> Result<Vec<(Vec<u8>, Location)>> {
+        let mut lowest = BinaryHeap::new();
+        for (key, location) in self.memory_range(lower, upper, keep) {
+            if lowest.len() < limit {
+                lowest.push((key, location));
+            } else if lowest.peek().is_some_and(|(highest, _)| key < *highest) {
+                lowest.pop();
+                lowest.push((key, location));
+            }
+        }
+        let mut memory = lowest.into_sorted_vec().into_iter().peekable();
+        let mut file = self.file_range(lower, upper, keep).peekable();
+        let mut page = Vec::with_capacity(limit.min(1024));
+        while page.len() < limit {
+            let from_memory = match (memory.peek(), file.peek()) {
+                (None, None) => break,
+                (Some((memory_key, _)), Some(Ok((file_key, _)))) => *memory_key < file_key.as_slice(),
+                (_, Some(Err(_))) | (None, Some(_)) => false,
+                (Some(_), None) => true,
+            };
+            match from_memory {
+                true => page.extend(memory.next().map(|(key, location)| (key.to_vec(), location))),
+                false => page.push(file.next().expect("peeked")?),
+            }
+        }
+        Ok(page)
+    }
+
+    fn memory_range<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Option<&'a [u8]>,
+        keep: impl Fn(&[u8]) -> bool + 'a,
+    ) -> impl Iterator<Item = (&'a [u8], Location)> + 'a {
+        self.memory
+            .iter()
+            .filter(move |(key, _)| in_range(key, lower, upper) && keep(key))
+            .filter_map(|(key, slot)| Some((&**key, slot.location()?)))
+    }
+
+    /// Entries of the index file in the range, by key, but for keys memory
+    /// has an entry for, live or deleted
+    fn file_range<'a>(
+        &'a self,
+        lower: Bound<&'a [u8]>,
+        upper: Option<&'a [u8]>,
+        keep: impl Fn(&[u8]) -> bool + 'a,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Location)>> + 'a {
+        let start = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => &[],
+        };
+        self.file
+            .iter()
+            .flat_map(move |file| file.entries_from(file.block_for(start).0))
+            .skip_while(move |entry| matches!(entry, Ok((key, _)) if !in_range(key, lower, None)))
+            .take_while(move |entry| entry.as_ref().map_or(true, |(key, _)| in_range(key, lower, upper)))
+            .filter(move |entry| match entry {
+                Ok((key, _)) => !self.memory.contains_key(key.as_slice()) && keep(key),
+                Err(_) => true,
+            })
+    }
+
+    /// Merge the in-memory entries into a new index file at `path` covering
+    /// the log up to `covers_end`, whose tag is `log_tag`, and empty memory
+    pub(crate) fn spill(&mut self, storage: &dyn Storage, path: &Path, covers_end: u64, log_tag: u64) -> Result<()> {
+        let expected_keys = self.memory.len() + self.file.as_ref().map_or(0, |file| file.entry_count as usize);
+        let mut writer = IndexWriter::create(storage, path, expected_keys)?;
+        for entry in self.merged() {
+            if let (key, Some(location)) = entry? {
+                writer.add(&key, location)?;
+            }
+        }
+        for (key, &expires_at) in &self.expiry {
+            writer.add_expiry(key, expires_at);
+        }
+        let mut file = writer.finish(storage, covers_end, log_tag)?;
+        // Memory keeps its own copy
+        file.expiry.clear();
+        self.file = Some(file);
+        self.memory.clear();
+        self.inline.clear();
+        self.memory_bytes = 0;
+        Ok(())
+    }
+
+    /// Sorted merge of the in-memory entries (`None` for a deleted key)
+    /// over the index file's
+    fn merged(&self) -> impl Iterator<Item = Result<(Vec<u8>, Option<Location>)>> + '_ {
+        let mut memory: Vec<(&Box<[u8]>, &Slot)> = self.memory.iter().collect();
+        memory.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        let memory = memory.into_iter().map(|(key, slot)| Ok((key.to_vec(), slot.location())));
+        let file = self
+            .file
+            .iter()
+            .flat_map(|file| file.entries_from(0))
+            .map(|entry| entry.map(|(key, location)| (key, Some(location))));
+        Merge { memory: memory.peekable(), file: file.peekable() }
+    }
+
+    /// Use the index file at `path` if it belongs to `log` (whose records
+    /// start at `log_start` and which is `log_len` bytes long), returning
+    /// the offset up to which it covers the log. Without a budget its
+    /// entries are read into memory. A stale or damaged file is removed.
+    pub(crate) fn load(
+        &mut self,
+        storage: &dyn Storage,
+        path: &Path,
+        log: &dyn StorageFile,
+        log_start: u64,
+        log_len: u64,
+    ) -> Result<Option<u64>> {
+        let mut file = match IndexFile::open(storage, path) {
+            Ok(Some(file)) => file,
+            Ok(None) => return Ok(None),
+            Err(_) => {
+                let _ = storage.remove(path);
+                return Ok(None);
+            }
+        };
+        let covers_end = file.covers_end;
+        if covers_end < log_start || covers_end > log_len || log_tag(log, log_start, covers_end)? != file.log_tag {
+            drop(file);
+            let _ = storage.remove(path);
+            return Ok(None);
+        }
+        self.expiry = std::mem::take(&mut file.expiry);
+        if self.bounded() {
+            self.live = file.entry_count as usize;
+            self.live_bytes = file.live_bytes;
+            self.file = Some(file);
+            return Ok(Some(covers_end));
+        }
+        self.memory.reserve(file.entry_count as usize);
+        let mut damaged = false;
+        let mut hasher = crc32fast::Hasher::new();
+        for entry in file.entries_from(0) {
+            let Ok((key, location)) = entry else {
+                damaged = true;
+                break;
+            };
+            hasher.update(&encode_entry(&key, location));
+            self.memory_bytes += key.len() + ENTRY_OVERHEAD;
+            self.memory.insert(key.into_boxed_slice(), Slot::new(Some(location), false));
+        }
+        if damaged || hasher.finalize() != file.entries_crc {
+            // Costs a full scan, as a missing snapshot does
+            self.memory.clear();
+            self.memory_bytes = 0;
+            self.expiry.clear();
+            drop(file);
+            let _ = storage.remove(path);
+            return Ok(None);
+        }
+        self.live = self.memory.len();
+        self.live_bytes = file.live_bytes;
+        Ok(Some(covers_end))
+    }
+
+    /// Use the index file built alongside a rewritten log, in place of any
+    /// entries so far
+    pub(crate) fn set_file(&mut self, mut file: IndexFile) {
+        self.expiry = std::mem::take(&mut file.expiry);
+        self.memory.clear();
+        self.inline.clear();
+        self.memory_bytes = 0;
+        self.live = file.entry_count as usize;
+        self.live_bytes = file.live_bytes;
+        self.file = Some(file);
+    }
+
+    /// Move the index file built for a rewritten log to `path`
+    pub(crate) fn persist(&mut self, storage: &dyn Storage, path: &Path) -> Result<()> {
+        let Some(file) = &mut self.file else { return Ok(()) };
+        storage.rename(&file.path, path)?;
+        file.path = path.to_path_buf();
+        Ok(())
+    }
+}
+
+/// Hash of the log bytes just before `end`, tying an index file that covers
+/// the log up to `end` to this log
+pub(crate) fn log_tag(log: &dyn StorageFile, log_start: u64, end: u64) -> io::Result<u64> {
+    let from = end.saturating_sub(LOG_TAG_BYTES).max(log_start);
+    let mut tail = vec![0u8; (end - from) as usize];
+    log.read_exact_at(&mut tail, from)?;
+    Ok(twox_hash::XxHash64::oneshot(end, &tail))
+}
+
+fn in_range(key: &[u8], lower: Bound<&[u8]>, upper: Option<&[u8]>) -> bool {
+    let above = match lower {
+        Bound::Included(lower) => key >= lower,
+        Bound::Excluded(lower) => key > lower,
+        Bound::Unbounded => true,
+    };
+    above && upper.is_none_or(|upper| key < upper)
+}
+
+/// Merge of two key-sorted streams; on equal keys the in-memory entry wins
+struct Merge<M: Iterator, F: Iterator> {
+    memory: Peekable<M>,
+    file: Peekable<F>,
+}
+
+type MergeItem = Result<(Vec<u8>, Option<Location>)>;
+
+impl<M: Iterator<Item = MergeItem>, F: Iterator<Item = MergeItem>> Iterator for Merge<M, F> {
+    type Item = MergeItem;
+
+    fn next(&mut self) -> Option<MergeItem> {
+        let order = match (self.memory.peek(), self.file.peek()) {
+            (None, None) => return None,
+            (Some(Ok((memory_key, _))), Some(Ok((file_key, _)))) => memory_key.cmp(file_key),
+            // A read error is passed on as soon as it is reached
+            (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+            (Some(_), _) => Ordering::Less,
+        };
+        match order {
+            Ordering::Less => self.memory.next(),
+            Ordering::Equal => {
+                self.file.next();
+                self.memory.next()
+            }
+            Ordering::Greater => self.file.next(),
+        }
+    }
+}
+
+/// Where lookups that miss the in-memory entries go
+pub(crate) struct IndexFile {
+    file: Box<dyn StorageFile>,
+    path: PathBuf,
+    /// Every SPARSE_EVERY-th key with the position of its entry
+    sparse: Vec<(Vec<u8>, u64)>,
+    bloom: BloomFilter,
+    entries_len: u64,
+    entry_count: u64,
+    covers_end: u64,
+    log_tag: u64,
+    /// Sum of the entries' record lengths
+    live_bytes: u64,
+    entries_crc: u32,
+    /// Until the index takes it over
+    expiry: HashMap<Box<[u8]>, u64>,
+}
+
+impl IndexFile {
+    fn open(storage: &dyn Storage, path: &Path) -> Result<Option<Self>> {
+        let file = match storage.open(path, false) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.len()?;
+        if len < TRAILER_SIZE as u64 {
+            bail!("index file truncated");
+        }
+        let mut trailer = [0u8; TRAILER_SIZE];
+        file.read_exact_at(&mut trailer, len - TRAILER_SIZE as u64)?;
+        if !trailer.starts_with(&INDEX_MAGIC) {
+            bail!("index file magic missing");
+        }
+        let field = |i: usize| u64::from_le_bytes(trailer[4 + i * 8..12 + i * 8].try_into().unwrap());
+        let (entries_len, sparse_len, bloom_len, expiry_len) = (field(0), field(1), field(2), field(3));
+        let meta_len = sparse_len.checked_add(bloom_len).and_then(|n| n.checked_add(expiry_len));
+        if meta_len.and_then(|n| n.checked_add(entries_len)) != Some(len - TRAILER_SIZE as u64) {
+            bail!("index file sizes do not add up");
+        }
+
+        let mut meta = vec![0u8; (sparse_len + bloom_len + expiry_len) as usize];
+        file.read_exact_at(&mut meta, entries_len)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&meta);
+        hasher.update(&trailer[..TRAILER_SIZE - 4]);
+        if hasher.finalize() != u32::from_le_bytes(trailer[TRAILER_SIZE - 4..].try_into().unwrap()) {
+            bail!("index file CRC mismatch");
+        }
+        let (mut sparse_buf, rest) = meta.split_at(sparse_len as usize);
+        let (bloom_buf, mut expiry_buf) = rest.split_at(bloom_len as usize);
+        let mut sparse = Vec::new();
+        while !sparse_buf.is_empty() {
+            let key_len = sparse_buf.get(..4).map_or(0, |len| u32::from_le_bytes(len.try_into().unwrap()) as usize);
+            let (key, pos) = sparse_buf
+                .get(4..4 + key_len)
+                .zip(sparse_buf.get(4 + key_len..12 + key_len))
+                .ok_or_else(|| anyhow!("index file key sample truncated"))?;
+            sparse.push((key.to_vec(), u64::from_le_bytes(pos.try_into().unwrap())));
+            sparse_buf = &sparse_buf[12 + key_len..];
+        }
+        let mut expiry = HashMap::new();
+        while !expiry_buf.is_empty() {
+            let key_len = expiry_buf.get(..4).map_or(0, |len| u32::from_le_bytes(len.try_into().unwrap()) as usize);
+            let (key, expires_at) = expiry_buf
+                .get(4..4 + key_len)
+                .zip(expiry_buf.get(4 + key_len..12 + key_len))
+                .ok_or_else(|| anyhow!("index file expiry times truncated"))?;
+            expiry.insert(key.into(), u64::from_le_bytes(expires_at.try_into().unwrap()));
+            expiry_buf = &expiry_buf[12 + key_len..];
+        }
+
+        Ok(Some(Self {
+            file,
+            path: path.to_path_buf(),
+            sparse,
+            bloom: BloomFilter::decode(bloom_buf)?,
+            entries_len,
+            entry_count: field(4),
+            covers_end: field(5),
+            log_tag: field(6),
+            live_bytes: field(7),
+            entries_crc: u32::from_le_bytes(trailer[TRAILER_SIZE - 8..TRAILER_SIZE - 4].try_into().unwrap()),
+            expiry,
+        }))
+    }
+
+    /// The span of entries that holds `key` if the file has it
+    fn block_for(&self, key: &[u8]) -> (u64, u64) {
+        let i = self.sparse.partition_point(|(sampled, _)| sampled.as_slice() <= key);
+        let start = i.checked_sub(1).map_or(0, |i| self.sparse[i].1);
+        let end = self.sparse.get(i).map_or(self.entries_len, |&(_, pos)| pos);
+        (start, end)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Location>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let (start, end) = self.block_for(key);
+        let mut block = vec![0u8; (end - start) as usize];
+        self.file.read_exact_at(&mut block, start).context("reading index file")?;
+
+        let mut rest = block.as_slice();
+        while let Some((entry_key, location, tail)) = parse_entry(rest) {
+            match entry_key.cmp(key) {
+                Ordering::Less => rest = tail,
+                Ordering::Equal => return Ok(Some(location)),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stream the entries from `pos` on
+    fn entries_from(&self, pos: u64) -> impl Iterator<Item = Result<(Vec<u8>, Location)>> + '_ {
+        let end = self.entries_len;
+        let mut reader = BufReader::with_capacity(64 * 1024, ReadAt { file: &*self.file, pos, end });
+        let mut pos = pos;
+        std::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let entry = read_entry(&mut reader, end - pos);
+            match &entry {
+                Ok((key, _)) => pos += 16 + key.len() as u64,
+                // The rest cannot be told apart from garbage
+                Err(_) => pos = end,
+            }
+            Some(entry.context("reading index file"))
+        })
+    }
+}
+
+/// The next entry of `reader`, which has `left` bytes of entries left.
+/// Entries are not checksummed, so a key length running past them is
+/// damage, and no key is allocated for it.
+fn read_entry(reader: &mut impl Read, left: u64) -> Result<(Vec<u8>, Location)> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let key_len = u32::from_le_bytes(len) as u64;
+    if key_len + 16 > left {
+        bail!("entry with a key of {} bytes runs past the {} bytes of entries left", key_len, left);
+    }
+    let mut key = vec![0u8; key_len as usize];
+    let mut location = [0u8; 12];
+    reader.read_exact(&mut key)?;
+    reader.read_exact(&mut location)?;
+    Ok((key, decode_location(&location)))
+}
+
+/// The entry at the start of `buf`, and the bytes after it
+fn parse_entry(buf: &[u8]) -> Option<(&[u8], Location, &[u8])> {
+    let key_len = u32::from_le_bytes(buf.get(..4)?.try_into().unwrap()) as usize;
+    let key = buf.get(4..4 + key_len)?;
+    let location = decode_location(buf.get(4 + key_len..16 + key_len)?);
+    Some((key, location, &buf[16 + key_len..]))
+}
+
+/// `key_len(4) | key | offset(8) | len(4)`
+fn encode_entry(key: &[u8], location: Location) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + key.len());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&location.offset.to_le_bytes());
+    buf.extend_from_slice(&location.len.to_le_bytes());
+    buf
+}
+
+/// `offset(8) | len(4)`
+fn decode_location(buf: &[u8]) -> Location {
+    Location {
+        offset: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+        len: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+    }
+}
+
+/// Builds an index file from entries added in key order, under a temporary
+/// name until it is finished
+pub(crate) struct IndexWriter {
+    out: BufWriter<Appender>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    pos: u64,
+    count: u64,
+    live_bytes: u64,
+    sparse: Vec<u8>,
+    bloom: BloomFilter,
+    expiry: Vec<u8>,
+    entries_crc: crc32fast::Hasher,
+}
+
+impl IndexWriter {
+    pub(crate) fn create(storage: &dyn Storage, path: &Path, expected_keys: usize) -> Result<Self> {
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = storage.open(&tmp_path, true)?;
+        file.set_len(0)?;
+        Ok(Self {
+            out: Appender::new(file),
+            path: path.to_path_buf(),
+            tmp_path,
+            pos: 0,
+            count: 0,
+            live_bytes: 0,
+            sparse: Vec::new(),
+            bloom: BloomFilter::new(expected_keys, 0.01),
+            expiry: Vec::new(),
+            entries_crc: crc32fast::Hasher::new(),
+        })
+    }
+
+    pub(crate) fn add(&mut self, key: &[u8], location: Location) -> Result<()> {
+        if self.count.is_multiple_of(SPARSE_EVERY) {
+            self.sparse.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            self.sparse.extend_from_slice(key);
+            self.sparse.extend_from_slice(&self.pos.to_le_bytes());
+        }
+        self.bloom.insert(key);
+        let entry = encode_entry(key, location);
+        self.entries_crc.update(&entry);
+        self.out.write_all(&entry)?;
+        self.pos += 16 + key.len() as u64;
+        self.count += 1;
+        self.live_bytes += location.len as u64;
+        Ok(())
+    }
+
+    /// Record that `key`, added or to be, expires at `expires_at`
+    pub(crate) fn add_expiry(&mut self, key: &[u8], expires_at: u64) {
+        self.expiry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.expiry.extend_from_slice(key);
+        self.expiry.extend_from_slice(&expires_at.to_le_bytes());
+    }
+
+    /// Write the sample, filter, expiry times and trailer, fsync, and
+    /// rename the file to its final name
+    pub(crate) fn finish(self, storage: &dyn Storage, covers_end: u64, log_tag: u64) -> Result<IndexFile> {
+        let path = self.path.clone();
+        let mut file = self.finish_unplaced(storage, covers_end, log_tag)?;
+        storage.rename(&file.path, &path)?;
+        file.path = path;
+        Ok(file)
+    }
+
+    /// `finish`, but leave the file under its temporary name for
+    /// `Index::persist`, for when the log it covers is not in place yet
+    pub(crate) fn finish_unplaced(mut self, storage: &dyn Storage, covers_end: u64, log_tag: u64) -> Result<IndexFile> {
+        let bloom = self.bloom.encode();
+        let mut trailer = Vec::with_capacity(TRAILER_SIZE);
+        trailer.extend_from_slice(&INDEX_MAGIC);
+        let fields = [
+            self.pos,
+            self.sparse.len() as u64,
+            bloom.len() as u64,
+            self.expiry.len() as u64,
+            self.count,
+            covers_end,
+            log_tag,
+            self.live_bytes,
+        ];
+        for field in fields {
+            trailer.extend_from_slice(&field.to_le_bytes());
+        }
+        trailer.extend_from_slice(&self.entries_crc.finalize().to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.sparse);
+        hasher.update(&bloom);
+        hasher.update(&self.expiry);
+        hasher.update(&trailer);
+        trailer.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+        self.out.write_all(&self.sparse)?;
+        self.out.write_all(&bloom)?;
+        self.out.write_all(&self.expiry)?;
+        self.out.write_all(&trailer)?;
+        Appender::finish(self.out)?.sync()?;
+        IndexFile::open(storage, &self.tmp_path)?.ok_or_else(|| anyhow!("index file vanished while being written"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EngineBuilder, MemStorage, Storage};
+    use std::path::Path;
+
+    #[test]
+    fn damaged_key_length_in_a_snapshot_falls_back_to_a_scan() {
+        let storage = MemStorage::new();
+        let open = || EngineBuilder::new("/test/data.db").storage(storage.clone()).open().unwrap();
+        let mut engine = open();
+        for i in 0..100u32 {
+            engine.put(i.to_be_bytes().to_vec(), vec![1; 10]).unwrap();
+        }
+        drop(engine);
+
+        // The first entry's key length, which the CRC does not cover
+        let index = Path::new("/test/data.db.index");
+        storage.open(index, false).unwrap().write_at(&u32::MAX.to_le_bytes(), 0).unwrap();
+        let engine = open();
+        assert_eq!(engine.len(), 100);
+        assert_eq!(engine.get(&7u32.to_be_bytes()).unwrap(), Some(vec![1; 10]));
+    }
+
+    #[test]
+    fn damaged_entry_in_a_snapshot_fails_its_crc_and_falls_back_to_a_scan() {
+        let storage = MemStorage::new();
+        let open = || EngineBuilder::new("/test/data.db").storage(storage.clone()).open().unwrap();
+        let mut engine = open();
+        for i in 0..100u32 {
+            engine.put(i.to_be_bytes().to_vec(), vec![i as u8; 10]).unwrap();
+        }
+        drop(engine);
+
+        // The low byte of the first entry's offset: after its 4-byte key
+        // length and 4-byte key
+        let index = Path::new("/test/data.db.index");
+        let mut file = storage.open(index, false).unwrap();
+        let mut byte = [0u8; 1];
+        file.read_exact_at(&mut byte, 8).unwrap();
+        file.write_at(&[byte[0] ^ 0x10], 8).unwrap();
+        drop(file);
+        let mut engine = open();
+        assert_eq!(engine.len(), 100);
+        for i in 0..100u32 {
+            assert_eq!(engine.get(&i.to_be_bytes()).unwrap(), Some(vec![i as u8; 10]));
+        }
+        engine.compact().unwrap();
+        assert_eq!(engine.get(&0u32.to_be_bytes()).unwrap(), Some(vec![0; 10]));
+    }
+}
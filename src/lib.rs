@@ -1,5 +1,78 @@
 pub mod record;
 pub mod engine;
+pub mod builder;
+pub mod error;
+pub mod compression;
+pub mod checksum;
+pub mod format;
+pub mod segment;
+pub mod scrub;
+pub mod tail;
+pub mod bloom;
+mod index;
+mod lsm;
+pub mod compaction;
+pub mod archive;
+pub mod memtable;
+pub mod cache;
+pub mod namespace;
+pub mod manager;
+pub mod storage;
+pub mod fault;
+#[cfg(test)]
+mod sim;
+mod vlog;
+mod ratelimit;
+mod slow;
+mod histogram;
+mod watch;
+mod syncer;
+mod adaptive;
+mod snapshot;
+mod sst;
+mod cursor;
+#[cfg(feature = "encryption")]
+mod encryption;
+pub mod ffi;
+pub mod codec;
+#[cfg(feature = "serde")]
+pub mod typed;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "s3")]
+pub mod s3;
 
-pub use record::Record;
-pub use engine::{Engine, SyncMode, IoMode};
\ No newline at end of file
+pub use record::{Record, RecordKind, RecordRef, ScanItem, Scanner, MAX_KEY_LEN, MAX_VAL_LEN};
+pub use engine::{Engine, EngineKind, SyncMode, IoMode, RecoveryMode, ReadConsistency, VerifyReport, PutOptions, ReadOptions, EngineStats, SpaceReport, SpaceBand, RangeSize};
+pub use builder::{EngineBuilder, RecoveryProgress};
+pub use error::Error;
+pub use compression::Compression;
+pub use checksum::Checksum;
+pub use format::Format;
+pub use segment::{verify_sealed, SegmentFooter};
+pub use scrub::{ScrubOptions, ScrubReport};
+pub use tail::LogTailer;
+pub use bloom::BloomFilter;
+pub use cache::{CacheStats, BLOCK_SIZE};
+pub use compaction::{CompactionStats, CompactionStrategy, Leveled, SizeTiered, TableInfo};
+pub use archive::{ArchiveMode, ArchivePolicy};
+pub use memtable::{BTreeMemtable, Memtable, SkipListMemtable};
+pub use vlog::GcReport;
+pub use ratelimit::RateLimit;
+pub use slow::{SlowOp, SlowOpKind};
+pub use histogram::LatencySnapshot;
+pub use watch::{ChangeEvent, ChangeOp};
+pub use syncer::SyncHandle;
+pub use sst::SstOptions;
+pub use cursor::{Cursor, Page};
+pub use namespace::{Namespace, NamespaceStats};
+pub use manager::{SharedEngine, StoreManager};
+pub use storage::{FsStorage, MemStorage, Storage, StorageFile};
+pub use fault::{Fault, FaultyStorage};
+pub use codec::{KeyCodec, ValueCodec, Store, Raw, Utf8};
+#[cfg(feature = "serde")]
+pub use codec::{Json, Bincode};
+#[cfg(feature = "serde")]
+pub use typed::TypedStore;
+#[cfg(feature = "s3")]
+pub use s3::{S3Client, S3Config};
\ No newline at end of file
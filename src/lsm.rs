@@ -0,0 +1,1202 @@
+//! Memtable and sorted table files, for `EngineKind::Lsm`
+//!
+//! Writes still go to the log first, which serves as the write-ahead log,
+//! and then into a sorted in-memory memtable. Once the memtable outgrows
+//! `EngineBuilder::memtable_size` it is written out as an immutable table
+//! file, sorted by key, and the log starts over empty. Reads check the
+//! memtable, then the tables from newest to oldest; a bloom filter per
+//! table keeps most lookups of keys a table does not hold off its file.
+//!
+//! After each flush the `CompactionStrategy` picks tables to merge. A
+//! merge keeps only the newest value of each key and drops tombstones that
+//! no table it leaves behind could still hold an older value for.
+//!
+//! `Engine::delete_range` writes one range tombstone instead of a
+//! tombstone per key. It turns the keys in its range that the memtable
+//! holds into tombstones and hides those of every table existing so far,
+//! that is with a lower sequence number than the next one. Tables flushed
+//! or merged later only hold values written after it, or none of the
+//! hidden ones, so a range tombstone is dropped once the last table from
+//! before it is merged away. Until then the manifest keeps it.
+//!
+//! Tables live in a directory next to the log (`<log>.tables/`). The
+//! `MANIFEST` file there names the tables in use; it is replaced
+//! atomically, after the table it adds is durable and before the log is
+//! reset, so a crash in between replays records into the memtable that a
+//! table already holds, which is harmless. Files the manifest does not name
+//! are left over from an interrupted flush or compaction and are removed
+//! at open.
+//!
+//! A table holds encoded records, so the log's checksum, compression and
+//! encryption settings apply to tables too:
+//!
+//! ```text
+//! header:  Format::encode_header, possibly empty
+//! records: record frames sorted by key, tombstones included
+//! sparse:  { key_len(4) | key | offset(8) }*  first key of each ~4KB block
+//! bloom:   BloomFilter::encode
+//! trailer: magic "MKVT"(4) | records_end(8) | sparse_len(8) | bloom_len(8)
+//!          | record_count(8) | crc32(4)
+//!
+//! MANIFEST: magic "MKVM"(4) | next_seq(8) | live_keys(8) | table_count(4)
+//!           | { seq(8) | level(4) }* in lookup order
+//!           | [ range_count(4) | { before(8) | start_len(4) | start
+//!           | end_len(4) | end }* ]  with range tombstones or archived tables
+//!           | [ archived_count(4) | seq(8)* ]  only with archived tables
+//!           | crc32(4)
+//! ```
+//!
+//! Tables named in the archived section are kept in the directory of the
+//! `ArchivePolicy` instead, or as well, and in its object store with the
+//! `s3` feature; see the `archive` module.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, ArchiveMode, ArchivePolicy};
+use crate::bloom::BloomFilter;
+use crate::compaction::{CompactionRound, CompactionStats, CompactionStrategy, CompactionTask, TableInfo};
+use crate::compression::Compression;
+use crate::engine::{link_or_copy, read_exact_at, sync_parent_dir};
+use crate::format::Format;
+use crate::memtable::Memtable;
+use crate::ratelimit::RateLimiter;
+use crate::record::{Record, RecordKind};
+
+const TABLE_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x54]; // "MKVT"
+const MANIFEST_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x4D]; // "MKVM"
+const TRAILER_SIZE: usize = 4 + 4 * 8 + 4;
+const MANIFEST: &str = "MANIFEST";
+/// A new block, and so a sparse index entry, starts after this many bytes
+const BLOCK_TARGET: u64 = 4096;
+/// Compactions run after one flush at most; the rest wait for the next
+const MAX_ROUNDS_PER_FLUSH: usize = 16;
+
+/// A key and its value, or `None` for a tombstone
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Creates an empty memtable
+pub(crate) type NewMemtable = fn() -> Box<dyn Memtable>;
+
+/// Picks the compression for a value of the given length
+pub(crate) type CompressionFor<'a> = &'a dyn Fn(usize) -> Compression;
+
+pub(crate) struct Lsm {
+    dir: PathBuf,
+    /// Writes since the last flush, tombstones included
+    memtable: Box<dyn Memtable>,
+    new_memtable: NewMemtable,
+    memtable_size: usize,
+    /// In lookup order: by level, newest first within level 0
+    tables: Vec<Table>,
+    next_seq: u64,
+    /// Live keys across the memtable and the tables
+    live: usize,
+    /// Expected to be few: every table entry read is checked against each
+    ranges: Vec<RangeTombstone>,
+    strategy: Box<dyn CompactionStrategy>,
+    stats: CompactionStats,
+    archive: Option<ArchivePolicy>,
+}
+
+impl Lsm {
+    /// Open the tables in `dir`, creating it if needed, and those in the
+    /// archive. `keys` carries the encryption keys to read them with.
+    pub(crate) fn open(
+        dir: PathBuf,
+        new_memtable: NewMemtable,
+        memtable_size: usize,
+        strategy: Box<dyn CompactionStrategy>,
+        archive: Option<ArchivePolicy>,
+        keys: &Format,
+    ) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(buf) => decode_manifest(&buf).with_context(|| format!("reading {}", dir.join(MANIFEST).display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut tables = Vec::new();
+        // Files in use, local or archived
+        let mut named = Vec::new();
+        for &(seq, level) in &manifest.tables {
+            let local = table_path(&dir, seq);
+            let archived = manifest.archived.contains(&seq);
+            let path = match &archive {
+                _ if !archived => local,
+                Some(policy) if policy.mode == ArchiveMode::Copy && local.exists() => local,
+                Some(policy) => table_path(&policy.dir, seq),
+                None => bail!("table {} is archived, but no archive is configured", seq),
+            };
+            if archived && !path.exists() {
+                if let Some(policy) = &archive {
+                    policy.fetch(&path).with_context(|| format!("fetching archived table {}", seq))?;
+                }
+            }
+            let mut table = Table::open(&path, seq, level, keys.keys_only())?;
+            table.archived = archived;
+            tables.push(table);
+            named.push(path);
+            if archived {
+                named.extend(archive.iter().map(|policy| table_path(&policy.dir, seq)));
+            }
+        }
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.file_name() != Some(MANIFEST.as_ref()) && !named.contains(&path) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+        // Copies of tables merged away, or cut short, before the manifest
+        // named them archived
+        if let Some(policy) = archive.as_ref().filter(|policy| policy.dir.is_dir()) {
+            for entry in fs::read_dir(&policy.dir)? {
+                let path = entry?.path();
+                let table_file = path.extension().is_some_and(|ext| ext == "sst" || ext == "tmp");
+                if table_file && !named.contains(&path) {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        Ok(Self {
+            dir,
+            memtable: new_memtable(),
+            new_memtable,
+            memtable_size,
+            tables,
+            next_seq: manifest.next_seq,
+            live: manifest.live,
+            ranges: manifest.ranges,
+            strategy,
+            stats: CompactionStats::default(),
+            archive,
+        })
+    }
+
+    /// Link the tables into `dir`, which must not exist, archived ones
+    /// included, and copy the manifest naming them, for
+    /// `Engine::checkpoint`
+    pub(crate) fn checkpoint(&self, dir: &Path) -> Result<()> {
+        fs::create_dir(dir)?;
+        for table in &self.tables {
+            link_or_copy(&table.path, &table_path(dir, table.seq))?;
+        }
+        // The copy has every table in its own directory
+        match fs::read(self.dir.join(MANIFEST)) {
+            Ok(buf) => {
+                let manifest = Manifest { archived: Vec::new(), ..decode_manifest(&buf)? };
+                write_manifest_in(dir, &manifest)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => sync_parent_dir(&dir.join(MANIFEST))?,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// Whether a `MANIFEST` in `dir` says an LSM engine wrote there
+    pub(crate) fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST).exists()
+    }
+
+    /// The live value of `key`
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.map(<[u8]>::to_vec));
+        }
+        for table in &self.tables {
+            if let Some(value) = table.get(key)? {
+                return Ok(if self.hidden(table, key) { None } else { value });
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether a range tombstone deleted `key` after `table` was written
+    fn hidden(&self, table: &Table, key: &[u8]) -> bool {
+        self.ranges.iter().any(|range| range.hides(table.seq, key))
+    }
+
+    /// Set `key` to `value`, or delete it for `None`, in the memtable.
+    /// Keeps the live count exact, so it looks the key up first.
+    pub(crate) fn apply(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        let was_live = self.get(&key)?.is_some();
+        match (was_live, value.is_some()) {
+            (false, true) => self.live += 1,
+            (true, false) => self.live -= 1,
+            _ => {}
+        }
+        self.memtable.insert(key, value);
+        Ok(())
+    }
+
+    /// Whether any key from `start` up to `end` is live
+    pub(crate) fn any_live_in(&self, start: &[u8], end: &[u8]) -> Result<bool> {
+        Ok(self.entries_from(start, false).next().transpose()?.is_some_and(|(key, _)| key.as_slice() < end))
+    }
+
+    /// Delete every key from `start` up to `end`, exclusive, calling
+    /// `deleted` with each live one, and return how many there were.
+    /// Counting them keeps the live count exact, so this reads every key in
+    /// the range, without values.
+    pub(crate) fn apply_range(&mut self, start: &[u8], end: &[u8], mut deleted: impl FnMut(&[u8])) -> Result<usize> {
+        let mut count = 0;
+        for entry in self.entries_from(start, false) {
+            let (key, _) = entry?;
+            if key.as_slice() >= end {
+                break;
+            }
+            deleted(&key);
+            count += 1;
+        }
+        let in_memtable: Vec<Vec<u8>> =
+            self.memtable.range_from(start).map(|(key, _)| key).take_while(|key| *key < end).map(<[u8]>::to_vec).collect();
+        for key in in_memtable {
+            self.memtable.insert(key, None);
+        }
+        if !self.tables.is_empty() {
+            self.ranges.push(RangeTombstone { start: start.to_vec(), end: end.to_vec(), before: self.next_seq });
+        }
+        self.live -= count;
+        Ok(count)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.live
+    }
+
+    /// The memtable has outgrown its size and should be flushed
+    pub(crate) fn memtable_full(&self) -> bool {
+        self.memtable.size_bytes() >= self.memtable_size
+    }
+
+    /// Nothing was written since the last flush, range tombstones included
+    pub(crate) fn memtable_is_empty(&self) -> bool {
+        self.memtable.is_empty() && !self.ranges.iter().any(|range| range.before == self.next_seq)
+    }
+
+    /// The tables, in lookup order
+    pub(crate) fn tables(&self) -> Vec<TableInfo> {
+        self.tables.iter().map(Table::info).collect()
+    }
+
+    /// The archived tables, in lookup order
+    pub(crate) fn archived_tables(&self) -> Vec<TableInfo> {
+        self.tables.iter().filter(|table| table.archived).map(Table::info).collect()
+    }
+
+    pub(crate) fn stats(&self) -> CompactionStats {
+        self.stats.clone()
+    }
+
+    /// Estimated bytes and entries from `start` up to `end`, exclusive:
+    /// the memtable's key and value bytes there, plus each table's blocks
+    /// that overlap the range and its records prorated to them
+    pub(crate) fn approximate_size(&self, start: &[u8], end: &[u8]) -> (u64, u64) {
+        let (mut bytes, mut entries) = (0, 0);
+        for (key, value) in self.memtable.range_from(start).take_while(|(key, _)| *key < end) {
+            bytes += (key.len() + value.map_or(0, <[u8]>::len)) as u64;
+            entries += 1;
+        }
+        for table in &self.tables {
+            let (table_bytes, table_entries) = table.approximate_size(start, end);
+            bytes += table_bytes;
+            entries += table_entries;
+        }
+        (bytes, entries)
+    }
+
+    /// Live pairs with keys from `start` on, by key; with `values` false
+    /// the values are left empty and not decoded
+    pub(crate) fn entries_from<'a>(&'a self, start: &[u8], values: bool) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        self.merged(start, values).filter_map(|entry| match entry {
+            Ok((key, value)) => Some(Ok((key, value?))),
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Sorted merge of the memtable and every table from `start` on, newest
+    /// version of each key only, tombstones included
+    fn merged<'a>(&'a self, start: &[u8], values: bool) -> Merge<'a> {
+        let memtable = self
+            .memtable
+            .range_from(start)
+            .map(move |(key, value)| Ok((key.to_vec(), value.map(|v| if values { v.to_vec() } else { Vec::new() }))));
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>> = vec![Box::new(memtable)];
+        for table in &self.tables {
+            sources.push(self.visible_entries(table, start, values));
+        }
+        Merge::new(sources)
+    }
+
+    /// `table`'s entries from `start` on, less those a range tombstone
+    /// hides. Any older entry of the same key is hidden as well, so
+    /// leaving them out of a merge never uncovers one.
+    fn visible_entries<'a>(&'a self, table: &'a Table, start: &[u8], values: bool) -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+        let entries = table.entries_from(start, values);
+        if self.ranges.is_empty() {
+            return Box::new(entries);
+        }
+        Box::new(entries.filter(move |entry| !matches!(entry, Ok((key, _)) if self.hidden(table, key))))
+    }
+
+    /// Write the memtable out as the newest table and empty it, then run
+    /// the compactions the strategy asks for, paced by `limiter`. `format`
+    /// is what the records are encoded in.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(entries = self.memtable.len())))]
+    pub(crate) fn flush(&mut self, format: &Format, compression_for: CompressionFor, limiter: Option<&RateLimiter>) -> Result<()> {
+        if self.memtable_is_empty() {
+            return Ok(());
+        }
+        // With only range tombstones to keep, the manifest takes them over
+        // from the log
+        if !self.memtable.is_empty() {
+            let seq = self.next_seq;
+            let mut writer = TableWriter::create(&self.dir, seq, 0, format, self.memtable.len())?;
+            for (key, value) in self.memtable.range_from(&[]) {
+                writer.add(key, value, format, compression_for)?;
+            }
+            let table = writer.finish(format.keys_only())?;
+            self.next_seq += 1;
+            self.tables.insert(0, table);
+        }
+        self.write_manifest()?;
+        self.memtable = (self.new_memtable)();
+
+        for _ in 0..MAX_ROUNDS_PER_FLUSH {
+            let Some(task) = self.strategy.pick(&self.tables()) else {
+                return self.archive_settled();
+            };
+            self.run(&task, format, compression_for, limiter)?;
+        }
+        Ok(())
+    }
+
+    /// Archive the tables the policy picks, once the strategy has nothing
+    /// left to merge: copy them, mark them in the manifest, then with
+    /// `ArchiveMode::Move` switch to the copy and delete the local file
+    fn archive_settled(&mut self) -> Result<()> {
+        let Some(policy) = &self.archive else { return Ok(()) };
+        let picked: Vec<usize> = (0..self.tables.len())
+            .filter(|&i| !self.tables[i].archived && policy.picks(self.tables[i].level, self.tables[i].size))
+            .collect();
+        if picked.is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(&policy.dir)?;
+        for &i in &picked {
+            let table = &self.tables[i];
+            let copy = table_path(&policy.dir, table.seq);
+            archive::copy_durably(&table.path, &copy)?;
+            policy.upload(&copy)?;
+        }
+        sync_parent_dir(&policy.dir.join(archive::CATALOG))?;
+        for &i in &picked {
+            self.tables[i].archived = true;
+        }
+        self.write_manifest()?;
+        self.write_catalog()?;
+        #[cfg(feature = "tracing")]
+        tracing::info!(tables = picked.len(), dir = %policy.dir.display(), "tables archived");
+        if policy.mode == ArchiveMode::Move {
+            for &i in &picked {
+                let table = &mut self.tables[i];
+                let local = std::mem::replace(&mut table.path, table_path(&policy.dir, table.seq));
+                table.file = File::open(&table.path).with_context(|| format!("opening table {}", table.path.display()))?;
+                let _ = fs::remove_file(&local);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the archive's catalog with one describing the archived
+    /// tables
+    fn write_catalog(&self) -> Result<()> {
+        let Some(policy) = &self.archive else { return Ok(()) };
+        archive::write_catalog(&policy.dir, &self.archived_tables())?;
+        policy.upload(&policy.dir.join(archive::CATALOG))
+    }
+
+    /// Merge every table into one at the deepest level, dropping
+    /// overwritten values and tombstones, range tombstones included.
+    /// Records are re-encoded in `format`. Expects an empty memtable.
+    pub(crate) fn compact(&mut self, format: &Format, compression_for: CompressionFor, limiter: Option<&RateLimiter>) -> Result<()> {
+        let Some(deepest) = self.tables.iter().map(|table| table.level).max() else { return Ok(()) };
+        let task = CompactionTask {
+            inputs: self.tables.iter().map(|table| table.seq).collect(),
+            output_level: deepest,
+            max_table_size: None,
+        };
+        self.run(&task, format, compression_for, limiter)?;
+        self.archive_settled()
+    }
+
+    /// Merge the tables `task` names into new ones, put those in their
+    /// place and record the round
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(inputs = task.inputs.len())))]
+    fn run(
+        &mut self,
+        task: &CompactionTask,
+        format: &Format,
+        compression_for: CompressionFor,
+        limiter: Option<&RateLimiter>,
+    ) -> Result<()> {
+        let mut positions = task
+            .inputs
+            .iter()
+            .map(|&id| self.tables.iter().position(|table| table.seq == id))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("compaction task names a table that does not exist: {:?}", task.inputs))?;
+        positions.sort_unstable();
+        positions.dedup();
+        let Some(&first) = positions.first() else { bail!("compaction task has no inputs") };
+
+        let inputs: Vec<&Table> = positions.iter().map(|&i| &self.tables[i]).collect();
+        // Tables left behind that may hold values older than the inputs'
+        let behind: Vec<&Table> =
+            (first..self.tables.len()).filter(|i| !positions.contains(i)).map(|i| &self.tables[i]).collect();
+        let expected_keys = inputs.iter().map(|table| table.record_count as usize).sum::<usize>();
+        let expected_keys = match task.max_table_size {
+            Some(max) => {
+                let input_bytes = inputs.iter().map(|table| table.size).sum::<u64>().max(1);
+                (expected_keys as u64 * max).div_ceil(input_bytes).min(expected_keys as u64) as usize
+            }
+            None => expected_keys,
+        };
+
+        let sources = inputs.iter().map(|table| self.visible_entries(table, &[], true)).collect();
+        let mut next_seq = self.next_seq;
+        let mut outputs = Vec::new();
+        let mut writer: Option<TableWriter> = None;
+        for entry in Merge::new(sources) {
+            let (key, value) = entry?;
+            if value.is_none() && !behind.iter().any(|table| table.covers(&key)) {
+                continue;
+            }
+            let out = match &mut writer {
+                Some(out) => out,
+                None => {
+                    next_seq += 1;
+                    writer.insert(TableWriter::create(&self.dir, next_seq - 1, task.output_level, format, expected_keys)?)
+                }
+            };
+            let start = out.pos;
+            out.add(&key, value.as_deref(), format, compression_for)?;
+            if let Some(limiter) = limiter {
+                limiter.acquire(out.pos - start);
+            }
+            #[cfg(feature = "metrics")]
+            crate::metrics::METRICS.compaction_bytes.inc_by(out.pos - start);
+            if task.max_table_size.is_some_and(|max| out.pos >= max) {
+                outputs.push(writer.take().unwrap().finish(format.keys_only())?);
+            }
+        }
+        if let Some(out) = writer {
+            outputs.push(out.finish(format.keys_only())?);
+        }
+
+        let round = CompactionRound {
+            input_tables: inputs.len(),
+            output_tables: outputs.len(),
+            output_level: task.output_level,
+            bytes_read: inputs.iter().map(|table| table.size).sum(),
+            bytes_written: outputs.iter().map(|table| table.size).sum(),
+        };
+        self.next_seq = next_seq;
+        let mut old = Vec::new();
+        for &i in positions.iter().rev() {
+            old.push(self.tables.remove(i));
+        }
+        self.tables.splice(first..first, outputs);
+        self.tables.sort_by_key(|table| table.level);
+        let oldest = self.tables.iter().map(|table| table.seq).min();
+        self.ranges.retain(|range| oldest.is_some_and(|seq| seq < range.before));
+        self.write_manifest()?;
+        let mut unarchived = false;
+        for table in old {
+            let _ = fs::remove_file(&table.path);
+            if table.archived {
+                // Whichever of the local file and the copy is left
+                let _ = fs::remove_file(table_path(&self.dir, table.seq));
+                if let Some(policy) = &self.archive {
+                    let copy = table_path(&policy.dir, table.seq);
+                    let _ = fs::remove_file(&copy);
+                    policy.remove_remote(&copy);
+                }
+                unarchived = true;
+            }
+        }
+        if unarchived {
+            self.write_catalog()?;
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            input_tables = round.input_tables,
+            output_tables = round.output_tables,
+            output_level = round.output_level,
+            bytes_read = round.bytes_read,
+            bytes_written = round.bytes_written,
+            "compaction round"
+        );
+        self.stats.record(round);
+        Ok(())
+    }
+
+    /// Replace the manifest with one naming the current tables
+    fn write_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_seq: self.next_seq,
+            live: self.live,
+            tables: self.tables.iter().map(|table| (table.seq, table.level)).collect(),
+            ranges: self.ranges.clone(),
+            archived: self.tables.iter().filter(|table| table.archived).map(|table| table.seq).collect(),
+        };
+        write_manifest_in(&self.dir, &manifest)
+    }
+
+    /// Check every record of every table, returning how many were checked
+    /// and the paths of tables with a record that failed
+    pub(crate) fn verify(&self) -> (usize, Vec<PathBuf>) {
+        let mut checked = 0;
+        let mut bad = Vec::new();
+        for table in &self.tables {
+            let mut ok = 0;
+            for entry in table.entries_from(&[], true) {
+                ok += entry.is_ok() as u64;
+            }
+            checked += ok as usize;
+            if ok != table.record_count {
+                bad.push(table.path.clone());
+            }
+        }
+        (checked, bad)
+    }
+}
+
+/// Replace the manifest in `dir` with `manifest`
+fn write_manifest_in(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let mut buf = Vec::with_capacity(28 + manifest.tables.len() * 12);
+    buf.extend_from_slice(&MANIFEST_MAGIC);
+    buf.extend_from_slice(&manifest.next_seq.to_le_bytes());
+    buf.extend_from_slice(&(manifest.live as u64).to_le_bytes());
+    buf.extend_from_slice(&(manifest.tables.len() as u32).to_le_bytes());
+    for &(seq, level) in &manifest.tables {
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&level.to_le_bytes());
+    }
+    // Left out when empty, so the manifest reads the same to a build
+    // without range deletes or archiving
+    if !manifest.ranges.is_empty() || !manifest.archived.is_empty() {
+        buf.extend_from_slice(&(manifest.ranges.len() as u32).to_le_bytes());
+        for range in &manifest.ranges {
+            buf.extend_from_slice(&range.before.to_le_bytes());
+            for key in [&range.start, &range.end] {
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(key);
+            }
+        }
+    }
+    if !manifest.archived.is_empty() {
+        buf.extend_from_slice(&(manifest.archived.len() as u32).to_le_bytes());
+        for seq in &manifest.archived {
+            buf.extend_from_slice(&seq.to_le_bytes());
+        }
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let path = dir.join(MANIFEST);
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+    let mut file = create_private(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_parent_dir(&path)?;
+    Ok(())
+}
+
+fn table_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:08}.sst", seq))
+}
+
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[derive(Default)]
+struct Manifest {
+    next_seq: u64,
+    live: usize,
+    /// Sequence number and level of each table, in lookup order
+    tables: Vec<(u64, u32)>,
+    ranges: Vec<RangeTombstone>,
+    /// Sequence numbers of the archived tables
+    archived: Vec<u64>,
+}
+
+fn decode_manifest(buf: &[u8]) -> Result<Manifest> {
+    if buf.len() < 28 || !buf.starts_with(&MANIFEST_MAGIC) {
+        bail!("not a table manifest");
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        bail!("table manifest CRC mismatch");
+    }
+    let next_seq = u64::from_le_bytes(body[4..12].try_into().unwrap());
+    let live = u64::from_le_bytes(body[12..20].try_into().unwrap()) as usize;
+    let count = u32::from_le_bytes(body[20..24].try_into().unwrap()) as usize;
+    let Some((seqs, mut rest)) = body.get(24..).filter(|seqs| seqs.len() >= count * 12).map(|seqs| seqs.split_at(count * 12)) else {
+        bail!("table manifest has an inconsistent table count");
+    };
+    let tables = seqs
+        .chunks_exact(12)
+        .map(|t| (u64::from_le_bytes(t[..8].try_into().unwrap()), u32::from_le_bytes(t[8..].try_into().unwrap())))
+        .collect();
+    fn take<'b>(rest: &mut &'b [u8], len: usize) -> Result<&'b [u8]> {
+        let taken = rest.get(..len).ok_or_else(|| anyhow!("table manifest range tombstones or archived tables truncated"))?;
+        *rest = &rest[len..];
+        Ok(taken)
+    }
+    let mut ranges = Vec::new();
+    let mut archived = Vec::new();
+    if !rest.is_empty() {
+        let range_count = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap());
+        for _ in 0..range_count {
+            let before = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+            let start_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+            let start = take(&mut rest, start_len)?.to_vec();
+            let end_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+            let end = take(&mut rest, end_len)?.to_vec();
+            ranges.push(RangeTombstone { start, end, before });
+        }
+    }
+    if !rest.is_empty() {
+        let archived_count = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap());
+        for _ in 0..archived_count {
+            archived.push(u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap()));
+        }
+    }
+    if !rest.is_empty() {
+        bail!("table manifest has bytes after its archived tables");
+    }
+    Ok(Manifest { next_seq, live, tables, ranges, archived })
+}
+
+/// Keys from `start` up to `end`, exclusive, deleted while `before` was the
+/// next table sequence number; see the module docs
+#[derive(Debug, Clone)]
+struct RangeTombstone {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    before: u64,
+}
+
+impl RangeTombstone {
+    /// Whether it deleted `key` as held by the table numbered `seq`
+    fn hides(&self, seq: u64, key: &[u8]) -> bool {
+        seq < self.before && self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+}
+
+/// Merge of key-sorted sources, newest first; on equal keys the newest
+/// source wins and the others' entries are skipped
+struct Merge<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>,
+    heads: Vec<Option<Result<Entry>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>) -> Self {
+        let heads = sources.iter_mut().map(|source| source.next()).collect();
+        Self { sources, heads }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        let mut best: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            match head {
+                None => {}
+                // A read error is passed on as soon as it is reached
+                Some(Err(_)) => {
+                    best = Some(i);
+                    break;
+                }
+                Some(Ok((key, _))) if best.is_none_or(|b| matches!(&self.heads[b], Some(Ok((best_key, _))) if key < best_key)) => {
+                    best = Some(i);
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+        let best = best?;
+        let item = std::mem::replace(&mut self.heads[best], self.sources[best].next())?;
+        if let Ok((key, _)) = &item {
+            for i in best + 1..self.heads.len() {
+                while matches!(&self.heads[i], Some(Ok((other, _))) if other == key) {
+                    self.heads[i] = self.sources[i].next();
+                }
+            }
+        }
+        Some(item)
+    }
+}
+
+/// An immutable sorted table file
+struct Table {
+    seq: u64,
+    level: u32,
+    path: PathBuf,
+    size: u64,
+    file: File,
+    /// The table's own settings, from its header
+    format: Format,
+    /// First key and offset of every block
+    sparse: Vec<(Vec<u8>, u64)>,
+    bloom: BloomFilter,
+    records_end: u64,
+    record_count: u64,
+    last_key: Vec<u8>,
+    /// Marked archived in the manifest; `path` is the archive's copy
+    /// unless the local file is kept
+    archived: bool,
+}
+
+impl Table {
+    fn open(path: &Path, seq: u64, level: u32, mut format: Format) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("opening table {}", path.display()))?;
+        let context = || format!("reading table {}", path.display());
+        format.read_header_from(&mut file).with_context(context)?;
+        let len = file.metadata()?.len();
+        if len < TRAILER_SIZE as u64 {
+            return Err(anyhow!("table truncated")).with_context(context);
+        }
+        let mut trailer = [0u8; TRAILER_SIZE];
+        read_exact_at(&file, &mut trailer, len - TRAILER_SIZE as u64)?;
+        if !trailer.starts_with(&TABLE_MAGIC) {
+            return Err(anyhow!("table magic missing")).with_context(context);
+        }
+        let field = |i: usize| u64::from_le_bytes(trailer[4 + i * 8..12 + i * 8].try_into().unwrap());
+        let (records_end, sparse_len, bloom_len) = (field(0), field(1), field(2));
+        if records_end.checked_add(sparse_len).and_then(|n| n.checked_add(bloom_len)) != Some(len - TRAILER_SIZE as u64) {
+            return Err(anyhow!("table sizes do not add up")).with_context(context);
+        }
+
+        let mut meta = vec![0u8; (sparse_len + bloom_len) as usize];
+        read_exact_at(&file, &mut meta, records_end)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&meta);
+        hasher.update(&trailer[..TRAILER_SIZE - 4]);
+        if hasher.finalize() != u32::from_le_bytes(trailer[TRAILER_SIZE - 4..].try_into().unwrap()) {
+            return Err(anyhow!("table CRC mismatch")).with_context(context);
+        }
+        let (mut sparse_buf, bloom_buf) = meta.split_at(sparse_len as usize);
+        let mut sparse = Vec::new();
+        while !sparse_buf.is_empty() {
+            let key_len = sparse_buf.get(..4).map_or(0, |len| u32::from_le_bytes(len.try_into().unwrap()) as usize);
+            let (key, offset) = sparse_buf
+                .get(4..4 + key_len)
+                .zip(sparse_buf.get(4 + key_len..12 + key_len))
+                .ok_or_else(|| anyhow!("table block index truncated"))
+                .with_context(context)?;
+            sparse.push((key.to_vec(), u64::from_le_bytes(offset.try_into().unwrap())));
+            sparse_buf = &sparse_buf[12 + key_len..];
+        }
+
+        let mut table = Self {
+            seq,
+            level,
+            path: path.to_path_buf(),
+            size: len,
+            file,
+            format,
+            sparse,
+            bloom: BloomFilter::decode(bloom_buf).with_context(context)?,
+            records_end,
+            record_count: field(3),
+            last_key: Vec::new(),
+            archived: false,
+        };
+        if let Some(last) = table.sparse.len().checked_sub(1) {
+            table.last_key = table.decode_block(last, false)?.pop().map(|(key, _)| key).unwrap_or_default();
+        }
+        Ok(table)
+    }
+
+    fn first_key(&self) -> &[u8] {
+        self.sparse.first().map_or(&[], |(key, _)| key)
+    }
+
+    /// Whether `key` is within the table's key range
+    fn covers(&self, key: &[u8]) -> bool {
+        self.first_key() <= key && key <= self.last_key.as_slice()
+    }
+
+    fn info(&self) -> TableInfo {
+        TableInfo {
+            id: self.seq,
+            level: self.level,
+            size: self.size,
+            records: self.record_count,
+            first_key: self.first_key().to_vec(),
+            last_key: self.last_key.clone(),
+        }
+    }
+
+    /// `Some(None)` if the table holds a tombstone for `key`
+    fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let Some(block) = self.sparse.partition_point(|(first, _)| first.as_slice() <= key).checked_sub(1) else {
+            return Ok(None);
+        };
+        let (start, buf) = self.read_block(block)?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (record_key, kind, size) = Record::decode_key_with(&buf[pos..], &self.format)
+                .with_context(|| self.record_context(start + pos as u64))?;
+            match record_key.as_slice().cmp(key) {
+                Ordering::Less => pos += size,
+                Ordering::Equal if kind == RecordKind::Delete => return Ok(Some(None)),
+                Ordering::Equal => {
+                    let (record, _) = Record::decode_with(&buf[pos..], &self.format)
+                        .with_context(|| self.record_context(start + pos as u64))?;
+                    return Ok(Some(Some(record.value)));
+                }
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Bytes of the blocks holding keys from `start` up to `end`,
+    /// exclusive, from the block index alone, and the records in them as a
+    /// share of the table's by bytes
+    fn approximate_size(&self, start: &[u8], end: &[u8]) -> (u64, u64) {
+        let Some(&(_, records_start)) = self.sparse.first() else { return (0, 0) };
+        if end <= self.first_key() || start > self.last_key.as_slice() {
+            return (0, 0);
+        }
+        let first = self.sparse.partition_point(|(first, _)| first.as_slice() <= start).saturating_sub(1);
+        let last = self.sparse.partition_point(|(first, _)| first.as_slice() < end) - 1;
+        let block_end = self.sparse.get(last + 1).map_or(self.records_end, |&(_, offset)| offset);
+        let bytes = block_end - self.sparse[first].1;
+        let records = (self.record_count as u128 * bytes as u128 / (self.records_end - records_start).max(1) as u128) as u64;
+        (bytes, records.max(1))
+    }
+
+    /// Offset and bytes of block `i`
+    fn read_block(&self, i: usize) -> Result<(u64, Vec<u8>)> {
+        let start = self.sparse[i].1;
+        let end = self.sparse.get(i + 1).map_or(self.records_end, |&(_, offset)| offset);
+        let mut buf = vec![0u8; (end - start) as usize];
+        read_exact_at(&self.file, &mut buf, start).with_context(|| format!("reading table {}", self.path.display()))?;
+        Ok((start, buf))
+    }
+
+    /// Entries with keys from `start` on, by key; with `values` false only
+    /// keys are decoded and values are left empty
+    fn entries_from<'a>(&'a self, start: &[u8], values: bool) -> impl Iterator<Item = Result<Entry>> + 'a {
+        let first = self.sparse.partition_point(|(first, _)| first.as_slice() <= start).saturating_sub(1);
+        let start = start.to_vec();
+        (first..self.sparse.len())
+            .flat_map(move |i| match self.decode_block(i, values) {
+                Ok(entries) => entries.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            })
+            .filter(move |entry| !matches!(entry, Ok((key, _)) if *key < start))
+    }
+
+    fn decode_block(&self, i: usize, values: bool) -> Result<Vec<Entry>> {
+        let (start, buf) = self.read_block(i)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let context = || self.record_context(start + pos as u64);
+            let (entry, size) = if values {
+                let (record, size) = Record::decode_with(&buf[pos..], &self.format).with_context(context)?;
+                let value = (record.kind == RecordKind::Put).then_some(record.value);
+                ((record.key, value), size)
+            } else {
+                let (key, kind, size) = Record::decode_key_with(&buf[pos..], &self.format).with_context(context)?;
+                ((key, (kind == RecordKind::Put).then(Vec::new)), size)
+            };
+            entries.push(entry);
+            pos += size;
+        }
+        Ok(entries)
+    }
+
+    fn record_context(&self, offset: u64) -> String {
+        format!("record at offset {} of table {}", offset, self.path.display())
+    }
+}
+
+/// Builds a table from entries added in key order, under a temporary name
+/// until it is finished
+struct TableWriter {
+    out: BufWriter<File>,
+    seq: u64,
+    level: u32,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    pos: u64,
+    block_start: Option<u64>,
+    count: u64,
+    sparse: Vec<u8>,
+    bloom: BloomFilter,
+    /// Reused to encode each record
+    encoded: Vec<u8>,
+}
+
+impl TableWriter {
+    fn create(dir: &Path, seq: u64, level: u32, format: &Format, expected_keys: usize) -> Result<Self> {
+        let path = table_path(dir, seq);
+        let tmp_path = path.with_extension("sst.tmp");
+        let mut out = BufWriter::new(create_private(&tmp_path)?);
+        let mut format = format.clone();
+        format.set_footer_offset(None);
+        let header = format.encode_header();
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            seq,
+            level,
+            path,
+            tmp_path,
+            pos: header.len() as u64,
+            block_start: None,
+            count: 0,
+            sparse: Vec::new(),
+            bloom: BloomFilter::new(expected_keys, 0.01),
+            encoded: Vec::new(),
+        })
+    }
+
+    fn add(&mut self, key: &[u8], value: Option<&[u8]>, format: &Format, compression_for: CompressionFor) -> Result<()> {
+        let (record, compression) = match value {
+            Some(value) => (Record::new(key.to_vec(), value.to_vec()), compression_for(value.len())),
+            None => (Record::tombstone(key.to_vec()), Compression::None),
+        };
+        record.encode_into(compression, format, &mut self.encoded)?;
+        if self.block_start.is_none_or(|start| self.pos - start >= BLOCK_TARGET) {
+            self.sparse.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            self.sparse.extend_from_slice(key);
+            self.sparse.extend_from_slice(&self.pos.to_le_bytes());
+            self.block_start = Some(self.pos);
+        }
+        self.bloom.insert(key);
+        self.out.write_all(&self.encoded)?;
+        self.pos += self.encoded.len() as u64;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Write the block index, filter and trailer, fsync, and rename the
+    /// file to its final name. `keys` carries the keys to read it with.
+    fn finish(mut self, keys: Format) -> Result<Table> {
+        let bloom = self.bloom.encode();
+        let mut trailer = Vec::with_capacity(TRAILER_SIZE);
+        trailer.extend_from_slice(&TABLE_MAGIC);
+        for field in [self.pos, self.sparse.len() as u64, bloom.len() as u64, self.count] {
+            trailer.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.sparse);
+        hasher.update(&bloom);
+        hasher.update(&trailer);
+        trailer.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+        self.out.write_all(&self.sparse)?;
+        self.out.write_all(&bloom)?;
+        self.out.write_all(&trailer)?;
+        self.out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Table::open(&self.path, self.seq, self.level, keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Engine, EngineKind};
+
+    // Table files are opened by path, so these run in a temporary directory
+    // rather than on `MemStorage`
+    fn open(dir: &Path) -> Engine {
+        Engine::builder(dir.join("data.db")).kind(EngineKind::Lsm).memtable_size(4096).open().unwrap()
+    }
+
+    fn key(prefix: &str, i: usize) -> Vec<u8> {
+        format!("{}_{:04}", prefix, i).into_bytes()
+    }
+
+    fn value(i: usize, round: usize) -> Vec<u8> {
+        format!("{:04}-{}-{}", i, round, "v".repeat(100)).into_bytes()
+    }
+
+    #[test]
+    fn memtable_flushes_to_tables_that_serve_reads_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = open(dir.path());
+        for i in 0..500 {
+            engine.put(key("key", i), value(i, 0)).unwrap();
+        }
+        let tables = engine.tables();
+        assert!(!tables.is_empty());
+        let in_tables: u64 = tables.iter().map(|table| table.records).sum();
+        assert!(in_tables > 400, "only {} of 500 records flushed", in_tables);
+        drop(engine);
+
+        let engine = open(dir.path());
+        assert_eq!(engine.len(), 500);
+        for i in 0..500 {
+            assert_eq!(engine.get(&key("key", i)).unwrap(), Some(value(i, 0)));
+        }
+        assert_eq!(engine.get(b"key_0500").unwrap(), None);
+    }
+
+    #[test]
+    fn range_and_prefix_deletes_hold_across_reopen_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = open(dir.path());
+        for i in 0..200 {
+            engine.put(key("a", i), value(i, 0)).unwrap();
+            engine.put(key("b", i), value(i, 0)).unwrap();
+        }
+        assert!(!engine.tables().is_empty());
+        assert_eq!(engine.delete_range(key("a", 50)..key("a", 150)).unwrap(), 100);
+        assert_eq!(engine.delete_prefix(b"b_").unwrap(), 200);
+        // Written after the range tombstone, so not hidden by it
+        engine.put(key("a", 100), value(100, 1)).unwrap();
+
+        let check = |engine: &Engine| {
+            assert_eq!(engine.len(), 101);
+            for i in 0..200 {
+                let expected = match i {
+                    100 => Some(value(i, 1)),
+                    50..150 => None,
+                    _ => Some(value(i, 0)),
+                };
+                assert_eq!(engine.get(&key("a", i)).unwrap(), expected, "a_{:04}", i);
+                assert_eq!(engine.get(&key("b", i)).unwrap(), None, "b_{:04}", i);
+            }
+            assert_eq!(engine.scan_prefix(b"").unwrap().len(), 101);
+        };
+        check(&engine);
+        drop(engine);
+
+        let mut engine = open(dir.path());
+        check(&engine);
+        engine.compact().unwrap();
+        check(&engine);
+        // The merged table holds only what is live
+        assert_eq!(engine.tables().iter().map(|table| table.records).sum::<u64>(), 101);
+        drop(engine);
+        check(&open(dir.path()));
+    }
+
+    #[test]
+    fn compaction_merges_into_one_table_of_the_newest_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = open(dir.path());
+        for round in 0..3 {
+            for i in 0..150 {
+                engine.put(key("key", i), value(i, round)).unwrap();
+            }
+        }
+        for i in (0..150).step_by(3) {
+            engine.delete(&key("key", i)).unwrap();
+        }
+        engine.compact().unwrap();
+
+        let tables = engine.tables();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].records, 100);
+        assert_eq!(tables[0].first_key, key("key", 1));
+        assert_eq!(tables[0].last_key, key("key", 149));
+        assert!(engine.compaction_stats().is_some_and(|stats| stats.rounds > 0));
+        drop(engine);
+
+        let engine = open(dir.path());
+        assert_eq!(engine.len(), 100);
+        for i in 0..150 {
+            let expected = (i % 3 != 0).then(|| value(i, 2));
+            assert_eq!(engine.get(&key("key", i)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn snapshots_of_an_lsm_store_carry_its_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = open(dir.path());
+        for i in 0..100 {
+            engine.put(key("key", i), value(i, 0)).unwrap();
+        }
+        engine.compact().unwrap();
+        // Some in tables, some still in the memtable
+        for i in 50..150 {
+            engine.put(key("key", i), value(i, 1)).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        assert_eq!(engine.export_snapshot(&mut snapshot).unwrap(), 150);
+
+        let copy = tempfile::tempdir().unwrap();
+        let mut imported = open(copy.path());
+        assert_eq!(imported.import_snapshot(snapshot.as_slice()).unwrap(), 150);
+        assert_eq!(imported.len(), 150);
+        for i in 0..150 {
+            assert_eq!(imported.get(&key("key", i)).unwrap(), Some(value(i, usize::from(i >= 50))));
+        }
+    }
+
+    #[test]
+    fn table_lookups_go_by_the_block_index_and_bloom_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let format = Format::default();
+        let mut writer = TableWriter::create(dir.path(), 1, 0, &format, 2000).unwrap();
+        for i in 0..2000 {
+            let value = (i % 10 != 0).then(|| value(i, 0));
+            writer.add(&key("key", i * 2), value.as_deref(), &format, &|_| Compression::None).unwrap();
+        }
+        let table = writer.finish(format.keys_only()).unwrap();
+        assert!(table.sparse.len() > 50, "{} blocks", table.sparse.len());
+        assert_eq!(table.record_count, 2000);
+        assert_eq!(table.first_key(), key("key", 0));
+        assert_eq!(table.last_key, key("key", 3998));
+
+        for i in 0..2000 {
+            let expected = (i % 10 != 0).then(|| value(i, 0));
+            assert_eq!(table.get(&key("key", i * 2)).unwrap(), Some(expected), "key_{:04}", i * 2);
+        }
+        // Keys between those in the table, and past either end
+        let mut passed_bloom = 0;
+        for i in 0..2000 {
+            let absent = key("key", i * 2 + 1);
+            passed_bloom += usize::from(table.bloom.may_contain(&absent));
+            assert_eq!(table.get(&absent).unwrap(), None);
+        }
+        assert!(passed_bloom < 100, "{} of 2000 absent keys passed the filter", passed_bloom);
+        assert_eq!(table.get(b"a").unwrap(), None);
+        assert_eq!(table.get(b"z").unwrap(), None);
+
+        // The same lookups once it is read back from its file
+        let table = Table::open(&table_path(dir.path(), 1), 1, 0, format.keys_only()).unwrap();
+        assert_eq!(table.get(&key("key", 1234)).unwrap(), Some(Some(value(617, 0))));
+        assert_eq!(table.get(&key("key", 1240)).unwrap(), Some(None));
+        assert_eq!(table.get(&key("key", 1235)).unwrap(), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! Many named engines under one directory, for one store per tenant
+//!
+//! Each store is a subdirectory, `<dir>/<name>/`, holding its log as
+//! `data.db` and whatever files the engine keeps next to it, so dropping a
+//! store removes one directory. Stores are opened with
+//! `SyncMode::Periodic`, and a single background thread shared by all of
+//! them syncs every store with unsynced writes once per interval, so an
+//! idle store's last writes are not left waiting for the next put.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::builder::EngineBuilder;
+use crate::engine::{Engine, SyncMode};
+
+/// Name of the log in a store's directory
+const LOG_NAME: &str = "data.db";
+/// Default for `StoreManager::open_with`'s `sync_interval`
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An open store, shared by everyone who opened it through the manager
+pub type SharedEngine = Arc<Mutex<Engine>>;
+
+type ConfigureFn = Box<dyn Fn(EngineBuilder) -> EngineBuilder + Send + Sync>;
+
+struct Shared {
+    dir: PathBuf,
+    sync_interval: Duration,
+    configure: ConfigureFn,
+    open: Mutex<BTreeMap<String, SharedEngine>>,
+    /// Held by the sync thread while it has a store's handle, and by
+    /// `drop_store`, so the handle is never taken for a caller's; taken
+    /// before `open`
+    syncing: Mutex<()>,
+    last_sync_error: Mutex<Option<String>>,
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+/// Creates, opens and drops stores by name under one directory; see the
+/// module docs
+pub struct StoreManager {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StoreManager {
+    /// Manage the stores in `dir`, creating it if missing, with default
+    /// engine settings and `DEFAULT_SYNC_INTERVAL`
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(dir, DEFAULT_SYNC_INTERVAL, |builder| builder)
+    }
+
+    /// `open`, syncing every `sync_interval` and opening each store with
+    /// the builder `configure` returns. The builder it is given already
+    /// has `SyncMode::Periodic(sync_interval)`.
+    pub fn open_with(
+        dir: impl AsRef<Path>,
+        sync_interval: Duration,
+        configure: impl Fn(EngineBuilder) -> EngineBuilder + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let shared = Arc::new(Shared {
+            dir,
+            sync_interval,
+            configure: Box::new(configure),
+            open: Mutex::new(BTreeMap::new()),
+            syncing: Mutex::new(()),
+            last_sync_error: Mutex::new(None),
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+        });
+        let thread = std::thread::Builder::new().name("mini-kv-sync".to_string()).spawn({
+            let shared = shared.clone();
+            move || run(&shared)
+        })?;
+        Ok(Self { shared, thread: Some(thread) })
+    }
+
+    /// Create the store `name` and open it; fails if it exists
+    pub fn create_store(&self, name: &str) -> Result<SharedEngine> {
+        let path = self.store_dir(name)?;
+        let mut open = self.shared.open.lock().unwrap();
+        if path.exists() {
+            bail!("store {} already exists", name);
+        }
+        fs::create_dir(&path).with_context(|| format!("creating {}", path.display()))?;
+        self.open_locked(&mut open, name, path)
+    }
+
+    /// The store `name`, opened if no one has it open yet; fails if it
+    /// does not exist
+    pub fn open_store(&self, name: &str) -> Result<SharedEngine> {
+        let path = self.store_dir(name)?;
+        let mut open = self.shared.open.lock().unwrap();
+        if let Some(engine) = open.get(name) {
+            return Ok(engine.clone());
+        }
+        if !path.join(LOG_NAME).exists() {
+            bail!("no store named {}", name);
+        }
+        self.open_locked(&mut open, name, path)
+    }
+
+    /// Close the store `name` and delete its files. Fails, leaving it be,
+    /// while a handle from `create_store` or `open_store` is still held.
+    pub fn drop_store(&self, name: &str) -> Result<()> {
+        let path = self.store_dir(name)?;
+        let _syncing = self.shared.syncing.lock().unwrap();
+        let mut open = self.shared.open.lock().unwrap();
+        if let Some(engine) = open.remove(name) {
+            match Arc::try_unwrap(engine) {
+                // Its drop writes an index snapshot, so close it first
+                Ok(engine) => drop(engine),
+                Err(engine) => {
+                    open.insert(name.to_string(), engine);
+                    bail!("store {} is still in use", name);
+                }
+            }
+        }
+        if !path.join(LOG_NAME).exists() {
+            bail!("no store named {}", name);
+        }
+        fs::remove_dir_all(&path).with_context(|| format!("removing {}", path.display()))
+    }
+
+    /// Names of every store in the directory, open or not, sorted
+    pub fn list_stores(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.shared.dir)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if valid_name(name) && entry.path().join(LOG_NAME).exists() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The error the background sync last hit, if any, with the store's
+    /// name
+    pub fn last_sync_error(&self) -> Option<String> {
+        self.shared.last_sync_error.lock().unwrap().clone()
+    }
+
+    fn store_dir(&self, name: &str) -> Result<PathBuf> {
+        if !valid_name(name) {
+            bail!("invalid store name {:?}: letters, digits, '-', '_' and '.' only, not starting with '.'", name);
+        }
+        Ok(self.shared.dir.join(name))
+    }
+
+    fn open_locked(&self, open: &mut BTreeMap<String, SharedEngine>, name: &str, path: PathBuf) -> Result<SharedEngine> {
+        let builder = Engine::builder(path.join(LOG_NAME)).sync_mode(SyncMode::Periodic(self.shared.sync_interval));
+        let engine = (self.shared.configure)(builder).open().with_context(|| format!("opening store {}", name))?;
+        let engine = Arc::new(Mutex::new(engine));
+        open.insert(name.to_string(), engine.clone());
+        Ok(engine)
+    }
+}
+
+impl Drop for StoreManager {
+    /// Stops the sync thread; the stores close once their last handle is
+    /// dropped, syncing as engines do
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+fn run(shared: &Shared) {
+    loop {
+        let stopped = shared.stopped.lock().unwrap();
+        let (stopped, _) = shared.wake.wait_timeout_while(stopped, shared.sync_interval, |stopped| !*stopped).unwrap();
+        if *stopped {
+            return;
+        }
+        drop(stopped);
+
+        // Sync without holding the map, so opening a store never waits on
+        // another's fsync. Weak handles, upgraded one store at a time, so
+        // `drop_store` only sees the callers' handles.
+        let open: Vec<_> = shared.open.lock().unwrap().iter().map(|(name, engine)| (name.clone(), Arc::downgrade(engine))).collect();
+        for (name, engine) in open {
+            let _syncing = shared.syncing.lock().unwrap();
+            let Some(engine) = Weak::upgrade(&engine) else { continue };
+            let Ok(mut engine) = engine.lock() else { continue };
+            if engine.unsynced_bytes() == 0 {
+                continue;
+            }
+            if let Err(e) = engine.sync() {
+                *shared.last_sync_error.lock().unwrap() = Some(format!("store {}: {:#}", name, e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_store_while_the_sync_thread_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        // Puts never sync themselves, so the thread has work every interval
+        let manager = StoreManager::open_with(dir.path(), Duration::from_millis(1), |builder| builder.sync_mode(SyncMode::Batch(usize::MAX))).unwrap();
+        let busy = manager.create_store("busy").unwrap();
+        for round in 0..200 {
+            let name = format!("store-{}", round);
+            let store = manager.create_store(&name).unwrap();
+            store.lock().unwrap().put(b"key".to_vec(), vec![0; 4096]).unwrap();
+            drop(store);
+            busy.lock().unwrap().put(b"key".to_vec(), vec![0; 4096]).unwrap();
+            std::thread::sleep(Duration::from_micros(round % 20 * 50));
+            manager.drop_store(&name).unwrap();
+        }
+        assert_eq!(manager.list_stores().unwrap(), vec!["busy".to_string()]);
+        assert_eq!(manager.last_sync_error(), None);
+    }
+}
@@ -0,0 +1,194 @@
+//! Sorted in-memory tables of recent writes, for `EngineKind::Lsm`
+//!
+//! The engine holds its memtable as a `Box<dyn Memtable>` and creates a
+//! fresh one after every flush, so any sorted map can stand in; pick one
+//! with `EngineBuilder::memtable`. Writes go through `&mut self`, as the
+//! engine serializes them anyway, but reads only need `&self`.
+//!
+//! ```
+//! use mini_kv::{BTreeMemtable, Memtable, SkipListMemtable};
+//!
+//! let mut memtable = SkipListMemtable::default();
+//! memtable.insert(b"b".to_vec(), Some(b"2".to_vec()));
+//! memtable.insert(b"a".to_vec(), None);
+//! assert_eq!(memtable.get(b"a"), Some(None));
+//! assert_eq!(memtable.range_from(b"").map(|(key, _)| key.to_vec()).collect::<Vec<_>>(), [b"a".to_vec(), b"b".to_vec()]);
+//! # let _ = BTreeMemtable::default();
+//! ```
+
+use std::collections::BTreeMap;
+
+/// Entries from a key on, in key order; `None` values are tombstones
+pub type MemtableIter<'a> = Box<dyn Iterator<Item = (&'a [u8], Option<&'a [u8]>)> + 'a>;
+
+/// A sorted map from key to value or tombstone
+pub trait Memtable: Send {
+    /// `Some(None)` if the key was deleted
+    fn get(&self, key: &[u8]) -> Option<Option<&[u8]>>;
+
+    /// Set `key` to `value`, or to a tombstone for `None`
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>);
+
+    /// Entries, tombstones included
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Memory used, roughly; the engine flushes once this reaches
+    /// `EngineBuilder::memtable_size`
+    fn size_bytes(&self) -> usize;
+
+    /// Entries with keys from `start` on, in key order
+    fn range_from<'a>(&'a self, start: &[u8]) -> MemtableIter<'a>;
+}
+
+/// Rough cost of a `BTreeMemtable` entry beyond its key and value
+const BTREE_OVERHEAD: usize = 64;
+
+/// A `BTreeMap` (the default)
+#[derive(Debug, Default)]
+pub struct BTreeMemtable {
+    map: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    bytes: usize,
+}
+
+impl Memtable for BTreeMemtable {
+    fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.map.get(key).map(Option::as_deref)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.bytes += entry_size(&key, value.as_deref()) + BTREE_OVERHEAD;
+        if let Some(old) = self.map.get(&key) {
+            self.bytes -= entry_size(&key, old.as_deref()) + BTREE_OVERHEAD;
+        }
+        self.map.insert(key, value);
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn range_from<'a>(&'a self, start: &[u8]) -> MemtableIter<'a> {
+        Box::new(self.map.range(start.to_vec()..).map(|(key, value)| (key.as_slice(), value.as_deref())))
+    }
+}
+
+fn entry_size(key: &[u8], value: Option<&[u8]>) -> usize {
+    key.len() + value.map_or(0, <[u8]>::len)
+}
+
+const MAX_HEIGHT: usize = 16;
+/// Rough cost of a `SkipListMemtable` node beyond its key, value and links
+const NODE_OVERHEAD: usize = 80;
+
+/// A skip list with its nodes in one arena, linked by index. Inserts never
+/// move existing nodes, which is the property lock-free variants build on.
+#[derive(Debug)]
+pub struct SkipListMemtable {
+    nodes: Vec<Node>,
+    /// First node at each level
+    head: [Option<usize>; MAX_HEIGHT],
+    height: usize,
+    rng: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct Node {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    /// Next node at each level the node is on
+    next: Vec<Option<usize>>,
+}
+
+impl Default for SkipListMemtable {
+    fn default() -> Self {
+        Self { nodes: Vec::new(), head: [None; MAX_HEIGHT], height: 1, rng: 0x9E37_79B9_7F4A_7C15, bytes: 0 }
+    }
+}
+
+impl SkipListMemtable {
+    /// The node after `node` (`None` is the head) at `level`
+    fn next(&self, node: Option<usize>, level: usize) -> Option<usize> {
+        match node {
+            Some(i) => self.nodes[i].next[level],
+            None => self.head[level],
+        }
+    }
+
+    /// The last node before `key` at each level, and the first node at or
+    /// after it
+    fn seek(&self, key: &[u8]) -> ([Option<usize>; MAX_HEIGHT], Option<usize>) {
+        let mut before = [None; MAX_HEIGHT];
+        let mut node = None;
+        for level in (0..self.height).rev() {
+            while let Some(next) = self.next(node, level).filter(|&next| self.nodes[next].key.as_slice() < key) {
+                node = Some(next);
+            }
+            before[level] = node;
+        }
+        (before, self.next(node, 0))
+    }
+
+    /// Each level up holds about a quarter of the nodes of the one below
+    fn random_height(&mut self) -> usize {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng.trailing_zeros() as usize / 2 + 1).min(MAX_HEIGHT)
+    }
+}
+
+impl Memtable for SkipListMemtable {
+    fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        let node = &self.nodes[self.seek(key).1?];
+        (node.key == key).then_some(node.value.as_deref())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let (before, found) = self.seek(&key);
+        if let Some(i) = found.filter(|&i| self.nodes[i].key == key) {
+            self.bytes += entry_size(&key, value.as_deref());
+            self.bytes -= entry_size(&key, self.nodes[i].value.as_deref());
+            self.nodes[i].value = value;
+            return;
+        }
+        let height = self.random_height();
+        self.height = self.height.max(height);
+        let i = self.nodes.len();
+        let next = (0..height).map(|level| self.next(before[level], level)).collect();
+        self.bytes += entry_size(&key, value.as_deref()) + height * 8 + NODE_OVERHEAD;
+        self.nodes.push(Node { key, value, next });
+        for (level, node) in before.iter().enumerate().take(height) {
+            match node {
+                Some(prev) => self.nodes[*prev].next[level] = Some(i),
+                None => self.head[level] = Some(i),
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn range_from<'a>(&'a self, start: &[u8]) -> MemtableIter<'a> {
+        let mut node = self.seek(start).1;
+        Box::new(std::iter::from_fn(move || {
+            let current = &self.nodes[node?];
+            node = current.next[0];
+            Some((current.key.as_slice(), current.value.as_deref()))
+        }))
+    }
+}
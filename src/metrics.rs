@@ -0,0 +1,50 @@
+//! Prometheus metrics, with the `metrics` feature
+//!
+//! The engine counts its work in metrics registered with the `prometheus`
+//! crate's default registry, so they are scraped along with an embedder's
+//! own. They are process-wide: every engine in the process adds to the
+//! same ones. `encode_text()` renders the registry for a `/metrics`
+//! endpoint.
+//!
+//! ```text
+//! mini_kv_puts_total               counter    put() calls that wrote a record
+//! mini_kv_gets_total               counter    get() calls
+//! mini_kv_deletes_total            counter    delete() calls that wrote a tombstone
+//! mini_kv_fsync_seconds            histogram  time spent in each sync()
+//! mini_kv_compaction_bytes_total   counter    bytes written by compaction
+//! ```
+
+use prometheus::{exponential_buckets, register_histogram, register_int_counter, Encoder, Histogram, IntCounter, TextEncoder};
+use std::sync::LazyLock;
+
+pub(crate) struct Metrics {
+    pub(crate) puts: IntCounter,
+    pub(crate) gets: IntCounter,
+    pub(crate) deletes: IntCounter,
+    pub(crate) fsync_seconds: Histogram,
+    pub(crate) compaction_bytes: IntCounter,
+}
+
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    puts: register_int_counter!("mini_kv_puts_total", "put() calls that wrote a record").unwrap(),
+    gets: register_int_counter!("mini_kv_gets_total", "get() calls").unwrap(),
+    deletes: register_int_counter!("mini_kv_deletes_total", "delete() calls that wrote a tombstone").unwrap(),
+    // 10us to about 5s
+    fsync_seconds: register_histogram!(
+        "mini_kv_fsync_seconds",
+        "Time spent in each sync()",
+        exponential_buckets(0.00001, 2.0, 20).unwrap()
+    )
+    .unwrap(),
+    compaction_bytes: register_int_counter!("mini_kv_compaction_bytes_total", "Bytes written by compaction").unwrap(),
+});
+
+/// Every metric in the default registry, mini-kv's included, in the
+/// Prometheus text format
+pub fn encode_text() -> String {
+    // Registered on first use; make sure a scrape before any sees them
+    LazyLock::force(&METRICS);
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf).expect("text encoding into a Vec cannot fail");
+    String::from_utf8(buf).expect("the text format is UTF-8")
+}
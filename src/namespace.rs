@@ -0,0 +1,292 @@
+//! Named keyspaces, for `Engine::namespace`
+//!
+//! Every record names the namespace its key is in by a u32 id in its
+//! header; records without one are in the default namespace, 0, which is
+//! what `Engine`'s own methods use. Names map to ids through a catalog kept
+//! in the log itself, as records in the reserved namespace `CATALOG`, so
+//! it is recovered, compacted and replicated like any other data.
+//!
+//! The index holds one flat keyspace, so a namespaced key is looked up
+//! under an escaped form: `ESCAPE | id (4, big-endian) | key`. Default
+//! namespace keys stay as they are unless they start with `ESCAPE`, and
+//! get the same form with id 0 then. Keys of one namespace so share a
+//! prefix and sort together, in key order.
+
+use anyhow::Result;
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime};
+
+use crate::cursor::{Cursor, Page};
+use crate::engine::{Engine, PutOptions, ReadOptions};
+use crate::storage::{FsStorage, Storage};
+
+/// Namespace id of the catalog of namespace names
+pub(crate) const CATALOG: u32 = u32::MAX;
+const ESCAPE: u8 = 0xFF;
+const ESCAPED_LEN: usize = 5;
+
+/// `key` of namespace `namespace` as the index holds it
+pub(crate) fn index_key(namespace: u32, key: &[u8]) -> Cow<'_, [u8]> {
+    if namespace == 0 && key.first() != Some(&ESCAPE) {
+        return Cow::Borrowed(key);
+    }
+    let mut escaped = Vec::with_capacity(ESCAPED_LEN + key.len());
+    escaped.push(ESCAPE);
+    escaped.extend_from_slice(&namespace.to_be_bytes());
+    escaped.extend_from_slice(key);
+    Cow::Owned(escaped)
+}
+
+/// `index_key`, reusing `key`'s allocation when it needs no escaping
+pub(crate) fn into_index_key(namespace: u32, key: Vec<u8>) -> Vec<u8> {
+    match index_key(namespace, &key) {
+        Cow::Borrowed(_) => key,
+        Cow::Owned(escaped) => escaped,
+    }
+}
+
+/// Where the index keys of `namespace` end, exclusive: at the next
+/// namespace's, which for the default one follow its escaped keys
+pub(crate) fn index_key_end(namespace: u32) -> Option<Vec<u8>> {
+    let next = namespace.checked_add(1)?;
+    Some(index_key(next, &[]).into_owned())
+}
+
+/// The namespace and key an index key stands for
+pub(crate) fn split_index_key(index_key: &[u8]) -> (u32, &[u8]) {
+    match index_key {
+        [ESCAPE, a, b, c, d, key @ ..] => (u32::from_be_bytes([*a, *b, *c, *d]), key),
+        _ => (0, index_key),
+    }
+}
+
+/// Keys and space of one namespace, from `Namespace::stats()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    pub keys: usize,
+    /// Log bytes of the records its live keys point at
+    pub live_bytes: u64,
+}
+
+/// A named keyspace of an engine, from `Engine::namespace`. Its keys are
+/// apart from those of the default namespace and of every other one: the
+/// same key can hold a different value in each, and scans see only their
+/// own namespace.
+pub struct Namespace<'a, S: Storage = FsStorage> {
+    engine: &'a mut Engine<S>,
+    id: u32,
+}
+
+impl<'a, S: Storage> Namespace<'a, S> {
+    pub(crate) fn new(engine: &'a mut Engine<S>, id: u32) -> Self {
+        Self { engine, id }
+    }
+
+    /// Id stored in the namespace's records
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.engine.put_in(self.id, key, value, &PutOptions::default())
+    }
+
+    pub fn put_with_options(&mut self, key: Vec<u8>, value: Vec<u8>, options: &PutOptions) -> Result<()> {
+        self.engine.put_in(self.id, key, value, options)
+    }
+
+    /// `put`, synced before it returns; see `Engine::put_durable`
+    pub fn put_durable(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.engine.put_in(self.id, key, value, &PutOptions { sync: Some(true), ..PutOptions::default() })
+    }
+
+    /// `put`, with the key expiring `ttl` from now
+    pub fn put_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let options = PutOptions { expires_at: Some(SystemTime::now() + ttl), ..PutOptions::default() };
+        self.engine.put_in(self.id, key, value, &options)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.engine.get_in(self.id, key, &ReadOptions::default())
+    }
+
+    /// See `Engine::get_with_options`
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        self.engine.get_in(self.id, key, options)
+    }
+
+    /// `get` for each of `keys`, as they are asked for; see
+    /// `Engine::get_many`
+    pub fn get_many<'b, K, I>(&'b self, keys: I) -> impl Iterator<Item = (K, Result<Option<Vec<u8>>>)> + 'b
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+        I::IntoIter: 'b,
+    {
+        self.engine.get_many_in(self.id, keys)
+    }
+
+    /// See `Engine::expire_at`
+    pub fn expire_at(&mut self, key: &[u8], at: SystemTime) -> Result<bool> {
+        self.engine.expire_at_in(self.id, key, at)
+    }
+
+    /// See `Engine::ttl`
+    pub fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.engine.ttl_in(self.id, key)
+    }
+
+    /// Returns false (and writes nothing) if the key was not present
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        self.engine.delete_in(self.id, key)
+    }
+
+    /// Delete every key of this namespace starting with `prefix`; see
+    /// `Engine::delete_prefix`
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        self.engine.delete_prefix_in(self.id, prefix)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.engine.contains_key_in(self.id, key)
+    }
+
+    /// Live pairs of this namespace whose key starts with `prefix`, sorted
+    /// by key
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.engine.scan_prefix_in(self.id, prefix)
+    }
+
+    /// A page of this namespace's live pairs after `cursor`; see
+    /// `Engine::scan_page`
+    pub fn scan_page(&self, cursor: Option<&Cursor>, limit: usize) -> Result<Page> {
+        self.engine.scan_page_in(self.id, cursor, limit)
+    }
+
+    /// Live keys of this namespace, sorted
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        self.engine.keys_in(self.id)
+    }
+
+    /// Counted by walking the namespace's index entries, so this costs
+    /// about as much as `keys()`
+    pub fn stats(&self) -> Result<NamespaceStats> {
+        self.engine.namespace_stats(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Engine, MemStorage, Storage};
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    const PATH: &str = "/test/data.db";
+
+    fn open(storage: &MemStorage) -> Engine<MemStorage> {
+        Engine::builder(PATH).storage(storage.clone()).open().unwrap()
+    }
+
+    #[test]
+    fn escaped_default_keys_and_namespaces_survive_compaction_and_a_rescan() {
+        let storage = MemStorage::new();
+        let mut engine = open(&storage);
+        engine.put(b"\xFFdefault".to_vec(), b"default escaped".to_vec()).unwrap();
+        // What the escaped form of the default key above would be if id 0
+        // were not put in front of it
+        engine.put(b"\xFF\x00\x00\x00\x01key".to_vec(), b"default lookalike".to_vec()).unwrap();
+        engine.put(b"plain".to_vec(), b"default plain".to_vec()).unwrap();
+        let mut users = engine.namespace("users").unwrap();
+        let id = users.id();
+        assert_eq!(id, 1);
+        users.put(b"key".to_vec(), b"users key".to_vec()).unwrap();
+        users.put(b"\xFFdefault".to_vec(), b"users escaped".to_vec()).unwrap();
+        engine.compact().unwrap();
+        drop(engine);
+        storage.remove(Path::new("/test/data.db.index")).unwrap();
+
+        let mut engine = open(&storage);
+        assert_eq!(engine.namespaces().unwrap(), ["users"]);
+        assert_eq!(engine.get(b"\xFFdefault").unwrap(), Some(b"default escaped".to_vec()));
+        assert_eq!(engine.get(b"\xFF\x00\x00\x00\x01key").unwrap(), Some(b"default lookalike".to_vec()));
+        assert_eq!(engine.get(b"plain").unwrap(), Some(b"default plain".to_vec()));
+        assert_eq!(engine.get(b"key").unwrap(), None);
+        assert_eq!(engine.scan_prefix(b"").unwrap().len(), 3);
+        let users = engine.namespace("users").unwrap();
+        assert_eq!(users.id(), id);
+        assert_eq!(users.get(b"key").unwrap(), Some(b"users key".to_vec()));
+        assert_eq!(users.get(b"\xFFdefault").unwrap(), Some(b"users escaped".to_vec()));
+        assert_eq!(users.keys().unwrap(), [b"key".to_vec(), b"\xFFdefault".to_vec()]);
+    }
+
+    #[test]
+    fn len_counts_namespace_catalog_entries() {
+        let storage = MemStorage::new();
+        let mut engine = open(&storage);
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let mut users = engine.namespace("users").unwrap();
+        users.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        users.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(users.stats().unwrap().keys, 2);
+        assert_eq!(engine.len(), 4);
+        assert_eq!(engine.stats().keys, 4);
+        drop(engine);
+
+        let engine = open(&storage);
+        assert_eq!(engine.len(), 4);
+        assert_eq!(engine.namespaces().unwrap(), ["users"]);
+    }
+
+    #[test]
+    fn pages_cover_each_namespace_over_a_spilled_index() {
+        let storage = MemStorage::new();
+        let mut engine = Engine::builder(PATH).storage(storage.clone()).index_memory_budget(2048).open().unwrap();
+        let key = |i: usize| match i % 3 {
+            0 => format!("key_{:03}", i).into_bytes(),
+            1 => format!("\u{FF}_{:03}", i).into_bytes(),
+            _ => [&[0xFF][..], format!("{:03}", i).as_bytes()].concat(),
+        };
+        for i in 0..300 {
+            engine.put(key(i), b"default".to_vec()).unwrap();
+            engine.namespace("one").unwrap().put(key(i), b"one".to_vec()).unwrap();
+            engine.namespace("two").unwrap().put(key(i), b"two".to_vec()).unwrap();
+        }
+        // Deletes shadowing spilled entries, and a key that expired
+        for i in (0..300).step_by(7) {
+            engine.delete(&key(i)).unwrap();
+            engine.namespace("one").unwrap().delete(&key(i)).unwrap();
+        }
+        engine.namespace("two").unwrap().expire_at(&key(5), SystemTime::UNIX_EPOCH).unwrap();
+
+        let pages = |engine: &mut Engine<MemStorage>, name: Option<&str>| {
+            let mut keys = Vec::new();
+            let mut cursor = None;
+            loop {
+                let (page, next) = match name {
+                    Some(name) => engine.namespace(name).unwrap().scan_page(cursor.as_ref(), 13).unwrap(),
+                    None => engine.scan_page(cursor.as_ref(), 13).unwrap(),
+                };
+                assert!(page.len() == 13 || next.is_none());
+                keys.extend(page.into_iter().map(|(key, _)| key));
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => return keys,
+                }
+            }
+        };
+        let mut expected: Vec<Vec<u8>> = (0..300).filter(|i| i % 7 != 0).map(key).collect();
+        expected.sort();
+        assert_eq!(pages(&mut engine, None), expected);
+        assert_eq!(pages(&mut engine, Some("one")), expected);
+        let mut two: Vec<Vec<u8>> = (0..300).filter(|&i| i != 5).map(key).collect();
+        two.sort();
+        assert_eq!(pages(&mut engine, Some("two")), two);
+
+        let one = engine.namespace("one").unwrap();
+        assert_eq!(one.keys().unwrap(), expected);
+        assert_eq!(one.stats().unwrap().keys, expected.len());
+        let two_stats = engine.namespace("two").unwrap().stats().unwrap();
+        assert_eq!(two_stats.keys, 299);
+        assert!(two_stats.live_bytes > 0);
+    }
+}
@@ -0,0 +1,50 @@
+//! Token buckets that pace writes and compaction, for
+//! `EngineBuilder::rate_limit`
+//!
+//! A bucket holds up to one second's worth of tokens and refills at its
+//! rate. Taking more than it holds leaves it in debt and sleeps the debt
+//! off, so a burst is let through at once but the average rate holds.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits for `EngineBuilder::rate_limit`; `None` leaves that one unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Key and value bytes `put()` takes per second
+    pub bytes_per_sec: Option<u64>,
+    /// `put()` and `delete()` calls per second
+    pub ops_per_sec: Option<u64>,
+    /// Bytes compaction writes per second, for both engine kinds
+    pub compaction_bytes_per_sec: Option<u64>,
+}
+
+pub(crate) struct RateLimiter {
+    /// Tokens per second, and the most the bucket holds
+    rate: f64,
+    /// Tokens left (negative when in debt) as of the instant
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_sec: u64) -> Self {
+        let rate = per_sec.max(1) as f64;
+        Self { rate, bucket: Mutex::new((rate, Instant::now())) }
+    }
+
+    /// Take `amount` tokens, sleeping while the bucket is in debt
+    pub(crate) fn acquire(&self, amount: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let (tokens, last) = *bucket;
+            let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.rate) - amount as f64;
+            *bucket = (tokens, now);
+            (tokens < 0.0).then(|| Duration::from_secs_f64(-tokens / self.rate))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
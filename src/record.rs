@@ -1,54 +1,757 @@
 use anyhow::{anyhow, Result};
+use std::borrow::Cow;
+use std::io::IoSlice;
 
-const MAX_KEY_LEN: usize = 1024 * 1024;      // 1MB
-const MAX_VAL_LEN: usize = 1024 * 1024 * 10; // 10MB
-const CRC_SIZE: usize = 4;
+use crate::checksum::Checksum;
+use crate::compression::{self, Codec, Compression};
+use crate::error::Error;
+use crate::format::Format;
+use crate::segment::{FOOTER_MAGIC, FOOTER_SIZE};
 
-#[derive(Debug, Clone)]
+/// Default for `EngineBuilder::max_key_len`
+pub const MAX_KEY_LEN: usize = 1024 * 1024;      // 1MB
+/// Default for `EngineBuilder::max_value_len`
+pub const MAX_VAL_LEN: usize = 1024 * 1024 * 10; // 10MB
+/// Largest encode buffer kept for reuse; one a big value grew past this
+/// is let go rather than held on to
+pub(crate) const MAX_REUSED_BUFFER: usize = 1024 * 1024;
+/// Largest `Checksum::size()`
+const MAX_CHECKSUM_SIZE: usize = 8;
+
+/// Marks the start of every record so recovery can resynchronize after a
+/// damaged region. Read as a little-endian u32 it is far above any key
+/// length, so an unmarked (legacy) record can never start with it.
+pub const RECORD_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0xFA]; // "MKV\xFA"
+pub const HEADER_SIZE: usize = 13;   // magic + flags + key_len + val_len
+const LEGACY_HEADER_SIZE: usize = 8; // key_len + val_len
+/// Longest header, with a namespace id and an expiry time
+pub(crate) const MAX_HEADER_SIZE: usize = HEADER_SIZE + NAMESPACE_LEN + EXPIRY_LEN;
+
+/// Record flag bits
+const FLAG_TOMBSTONE: u8 = 0x01;
+/// Bits 1-2: codec the stored value is compressed with (0 = none)
+const COMPRESSION_MASK: u8 = 0x06;
+const COMPRESSION_LZ4: u8 = 0x02;
+const COMPRESSION_ZSTD: u8 = 0x04;
+/// Zstd against the dictionary in the file header
+const COMPRESSION_ZSTD_DICT: u8 = 0x06;
+/// Key and value are AES-256-GCM encrypted: a nonce follows the header and
+/// an auth tag follows the ciphertext
+const FLAG_ENCRYPTED: u8 = 0x08;
+/// A u32 key version precedes the nonce; encrypted records without it
+/// were sealed with key version 0
+const FLAG_KEY_ID: u8 = 0x10;
+/// The value is a pointer into the value log, not the value itself
+const FLAG_VALUE_POINTER: u8 = 0x20;
+/// A u32 namespace id follows the lengths; records without it are in the
+/// default namespace, 0
+const FLAG_NAMESPACE: u8 = 0x40;
+/// Bit 7 on a tombstone: every key from the record's key up to its value,
+/// exclusive, was deleted
+const FLAG_RANGE: u8 = 0x80;
+/// Bit 7 on any other record: a u64 expiry time, in milliseconds since the
+/// Unix epoch, follows the lengths and any namespace id. This takes the
+/// last flag bit, so none is unknown.
+const FLAG_EXPIRES: u8 = 0x80;
+const NAMESPACE_LEN: usize = 4;
+const EXPIRY_LEN: usize = 8;
+const KEY_ID_LEN: usize = 4;
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Put,
+    /// Tombstone: the key was deleted, `value` is empty
+    Delete,
+    /// A put whose value went to the value log; `value` holds where
+    ValuePointer,
+    /// Range tombstone: every key from `key` up to `value`, exclusive, was
+    /// deleted (see `Engine::delete_range`)
+    RangeDelete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Record {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
+    pub kind: RecordKind,
+    /// Id of the namespace the key is in (see `Engine::namespace`); 0 for
+    /// the default one
+    pub namespace: u32,
+    /// When the key expires, in milliseconds since the Unix epoch (see
+    /// `Engine::put_with_ttl`); never for tombstones
+    pub expires_at: Option<u64>,
+}
+
+/// A record whose key and value borrow from the buffer it was decoded
+/// from; see `RecordRef::decode_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordRef<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    pub kind: RecordKind,
+    pub namespace: u32,
+    pub expires_at: Option<u64>,
+}
+
+/// Namespace, key, kind and size of a record, from `Record::try_decode_key`
+pub(crate) type DecodedKey = (u32, Vec<u8>, RecordKind, usize);
+
+/// Parsed record header
+struct Header {
+    /// Including the namespace id and expiry time, if there are any
+    len: usize,
+    flags: u8,
+    key_len: usize,
+    val_len: usize,
+    namespace: u32,
+    expires_at: Option<u64>,
+}
+
+fn parse_header(buf: &[u8]) -> Result<Header> {
+    if buf.starts_with(&RECORD_MAGIC) {
+        if buf.len() < HEADER_SIZE { return Err(anyhow!("Buffer too short")); }
+        let flags = buf[4];
+        if flags & FLAG_KEY_ID != 0 && flags & FLAG_ENCRYPTED == 0 {
+            return Err(anyhow!("Key version on an unencrypted record"));
+        }
+        if flags & FLAG_TOMBSTONE != 0 && flags & FLAG_VALUE_POINTER != 0 {
+            return Err(anyhow!("Value pointer on a tombstone"));
+        }
+        let key_len = u32::from_le_bytes(buf[5..9].try_into().unwrap()) as usize;
+        let val_len = u32::from_le_bytes(buf[9..13].try_into().unwrap()) as usize;
+        let mut len = HEADER_SIZE;
+        let mut field = |present: bool, field_len: usize| -> Result<Option<&[u8]>> {
+            if !present {
+                return Ok(None);
+            }
+            let bytes = buf.get(len..len + field_len).ok_or_else(|| anyhow!("Buffer too short"))?;
+            len += field_len;
+            Ok(Some(bytes))
+        };
+        let namespace = field(flags & FLAG_NAMESPACE != 0, NAMESPACE_LEN)?
+            .map_or(0, |id| u32::from_le_bytes(id.try_into().unwrap()));
+        let expires_at = field(flags & FLAG_EXPIRES != 0 && flags & FLAG_TOMBSTONE == 0, EXPIRY_LEN)?
+            .map(|at| u64::from_le_bytes(at.try_into().unwrap()));
+        Ok(Header { len, flags, key_len, val_len, namespace, expires_at })
+    } else {
+        // Records written before sync markers were introduced
+        if buf.len() < LEGACY_HEADER_SIZE { return Err(anyhow!("Buffer too short")); }
+        let key_len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let val_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+        Ok(Header { len: LEGACY_HEADER_SIZE, flags: 0, key_len, val_len, namespace: 0, expires_at: None })
+    }
+}
+
+impl Header {
+    fn kind(&self) -> RecordKind {
+        if self.flags & FLAG_TOMBSTONE != 0 && self.flags & FLAG_RANGE != 0 {
+            RecordKind::RangeDelete
+        } else if self.flags & FLAG_TOMBSTONE != 0 {
+            RecordKind::Delete
+        } else if self.flags & FLAG_VALUE_POINTER != 0 {
+            RecordKind::ValuePointer
+        } else {
+            RecordKind::Put
+        }
+    }
+
+    fn encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    fn key_id_len(&self) -> usize {
+        if self.flags & FLAG_KEY_ID != 0 { KEY_ID_LEN } else { 0 }
+    }
+
+    /// Key version, nonce and tag bytes around the ciphertext of an
+    /// encrypted record
+    fn sealing_len(&self) -> usize {
+        if self.encrypted() { self.key_id_len() + NONCE_LEN + TAG_LEN } else { 0 }
+    }
+
+    /// Offset of the checksum: header, sealing, key and value. The lengths
+    /// come straight from the buffer, so `None` if they overflow.
+    fn data_len(&self) -> Option<usize> {
+        (self.len + self.sealing_len()).checked_add(self.key_len)?.checked_add(self.val_len)
+    }
+}
+
+/// Check the framing and checksum of the record at the start of `buf`,
+/// returning its header and total size
+fn check(buf: &[u8], checksum: Checksum) -> Result<(Header, usize)> {
+    let header = parse_header(buf)?;
+    let data_end = header.data_len().ok_or_else(|| anyhow!("Record lengths overflow"))?;
+    let total_len = data_end.checked_add(checksum.size()).ok_or_else(|| anyhow!("Record lengths overflow"))?;
+
+    if buf.len() < total_len { return Err(anyhow!("Incomplete buffer")); }
+
+    if !checksum.matches(&buf[..data_end], &buf[data_end..total_len]) {
+        return Err(anyhow!("{:?} mismatch", checksum));
+    }
+    Ok((header, total_len))
+}
+
+/// `check`, then fail with `Error::KeyTooLarge` or `Error::ValueTooLarge`
+/// for a key or stored value over `format`'s limits. A compressed value
+/// is checked again as it is decompressed.
+fn check_within_limits(buf: &[u8], format: &Format) -> Result<(Header, usize)> {
+    let (header, total_len) = check(buf, format.checksum())?;
+    if header.key_len > format.max_key_len() {
+        return Err(Error::KeyTooLarge { len: header.key_len, max: format.max_key_len() }.into());
+    }
+    if header.val_len > format.max_value_len() {
+        return Err(Error::ValueTooLarge { len: header.val_len, max: format.max_value_len() }.into());
+    }
+    Ok((header, total_len))
+}
+
+/// Key and stored (possibly compressed) value of a checked record,
+/// decrypting them if needed
+fn open_body<'b>(buf: &'b [u8], header: &Header, format: &Format) -> Result<(Vec<u8>, Cow<'b, [u8]>)> {
+    let body = &buf[header.len..header.len + header.sealing_len() + header.key_len + header.val_len];
+    if header.encrypted() {
+        // The key version is authenticated along with the header
+        let (key_id, sealed) = body.split_at(header.key_id_len());
+        let key_id = if key_id.is_empty() { 0 } else { u32::from_le_bytes(key_id.try_into().unwrap()) };
+        let aad = &buf[..header.len + header.key_id_len()];
+        let mut plaintext = format.decrypt(key_id, aad, sealed)?;
+        let value = plaintext.split_off(header.key_len);
+        Ok((plaintext, Cow::Owned(value)))
+    } else {
+        let (key, value) = body.split_at(header.key_len);
+        Ok((key.to_vec(), Cow::Borrowed(value)))
+    }
+}
+
+/// Decode a record that already passed `check`
+fn decode_checked(buf: &[u8], header: &Header, format: &Format) -> Result<Record> {
+    let (key, stored) = open_body(buf, header, format)?;
+    let value = match header.flags & COMPRESSION_MASK {
+        COMPRESSION_LZ4 => compression::decompress(Codec::Lz4, &stored, format)?,
+        COMPRESSION_ZSTD => compression::decompress(Codec::Zstd, &stored, format)?,
+        COMPRESSION_ZSTD_DICT => compression::decompress(Codec::ZstdDict, &stored, format)?,
+        _ => stored.into_owned(),
+    };
+    Ok(Record { key, value, kind: header.kind(), namespace: header.namespace, expires_at: header.expires_at })
 }
 
 impl Record {
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
-        Self { key, value }
+        Self { key, value, kind: RecordKind::Put, namespace: 0, expires_at: None }
+    }
+
+    pub fn tombstone(key: Vec<u8>) -> Self {
+        Self { key, value: Vec::new(), kind: RecordKind::Delete, namespace: 0, expires_at: None }
+    }
+
+    /// A tombstone for every key from `start` up to `end`, exclusive
+    pub fn range_tombstone(start: Vec<u8>, end: Vec<u8>) -> Self {
+        Self { key: start, value: end, kind: RecordKind::RangeDelete, namespace: 0, expires_at: None }
+    }
+
+    /// A put of `key` whose value is in the value log at `pointer`
+    pub(crate) fn value_pointer(key: Vec<u8>, pointer: Vec<u8>) -> Self {
+        Self { key, value: pointer, kind: RecordKind::ValuePointer, namespace: 0, expires_at: None }
+    }
+
+    /// The record with its key in namespace `namespace`
+    pub fn in_namespace(self, namespace: u32) -> Self {
+        Self { namespace, ..self }
+    }
+
+    /// The record with its key expiring at `expires_at`, in milliseconds
+    /// since the Unix epoch; ignored for tombstones
+    pub fn with_expiry(self, expires_at: Option<u64>) -> Self {
+        Self { expires_at, ..self }
     }
 
     pub fn encode(&self) -> Vec<u8> {
-        let key_len = self.key.len() as u32;
-        let val_len = self.value.len() as u32;
-        let mut buf = Vec::with_capacity(8 + self.key.len() + self.value.len() + CRC_SIZE);
-        
-        buf.extend_from_slice(&key_len.to_le_bytes());
-        buf.extend_from_slice(&val_len.to_le_bytes());
+        self.frame(0, &self.value, Checksum::default())
+    }
+
+    /// Encode for a log in `format`, with the value compressed by
+    /// `compression`, and encrypted with the newest key if `format` has
+    /// any. The value is stored as-is when compressing does not make it
+    /// smaller.
+    pub fn encode_with(&self, compression: Compression, format: &Format) -> Result<Vec<u8>> {
+        Ok(self.encode_parts(compression, format, Vec::new())?.into_vec())
+    }
+
+    /// `encode_with`, into `buf` in place of what it held, so encoding
+    /// many records can reuse one allocation
+    pub fn encode_into(&self, compression: Compression, format: &Format, buf: &mut Vec<u8>) -> Result<()> {
+        *buf = self.encode_parts(compression, format, std::mem::take(buf))?.into_vec();
+        Ok(())
+    }
+
+    /// `encode_with`, but as parts to write back to back (see
+    /// `EncodedRecord::io_slices`), so the key and a value stored as-is are
+    /// never copied. The checksum is computed over the parts in place.
+    /// The header is built in `buf`, whose contents are dropped; get it
+    /// back with `EncodedRecord::into_buffer` to reuse.
+    pub(crate) fn encode_parts(&self, compression: Compression, format: &Format, buf: Vec<u8>) -> Result<EncodedRecord<'_>> {
+        let compressed = match self.kind {
+            RecordKind::Put => compression.compress(&self.value, format)?,
+            RecordKind::Delete | RecordKind::ValuePointer | RecordKind::RangeDelete => None,
+        };
+        let (flags, stored) = match compressed {
+            Some((Codec::Lz4, stored)) => (COMPRESSION_LZ4, Cow::Owned(stored)),
+            Some((Codec::Zstd, stored)) => (COMPRESSION_ZSTD, Cow::Owned(stored)),
+            Some((Codec::ZstdDict, stored)) => (COMPRESSION_ZSTD_DICT, Cow::Owned(stored)),
+            None => (0, Cow::Borrowed(self.value.as_slice())),
+        };
+        let checksum = format.checksum();
+        let Some(key_id) = format.encryption_key_id() else {
+            let head = self.header(buf, flags, stored.len());
+            let mut digest = checksum.digest();
+            digest.update(&head);
+            digest.update(&self.key);
+            digest.update(&stored);
+            return Ok(EncodedRecord { head, key: &self.key, value: stored, sum: digest.finish().to_le_bytes(), checksum });
+        };
+
+        let mut buf = if key_id == 0 {
+            self.header(buf, flags | FLAG_ENCRYPTED, stored.len())
+        } else {
+            let mut buf = self.header(buf, flags | FLAG_ENCRYPTED | FLAG_KEY_ID, stored.len());
+            buf.extend_from_slice(&key_id.to_le_bytes());
+            buf
+        };
+        let mut plaintext = Vec::with_capacity(self.key.len() + stored.len());
+        plaintext.extend_from_slice(&self.key);
+        plaintext.extend_from_slice(&stored);
+        let sealed = format.encrypt(key_id, &buf, &plaintext)?;
+        buf.reserve_exact(sealed.len() + checksum.size());
+        buf.extend_from_slice(&sealed);
+        let mut digest = checksum.digest();
+        digest.update(&buf);
+        let sum = digest.finish().to_le_bytes();
+        Ok(EncodedRecord { head: buf, key: &[], value: Cow::Borrowed(&[]), sum, checksum })
+    }
+
+    /// Magic, flags, lengths and any namespace id and expiry time in `buf`,
+    /// emptied first, with room reserved for a key version
+    fn header(&self, mut buf: Vec<u8>, flags: u8, val_len: usize) -> Vec<u8> {
+        buf.clear();
+        buf.reserve(MAX_HEADER_SIZE + KEY_ID_LEN);
+        let mut flags = match self.kind {
+            RecordKind::Put => flags,
+            RecordKind::Delete => flags | FLAG_TOMBSTONE,
+            RecordKind::ValuePointer => flags | FLAG_VALUE_POINTER,
+            RecordKind::RangeDelete => flags | FLAG_TOMBSTONE | FLAG_RANGE,
+        };
+        if self.namespace != 0 {
+            flags |= FLAG_NAMESPACE;
+        }
+        let expires_at = self.expires_at.filter(|_| matches!(self.kind, RecordKind::Put | RecordKind::ValuePointer));
+        if expires_at.is_some() {
+            flags |= FLAG_EXPIRES;
+        }
+        buf.extend_from_slice(&RECORD_MAGIC);
+        buf.push(flags);
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(val_len as u32).to_le_bytes());
+        if self.namespace != 0 {
+            buf.extend_from_slice(&self.namespace.to_le_bytes());
+        }
+        if let Some(expires_at) = expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        buf
+    }
+
+    fn frame(&self, flags: u8, value: &[u8], checksum: Checksum) -> Vec<u8> {
+        let mut buf = self.header(Vec::new(), flags, value.len());
+        buf.reserve_exact(self.key.len() + value.len() + checksum.size());
         buf.extend_from_slice(&self.key);
-        buf.extend_from_slice(&self.value);
+        buf.extend_from_slice(value);
         
-        let crc = crc32fast::hash(&buf);
-        buf.extend_from_slice(&crc.to_le_bytes());
+        checksum.append(&mut buf);
         buf
     }
 
+    /// Read only the header and return the size of the framed record
+    /// starting at `buf`, without hashing or copying anything.
+    /// Returns `None` if the header is truncated or invalid, or the record
+    /// runs past the end of `buf`.
+    pub fn frame_len(buf: &[u8]) -> Option<usize> {
+        Self::frame_len_with(buf, &Format::default())
+    }
+
+    /// `frame_len` for a log in `format`
+    pub fn frame_len_with(buf: &[u8], format: &Format) -> Option<usize> {
+        let total_len = Self::header_frame_len_with(buf, format)?;
+        if buf.len() < total_len { return None; }
+        Some(total_len)
+    }
+
+    /// Like `frame_len`, but `buf` only needs to hold the header (at most
+    /// `HEADER_SIZE` bytes, plus 4 with a namespace id and 8 with an expiry
+    /// time); the record body is not required to be present.
+    pub fn header_frame_len(buf: &[u8]) -> Option<usize> {
+        Self::header_frame_len_with(buf, &Format::default())
+    }
+
+    pub fn header_frame_len_with(buf: &[u8], format: &Format) -> Option<usize> {
+        parse_header(buf).ok()?.data_len()?.checked_add(format.checksum().size())
+    }
+
+    /// Decode a record from a log in the default format, with keys up to
+    /// `MAX_KEY_LEN` and values up to `MAX_VAL_LEN`
     pub fn decode(buf: &[u8]) -> Result<(Self, usize)> {
-        if buf.len() < 12 { return Err(anyhow!("Buffer too short")); }
+        Self::decode_with(buf, &Format::default())
+    }
 
-        let key_len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
-        let val_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
-        let total_len = 8 + key_len + val_len + CRC_SIZE;
+    /// Decode a record from a log in `format`, within its key and value
+    /// length limits
+    pub fn decode_with(buf: &[u8], format: &Format) -> Result<(Self, usize)> {
+        let (header, total_len) = check_within_limits(buf, format)?;
+        Ok((decode_checked(buf, &header, format)?, total_len))
+    }
 
-        if buf.len() < total_len { return Err(anyhow!("Incomplete buffer")); }
+    /// Check the framing and checksum of the record at the start of `buf`
+    /// without decoding it, returning its size
+    pub fn check_with(buf: &[u8], format: &Format) -> Result<usize> {
+        check(buf, format.checksum()).map(|(_, size)| size)
+    }
 
-        let data_end = total_len - CRC_SIZE;
-        let expected_crc = u32::from_le_bytes(buf[data_end..total_len].try_into().unwrap());
-        let actual_crc = crc32fast::hash(&buf[..data_end]);
-        
-        if expected_crc != actual_crc {
-            return Err(anyhow!("CRC mismatch"));
+    /// Verify the record at the start of `buf` and return just its key and
+    /// kind, without decompressing the value
+    pub fn decode_key(buf: &[u8]) -> Result<(Vec<u8>, RecordKind, usize)> {
+        Self::decode_key_with(buf, &Format::default())
+    }
+
+    pub fn decode_key_with(buf: &[u8], format: &Format) -> Result<(Vec<u8>, RecordKind, usize)> {
+        let (header, total_len) = check_within_limits(buf, format)?;
+        let (key, _) = open_body(buf, &header, format)?;
+        Ok((key, header.kind(), total_len))
+    }
+
+    /// Like `decode_key_with`, but tells damage from a record that is intact
+    /// yet cannot be read: `Ok(None)` if the framing or checksum is bad, `Err` if
+    /// decryption fails (wrong or missing key, or tampering). The key comes
+    /// with the record's namespace.
+    pub(crate) fn try_decode_key(buf: &[u8], format: &Format) -> Result<Option<DecodedKey>> {
+        let Ok((header, total_len)) = check(buf, format.checksum()) else { return Ok(None) };
+        let (key, _) = open_body(buf, &header, format)?;
+        Ok(Some((header.namespace, key, header.kind(), total_len)))
+    }
+
+    /// Whether the record at the start of `buf` stores its key and value
+    /// as-is, so `RecordRef` can borrow them. Reads only the header.
+    pub(crate) fn is_plain(buf: &[u8]) -> bool {
+        parse_header(buf).is_ok_and(|header| !header.encrypted() && header.flags & COMPRESSION_MASK == 0)
+    }
+
+    /// Expiry time from the header at the start of `buf`, which is never
+    /// encrypted
+    pub(crate) fn header_expiry(buf: &[u8]) -> Option<u64> {
+        parse_header(buf).ok()?.expires_at
+    }
+
+    /// Key length and stored (possibly compressed) value length from the
+    /// header at the start of `buf`
+    pub(crate) fn stored_lens(buf: &[u8]) -> Option<(usize, usize)> {
+        let header = parse_header(buf).ok()?;
+        Some((header.key_len, header.val_len))
+    }
+
+    /// Find the next sync marker in `buf` that starts a valid record.
+    /// Used to skip past a damaged region; only marked records can be
+    /// found this way, legacy records after the damage stay lost.
+    pub fn resync(buf: &[u8]) -> Option<usize> {
+        Self::resync_with(buf, &Format::default())
+    }
+
+    /// `resync` for a log in `format`
+    pub fn resync_with(buf: &[u8], format: &Format) -> Option<usize> {
+        let mut from = 0;
+        while let Some(i) = buf[from..].windows(RECORD_MAGIC.len()).position(|w| w == RECORD_MAGIC) {
+            let candidate = from + i;
+            if check(&buf[candidate..], format.checksum()).is_ok() {
+                return Some(candidate);
+            }
+            from = candidate + 1;
         }
+        None
+    }
+}
 
-        let key = buf[8..8 + key_len].to_vec();
-        let value = buf[8 + key_len..8 + key_len + val_len].to_vec();
-        Ok((Record { key, value }, total_len))
+impl<'a> RecordRef<'a> {
+    /// `decode_with` for a log in the default format
+    pub fn decode(buf: &'a [u8]) -> Result<(Self, usize)> {
+        Self::decode_with(buf, &Format::default())
     }
-}
\ No newline at end of file
+
+    /// Verify the record at the start of `buf`, like `Record::decode_with`,
+    /// but point into `buf` for its key and value instead of copying them.
+    /// Only a record stored as-is can be borrowed: one that is compressed
+    /// or encrypted fails, and needs `Record::decode_with`.
+    pub fn decode_with(buf: &'a [u8], format: &Format) -> Result<(Self, usize)> {
+        let (header, total_len) = check_within_limits(buf, format)?;
+        if header.encrypted() || header.flags & COMPRESSION_MASK != 0 {
+            return Err(anyhow!("Record is stored compressed or encrypted"));
+        }
+        let body = &buf[header.len..header.len + header.key_len + header.val_len];
+        let (key, value) = body.split_at(header.key_len);
+        Ok((Self { key, value, kind: header.kind(), namespace: header.namespace, expires_at: header.expires_at }, total_len))
+    }
+
+    pub fn to_record(&self) -> Record {
+        Record {
+            key: self.key.to_vec(),
+            value: self.value.to_vec(),
+            kind: self.kind,
+            namespace: self.namespace,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// An encoded record as the parts of its frame, from `Record::encode_parts`
+pub(crate) struct EncodedRecord<'a> {
+    /// The header, followed for an encrypted record by the key version and
+    /// the sealed key and value
+    head: Vec<u8>,
+    /// Empty for an encrypted record
+    key: &'a [u8],
+    /// The stored value; empty for an encrypted record
+    value: Cow<'a, [u8]>,
+    /// Little-endian, of which `checksum.size()` bytes are written
+    sum: [u8; MAX_CHECKSUM_SIZE],
+    checksum: Checksum,
+}
+
+impl EncodedRecord<'_> {
+    pub(crate) fn len(&self) -> usize {
+        self.head.len() + self.key.len() + self.value.len() + self.checksum.size()
+    }
+
+    /// The frame as slices for `write_vectored`
+    pub(crate) fn io_slices(&self) -> [IoSlice<'_>; 4] {
+        [
+            IoSlice::new(&self.head),
+            IoSlice::new(self.key),
+            IoSlice::new(&self.value),
+            IoSlice::new(&self.sum[..self.checksum.size()]),
+        ]
+    }
+
+    /// The frame in one buffer
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        let mut buf = self.head;
+        buf.reserve_exact(self.key.len() + self.value.len() + self.checksum.size());
+        buf.extend_from_slice(self.key);
+        buf.extend_from_slice(&self.value);
+        buf.extend_from_slice(&self.sum[..self.checksum.size()]);
+        buf
+    }
+
+    /// The buffer the head was built in, for the next `encode_parts`
+    pub(crate) fn into_buffer(self) -> Vec<u8> {
+        self.head
+    }
+}
+
+/// One step of a `Scanner` walk
+#[derive(Debug, Clone)]
+pub enum ScanItem {
+    /// A record that passed its checks, occupying `size` bytes at `offset`
+    Valid { offset: u64, size: usize, record: Record },
+    /// A record that passed its checks but whose value this build cannot
+    /// decode, e.g. compressed with a codec whose feature is disabled, or
+    /// encrypted without the key at hand (so `key` is unknown too)
+    Unreadable { offset: u64, size: usize, key: Option<Vec<u8>>, namespace: u32, reason: String },
+    /// A damaged region of `len` bytes starting at `offset`, up to the next
+    /// valid marked record (or the end of the buffer for a torn tail)
+    Corrupt { offset: u64, len: usize, reason: String },
+}
+
+/// Walks a log buffer record by record, resynchronizing at the next sync
+/// marker after damage instead of stopping. The segment footer is skipped.
+pub struct Scanner<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    format: Cow<'a, Format>,
+}
+
+impl<'a> Scanner<'a> {
+    /// Scan `buf` as records in the default format, from its first byte
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0, format: Cow::Owned(Format::default()) }
+    }
+
+    /// Scan a whole log file in `buf`, starting after its header at
+    /// `start` (see `Format::parse`). Offsets stay relative to `buf`.
+    pub fn with_format(buf: &'a [u8], start: usize, format: &'a Format) -> Self {
+        Self { buf, pos: start, format: Cow::Borrowed(format) }
+    }
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = ScanItem;
+
+    fn next(&mut self) -> Option<ScanItem> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        if self.format.footer_offset() == Some(self.pos as u64) && self.buf[self.pos..].starts_with(&FOOTER_MAGIC) {
+            self.pos += FOOTER_SIZE;
+            return self.next();
+        }
+        let offset = self.pos;
+        let rest = &self.buf[offset..];
+        match check(rest, self.format.checksum()) {
+            Ok((header, size)) => {
+                self.pos += size;
+                let offset = offset as u64;
+                Some(match decode_checked(rest, &header, &self.format) {
+                    Ok(record) => ScanItem::Valid { offset, size, record },
+                    Err(e) => {
+                        let key = open_body(rest, &header, &self.format).ok().map(|(key, _)| key);
+                        ScanItem::Unreadable { offset, size, key, namespace: header.namespace, reason: e.to_string() }
+                    }
+                })
+            }
+            Err(e) => {
+                let len = match Record::resync_with(&rest[1..], &self.format) {
+                    Some(skip) => 1 + skip,
+                    None => rest.len(),
+                };
+                self.pos += len;
+                Some(ScanItem::Corrupt { offset: offset as u64, len, reason: e.to_string() })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn record() -> impl Strategy<Value = Record> {
+        let kind = prop_oneof![
+            Just(RecordKind::Put),
+            Just(RecordKind::Delete),
+            Just(RecordKind::ValuePointer),
+            Just(RecordKind::RangeDelete),
+        ];
+        let bytes = || proptest::collection::vec(any::<u8>(), 0..300);
+        (kind, bytes(), bytes(), any::<u32>(), any::<Option<u64>>()).prop_map(|(kind, key, value, namespace, expires_at)| {
+            // What a record of each kind can hold: tombstones carry no
+            // value, and no expiry
+            let record = match kind {
+                RecordKind::Put => Record::new(key, value).with_expiry(expires_at),
+                RecordKind::Delete => Record::tombstone(key),
+                RecordKind::ValuePointer => Record::value_pointer(key, value).with_expiry(expires_at),
+                RecordKind::RangeDelete => Record::range_tombstone(key, value),
+            };
+            record.in_namespace(namespace)
+        })
+    }
+
+    fn checksum() -> impl Strategy<Value = Checksum> {
+        prop_oneof![Just(Checksum::Crc32), Just(Checksum::Crc32c), Just(Checksum::XxHash64)]
+    }
+
+    /// Every codec this build has
+    fn compression() -> impl Strategy<Value = Compression> {
+        let codecs = vec![
+            Compression::None,
+            #[cfg(feature = "compression-lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd { level: 1 },
+        ];
+        proptest::sample::select(codecs)
+    }
+
+    fn format(checksum: Checksum) -> Format {
+        let mut format = Format::default();
+        format.set_checksum(checksum);
+        format
+    }
+
+    proptest! {
+        #[test]
+        fn decode_undoes_encode(record in record(), compression in compression(), checksum in checksum()) {
+            let format = format(checksum);
+            let encoded = record.encode_with(compression, &format).unwrap();
+            let (decoded, size) = Record::decode_with(&encoded, &format).unwrap();
+            prop_assert_eq!(&decoded, &record);
+            prop_assert_eq!(size, encoded.len());
+            prop_assert_eq!(Record::frame_len_with(&encoded, &format), Some(size));
+            if Record::is_plain(&encoded) {
+                let (borrowed, size) = RecordRef::decode_with(&encoded, &format).unwrap();
+                prop_assert_eq!(borrowed.to_record(), record);
+                prop_assert_eq!(size, encoded.len());
+            }
+        }
+
+        #[test]
+        fn no_prefix_of_a_record_decodes(record in record(), compression in compression(), checksum in checksum(), cut in any::<prop::sample::Index>()) {
+            let format = format(checksum);
+            let encoded = record.encode_with(compression, &format).unwrap();
+            let prefix = &encoded[..cut.index(encoded.len())];
+            prop_assert!(Record::decode_with(prefix, &format).is_err());
+            prop_assert!(Record::frame_len_with(prefix, &format).is_none());
+        }
+
+        #[test]
+        fn decoding_any_bytes_does_not_panic(buf in proptest::collection::vec(any::<u8>(), 0..64), checksum in checksum()) {
+            let format = format(checksum);
+            let _ = Record::decode_with(&buf, &format);
+            let _ = RecordRef::decode_with(&buf, &format);
+            let _ = Record::header_frame_len_with(&buf, &format);
+            prop_assert!(Scanner::with_format(&buf, 0, &format).count() <= buf.len());
+        }
+
+        #[test]
+        fn trailing_bytes_do_not_change_a_record(
+            record in record(),
+            compression in compression(),
+            checksum in checksum(),
+            garbage in proptest::collection::vec(any::<u8>(), 1..64),
+        ) {
+            let format = format(checksum);
+            let mut buf = record.encode_with(compression, &format).unwrap();
+            let len = buf.len();
+            buf.extend_from_slice(&garbage);
+            let (decoded, size) = Record::decode_with(&buf, &format).unwrap();
+            prop_assert_eq!(decoded, record);
+            prop_assert_eq!(size, len);
+        }
+    }
+
+    #[test]
+    fn decoding_enforces_the_length_limits() {
+        let encoded = Record::new(vec![1; 8], vec![2; 16]).encode_with(Compression::None, &Format::default()).unwrap();
+        let error = |result: Result<()>| result.unwrap_err().downcast::<Error>().unwrap();
+        let decode_all = |format: &Format| -> [Result<()>; 3] {
+            [
+                Record::decode_with(&encoded, format).map(drop),
+                RecordRef::decode_with(&encoded, format).map(drop),
+                Record::decode_key_with(&encoded, format).map(drop),
+            ]
+        };
+
+        let mut format = Format::default();
+        format.set_max_key_len(7);
+        for result in decode_all(&format) {
+            assert_eq!(error(result), Error::KeyTooLarge { len: 8, max: 7 });
+        }
+        let mut format = Format::default();
+        format.set_max_value_len(15);
+        let [value, borrowed, key] = decode_all(&format);
+        assert_eq!(error(value), Error::ValueTooLarge { len: 16, max: 15 });
+        assert_eq!(error(borrowed), Error::ValueTooLarge { len: 16, max: 15 });
+        // Only the key is asked for, but the record is over the limits
+        assert_eq!(error(key), Error::ValueTooLarge { len: 16, max: 15 });
+        format.set_max_key_len(8);
+        format.set_max_value_len(16);
+        assert!(decode_all(&format).into_iter().all(|result| result.is_ok()));
+    }
+}
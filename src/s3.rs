@@ -0,0 +1,277 @@
+//! Minimal client for S3-compatible object stores, for archiving tables
+//! (`ArchivePolicy::remote`) and uploading backups
+//!
+//! Requests are signed with AWS Signature Version 4 and addressed
+//! path-style, `<endpoint>/<bucket>/<key>`, which AWS, MinIO, Ceph and
+//! most other implementations accept. Bodies are hashed in full, so an
+//! object is held in memory while it is sent or received.
+//!
+//! A backup is a checkpoint (`Engine::checkpoint`) uploaded file by file
+//! under `<prefix>backups/<name>/`, followed by a `FILES` object listing
+//! them, which `download_dir` reads back. A backup without `FILES` was cut
+//! short.
+
+use anyhow::{anyhow, bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::engine::sync_parent_dir;
+
+/// Object listing every file of a backup, one relative path per line
+const FILES: &str = "FILES";
+
+/// Where objects go and how to sign for them
+#[derive(Clone, PartialEq, Eq)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://minio:9000`,
+    /// without a trailing slash or a default port
+    pub endpoint: String,
+    pub bucket: String,
+    /// `us-east-1` for most S3-compatible servers that ignore it
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key, e.g. `edge-7/`
+    pub prefix: String,
+}
+
+impl S3Config {
+    /// Credentials and region from `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_REGION` (default `us-east-1`)
+    pub fn from_env(endpoint: &str, bucket: &str, prefix: &str) -> Result<Self> {
+        let var = |name: &str| std::env::var(name).with_context(|| format!("reading {}", name));
+        Ok(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+impl fmt::Debug for S3Config {
+    /// Leaves the secret key out
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Puts, gets and deletes objects under `S3Config::prefix`
+pub struct S3Client {
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+impl S3Client {
+    pub fn new(config: S3Config) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(10))
+            .timeout_read(Duration::from_secs(60))
+            .timeout_write(Duration::from_secs(60))
+            .build();
+        Self { config, agent }
+    }
+
+    pub fn config(&self) -> &S3Config {
+        &self.config
+    }
+
+    pub fn put(&self, key: &str, body: &[u8]) -> Result<()> {
+        self.request("PUT", key, body)?.ok_or_else(|| anyhow!("bucket {} not found", self.config.bucket))?;
+        Ok(())
+    }
+
+    /// The object's bytes, or `None` if there is no such object
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(response) = self.request("GET", key, &[])? else { return Ok(None) };
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body).with_context(|| format!("reading object {}", key))?;
+        Ok(Some(body))
+    }
+
+    /// Succeeds whether or not the object existed
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.request("DELETE", key, &[])?;
+        Ok(())
+    }
+
+    pub fn upload_file(&self, key: &str, path: &Path) -> Result<()> {
+        let body = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        self.put(key, &body)
+    }
+
+    /// Write the object to `path` through a temporary file, durably;
+    /// returns false, writing nothing, if there is no such object
+    pub fn download_file(&self, key: &str, path: &Path) -> Result<bool> {
+        let Some(body) = self.get(key)? else { return Ok(false) };
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&body)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        sync_parent_dir(path)?;
+        Ok(true)
+    }
+
+    /// Upload every file under `dir`, e.g. a checkpoint, as backup `name`,
+    /// then its `FILES` list
+    pub fn upload_dir(&self, dir: &Path, name: &str) -> Result<()> {
+        let mut files = Vec::new();
+        collect_files(dir, Path::new(""), &mut files)?;
+        let mut list = String::new();
+        for relative in &files {
+            let relative = relative.to_str().ok_or_else(|| anyhow!("{} is not UTF-8", relative.display()))?;
+            let relative = relative.replace(std::path::MAIN_SEPARATOR, "/");
+            self.upload_file(&backup_key(name, &relative), &dir.join(&relative))?;
+            list.push_str(&relative);
+            list.push('\n');
+        }
+        self.put(&backup_key(name, FILES), list.as_bytes())
+    }
+
+    /// Download backup `name` into `dir`, created if missing
+    pub fn download_dir(&self, name: &str, dir: &Path) -> Result<()> {
+        let list = self.get(&backup_key(name, FILES))?.ok_or_else(|| anyhow!("no complete backup named {}", name))?;
+        let list = String::from_utf8(list).context("backup file list is not UTF-8")?;
+        for relative in list.lines() {
+            if relative.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+                bail!("backup {} lists an unsafe path {:?}", name, relative);
+            }
+            let path = dir.join(relative);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if !self.download_file(&backup_key(name, relative), &path)? {
+                bail!("backup {} is missing {}", name, relative);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a signed request for `key`, under the prefix; `None` for a 404
+    fn request(&self, method: &str, key: &str, body: &[u8]) -> Result<Option<ureq::Response>> {
+        let config = &self.config;
+        let path = format!("/{}/{}", uri_encode(&config.bucket, false), uri_encode(&format!("{}{}", config.prefix, key), true));
+        let host = config.endpoint.split_once("://").map_or(config.endpoint.as_str(), |(_, rest)| rest);
+        let host = host.split('/').next().unwrap_or(host);
+        let payload_hash = hex(&Sha256::digest(body));
+        let date = amz_date(SystemTime::now());
+        let headers = [("host", host), ("x-amz-content-sha256", &payload_hash), ("x-amz-date", &date)];
+        let authorization = self.authorization(method, &path, "", &headers, &payload_hash, &date);
+
+        let request = self
+            .agent
+            .request(method, &format!("{}{}", config.endpoint, path))
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &date)
+            .set("authorization", &authorization);
+        match request.send_bytes(body) {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(status, response)) => {
+                let detail = response.into_string().unwrap_or_default();
+                bail!("{} {} failed with status {}: {}", method, key, status, detail.trim())
+            }
+            Err(e) => Err(anyhow::Error::from(e).context(format!("{} {}", method, key))),
+        }
+    }
+
+    /// Signature Version 4 `Authorization` header for a request with
+    /// `headers`, lowercase and sorted by name
+    fn authorization(&self, method: &str, path: &str, query: &str, headers: &[(&str, &str)], payload_hash: &str, date: &str) -> String {
+        let config = &self.config;
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let canonical_request =
+            format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, payload_hash);
+        let day = &date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", day, config.region);
+        let string_to_sign =
+            format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+        let mut key = hmac(format!("AWS4{}", config.secret_key).as_bytes(), day.as_bytes());
+        for part in [config.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            config.access_key, scope, signed_headers, signature
+        )
+    }
+}
+
+/// Key of file `relative` of backup `name`, under the prefix
+fn backup_key(name: &str, relative: &str) -> String {
+    format!("backups/{}/{}", name, relative)
+}
+
+/// Files under `dir`, as paths relative to it, sorted
+fn collect_files(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir.join(relative))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            collect_files(dir, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode all but the unreserved characters, and `/` if
+/// `keep_slash`
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// `time` as `YYYYMMDDTHHMMSSZ`
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
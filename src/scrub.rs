@@ -0,0 +1,213 @@
+//! Background scrubbing of the log
+//!
+//! A scrubber thread re-reads the durable part of the log at a limited
+//! rate, checking each record's framing and checksum, so latent disk
+//! corruption shows up in `Engine::scrub_report()` instead of during a
+//! recovery. Values are not decoded, so no keys or codecs are needed.
+
+use anyhow::Result;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::format::Format;
+use crate::record::{Record, MAX_HEADER_SIZE, RECORD_MAGIC};
+use crate::segment::{FOOTER_MAGIC, FOOTER_SIZE};
+
+/// Bytes read from the log at a time
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Settings for `EngineBuilder::scrub`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrubOptions {
+    /// Read rate limit, so scrubbing stays out of the way of foreground I/O
+    pub bytes_per_sec: u64,
+    /// Pause between the end of one pass over the log and the next
+    pub pass_interval: Duration,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self { bytes_per_sec: 8 * 1024 * 1024, pass_interval: Duration::from_secs(3600) }
+    }
+}
+
+/// What the scrubber has seen so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScrubReport {
+    /// Complete passes over the log
+    pub passes: u64,
+    pub bytes_scanned: u64,
+    pub records_checked: u64,
+    /// Start offset and reason of each damaged region found; a region seen
+    /// again by a later pass is not repeated
+    pub corruptions: Vec<(u64, String)>,
+    /// The error that cut the most recent failed pass short, if any
+    pub last_error: Option<String>,
+}
+
+struct Shared {
+    path: PathBuf,
+    options: ScrubOptions,
+    /// End of the durable part of the log; nothing after it is read.
+    /// Locked while a pass opens the file, so it never pairs a new end
+    /// with a replaced file.
+    end: Mutex<u64>,
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    report: Mutex<ScrubReport>,
+}
+
+/// Handle to the scrubber thread, which stops when this is dropped
+pub(crate) struct Scrubber {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    pub(crate) fn start(path: PathBuf, options: ScrubOptions, end: u64) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            path,
+            options,
+            end: Mutex::new(end),
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+            report: Mutex::new(ScrubReport::default()),
+        });
+        let thread = std::thread::Builder::new().name("mini-kv-scrub".to_string()).spawn({
+            let shared = shared.clone();
+            move || run(&shared)
+        })?;
+        Ok(Self { shared, thread: Some(thread) })
+    }
+
+    /// Move the end of the durable log after a sync
+    pub(crate) fn set_end(&self, end: u64) {
+        *self.lock_end() = end;
+    }
+
+    /// Hold while replacing the log file, then set the new end
+    pub(crate) fn lock_end(&self) -> MutexGuard<'_, u64> {
+        self.shared.end.lock().unwrap()
+    }
+
+    pub(crate) fn report(&self) -> ScrubReport {
+        self.shared.report.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    /// Sleep for `duration`, or less if stopped; false once stopped
+    fn pause(&self, duration: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self.wake.wait_timeout_while(stopped, duration, |stopped| !*stopped).unwrap();
+        !*stopped
+    }
+
+    fn record_corruption(&self, offset: u64, reason: String) {
+        let mut report = self.report.lock().unwrap();
+        if !report.corruptions.iter().any(|&(seen, _)| seen == offset) {
+            report.corruptions.push((offset, reason));
+        }
+    }
+}
+
+fn run(shared: &Shared) {
+    loop {
+        match scrub_pass(shared) {
+            Ok(true) => shared.report.lock().unwrap().passes += 1,
+            Ok(false) => return,
+            Err(e) => shared.report.lock().unwrap().last_error = Some(format!("{:#}", e)),
+        }
+        if !shared.pause(shared.options.pass_interval) {
+            return;
+        }
+    }
+}
+
+/// Check every record up to the durable end once. Returns false if
+/// stopped partway.
+fn scrub_pass(shared: &Shared) -> Result<bool> {
+    // Opened per pass, so a compaction's new file (and header) is picked up
+    let (mut file, end) = {
+        let end = shared.end.lock().unwrap();
+        (File::open(&shared.path)?, *end)
+    };
+    let (format, start) = Format::read_from(&mut file)?;
+    let end = end.min(file.metadata()?.len());
+    file.seek(SeekFrom::Start(start as u64))?;
+
+    // `window[at..]` holds the file from `pos` on; the file cursor is at
+    // the end of `window`
+    let mut pos = start as u64;
+    let mut window = Vec::new();
+    let mut at = 0;
+    let mut damaged: Option<(u64, String)> = None;
+    while pos < end {
+        // Bytes the next step looks at: a whole record, if its header says
+        // how long it is and it ends in time
+        let left = (end - pos) as usize;
+        let need = match Record::header_frame_len_with(&window[at..], &format) {
+            Some(size) => size.min(left),
+            None => MAX_HEADER_SIZE.max(FOOTER_SIZE).min(left),
+        };
+        if window.len() - at < need {
+            window.drain(..at);
+            at = 0;
+            let want = need.max(CHUNK_BYTES.min(left));
+            let read = (&mut file).take((want - window.len()) as u64).read_to_end(&mut window)?;
+            if read == 0 {
+                break; // the file shrank under us, so it was replaced
+            }
+            shared.report.lock().unwrap().bytes_scanned += read as u64;
+            let delay = Duration::from_secs_f64(read as f64 / shared.options.bytes_per_sec.max(1) as f64);
+            if !shared.pause(delay) {
+                return Ok(false);
+            }
+            continue;
+        }
+
+        let rest = &window[at..];
+        let consumed = if format.footer_offset() == Some(pos) && rest.starts_with(&FOOTER_MAGIC) {
+            FOOTER_SIZE.min(rest.len())
+        } else {
+            match Record::check_with(rest, &format) {
+                Ok(size) => {
+                    shared.report.lock().unwrap().records_checked += 1;
+                    if let Some((offset, reason)) = damaged.take() {
+                        shared.record_corruption(offset, reason);
+                    }
+                    size
+                }
+                // Skip to the next sync marker; anything up to a record
+                // that checks out is one damaged region
+                Err(e) => {
+                    damaged.get_or_insert((pos, e.to_string()));
+                    match rest[1..].windows(RECORD_MAGIC.len()).position(|w| w == RECORD_MAGIC) {
+                        Some(i) => 1 + i,
+                        None => rest.len().saturating_sub(RECORD_MAGIC.len() - 1).max(1),
+                    }
+                }
+            }
+        };
+        at += consumed;
+        pos += consumed as u64;
+    }
+    if let Some((offset, reason)) = damaged {
+        shared.record_corruption(offset, reason);
+    }
+    Ok(true)
+}
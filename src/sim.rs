@@ -0,0 +1,314 @@
+//! Deterministic crash simulation
+//!
+//! A script of operations, generated from a seed, is run against an
+//! `Engine` on `FaultyStorage` over `MemStorage`. Just before each write
+//! and each sync (`FaultyStorage::on_crash_point`), an engine is opened on
+//! what a crash there would leave (`MemStorage::after_crash`). It must
+//! hold the state after some prefix of the script: no shorter than the
+//! operations known to be durable at that point, no longer than the ones
+//! begun. It must then verify clean and take new writes. One run of the
+//! script checks every crash point, so no crash costs more than a
+//! recovery.
+//!
+//! A power loss can also leave part of what was written after the last
+//! sync, with the last block torn. So each crash point is checked a second
+//! time on a torn image (`tear`), which must recover the same way: records
+//! cut short or zeroed are truncated, never returned.
+//!
+//! Everything runs in memory on one thread, so a failure replays exactly
+//! from the seed, the configuration and the crash point in its message.
+//! Rerunning the script with that point as a `Fault::CrashAtWrite` or
+//! `Fault::CrashAtSync` stops the engine there for a closer look; a test
+//! checks that this leaves the same image.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{Engine, EngineBuilder, FaultyStorage, MemStorage, Storage, SyncMode};
+
+const PATH: &str = "/sim/data.db";
+
+/// Few keys, so the script overwrites and deletes live ones
+const KEYS: u32 = 8;
+
+/// The most a disk is taken to persist atomically
+const BLOCK: usize = 4096;
+
+type State = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Sync,
+    Compact,
+    /// Drop the engine, shutting down cleanly, and open it again
+    Reopen,
+}
+
+/// Engine settings a script runs under
+struct Config {
+    name: &'static str,
+    build: fn(EngineBuilder) -> EngineBuilder,
+}
+
+const CONFIGS: &[Config] = &[
+    Config { name: "batch", build: |builder| builder.sync_mode(SyncMode::Batch(4)) },
+    Config { name: "always, write buffer", build: |builder| builder.sync_mode(SyncMode::Always).write_buffer(96) },
+    Config { name: "index file", build: |builder| builder.sync_mode(SyncMode::Batch(3)).index_memory_budget(128) },
+    Config { name: "no snapshot", build: |builder| builder.sync_mode(SyncMode::Batch(5)).index_snapshot(false) },
+];
+
+fn script(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let key = |rng: &mut StdRng| format!("key{}", rng.gen_range(0..KEYS)).into_bytes();
+    (0..len)
+        .map(|_| match rng.gen_range(0..100) {
+            0..=54 => {
+                let key = key(&mut rng);
+                let value = (0..rng.gen_range(0..48)).map(|_| rng.gen()).collect();
+                Op::Put(key, value)
+            }
+            55..=74 => Op::Delete(key(&mut rng)),
+            75..=86 => Op::Sync,
+            87..=91 => Op::Compact,
+            _ => Op::Reopen,
+        })
+        .collect()
+}
+
+/// The state after each prefix of `ops`, from the empty one
+fn states(ops: &[Op]) -> Vec<State> {
+    let mut state = State::new();
+    let mut states = vec![state.clone()];
+    for op in ops {
+        match op {
+            Op::Put(key, value) => {
+                state.insert(key.clone(), value.clone());
+            }
+            Op::Delete(key) => {
+                state.remove(key);
+            }
+            Op::Sync | Op::Compact | Op::Reopen => {}
+        }
+        states.push(state.clone());
+    }
+    states
+}
+
+/// `crash_test`'s workload: unique keys put in order
+fn sequential(len: usize) -> Vec<Op> {
+    (0..len).map(|i| Op::Put(format!("key_{}", i).into_bytes(), vec![i as u8; 128])).collect()
+}
+
+/// How far a run got: ops `..durable` were durable, ops `..begun` begun
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Run {
+    durable: usize,
+    begun: usize,
+}
+
+fn open<S: Storage>(storage: S, config: &Config) -> Result<Engine<S>> {
+    (config.build)(Engine::builder(PATH)).storage(storage).open()
+}
+
+/// Run `ops` until they finish or `storage` goes down, keeping `progress`
+/// up to date
+fn run(ops: &[Op], storage: &FaultyStorage<MemStorage>, config: &Config, progress: &Mutex<Run>) -> Result<()> {
+    let mut engine = match open(storage.clone(), config) {
+        Ok(engine) => Some(engine),
+        Err(_) if storage.is_down() => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for (i, op) in ops.iter().enumerate() {
+        progress.lock().unwrap().begun = i + 1;
+        let result = (|| {
+            if let Op::Reopen = op {
+                engine = None;
+                engine = Some(open(storage.clone(), config)?);
+                return Ok(false);
+            }
+            let engine = engine.as_mut().unwrap();
+            let synced = match op {
+                Op::Put(key, value) => {
+                    engine.put(key.clone(), value.clone())?;
+                    engine.unsynced_bytes() == 0
+                }
+                Op::Delete(key) => engine.delete(key)? && engine.unsynced_bytes() == 0,
+                Op::Sync => {
+                    engine.sync()?;
+                    true
+                }
+                Op::Compact => {
+                    engine.compact()?;
+                    true
+                }
+                Op::Reopen => unreachable!(),
+            };
+            Ok::<_, anyhow::Error>(synced)
+        })();
+        if storage.is_down() {
+            return Ok(());
+        }
+        // A write that leaves nothing unsynced made everything before it
+        // durable too
+        if result.with_context(|| format!("op {} ({:?}) failed with no fault injected", i, op))? {
+            progress.lock().unwrap().durable = i + 1;
+        }
+    }
+    // Dropping the engine may sync, or crash
+    drop(engine);
+    Ok(())
+}
+
+fn contents<S: Storage>(engine: &Engine<S>) -> Result<State> {
+    Ok(engine.scan_prefix(b"")?.into_iter().collect())
+}
+
+/// Open what a crash left and check it against the script
+fn check_recovery(image: MemStorage, config: &Config, states: &[State], run: &Run) -> Result<()> {
+    let mut engine = open(image.clone(), config).context("reopening")?;
+    let recovered = contents(&engine)?;
+    if !(run.durable..=run.begun).any(|i| states[i] == recovered) {
+        let nearest = states.iter().position(|state| *state == recovered);
+        bail!(
+            "recovered {} keys, the state after none of ops {}..={} (durable..=begun); matches prefix {:?}",
+            recovered.len(),
+            run.durable,
+            run.begun,
+            nearest
+        );
+    }
+    let report = engine.verify()?;
+    if !report.is_healthy() {
+        bail!("recovered log does not verify: {:?}", report);
+    }
+    engine.put(b"after".to_vec(), b"crash".to_vec())?;
+    engine.sync()?;
+    drop(engine);
+    let engine = open(image, config).context("reopening after recovery")?;
+    let mut expected = recovered;
+    expected.insert(b"after".to_vec(), b"crash".to_vec());
+    if contents(&engine)? != expected {
+        bail!("a write after recovery was lost");
+    }
+    Ok(())
+}
+
+/// Make `image`, what a crash of `current` left, keep a random part of
+/// each file's unsynced bytes, then tear the last block of that part: cut
+/// it short, or zero it, from a random point. A file whose name still
+/// refers to an older file than in `current` is left as it is.
+fn tear(current: &MemStorage, image: &MemStorage, rng: &mut StdRng) -> Result<()> {
+    use crate::Storage;
+    for path in image.paths() {
+        let (Some(written), Some(synced)) = (current.read(&path), image.read(&path)) else { continue };
+        if written.len() <= synced.len() || !written.starts_with(&synced) {
+            continue;
+        }
+        let end = rng.gen_range(synced.len() + 1..=written.len());
+        let block_start = ((end - 1) / BLOCK * BLOCK).max(synced.len());
+        let torn_at = rng.gen_range(block_start..end);
+        let mut kept = written[synced.len()..torn_at].to_vec();
+        if rng.gen() {
+            kept.resize(end - synced.len(), 0);
+        }
+        let mut file = image.open(&path, false)?;
+        file.write_at(&kept, synced.len() as u64)?;
+        file.sync()?;
+    }
+    Ok(())
+}
+
+/// Check what a crash of `current` now would leave, whole and torn
+fn check_crash_point(current: &MemStorage, config: &Config, states: &[State], run: &Run, rng: &mut StdRng) -> Result<()> {
+    check_recovery(current.after_crash(), config, states, run)?;
+    let torn = current.after_crash();
+    tear(current, &torn, rng)?;
+    check_recovery(torn, config, states, run).context("torn")
+}
+
+/// Run `ops` under `config` once, checking every crash point on the way
+/// and the final state; returns the number of crash points. `seed` drives
+/// the tearing.
+fn simulate(ops: &[Op], seed: u64, config: &'static Config) -> Result<u64> {
+    let states = Arc::new(states(ops));
+    let progress = Arc::new(Mutex::new(Run::default()));
+    let failure = Arc::new(Mutex::new(None));
+    let storage = FaultyStorage::new(MemStorage::new());
+    storage.on_crash_point({
+        let (current, states, progress, failure) = (storage.inner().clone(), states.clone(), progress.clone(), failure.clone());
+        let mut rng = StdRng::seed_from_u64(seed);
+        move |point| {
+            let mut failure = failure.lock().unwrap();
+            if failure.is_none() {
+                let run = *progress.lock().unwrap();
+                let result = check_crash_point(&current, config, &states, &run, &mut rng);
+                *failure = result.with_context(|| format!("crash at {:?}, {:?}", point, run)).err();
+            }
+        }
+    });
+    run(ops, &storage, config, &progress)?;
+    if let Some(e) = failure.lock().unwrap().take() {
+        return Err(e);
+    }
+    let engine = open(storage.inner().clone(), config)?;
+    if contents(&engine)? != states[ops.len()] {
+        bail!("a clean run does not hold the final state");
+    }
+    Ok(storage.writes() + storage.syncs())
+}
+
+/// Every file of `storage` and its contents
+fn snapshot(storage: &MemStorage) -> Vec<(PathBuf, Vec<u8>)> {
+    storage.paths().into_iter().map(|path| (path.clone(), storage.read(&path).unwrap())).collect()
+}
+
+#[test]
+fn recovers_a_durable_prefix_after_every_crash_point() {
+    for config in CONFIGS {
+        for seed in 0..16 {
+            simulate(&script(seed, 80), seed, config).with_context(|| format!("seed {} under {}", seed, config.name)).unwrap();
+        }
+    }
+}
+
+#[test]
+fn recovers_the_crash_test_workload_in_process() {
+    for config in &CONFIGS[..2] {
+        simulate(&sequential(200), 0, config).with_context(|| format!("sequential puts under {}", config.name)).unwrap();
+    }
+}
+
+/// A rerun stopped by `Fault::CrashAtWrite` or `Fault::CrashAtSync` leaves
+/// the image the crash point hook saw, as far as the script had got
+#[test]
+fn crash_points_match_reruns_stopped_there() {
+    let config = &CONFIGS[0];
+    let ops = script(7, 40);
+    let points = Arc::new(Mutex::new(Vec::new()));
+    let progress = Arc::new(Mutex::new(Run::default()));
+    let storage = FaultyStorage::new(MemStorage::new());
+    storage.on_crash_point({
+        let (current, points, progress) = (storage.inner().clone(), points.clone(), progress.clone());
+        move |point| points.lock().unwrap().push((point, *progress.lock().unwrap(), snapshot(&current.after_crash())))
+    });
+    run(&ops, &storage, config, &progress).unwrap();
+
+    let points = points.lock().unwrap();
+    assert_eq!(points.len() as u64, storage.writes() + storage.syncs());
+    for (point, seen, image) in points.iter() {
+        let storage = FaultyStorage::new(MemStorage::new());
+        storage.inject(*point);
+        let progress = Mutex::new(Run::default());
+        run(&ops, &storage, config, &progress).unwrap();
+        assert!(storage.is_down(), "{:?} never fired", point);
+        assert_eq!(*progress.lock().unwrap(), *seen, "{:?}", point);
+        assert!(snapshot(&storage.inner().after_crash()) == *image, "{:?} left a different image", point);
+    }
+}
@@ -0,0 +1,73 @@
+//! Slow-operation log, for `EngineBuilder::slow_op_threshold`
+//!
+//! A `put()`, `get()` or `sync()` that takes at least the threshold is
+//! reported with its duration, key size and the engine's sync mode: to
+//! the `EngineBuilder::on_slow_op` callback if there is one, otherwise as
+//! a `tracing` warning with the `tracing` feature, or a line on stderr.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::engine::SyncMode;
+
+/// The kind of call a `SlowOp` timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowOpKind {
+    Put,
+    Get,
+    Sync,
+}
+
+/// A call that took at least `EngineBuilder::slow_op_threshold`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlowOp {
+    pub kind: SlowOpKind,
+    pub duration: Duration,
+    /// 0 for a sync
+    pub key_len: usize,
+    pub sync_mode: SyncMode,
+}
+
+impl fmt::Display for SlowOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            SlowOpKind::Put => "put",
+            SlowOpKind::Get => "get",
+            SlowOpKind::Sync => "sync",
+        };
+        write!(f, "slow {} took {:?}", kind, self.duration)?;
+        if self.kind != SlowOpKind::Sync {
+            write!(f, " ({}-byte key)", self.key_len)?;
+        }
+        write!(f, ", sync mode {:?}", self.sync_mode)
+    }
+}
+
+pub(crate) type SlowOpFn = Box<dyn Fn(&SlowOp) + Send + Sync>;
+
+pub(crate) struct SlowOpLog {
+    threshold: Duration,
+    callback: Option<SlowOpFn>,
+}
+
+impl SlowOpLog {
+    pub(crate) fn new(threshold: Duration, callback: Option<SlowOpFn>) -> Self {
+        Self { threshold, callback }
+    }
+
+    /// Report the call of `kind` begun at `started`, if it was slow
+    pub(crate) fn check(&self, kind: SlowOpKind, started: Instant, key_len: usize, sync_mode: SyncMode) {
+        let duration = started.elapsed();
+        if duration < self.threshold {
+            return;
+        }
+        let op = SlowOp { kind, duration, key_len, sync_mode };
+        match &self.callback {
+            Some(callback) => callback(&op),
+            #[cfg(feature = "tracing")]
+            None => tracing::warn!(kind = ?op.kind, duration = ?op.duration, key_len, sync_mode = ?op.sync_mode, "slow operation"),
+            #[cfg(not(feature = "tracing"))]
+            None => eprintln!("mini-kv: {}", op),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Portable snapshots, for `Engine::export_snapshot` and
+//! `Engine::import_snapshot`
+//!
+//! A snapshot is a single stream holding the latest value of every live
+//! key, with its namespace and expiry time, and nothing of the log it came
+//! from: no offsets, tombstones or overwritten records. Every integer is
+//! little-endian and of fixed width, so a snapshot reads the same on any
+//! machine. The header names its checksum algorithm, and the namespaces
+//! by name, since ids are only meaningful within one database:
+//!
+//! ```text
+//! header: magic "MKVSNAP\0"(8) | version(2) | checksum(1) | namespaces(4)
+//!         | { id(4) | name_len(4) | name }* | sum
+//! entry:  1(1) | namespace(4) | expires_at(8) | key_len(4) | value_len(4)
+//!         | key | value | sum
+//! end:    0(1) | entries(8) | sum
+//! ```
+//!
+//! Each header and entry `sum` covers the bytes of that part before it;
+//! the one at the end covers the whole stream before it, so a snapshot
+//! cut short, or with parts dropped or reordered, fails at its end.
+//! `expires_at` is in milliseconds since the Unix epoch, 0 for none, and
+//! namespace 0 is the default one, which the header does not list.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::checksum::{Checksum, Digest};
+
+const MAGIC: [u8; 8] = *b"MKVSNAP\0";
+const VERSION: u16 = 1;
+const ENTRY: u8 = 1;
+const END: u8 = 0;
+
+/// One key of a snapshot
+pub(crate) struct Entry {
+    pub(crate) namespace: u32,
+    /// Milliseconds since the Unix epoch, 0 for none
+    pub(crate) expires_at: u64,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+/// Writes a snapshot to `W` as its entries come
+pub(crate) struct SnapshotWriter<W: Write> {
+    out: W,
+    checksum: Checksum,
+    /// Of everything written so far
+    digest: Digest,
+    entries: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Write the header, naming `namespaces` (id and name)
+    pub(crate) fn new(out: W, checksum: Checksum, namespaces: &[(u32, String)]) -> Result<Self> {
+        let mut writer = Self { out, checksum, digest: checksum.digest(), entries: 0, buf: Vec::new() };
+        writer.buf.extend_from_slice(&MAGIC);
+        writer.buf.extend_from_slice(&VERSION.to_le_bytes());
+        writer.buf.push(checksum.id());
+        writer.buf.extend_from_slice(&(namespaces.len() as u32).to_le_bytes());
+        for (id, name) in namespaces {
+            writer.buf.extend_from_slice(&id.to_le_bytes());
+            writer.buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            writer.buf.extend_from_slice(name.as_bytes());
+        }
+        writer.write_part()?;
+        Ok(writer)
+    }
+
+    pub(crate) fn entry(&mut self, namespace: u32, expires_at: u64, key: &[u8], value: &[u8]) -> Result<()> {
+        self.buf.push(ENTRY);
+        self.buf.extend_from_slice(&namespace.to_le_bytes());
+        self.buf.extend_from_slice(&expires_at.to_le_bytes());
+        self.buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(key);
+        self.buf.extend_from_slice(value);
+        self.write_part()?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Write the end; returns the entries written
+    pub(crate) fn finish(mut self) -> Result<u64> {
+        self.buf.push(END);
+        self.buf.extend_from_slice(&self.entries.to_le_bytes());
+        self.digest.update(&self.buf);
+        let sum = self.digest.finish().to_le_bytes();
+        self.buf.extend_from_slice(&sum[..self.checksum.size()]);
+        self.out.write_all(&self.buf)?;
+        self.out.flush()?;
+        Ok(self.entries)
+    }
+
+    /// Seal the part in `buf` with its checksum and write it out
+    fn write_part(&mut self) -> Result<()> {
+        self.checksum.append(&mut self.buf);
+        self.digest.update(&self.buf);
+        self.out.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+/// Reads a snapshot from `R`, checking each part as it goes
+pub(crate) struct SnapshotReader<R: Read> {
+    input: R,
+    checksum: Checksum,
+    digest: Digest,
+    entries: u64,
+    /// The part being read, for its checksum
+    part: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Read and check the header; returns the reader and the namespaces
+    /// it names, by id
+    pub(crate) fn new(input: R) -> Result<(Self, HashMap<u32, String>)> {
+        let mut reader = Self {
+            input,
+            checksum: Checksum::default(),
+            digest: Checksum::default().digest(),
+            entries: 0,
+            part: Vec::new(),
+            done: false,
+        };
+        let magic = reader.take(MAGIC.len()).context("reading the snapshot header")?;
+        if magic != MAGIC {
+            bail!("not a mini-kv snapshot");
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != VERSION {
+            bail!("snapshot version {} is not supported by this version of mini-kv", version);
+        }
+        reader.checksum = Checksum::from_id(reader.take(1)?[0])?;
+        reader.digest = reader.checksum.digest();
+        let count = reader.u32()?;
+        let mut namespaces = HashMap::new();
+        for _ in 0..count {
+            let id = reader.u32()?;
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(len)?).context("snapshot namespace name")?;
+            namespaces.insert(id, name);
+        }
+        reader.check_part("header")?;
+        Ok((reader, namespaces))
+    }
+
+    /// The next entry, or `None` at a good end
+    pub(crate) fn next_entry(&mut self) -> Result<Option<Entry>> {
+        if self.done {
+            return Ok(None);
+        }
+        match self.take(1)?[0] {
+            ENTRY => {}
+            END => {
+                let entries = u64::from_le_bytes(self.array()?);
+                let stored = self.read_sum()?;
+                let part = std::mem::take(&mut self.part);
+                let mut digest = std::mem::replace(&mut self.digest, self.checksum.digest());
+                digest.update(&part);
+                if digest.finish().to_le_bytes()[..self.checksum.size()] != *stored {
+                    bail!("snapshot fails its checksum at its end, after {} entries", self.entries);
+                }
+                if entries != self.entries {
+                    bail!("snapshot ends after {} entries but holds {}", entries, self.entries);
+                }
+                self.done = true;
+                return Ok(None);
+            }
+            tag => bail!("snapshot has an unknown part {} after entry {}", tag, self.entries),
+        }
+        let namespace = self.u32()?;
+        let expires_at = u64::from_le_bytes(self.array()?);
+        let key_len = self.u32()? as usize;
+        let value_len = self.u32()? as usize;
+        let key = self.take(key_len)?;
+        let value = self.take(value_len)?;
+        self.check_part("entry")?;
+        self.entries += 1;
+        Ok(Some(Entry { namespace, expires_at, key, value }))
+    }
+
+    /// The next `len` bytes, added to the current part
+    fn take(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.input).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            bail!("snapshot is cut short after {} entries", self.entries);
+        }
+        self.part.extend_from_slice(&buf);
+        Ok(buf)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(&self.take(N)?);
+        Ok(array)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    /// The sum after the current part
+    fn read_sum(&mut self) -> Result<Vec<u8>> {
+        let mut stored = vec![0; self.checksum.size()];
+        self.input.read_exact(&mut stored).with_context(|| format!("snapshot is cut short after {} entries", self.entries))?;
+        Ok(stored)
+    }
+
+    /// Read the sum of the current part, `what`, and check it
+    fn check_part(&mut self, what: &str) -> Result<()> {
+        let stored = self.read_sum()?;
+        let part = std::mem::take(&mut self.part);
+        if !self.checksum.matches(&part, &stored) {
+            bail!("snapshot {} after {} entries fails its checksum", what, self.entries);
+        }
+        self.digest.update(&part);
+        self.digest.update(&stored);
+        Ok(())
+    }
+}
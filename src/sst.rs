@@ -0,0 +1,440 @@
+//! SST files in RocksDB's block-based table format, for
+//! `Engine::export_sst`
+//!
+//! What RocksDB's own `SstFileWriter` makes with its default options, so
+//! `IngestExternalFile` takes them as they are: format version 2,
+//! uncompressed blocks with masked CRC32C trailers, no filter, and a
+//! binary search index over the data blocks. Every key is stored as a put
+//! at sequence number 0, which ingestion replaces with a global one.
+//!
+//! ```text
+//! file:    data block* | index block | properties block | metaindex block | footer
+//! block:   entry* | restart(4)* | restarts(4) | compression 0(1) | crc32c(4)
+//! entry:   shared | unshared | value_len (varints) | key[shared..] | value
+//! key:     user key | (sequence << 8 | type 1)(8)
+//! footer:  checksum type 1(1) | metaindex handle | index handle, padded to
+//!          40 | format version 2(4) | magic 0x88e241b785f4cff7(8)
+//! ```
+//!
+//! A handle is the varint offset and size of a block, not counting its
+//! trailer. Integers of fixed width are little-endian.
+
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const FORMAT_VERSION: u32 = 2;
+const MAGIC: u64 = 0x88e2_41b7_85f4_cff7;
+const CHECKSUM_CRC32C: u8 = 1;
+const NO_COMPRESSION: u8 = 0;
+/// Of a put, at sequence number 0
+const VALUE_TRAILER: u64 = 1;
+const DATA_RESTART_INTERVAL: usize = 16;
+/// RocksDB's "unknown", which ingestion accepts into any column family
+const UNKNOWN_COLUMN_FAMILY: u64 = 0x7fff_ffff;
+
+/// How `Engine::export_sst` cuts the keyspace into files
+#[derive(Debug, Clone)]
+pub struct SstOptions {
+    /// Start a new file once one reaches this size, as RocksDB's
+    /// `target_file_size_base`; 0 for a single file
+    pub target_file_size: u64,
+    /// Uncompressed size of the data blocks, as RocksDB's `block_size`
+    pub block_size: usize,
+}
+
+impl Default for SstOptions {
+    fn default() -> Self {
+        Self { target_file_size: 64 * 1024 * 1024, block_size: 4096 }
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn put_handle(buf: &mut Vec<u8>, handle: (u64, u64)) {
+    put_varint(buf, handle.0);
+    put_varint(buf, handle.1);
+}
+
+/// A block of sorted entries, prefix-compressed between restart points
+struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    /// Entries since the last restart point
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new(restart_interval: usize) -> Self {
+        Self { buf: Vec::new(), restarts: vec![0], restart_interval, counter: 0, last_key: Vec::new() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Size once finished, without the trailer
+    fn size(&self) -> usize {
+        self.buf.len() + 4 * (self.restarts.len() + 1)
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = match self.counter < self.restart_interval {
+            true => key.iter().zip(&self.last_key).take_while(|(a, b)| a == b).count(),
+            false => {
+                self.restarts.push(self.buf.len() as u32);
+                self.counter = 0;
+                0
+            }
+        };
+        put_varint(&mut self.buf, shared as u64);
+        put_varint(&mut self.buf, (key.len() - shared) as u64);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    /// The finished block, leaving this one empty
+    fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buf);
+        for restart in &self.restarts {
+            block.extend_from_slice(&restart.to_le_bytes());
+        }
+        block.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        *self = Self::new(self.restart_interval);
+        block
+    }
+}
+
+/// Writes one SST file to `W`, given its keys in increasing order
+pub(crate) struct SstWriter<W: Write> {
+    out: W,
+    block_size: usize,
+    /// Bytes written so far
+    offset: u64,
+    data: BlockBuilder,
+    index: BlockBuilder,
+    /// Internal key of the last entry added
+    last_key: Vec<u8>,
+    entries: u64,
+    data_blocks: u64,
+    raw_key_size: u64,
+    raw_value_size: u64,
+}
+
+impl<W: Write> SstWriter<W> {
+    pub(crate) fn new(out: W, options: &SstOptions) -> Self {
+        Self {
+            out,
+            block_size: options.block_size.max(1),
+            offset: 0,
+            data: BlockBuilder::new(DATA_RESTART_INTERVAL),
+            index: BlockBuilder::new(1),
+            last_key: Vec::new(),
+            entries: 0,
+            data_blocks: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+        }
+    }
+
+    pub(crate) fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.entries > 0 && key <= &self.last_key[..self.last_key.len() - 8] {
+            bail!("SST keys must be added in increasing order, but {} follows {}", key.escape_ascii(), self.last_key[..self.last_key.len() - 8].escape_ascii());
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.last_key.extend_from_slice(&VALUE_TRAILER.to_le_bytes());
+        self.data.add(&self.last_key, value);
+        self.entries += 1;
+        self.raw_key_size += self.last_key.len() as u64;
+        self.raw_value_size += value.len() as u64;
+        if self.data.size() >= self.block_size {
+            self.flush_data()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes the file holds so far, counting the block being built
+    pub(crate) fn size(&self) -> u64 {
+        self.offset + self.data.size() as u64
+    }
+
+    /// Write the rest of the file; returns `out`
+    pub(crate) fn finish(mut self) -> Result<W> {
+        if !self.data.is_empty() {
+            self.flush_data()?;
+        }
+        let data_size = self.offset;
+        let index = self.index.finish();
+        let index_size = index.len() as u64 + 5;
+        let index_handle = self.write_block(index)?;
+
+        let mut properties = BlockBuilder::new(usize::MAX);
+        for (name, value) in self.properties(data_size, index_size) {
+            properties.add(name.as_bytes(), &value);
+        }
+        let properties_handle = self.write_block(properties.finish())?;
+
+        let mut metaindex = BlockBuilder::new(1);
+        let mut handle = Vec::new();
+        put_handle(&mut handle, properties_handle);
+        metaindex.add(b"rocksdb.properties", &handle);
+        let metaindex_handle = self.write_block(metaindex.finish())?;
+
+        let mut footer = vec![CHECKSUM_CRC32C];
+        put_handle(&mut footer, metaindex_handle);
+        put_handle(&mut footer, index_handle);
+        footer.resize(41, 0);
+        footer.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        footer.extend_from_slice(&MAGIC.to_le_bytes());
+        self.out.write_all(&footer)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Write out the data block and index it under its last key
+    fn flush_data(&mut self) -> Result<()> {
+        let block = self.data.finish();
+        let handle = self.write_block(block)?;
+        let mut value = Vec::new();
+        put_handle(&mut value, handle);
+        self.index.add(&self.last_key, &value);
+        self.data_blocks += 1;
+        Ok(())
+    }
+
+    /// Write `block` with its trailer; returns its handle
+    fn write_block(&mut self, mut block: Vec<u8>) -> Result<(u64, u64)> {
+        let handle = (self.offset, block.len() as u64);
+        block.push(NO_COMPRESSION);
+        let crc = crc32c::crc32c(&block);
+        let masked = crc.rotate_right(15).wrapping_add(0xa282_ead8);
+        block.extend_from_slice(&masked.to_le_bytes());
+        self.out.write_all(&block)?;
+        self.offset += block.len() as u64;
+        Ok(handle)
+    }
+
+    /// The table properties, sorted by name as the block needs them
+    fn properties(&self, data_size: u64, index_size: u64) -> BTreeMap<&'static str, Vec<u8>> {
+        let number = |n: u64| {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, n);
+            buf
+        };
+        BTreeMap::from([
+            ("rocksdb.block.based.table.index.type", 0u32.to_le_bytes().to_vec()),
+            ("rocksdb.block.based.table.prefix.filtering", b"0".to_vec()),
+            ("rocksdb.block.based.table.whole.key.filtering", b"1".to_vec()),
+            ("rocksdb.column.family.id", number(UNKNOWN_COLUMN_FAMILY)),
+            ("rocksdb.comparator", b"leveldb.BytewiseComparator".to_vec()),
+            ("rocksdb.compression", b"NoCompression".to_vec()),
+            ("rocksdb.data.size", number(data_size)),
+            ("rocksdb.deleted.keys", number(0)),
+            ("rocksdb.external_sst_file.global_seqno", 0u64.to_le_bytes().to_vec()),
+            ("rocksdb.external_sst_file.version", 2u32.to_le_bytes().to_vec()),
+            ("rocksdb.filter.size", number(0)),
+            ("rocksdb.fixed.key.length", number(0)),
+            ("rocksdb.format.version", number(0)),
+            ("rocksdb.index.key.is.user.key", number(0)),
+            ("rocksdb.index.size", number(index_size)),
+            ("rocksdb.index.value.is.delta.encoded", number(0)),
+            ("rocksdb.merge.operands", number(0)),
+            ("rocksdb.merge.operator", b"nullptr".to_vec()),
+            ("rocksdb.num.data.blocks", number(self.data_blocks)),
+            ("rocksdb.num.entries", number(self.entries)),
+            ("rocksdb.num.range-deletions", number(0)),
+            ("rocksdb.prefix.extractor.name", b"nullptr".to_vec()),
+            ("rocksdb.property.collectors", b"[]".to_vec()),
+            ("rocksdb.raw.key.size", number(self.raw_key_size)),
+            ("rocksdb.raw.value.size", number(self.raw_value_size)),
+            ("rocksdb.user.defined.timestamps.persisted", number(1)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    fn get_varint(buf: &mut &[u8]) -> u64 {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf.split_first().unwrap();
+            *buf = rest;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        n
+    }
+
+    fn get_handle(buf: &mut &[u8]) -> (u64, u64) {
+        (get_varint(buf), get_varint(buf))
+    }
+
+    /// The block `handle` points at, after checking its trailer
+    fn read_block(file: &[u8], (offset, size): (u64, u64)) -> &[u8] {
+        let (offset, size) = (offset as usize, size as usize);
+        let block = &file[offset..offset + size];
+        assert_eq!(file[offset + size], NO_COMPRESSION);
+        let crc = crc32c::crc32c(&file[offset..offset + size + 1]);
+        let masked = crc.rotate_right(15).wrapping_add(0xa282_ead8);
+        assert_eq!(file[offset + size + 1..offset + size + 5], masked.to_le_bytes());
+        block
+    }
+
+    /// The entries of `block`, checking that each restart point is an
+    /// entry that shares nothing with the one before
+    fn entries(block: &[u8]) -> Pairs {
+        let count = u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap()) as usize;
+        let restarts_at = block.len() - 4 - 4 * count;
+        let restarts: Vec<usize> = block[restarts_at..block.len() - 4]
+            .chunks_exact(4)
+            .map(|restart| u32::from_le_bytes(restart.try_into().unwrap()) as usize)
+            .collect();
+        assert_eq!(restarts[0], 0);
+        let mut rest = &block[..restarts_at];
+        let (mut entries, mut key) = (Vec::new(), Vec::new());
+        while !rest.is_empty() {
+            let at = restarts_at - rest.len();
+            let shared = get_varint(&mut rest) as usize;
+            let unshared = get_varint(&mut rest) as usize;
+            let value_len = get_varint(&mut rest) as usize;
+            assert!(!restarts.contains(&at) || shared == 0, "restart at {} shares {} bytes", at, shared);
+            key.truncate(shared);
+            key.extend_from_slice(&rest[..unshared]);
+            entries.push((key.clone(), rest[unshared..unshared + value_len].to_vec()));
+            rest = &rest[unshared + value_len..];
+        }
+        entries
+    }
+
+    fn write(pairs: &[(Vec<u8>, Vec<u8>)], block_size: usize) -> Vec<u8> {
+        let mut writer = SstWriter::new(Vec::new(), &SstOptions { block_size, ..SstOptions::default() });
+        for (key, value) in pairs {
+            writer.add(key, value).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    /// Read `file` back through its footer, index and data blocks, checking
+    /// the layout along the way; returns the pairs and the properties
+    fn read(file: &[u8]) -> (Pairs, BTreeMap<String, Vec<u8>>) {
+        let footer = &file[file.len() - 53..];
+        assert_eq!(footer[0], CHECKSUM_CRC32C);
+        assert_eq!(footer[41..45], FORMAT_VERSION.to_le_bytes());
+        assert_eq!(footer[45..], MAGIC.to_le_bytes());
+        let mut handles = &footer[1..41];
+        let (metaindex_handle, index_handle) = (get_handle(&mut handles), get_handle(&mut handles));
+        assert!(handles.iter().all(|&byte| byte == 0));
+
+        let mut pairs = Vec::new();
+        let mut end = 0;
+        for (last_key, handle) in entries(read_block(file, index_handle)) {
+            let handle = get_handle(&mut handle.as_slice());
+            assert_eq!(handle.0, end, "data blocks are not contiguous");
+            end = handle.0 + handle.1 + 5;
+            let block = entries(read_block(file, handle));
+            assert_eq!(block.last().unwrap().0, last_key);
+            for (key, value) in block {
+                let (user_key, trailer) = key.split_at(key.len() - 8);
+                assert_eq!(trailer, VALUE_TRAILER.to_le_bytes());
+                pairs.push((user_key.to_vec(), value));
+            }
+        }
+        assert_eq!(end, index_handle.0);
+
+        let metaindex = entries(read_block(file, metaindex_handle));
+        assert_eq!(metaindex.len(), 1);
+        assert_eq!(metaindex[0].0, b"rocksdb.properties");
+        let properties_handle = get_handle(&mut metaindex[0].1.as_slice());
+        let properties = entries(read_block(file, properties_handle))
+            .into_iter()
+            .map(|(name, value)| (String::from_utf8(name).unwrap(), value))
+            .collect();
+        assert_eq!(number(&properties, "rocksdb.data.size"), index_handle.0);
+        assert_eq!(number(&properties, "rocksdb.index.size"), index_handle.1 + 5);
+        (pairs, properties)
+    }
+
+    fn number(properties: &BTreeMap<String, Vec<u8>>, name: &str) -> u64 {
+        get_varint(&mut properties[name].as_slice())
+    }
+
+    fn pairs(n: usize) -> Pairs {
+        (0..n).map(|i| (format!("key{:05}", i).into_bytes(), format!("value {}", i).repeat(i % 5).into_bytes())).collect()
+    }
+
+    #[test]
+    fn single_block_file_reads_back() {
+        let input = pairs(10);
+        let (output, properties) = read(&write(&input, 4096));
+        assert_eq!(output, input);
+        assert_eq!(number(&properties, "rocksdb.num.entries"), 10);
+        assert_eq!(number(&properties, "rocksdb.num.data.blocks"), 1);
+        assert_eq!(properties["rocksdb.comparator"], b"leveldb.BytewiseComparator");
+        assert_eq!(number(&properties, "rocksdb.column.family.id"), UNKNOWN_COLUMN_FAMILY);
+    }
+
+    #[test]
+    fn small_blocks_split_the_keys_across_many() {
+        let input = pairs(500);
+        let (output, properties) = read(&write(&input, 128));
+        assert_eq!(output, input);
+        let blocks = number(&properties, "rocksdb.num.data.blocks");
+        assert!(blocks > 50, "{} data blocks", blocks);
+        let raw_keys: usize = input.iter().map(|(key, _)| key.len() + 8).sum();
+        assert_eq!(number(&properties, "rocksdb.raw.key.size"), raw_keys as u64);
+    }
+
+    #[test]
+    fn empty_file_has_no_data_blocks() {
+        let (output, properties) = read(&write(&[], 4096));
+        assert!(output.is_empty());
+        assert_eq!(number(&properties, "rocksdb.num.data.blocks"), 0);
+    }
+
+    #[test]
+    fn keys_out_of_order_or_repeated_are_rejected() {
+        let mut writer = SstWriter::new(Vec::new(), &SstOptions::default());
+        writer.add(b"b", b"1").unwrap();
+        assert!(writer.add(b"a", b"2").is_err());
+        assert!(writer.add(b"b", b"2").is_err());
+        // A prefix sorts first
+        let mut writer = SstWriter::new(Vec::new(), &SstOptions::default());
+        writer.add(b"ab", b"1").unwrap();
+        assert!(writer.add(b"a", b"2").is_err());
+        writer.add(b"abc", b"3").unwrap();
+    }
+
+    #[cfg(feature = "bench-rocksdb")]
+    #[test]
+    fn rocksdb_ingests_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = pairs(500);
+        let path = dir.path().join("000001.sst");
+        std::fs::write(&path, write(&input, 128)).unwrap();
+        let db = rocksdb::DB::open_default(dir.path().join("db")).unwrap();
+        db.ingest_external_file(vec![&path]).unwrap();
+        for (key, value) in &input {
+            assert_eq!(db.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(db.get(b"key00500").unwrap(), None);
+    }
+}
@@ -0,0 +1,429 @@
+//! File access for the log and the index file, for `EngineBuilder::storage`
+//!
+//! `FsStorage`, the default, is the local file system, and `MemStorage`
+//! keeps the files in memory. Another `Storage` can keep them anywhere
+//! (behind a wrapper that injects faults, say) as long as it keeps the
+//! promises below: `append` and `write_at` are visible to `read_at` and
+//! `len` at once, `sync` makes them durable, and `rename` replaces its
+//! target atomically and durably once `sync_dir` returns.
+//!
+//! Only the log and the index file go through it. The value log, table
+//! files, the scrubber, checkpoints and archives open their files on the
+//! local file system by path, so `Engine` refuses them on storage that is
+//! not `is_file_system`.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where the engine opens, renames and removes its files
+pub trait Storage: Send + Sync {
+    /// Open the file at `path` for reading and writing, creating it empty
+    /// (readable by the owner only) if `create` is set and it is missing.
+    /// Fails with `io::ErrorKind::NotFound` if it is missing otherwise.
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Move `from` to `to`, replacing any file there, atomically
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Make the last creation, rename or removal of `path` durable
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Whether paths name files on the local file system, as the parts of
+    /// the engine that bypass `Storage` need
+    fn is_file_system(&self) -> bool {
+        false
+    }
+}
+
+/// An open file of a `Storage`
+pub trait StorageFile: Send + Sync {
+    /// Write `buf` at the end of the file
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// `append` for a frame in parts, without joining them first
+    fn append_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+        for buf in bufs.iter() {
+            self.append(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite bytes at `offset`, extending the file if they run past
+    /// its end
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Read up to `buf.len()` bytes at `offset`, returning how many; 0 at
+    /// or past the end
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Fill `buf` from `offset`, failing with
+    /// `io::ErrorKind::UnexpectedEof` if the file ends first
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Make everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cut the file to `len` bytes, or extend it with zeros; later appends
+    /// go after that
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// A second handle to the same file whose `sync` covers what this one
+    /// wrote, for syncing on another thread; `None` if there is no such
+    /// thing
+    fn try_clone(&self) -> io::Result<Option<Box<dyn StorageFile>>> {
+        Ok(None)
+    }
+}
+
+/// The local file system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsStorage;
+
+impl Storage for FsStorage {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = OpenOptions::new();
+        options.create(create).read(true).write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600); // Owner read/write only
+        }
+        let mut writer = options.open(path)?;
+        writer.seek(SeekFrom::End(0))?;
+        // Reads would move the cursor on Windows, so they get a handle of
+        // their own
+        let reader = File::open(path)?;
+        Ok(Box::new(FsFile { writer, reader }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        crate::engine::sync_parent_dir(path)
+    }
+
+    fn is_file_system(&self) -> bool {
+        true
+    }
+}
+
+struct FsFile {
+    /// Its cursor stays at the end of the file
+    writer: File,
+    reader: File,
+}
+
+impl StorageFile for FsFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)
+    }
+
+    fn append_vectored(&mut self, bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+        write_all_vectored(&mut self.writer, bufs)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.writer.write_all(buf)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.reader, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.reader, buf, offset)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.writer.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.writer.set_len(len)?;
+        self.writer.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Option<Box<dyn StorageFile>>> {
+        Ok(Some(Box::new(FsFile { writer: self.writer.try_clone()?, reader: self.reader.try_clone()? })))
+    }
+}
+
+/// Files in memory, for tests and stores that need not outlive the
+/// process. Clones share the files.
+///
+/// Durability is simulated: each file remembers what its last `sync` left
+/// on "disk", and each directory which names its last `sync_dir` left in
+/// it. `after_crash` returns that image as a store of its own, which an
+/// engine reopened on it recovers from as from a power loss.
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    dirs: Arc<Mutex<MemDirs>>,
+}
+
+#[derive(Debug, Default)]
+struct MemDirs {
+    /// Names as they are now
+    current: HashMap<PathBuf, Arc<Mutex<MemFileData>>>,
+    /// Names as of the last `sync_dir` of their directory
+    durable: HashMap<PathBuf, Arc<Mutex<MemFileData>>>,
+}
+
+#[derive(Debug, Default)]
+struct MemFileData {
+    data: Vec<u8>,
+    /// Contents as of the last `sync`
+    synced: Vec<u8>,
+    /// Lowest offset changed since then
+    dirty_from: usize,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store holding just `contents` at `path`, synced, as if a log had
+    /// been copied in; an engine opened on it recovers from those bytes
+    pub fn with_file(path: impl AsRef<Path>, contents: Vec<u8>) -> Self {
+        let file = MemFileData { data: contents.clone(), dirty_from: contents.len(), synced: contents };
+        let file = Arc::new(Mutex::new(file));
+        let path = path.as_ref().to_path_buf();
+        let dirs = MemDirs { current: HashMap::from([(path.clone(), file.clone())]), durable: HashMap::from([(path, file)]) };
+        MemStorage { dirs: Arc::new(Mutex::new(dirs)) }
+    }
+
+    /// A separate store holding what would be left after a power loss
+    /// now: the files named when their directory was last synced, each
+    /// with the contents of its last sync. This store and its open files
+    /// carry on unaffected.
+    pub fn after_crash(&self) -> MemStorage {
+        let dirs = self.dirs.lock().unwrap();
+        let survivors: HashMap<_, _> = dirs
+            .durable
+            .iter()
+            .map(|(path, file)| {
+                let synced = file.lock().unwrap().synced.clone();
+                let file = MemFileData { data: synced.clone(), dirty_from: synced.len(), synced };
+                (path.clone(), Arc::new(Mutex::new(file)))
+            })
+            .collect();
+        let dirs = MemDirs { current: survivors.clone(), durable: survivors };
+        MemStorage { dirs: Arc::new(Mutex::new(dirs)) }
+    }
+
+    /// Current contents of the file at `path`, if there is one
+    pub fn read(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        let dirs = self.dirs.lock().unwrap();
+        dirs.current.get(path.as_ref()).map(|file| file.lock().unwrap().data.clone())
+    }
+
+    /// Paths of the files, sorted
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.dirs.lock().unwrap().current.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        let mut dirs = self.dirs.lock().unwrap();
+        let file = match dirs.current.get(path) {
+            Some(file) => file.clone(),
+            None if create => dirs.current.entry(path.to_path_buf()).or_default().clone(),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        Ok(Box::new(MemFile { file }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut dirs = self.dirs.lock().unwrap();
+        let file = dirs.current.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        dirs.current.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut dirs = self.dirs.lock().unwrap();
+        dirs.current.remove(path).map(drop).ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.dirs.lock().unwrap().current.contains_key(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut dirs = self.dirs.lock().unwrap();
+        let dirs = &mut *dirs;
+        let dir = path.parent();
+        dirs.durable.retain(|path, _| path.parent() != dir);
+        for (path, file) in &dirs.current {
+            if path.parent() == dir {
+                dirs.durable.insert(path.clone(), file.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MemFile {
+    file: Arc<Mutex<MemFileData>>,
+}
+
+impl StorageFile for MemFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.dirty_from = file.dirty_from.min(file.data.len());
+        file.data.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let offset = offset as usize;
+        file.dirty_from = file.dirty_from.min(offset);
+        if file.data.len() < offset + buf.len() {
+            file.data.resize(offset + buf.len(), 0);
+        }
+        file.data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let file = self.file.lock().unwrap();
+        let rest = file.data.get(offset as usize..).unwrap_or_default();
+        let n = buf.len().min(rest.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let file = &mut *file;
+        file.synced.truncate(file.dirty_from);
+        let kept = file.synced.len();
+        file.synced.extend_from_slice(&file.data[kept..]);
+        file.dirty_from = file.data.len();
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.lock().unwrap().data.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let len = len as usize;
+        file.dirty_from = file.dirty_from.min(len).min(file.data.len());
+        file.data.resize(len, 0);
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Option<Box<dyn StorageFile>>> {
+        Ok(Some(Box::new(MemFile { file: self.file.clone() })))
+    }
+}
+
+/// `write_all` for a frame in parts, without joining them first
+fn write_all_vectored(out: &mut impl Write, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Positioned reads over part of a file, for `io::Read` users, so
+/// streaming never moves a shared cursor
+pub(crate) struct ReadAt<'a> {
+    pub(crate) file: &'a dyn StorageFile,
+    pub(crate) pos: u64,
+    pub(crate) end: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.end.saturating_sub(self.pos)) as usize;
+        let read = self.file.read_at(&mut buf[..len], self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+/// Buffers writes into appends to a `StorageFile`
+pub(crate) struct Appender {
+    file: Box<dyn StorageFile>,
+}
+
+impl Appender {
+    pub(crate) fn new(file: Box<dyn StorageFile>) -> io::BufWriter<Self> {
+        io::BufWriter::new(Self { file })
+    }
+
+    /// The file, with everything buffered appended
+    pub(crate) fn finish(out: io::BufWriter<Self>) -> io::Result<Box<dyn StorageFile>> {
+        Ok(out.into_inner().map_err(|e| e.into_error())?.file)
+    }
+}
+
+impl Write for Appender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
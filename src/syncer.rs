@@ -0,0 +1,145 @@
+//! Background syncs of the log, for `Engine::sync_async()`
+//!
+//! A syncer thread takes syncs queued by the engine, each with a second
+//! handle to the log from `StorageFile::try_clone`, and marks each one's
+//! `SyncHandle` done once its fsync returns. Syncs queued while one runs
+//! share the next fsync.
+
+use anyhow::{anyhow, Result};
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+use crate::storage::StorageFile;
+
+#[derive(Default)]
+struct State {
+    done: bool,
+    /// The fsync's error, until `SyncHandle::wait` takes it
+    error: Option<io::Error>,
+    failed: bool,
+}
+
+#[derive(Default)]
+struct Completion {
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+impl Completion {
+    fn finish(&self, result: io::Result<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.done = true;
+        if let Err(e) = result {
+            state.failed = true;
+            state.error = Some(e);
+        }
+        self.finished.notify_all();
+    }
+}
+
+/// Resolves once the writes made before `Engine::sync_async()` returned it
+/// are durable
+pub struct SyncHandle {
+    completion: Arc<Completion>,
+}
+
+impl SyncHandle {
+    /// A handle already done, for a sync made in place
+    pub(crate) fn finished(result: io::Result<()>) -> Self {
+        let completion = Arc::new(Completion::default());
+        completion.finish(result);
+        Self { completion }
+    }
+
+    /// Whether the sync has finished, successfully or not, without blocking
+    pub fn is_done(&self) -> bool {
+        self.completion.state.lock().unwrap().done
+    }
+
+    /// Block until the sync has finished; fails if its fsync did, in which
+    /// case the writes may not be durable
+    pub fn wait(self) -> Result<()> {
+        let state = self.completion.state.lock().unwrap();
+        let mut state = self.completion.finished.wait_while(state, |state| !state.done).unwrap();
+        match state.error.take() {
+            Some(e) => Err(anyhow!(e).context("background sync of the log failed")),
+            None if state.failed => Err(anyhow!("background sync of the log failed")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A sync the engine queued and has not yet seen finish: the durable
+/// index and log end it makes durable
+pub(crate) struct PendingSync {
+    completion: Arc<Completion>,
+    pub(crate) logical_index: usize,
+    pub(crate) pos: u64,
+}
+
+impl PendingSync {
+    /// `Some(succeeded)` once finished
+    pub(crate) fn outcome(&self) -> Option<bool> {
+        let state = self.completion.state.lock().unwrap();
+        state.done.then_some(!state.failed)
+    }
+}
+
+struct Job {
+    file: Box<dyn StorageFile>,
+    completion: Arc<Completion>,
+}
+
+/// Handle to the syncer thread, which finishes the syncs queued and stops
+/// when this is dropped
+pub(crate) struct Syncer {
+    jobs: Option<Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub(crate) fn start() -> Result<Self> {
+        let (jobs, queue) = mpsc::channel();
+        let thread = std::thread::Builder::new().name("mini-kv-sync-async".to_string()).spawn(move || run(queue))?;
+        Ok(Self { jobs: Some(jobs), thread: Some(thread) })
+    }
+
+    /// Sync `file`, a second handle to the log, in the background
+    pub(crate) fn queue(&self, file: Box<dyn StorageFile>, logical_index: usize, pos: u64) -> (SyncHandle, PendingSync) {
+        let completion = Arc::new(Completion::default());
+        let job = Job { file, completion: completion.clone() };
+        if let Err(mpsc::SendError(job)) = self.jobs.as_ref().expect("jobs is only taken on drop").send(job) {
+            job.completion.finish(Err(io::Error::other("the sync thread has stopped")));
+        }
+        (SyncHandle { completion: completion.clone() }, PendingSync { completion, logical_index, pos })
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(queue: Receiver<Job>) {
+    while let Ok(job) = queue.recv() {
+        // Every job queued by now was queued after its writes, so the
+        // newest handle's fsync covers them all
+        let mut jobs = vec![job];
+        jobs.extend(queue.try_iter());
+        let mut newest = jobs.pop().expect("one job at least");
+        let result = newest.file.sync();
+        for job in jobs {
+            job.completion.finish(match &result {
+                Ok(()) => Ok(()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            });
+        }
+        newest.completion.finish(result);
+    }
+}
@@ -0,0 +1,256 @@
+//! Following a log from another process, `tail -f` style
+//!
+//! A `LogTailer` reads the log through its own read-only handle, never
+//! taking part in the writer's locking or recovery, and hands out each
+//! record once it is durable. Saving `offset()` after a record and passing
+//! it to the next `open` resumes right after it, so an external indexer
+//! sees every record once across restarts.
+//!
+//! The writer publishes how far the log is synced in `<log>.synced`,
+//! after every sync and at open, and the tailer reads no further: a record
+//! it hands out survives a crash of the writer, so recovery never cuts it
+//! off and writes over its offset. A record shows up once its sync is
+//! done (for `sync_async`, once the writer notices that it is), and not
+//! at all while no engine has opened the log and written the file. The
+//! tailer reports `Error::LogRewritten` once `compact()` or another
+//! rewrite has replaced the file, since offsets into the old one mean
+//! nothing in the new one; reopen from the start to continue.
+//!
+//! ```text
+//! <log>.synced: synced_end(8) | crc32(4)
+//! ```
+
+use anyhow::Result;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::format::Format;
+use crate::record::{Record, MAX_HEADER_SIZE};
+use crate::segment::FOOTER_SIZE;
+
+/// Bytes read from the log at a time
+const CHUNK_BYTES: usize = 1024 * 1024;
+const SYNCED_END_SIZE: usize = 8 + 4;
+
+/// `<log>.synced`, where the writer publishes how far the log is synced
+pub(crate) fn synced_path(path: &Path) -> PathBuf {
+    let mut synced = path.as_os_str().to_os_string();
+    synced.push(".synced");
+    PathBuf::from(synced)
+}
+
+pub(crate) fn encode_synced_end(end: u64) -> [u8; SYNCED_END_SIZE] {
+    let mut buf = [0; SYNCED_END_SIZE];
+    buf[..8].copy_from_slice(&end.to_le_bytes());
+    let crc = crc32fast::hash(&buf[..8]);
+    buf[8..].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// `None` for a file not written yet, or caught halfway through a write
+fn decode_synced_end(buf: &[u8]) -> Option<u64> {
+    let (end, crc) = (buf.get(..8)?, buf.get(8..SYNCED_END_SIZE)?);
+    (crc32fast::hash(end).to_le_bytes() == crc).then(|| u64::from_le_bytes(end.try_into().unwrap()))
+}
+
+/// Reader of the records another process appends to a log
+pub struct LogTailer {
+    path: PathBuf,
+    file: File,
+    format: Format,
+    /// Offset of the next record to hand out
+    offset: u64,
+    /// Bytes read from the file at `offset` and not handed out yet
+    buf: Vec<u8>,
+    /// `<log>.synced`, once it exists
+    synced: Option<File>,
+}
+
+impl LogTailer {
+    /// Open the log at `path` to read the records from `offset` on, an
+    /// offset a previous tailer's `offset()` returned, or 0 for the first
+    /// record. The records must not be encrypted.
+    pub fn open(path: impl AsRef<Path>, offset: u64) -> Result<Self> {
+        Self::with_format(path, offset, Format::default())
+    }
+
+    /// `open` with key material for the log, e.g. an encryption key added
+    /// with `Format::add_encryption_key`
+    pub fn with_format(path: impl AsRef<Path>, offset: u64, mut format: Format) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+        let log_start = format.read_header_from(&mut file)? as u64;
+        Ok(Self { path, file, format, offset: offset.max(log_start), buf: Vec::new(), synced: None })
+    }
+
+    /// Offset just past the last record handed out, where to resume
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next record in the log and its offset, or `None` if the writer
+    /// has not appended and synced a complete one yet. Values of records the engine
+    /// moved to a value log are `RecordKind::ValuePointer` records, not
+    /// looked up. An intact record whose value cannot be decoded, e.g.
+    /// encrypted with a key the tailer lacks, is an error, and the next
+    /// call moves on past it.
+    pub fn poll(&mut self) -> Result<Option<(u64, Record)>> {
+        self.next_record(true)
+    }
+
+    /// `poll`; a record that fails its checks may be the torn tail of a
+    /// crashed writer, since cut off and written over by its recovery, so
+    /// with `retry` it is read again before being called damaged
+    fn next_record(&mut self, retry: bool) -> Result<Option<(u64, Record)>> {
+        if self.format.footer_offset() == Some(self.offset) {
+            if !self.fill(FOOTER_SIZE)? {
+                return Ok(None);
+            }
+            self.consume(FOOTER_SIZE);
+        }
+        let size = match Record::header_frame_len_with(&self.buf, &self.format) {
+            Some(size) => size,
+            None if self.buf.len() < MAX_HEADER_SIZE => {
+                // A record at the end can be shorter than the longest header
+                let had = self.buf.len();
+                if !self.fill(MAX_HEADER_SIZE)? && self.buf.len() == had {
+                    return Ok(None);
+                }
+                return self.next_record(retry);
+            }
+            None => return self.damaged(retry, "invalid record header"),
+        };
+        if !self.fill(size)? {
+            return Ok(None);
+        }
+        if let Err(e) = Record::check_with(&self.buf[..size], &self.format) {
+            return self.damaged(retry, &e.to_string());
+        }
+        let (offset, decoded) = (self.offset, Record::decode_with(&self.buf[..size], &self.format));
+        self.consume(size);
+        Ok(Some((offset, decoded?.0)))
+    }
+
+    /// Records as `poll` finds them, checking for new ones every `interval`
+    /// once caught up; never ends unless an error does
+    pub fn follow(&mut self, interval: Duration) -> impl Iterator<Item = Result<(u64, Record)>> + '_ {
+        std::iter::from_fn(move || loop {
+            match self.poll() {
+                Ok(Some(item)) => return Some(Ok(item)),
+                Ok(None) => std::thread::sleep(interval),
+                Err(e) => return Some(Err(e)),
+            }
+        })
+    }
+
+    /// Read until `buf` holds at least `len` bytes; false if the file does
+    /// not have them synced yet. Records synced before a rewrite are all
+    /// read from the old file before the rewrite is reported.
+    fn fill(&mut self, len: usize) -> Result<bool> {
+        while self.buf.len() < len {
+            let end = self.offset + self.buf.len() as u64;
+            let synced = self.synced_end()?.saturating_sub(end);
+            let want = ((len - self.buf.len()).max(CHUNK_BYTES) as u64).min(synced);
+            self.file.seek(SeekFrom::Start(end))?;
+            if want == 0 || (&mut self.file).take(want).read_to_end(&mut self.buf)? == 0 {
+                self.check_replaced()?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// How far the writer last said the log is synced; 0 before it has
+    fn synced_end(&mut self) -> Result<u64> {
+        if self.synced.is_none() {
+            match File::open(synced_path(&self.path)) {
+                Ok(file) => self.synced = Some(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let Some(file) = &mut self.synced else { return Ok(0) };
+        let mut buf = Vec::with_capacity(SYNCED_END_SIZE);
+        file.seek(SeekFrom::Start(0))?;
+        file.take(SYNCED_END_SIZE as u64).read_to_end(&mut buf)?;
+        Ok(decode_synced_end(&buf).unwrap_or(0))
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        self.offset += len as u64;
+    }
+
+    fn damaged(&mut self, retry: bool, reason: &str) -> Result<Option<(u64, Record)>> {
+        if retry {
+            self.buf.clear();
+            return self.next_record(false);
+        }
+        Err(Error::Corruption { offset: self.offset, reason: reason.to_string() }.into())
+    }
+
+    /// Fail if the file at `path` is no longer the one being read
+    fn check_replaced(&self) -> Result<()> {
+        let (current, open) = (std::fs::metadata(&self.path)?, self.file.metadata()?);
+        #[cfg(unix)]
+        let replaced = {
+            use std::os::unix::fs::MetadataExt;
+            (current.dev(), current.ino()) != (open.dev(), open.ino())
+        };
+        // Without inode numbers only a file shorter than the read offset
+        // gives a rewrite away
+        #[cfg(not(unix))]
+        let replaced = current.len() < self.offset;
+        if replaced || open.len() < self.offset {
+            return Err(Error::LogRewritten { offset: self.offset }.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Engine, SyncMode};
+    use std::fs;
+
+    fn keys(tailer: &mut LogTailer) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| tailer.poll().unwrap()).map(|(_, record)| record.key).collect()
+    }
+
+    #[test]
+    fn records_are_handed_out_once_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+        let mut engine = Engine::builder(&path).sync_mode(SyncMode::Batch(1000)).open().unwrap();
+        let mut tailer = LogTailer::open(&path, 0).unwrap();
+        engine.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        engine.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert!(keys(&mut tailer).is_empty());
+
+        engine.sync().unwrap();
+        engine.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(keys(&mut tailer), [b"a".to_vec(), b"b".to_vec()]);
+        let offset = tailer.offset();
+
+        // A power loss takes `c` with it, and the next writer puts `d`
+        // where it was
+        std::mem::forget(engine);
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(offset).unwrap();
+        let mut engine = Engine::builder(&path).sync_mode(SyncMode::Batch(1000)).open().unwrap();
+        assert_eq!(engine.get(b"c").unwrap(), None);
+        engine.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        assert!(keys(&mut tailer).is_empty());
+        engine.sync().unwrap();
+        assert_eq!(keys(&mut tailer), [b"d".to_vec()]);
+        drop(engine);
+
+        // Reopening publishes what recovery found
+        fs::remove_file(synced_path(&path)).unwrap();
+        drop(Engine::open(&path).unwrap());
+        assert_eq!(keys(&mut LogTailer::open(&path, 0).unwrap()), [b"a".to_vec(), b"b".to_vec(), b"d".to_vec()]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Serde-typed values on top of the byte-oriented engine (feature `serde`)
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::engine::Engine;
+
+/// Leading byte of each stored value, recording how the rest was encoded
+const TAG_JSON: u8 = b'j';
+const TAG_BINCODE: u8 = b'b';
+
+/// A store whose values are all of type `T`
+///
+/// Values are prefixed with a one-byte encoding tag, so `get` decodes
+/// whatever mix of `put_json` and `put_bincode` wrote them. Keys stay raw
+/// bytes.
+pub struct TypedStore<T> {
+    engine: Engine,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> TypedStore<T> {
+    pub fn new(engine: Engine) -> Self {
+        Self { engine, _value: PhantomData }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(Engine::open(path)?))
+    }
+
+    /// Store `value` as JSON: larger, but readable with the plain tools
+    pub fn put_json(&mut self, key: impl Into<Vec<u8>>, value: &T) -> Result<()> {
+        let mut bytes = vec![TAG_JSON];
+        serde_json::to_writer(&mut bytes, value).context("serializing value as JSON")?;
+        self.engine.put(key.into(), bytes)
+    }
+
+    /// Store `value` as bincode: compact and fast
+    pub fn put_bincode(&mut self, key: impl Into<Vec<u8>>, value: &T) -> Result<()> {
+        let mut bytes = vec![TAG_BINCODE];
+        bincode::serialize_into(&mut bytes, value).context("serializing value as bincode")?;
+        self.engine.put(key.into(), bytes)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<T>> {
+        let Some(bytes) = self.engine.get(key)? else {
+            return Ok(None);
+        };
+        let value = match bytes.split_first() {
+            Some((&TAG_JSON, body)) => serde_json::from_slice(body).context("decoding JSON value")?,
+            Some((&TAG_BINCODE, body)) => bincode::deserialize(body).context("decoding bincode value")?,
+            Some((tag, _)) => bail!("value for key {:?} has unknown encoding tag 0x{:02x}", key.escape_ascii().to_string(), tag),
+            None => bail!("value for key {:?} is empty, not a typed value", key.escape_ascii().to_string()),
+        };
+        Ok(Some(value))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        self.engine.delete(key)
+    }
+
+    /// The underlying engine, for raw access, `sync`, `verify` and so on
+    pub fn engine(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    pub fn into_engine(self) -> Engine {
+        self.engine
+    }
+}
@@ -0,0 +1,591 @@
+//! Value log for large values, for `EngineBuilder::value_log`
+//!
+//! Values at or above the threshold are appended to a separate value log,
+//! and the record in the main log only holds a `ValuePointer` to them. A
+//! rewrite of the main log then copies 16-byte pointers instead of the
+//! values, and the value log reclaims space on its own schedule in
+//! `Engine::gc_value_log`, WiscKey style.
+//!
+//! The value log is a directory next to the log (`<log>.vlog/`) of segment
+//! files, `{id:08}.vlog`, each a file header followed by record frames of
+//! the key and value, so checksums, compression and encryption apply as in
+//! the log. Only the newest segment is appended to. Collecting a segment
+//! copies its live values to the newest one, appends fresh pointers to the
+//! main log, syncs both and only then deletes the segment.
+//!
+//! With `EngineBuilder::cold_dir`, collection moves the sealed segments it
+//! keeps, those with few values overwritten since, to the cold directory,
+//! and a `COLD` file in the value log directory names them, so reads and
+//! the next open know which tier holds each segment. A segment is copied,
+//! made durable, listed in `COLD` and only then deleted locally; collecting
+//! one removes it from `COLD` before deleting the file.
+//!
+//! ```text
+//! pointer: segment(4) | offset(8) | len(4)
+//! COLD:    magic "MKVC"(4) | count(4) | id(4)* | crc32(4)
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::copy_durably;
+use crate::compression::Compression;
+use crate::engine::{copy_prefix, link_or_copy, read_exact_at, sync_parent_dir};
+use crate::format::Format;
+use crate::record::{Record, ScanItem, Scanner, MAX_HEADER_SIZE, MAX_REUSED_BUFFER};
+
+/// A new segment is started once the newest reaches this size
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const COLD_MAGIC: [u8; 4] = [0x4D, 0x4B, 0x56, 0x43]; // "MKVC"
+const COLD: &str = "COLD";
+
+/// A value with its key and namespace, and where it is
+type StoredValue = (Record, ValuePointer);
+
+/// Where a value lives in the value log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ValuePointer {
+    pub(crate) segment: u32,
+    pub(crate) offset: u64,
+    /// Length of the whole record frame
+    pub(crate) len: u32,
+}
+
+impl ValuePointer {
+    pub(crate) const SIZE: usize = 16;
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend_from_slice(&self.segment.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != Self::SIZE {
+            bail!("value pointer is {} bytes, expected {}", buf.len(), Self::SIZE);
+        }
+        Ok(Self {
+            segment: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(buf[4..12].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+        })
+    }
+}
+
+/// Result of `Engine::gc_value_log()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Segments whose live values were counted
+    pub segments_checked: usize,
+    /// Segments deleted after their live values were moved
+    pub segments_removed: usize,
+    /// Live values copied to the newest segment
+    pub values_moved: usize,
+    /// Size of the deleted segments less the values moved out of them
+    pub bytes_reclaimed: u64,
+    /// Segments kept and moved to `EngineBuilder::cold_dir`
+    pub segments_moved_cold: usize,
+}
+
+pub(crate) struct ValueLog {
+    dir: PathBuf,
+    cold_dir: Option<PathBuf>,
+    /// By id; the last one is appended to
+    segments: BTreeMap<u32, Segment>,
+    /// Append handle of the newest segment
+    writer: File,
+    /// Settings for new segments, plus the encryption keys
+    format: Format,
+    /// Appended to since the last sync
+    dirty: bool,
+    /// Reused to encode each appended value
+    encoded: Vec<u8>,
+}
+
+struct Segment {
+    path: PathBuf,
+    file: File,
+    /// Offset of the first record, just past the header
+    start: u64,
+    len: u64,
+    /// The segment's own settings, from its header
+    format: Format,
+    /// Listed in `COLD`; `path` is in the cold directory
+    cold: bool,
+}
+
+impl ValueLog {
+    /// Open the segments in `dir`, creating it and a first segment if
+    /// needed, and those `COLD` lists in `cold_dir`. New segments take
+    /// their checksum and keys from `format`.
+    pub(crate) fn open(dir: PathBuf, cold_dir: Option<PathBuf>, format: &Format) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut new_format = format.keys_only();
+        new_format.set_checksum(format.checksum());
+
+        let cold = read_cold(&dir)?;
+        let mut segments = BTreeMap::new();
+        for &id in &cold {
+            let Some(cold_dir) = &cold_dir else {
+                bail!("value log segment {} is in the cold tier, but no cold directory is configured", id);
+            };
+            let mut segment = Segment::open(segment_path(cold_dir, id), format.keys_only())?;
+            segment.cold = true;
+            segments.insert(id, segment);
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(id) = segment_id(&path) else { continue };
+            if cold.contains(&id) {
+                // Moved, but not yet deleted here
+                fs::remove_file(&path)?;
+            } else {
+                segments.insert(id, Segment::open(path, format.keys_only())?);
+            }
+        }
+        // Copies of segments collected, or cut short, before `COLD` listed
+        // them
+        if let Some(cold_dir) = cold_dir.as_ref().filter(|dir| dir.is_dir()) {
+            for entry in fs::read_dir(cold_dir)? {
+                let path = entry?.path();
+                let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+                let stale = match segment_id(&path) {
+                    Some(id) => !cold.contains(&id),
+                    None => name.ends_with(".vlog.tmp"),
+                };
+                if stale {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        let writer = match segments.iter().next_back() {
+            Some((_, segment)) => OpenOptions::new().append(true).open(&segment.path)?,
+            None => {
+                let (segment, writer) = Segment::create(&dir, 1, &new_format)?;
+                segments.insert(1, segment);
+                writer
+            }
+        };
+        Ok(Self { dir, cold_dir, segments, writer, format: new_format, dirty: false, encoded: Vec::new() })
+    }
+
+    /// Whether `dir` holds a value log
+    pub(crate) fn exists(dir: &Path) -> bool {
+        dir.is_dir()
+    }
+
+    /// Append the key and value of `record` to the newest segment
+    pub(crate) fn append(&mut self, record: &Record, compression: Compression) -> Result<ValuePointer> {
+        if self.newest().len >= SEGMENT_SIZE {
+            self.roll()?;
+        }
+        let id = self.newest_id();
+        let segment = self.segments.get_mut(&id).unwrap();
+        record.encode_into(compression, &segment.format, &mut self.encoded)?;
+        let pointer = ValuePointer {
+            segment: id,
+            offset: segment.len,
+            len: u32::try_from(self.encoded.len())
+                .map_err(|_| anyhow!("value of {} bytes is too large", record.value.len()))?,
+        };
+        if let Err(e) = self.writer.write_all(&self.encoded) {
+            // Cut off what did get written, so the next value goes where
+            // its pointer says
+            self.writer.set_len(segment.len)?;
+            return Err(e.into());
+        }
+        segment.len += self.encoded.len() as u64;
+        if self.encoded.capacity() > MAX_REUSED_BUFFER {
+            self.encoded = Vec::new();
+        }
+        self.dirty = true;
+        Ok(pointer)
+    }
+
+    /// Seal the newest segment and start appending to a fresh one
+    pub(crate) fn roll(&mut self) -> Result<()> {
+        self.sync()?;
+        let id = self.newest_id().checked_add(1).ok_or_else(|| anyhow!("value log segment ids exhausted"))?;
+        let (segment, writer) = Segment::create(&self.dir, id, &self.format)?;
+        self.segments.insert(id, segment);
+        self.writer = writer;
+        Ok(())
+    }
+
+    /// Read and check the value `pointer` refers to, which must be stored
+    /// under `key`
+    pub(crate) fn read(&self, pointer: ValuePointer, key: &[u8]) -> Result<Vec<u8>> {
+        let segment = self
+            .segments
+            .get(&pointer.segment)
+            .ok_or_else(|| anyhow!("value log segment {} is missing", pointer.segment))?;
+        let mut buf = vec![0u8; pointer.len as usize];
+        read_exact_at(&segment.file, &mut buf, pointer.offset)
+            .with_context(|| format!("reading {} at offset {}", segment.path.display(), pointer.offset))?;
+        let (record, size) = Record::decode_with(&buf, &segment.format)
+            .with_context(|| format!("value at offset {} of {}", pointer.offset, segment.path.display()))?;
+        if size != buf.len() || record.key != key {
+            bail!("value pointer to offset {} of {} does not match its record", pointer.offset, segment.path.display());
+        }
+        Ok(record.value)
+    }
+
+    /// Whether `pointer` could refer to a value that made it to disk: the
+    /// segment is gone (collected, so a later record replaced this one), or
+    /// a record header of the right length sits where it points. Checks
+    /// only the header, so opening does not read every value.
+    pub(crate) fn written(&self, pointer: ValuePointer) -> Result<bool> {
+        let Some(segment) = self.segments.get(&pointer.segment) else { return Ok(true) };
+        if pointer.offset.saturating_add(pointer.len as u64) > segment.len {
+            return Ok(false);
+        }
+        let mut header = vec![0u8; MAX_HEADER_SIZE.min(pointer.len as usize)];
+        read_exact_at(&segment.file, &mut header, pointer.offset)?;
+        Ok(Record::header_frame_len_with(&header, &segment.format) == Some(pointer.len as usize))
+    }
+
+    /// Make every value appended so far durable
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            self.writer.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Link the segments into `dir`, which must not exist, but for the
+    /// newest, which is copied up to what was appended so far, for
+    /// `Engine::checkpoint`. Call `sync` first.
+    pub(crate) fn checkpoint(&self, dir: &Path) -> Result<()> {
+        fs::create_dir(dir)?;
+        let newest = self.newest_id();
+        for (&id, segment) in &self.segments {
+            let to = dir.join(segment.path.file_name().unwrap());
+            if id == newest {
+                copy_prefix(&segment.path, &to, segment.len)?;
+            } else {
+                link_or_copy(&segment.path, &to)?;
+            }
+        }
+        sync_parent_dir(&dir.join(format!("{:08}.vlog", newest)))?;
+        Ok(())
+    }
+
+    /// Ids of all segments, oldest first
+    pub(crate) fn segment_ids(&self) -> Vec<u32> {
+        self.segments.keys().copied().collect()
+    }
+
+    pub(crate) fn newest_id(&self) -> u32 {
+        *self.segments.keys().next_back().unwrap()
+    }
+
+    fn newest(&self) -> &Segment {
+        self.segments.values().next_back().unwrap()
+    }
+
+    /// Bytes of the segments kept in the value log directory, not those
+    /// moved to the cold tier
+    pub(crate) fn local_size(&self) -> u64 {
+        self.segments.values().filter(|segment| !segment.cold).map(|segment| segment.len).sum()
+    }
+
+    pub(crate) fn segment_len(&self, id: u32) -> u64 {
+        self.segments.get(&id).map_or(0, |segment| segment.len)
+    }
+
+    /// Where the records of segment `id` start
+    pub(crate) fn segment_start(&self, id: u32) -> u64 {
+        self.segments.get(&id).map_or(0, |segment| segment.start)
+    }
+
+    /// Every value in segment `id` with its key and pointer, or `None` if
+    /// the segment has a damaged or unreadable record before its end, so
+    /// not all of its values are known. Damage at the very end is a torn
+    /// write that no pointer refers to.
+    pub(crate) fn values(&self, id: u32) -> Result<Option<Vec<StoredValue>>> {
+        let Some(segment) = self.segments.get(&id) else { return Ok(Some(Vec::new())) };
+        let mut buf = Vec::new();
+        File::open(&segment.path)?.read_to_end(&mut buf)?;
+        let mut values = Vec::new();
+        for item in Scanner::with_format(&buf, segment.start as usize, &segment.format) {
+            match item {
+                ScanItem::Valid { offset, size, record } => {
+                    let pointer = ValuePointer { segment: id, offset, len: size as u32 };
+                    values.push((record, pointer));
+                }
+                ScanItem::Corrupt { offset, len, .. } if offset as usize + len == buf.len() => {}
+                ScanItem::Corrupt { .. } | ScanItem::Unreadable { .. } => return Ok(None),
+            }
+        }
+        Ok(Some(values))
+    }
+
+    /// Delete segment `id`; never the newest
+    pub(crate) fn remove(&mut self, id: u32) -> Result<()> {
+        if id == self.newest_id() {
+            bail!("cannot remove the value log segment being appended to");
+        }
+        if let Some(segment) = self.segments.remove(&id) {
+            if segment.cold {
+                self.write_cold()?;
+            }
+            fs::remove_file(&segment.path)?;
+            sync_parent_dir(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    /// Move sealed segment `id` to the cold directory; false if there is
+    /// none, or the segment is already there
+    pub(crate) fn move_cold(&mut self, id: u32) -> Result<bool> {
+        if id == self.newest_id() {
+            bail!("cannot move the value log segment being appended to");
+        }
+        let Some(cold_dir) = &self.cold_dir else { return Ok(false) };
+        let Some(segment) = self.segments.get(&id).filter(|segment| !segment.cold) else { return Ok(false) };
+        fs::create_dir_all(cold_dir)?;
+        let to = segment_path(cold_dir, id);
+        copy_durably(&segment.path, &to)?;
+        sync_parent_dir(&to)?;
+        let file = File::open(&to).with_context(|| format!("opening {}", to.display()))?;
+        let segment = self.segments.get_mut(&id).unwrap();
+        segment.cold = true;
+        let local = std::mem::replace(&mut segment.path, to);
+        segment.file = file;
+        self.write_cold()?;
+        fs::remove_file(&local)?;
+        sync_parent_dir(&local)?;
+        Ok(true)
+    }
+
+    /// Replace `COLD` with the list of segments now in the cold directory
+    fn write_cold(&self) -> Result<()> {
+        let cold: Vec<u32> = self.segments.iter().filter(|(_, segment)| segment.cold).map(|(&id, _)| id).collect();
+        let mut buf = Vec::with_capacity(12 + cold.len() * 4);
+        buf.extend_from_slice(&COLD_MAGIC);
+        buf.extend_from_slice(&(cold.len() as u32).to_le_bytes());
+        for id in &cold {
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        let path = self.dir.join(COLD);
+        let tmp_path = self.dir.join(format!("{}.tmp", COLD));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_parent_dir(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn add_encryption_key(&mut self, id: u32, key: &[u8; 32]) {
+        self.format.add_encryption_key(id, key);
+        for segment in self.segments.values_mut() {
+            segment.format.add_encryption_key(id, key);
+        }
+    }
+}
+
+impl Segment {
+    fn open(path: PathBuf, mut format: Format) -> Result<Self> {
+        let mut file = File::open(&path).with_context(|| format!("opening {}", path.display()))?;
+        let start = format.read_header_from(&mut file).with_context(|| format!("reading {}", path.display()))? as u64;
+        let len = file.metadata()?.len();
+        Ok(Self { path, file, start, len, format, cold: false })
+    }
+
+    /// A new, durable segment holding just the header for `format`, and an
+    /// append handle to it
+    fn create(dir: &Path, id: u32, format: &Format) -> Result<(Self, File)> {
+        let path = segment_path(dir, id);
+        let mut options = OpenOptions::new();
+        options.create_new(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut writer = options.open(&path)?;
+        writer.write_all(&format.encode_header())?;
+        writer.sync_all()?;
+        sync_parent_dir(&path)?;
+        Ok((Segment::open(path, format.keys_only())?, writer))
+    }
+}
+
+fn segment_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:08}.vlog", id))
+}
+
+/// The id of segment file `path`, if it is one
+fn segment_id(path: &Path) -> Option<u32> {
+    path.file_name()?.to_str()?.strip_suffix(".vlog")?.parse().ok()
+}
+
+/// The segments `COLD` in `dir` lists; none if there is no `COLD`
+fn read_cold(dir: &Path) -> Result<Vec<u32>> {
+    let path = dir.join(COLD);
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    if buf.len() < 12 || !buf.starts_with(&COLD_MAGIC) {
+        bail!("{} is not a cold segment list", path.display());
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        bail!("cold segment list CRC mismatch");
+    }
+    let count = u32::from_le_bytes(body[4..8].try_into().unwrap()) as usize;
+    if body.len() != 8 + count * 4 {
+        bail!("cold segment list is {} bytes, expected {}", body.len(), 8 + count * 4);
+    }
+    Ok(body[8..].chunks_exact(4).map(|id| u32::from_le_bytes(id.try_into().unwrap())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &Path, cold_dir: Option<&Path>) -> Result<ValueLog> {
+        ValueLog::open(dir.join("data.db.vlog"), cold_dir.map(Path::to_path_buf), &Format::default())
+    }
+
+    fn append(value_log: &mut ValueLog, key: &[u8], value: Vec<u8>) -> ValuePointer {
+        value_log.append(&Record::new(key.to_vec(), value), Compression::None).unwrap()
+    }
+
+    #[test]
+    fn pointers_round_trip_and_reject_other_lengths() {
+        let pointer = ValuePointer { segment: 7, offset: 1 << 40, len: 65_536 };
+        assert_eq!(ValuePointer::decode(&pointer.encode()).unwrap(), pointer);
+        assert!(ValuePointer::decode(&pointer.encode()[..15]).is_err());
+    }
+
+    #[test]
+    fn values_survive_a_roll_and_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut value_log = open(dir.path(), None).unwrap();
+        let first = append(&mut value_log, b"a", vec![1; 5000]);
+        value_log.roll().unwrap();
+        let second = append(&mut value_log, b"b", vec![2; 5000]);
+        assert_eq!((first.segment, second.segment), (1, 2));
+        value_log.sync().unwrap();
+        drop(value_log);
+
+        let value_log = open(dir.path(), None).unwrap();
+        assert_eq!(value_log.segment_ids(), vec![1, 2]);
+        assert_eq!(value_log.read(first, b"a").unwrap(), vec![1; 5000]);
+        assert_eq!(value_log.read(second, b"b").unwrap(), vec![2; 5000]);
+        // A pointer must name the key its record holds
+        assert!(value_log.read(first, b"b").is_err());
+        let values = value_log.values(1).unwrap().unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1, first);
+    }
+
+    #[test]
+    fn written_checks_the_header_a_pointer_lands_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut value_log = open(dir.path(), None).unwrap();
+        let pointer = append(&mut value_log, b"a", vec![1; 100]);
+        assert!(value_log.written(pointer).unwrap());
+        // Past the end, as after a crash that lost the append
+        let lost = ValuePointer { offset: pointer.offset + pointer.len as u64, ..pointer };
+        assert!(!value_log.written(lost).unwrap());
+        // Not at the start of a record of that length
+        assert!(!value_log.written(ValuePointer { len: pointer.len - 1, ..pointer }).unwrap());
+    }
+
+    #[test]
+    fn pointers_to_a_collected_segment_count_as_written_but_fail_to_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut value_log = open(dir.path(), None).unwrap();
+        let pointer = append(&mut value_log, b"a", vec![1; 100]);
+        assert!(value_log.remove(1).is_err(), "removed the newest segment");
+        value_log.roll().unwrap();
+        value_log.remove(1).unwrap();
+        assert!(!segment_path(&dir.path().join("data.db.vlog"), 1).exists());
+        // A later record replaced the one pointing here
+        assert!(value_log.written(pointer).unwrap());
+        let e = value_log.read(pointer, b"a").unwrap_err();
+        assert!(format!("{:#}", e).contains("segment 1 is missing"), "{:#}", e);
+    }
+
+    #[test]
+    fn cold_segments_are_read_from_the_cold_dir_and_needed_to_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let cold_dir = dir.path().join("cold");
+        let mut value_log = open(dir.path(), Some(&cold_dir)).unwrap();
+        let pointer = append(&mut value_log, b"a", vec![1; 100]);
+        assert!(value_log.move_cold(1).is_err(), "moved the newest segment");
+        value_log.roll().unwrap();
+        assert!(value_log.move_cold(1).unwrap());
+        assert!(!value_log.move_cold(1).unwrap());
+        assert!(!segment_path(&dir.path().join("data.db.vlog"), 1).exists());
+        assert!(segment_path(&cold_dir, 1).exists());
+        assert_eq!(value_log.local_size(), value_log.segment_len(2));
+        assert_eq!(value_log.read(pointer, b"a").unwrap(), vec![1; 100]);
+        drop(value_log);
+
+        let e = open(dir.path(), None).err().unwrap();
+        assert!(format!("{:#}", e).contains("segment 1 is in the cold tier"), "{:#}", e);
+        let mut value_log = open(dir.path(), Some(&cold_dir)).unwrap();
+        assert_eq!(value_log.read(pointer, b"a").unwrap(), vec![1; 100]);
+        assert_eq!(read_cold(&dir.path().join("data.db.vlog")).unwrap(), vec![1]);
+
+        // Collecting a cold segment takes it off the list
+        value_log.remove(1).unwrap();
+        assert!(read_cold(&dir.path().join("data.db.vlog")).unwrap().is_empty());
+        assert!(!segment_path(&cold_dir, 1).exists());
+    }
+
+    #[test]
+    fn a_copy_left_locally_after_the_move_is_deleted_at_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let cold_dir = dir.path().join("cold");
+        let vlog_dir = dir.path().join("data.db.vlog");
+        let mut value_log = open(dir.path(), Some(&cold_dir)).unwrap();
+        let pointer = append(&mut value_log, b"a", vec![1; 100]);
+        value_log.roll().unwrap();
+        value_log.move_cold(1).unwrap();
+        drop(value_log);
+        // As if the crash came between writing `COLD` and the delete
+        fs::copy(segment_path(&cold_dir, 1), segment_path(&vlog_dir, 1)).unwrap();
+        // And a copy that never made it into `COLD`
+        fs::copy(segment_path(&cold_dir, 1), segment_path(&cold_dir, 9)).unwrap();
+
+        let value_log = open(dir.path(), Some(&cold_dir)).unwrap();
+        assert!(!segment_path(&vlog_dir, 1).exists());
+        assert!(!segment_path(&cold_dir, 9).exists());
+        assert_eq!(value_log.segment_ids(), vec![1, 2]);
+        assert_eq!(value_log.read(pointer, b"a").unwrap(), vec![1; 100]);
+    }
+
+    #[test]
+    fn damaged_cold_list_fails_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let cold_dir = dir.path().join("cold");
+        let mut value_log = open(dir.path(), Some(&cold_dir)).unwrap();
+        append(&mut value_log, b"a", vec![1; 100]);
+        value_log.roll().unwrap();
+        value_log.move_cold(1).unwrap();
+        drop(value_log);
+        let path = dir.path().join("data.db.vlog").join(COLD);
+        let mut buf = fs::read(&path).unwrap();
+        buf[8] ^= 1;
+        fs::write(&path, &buf).unwrap();
+        assert!(open(dir.path(), Some(&cold_dir)).is_err());
+    }
+}
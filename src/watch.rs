@@ -0,0 +1,69 @@
+//! Change notifications, for `Engine::watch`
+//!
+//! Each watcher is a channel with a key prefix. After a put or delete has
+//! updated the index, so a `get()` already sees it, every watcher whose
+//! prefix the key starts with is sent a `ChangeEvent`. Channels are
+//! unbounded, so a slow watcher never stalls writes; one whose receiver
+//! was dropped is forgotten at the next event it would get.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// What a write did to its key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Put,
+    Delete,
+}
+
+/// One write seen by `Engine::watch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: Vec<u8>,
+    pub op: ChangeOp,
+    /// Writes the engine has taken since it was opened, this one included;
+    /// a watcher sees them in increasing order
+    pub seq: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Vec<(Vec<u8>, Sender<ChangeEvent>)>,
+    seq: u64,
+}
+
+impl Watchers {
+    pub(crate) fn subscribe(&mut self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.watchers.push((prefix.to_vec(), tx));
+        rx
+    }
+
+    /// Whether any watcher wants writes to `key`, so the caller knows to
+    /// keep a copy of it for `notify`
+    pub(crate) fn wants(&self, key: &[u8]) -> bool {
+        self.watchers.iter().any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Count a write and tell the watchers interested in its key, which is
+    /// `None` if `wants` said there are none
+    pub(crate) fn notify(&mut self, key: Option<Vec<u8>>, op: ChangeOp) {
+        self.seq += 1;
+        let Some(key) = key else { return };
+        let seq = self.seq;
+        self.watchers.retain(|(prefix, tx)| {
+            !key.starts_with(prefix) || tx.send(ChangeEvent { key: key.clone(), op, seq }).is_ok()
+        });
+    }
+
+    /// `notify` for one write that changed every key in `keys`, which are
+    /// those `wants` said a watcher is interested in
+    pub(crate) fn notify_all(&mut self, keys: Vec<Vec<u8>>, op: ChangeOp) {
+        self.seq += 1;
+        let seq = self.seq;
+        for key in keys {
+            self.watchers.retain(|(prefix, tx)| {
+                !key.starts_with(prefix) || tx.send(ChangeEvent { key: key.clone(), op, seq }).is_ok()
+            });
+        }
+    }
+}